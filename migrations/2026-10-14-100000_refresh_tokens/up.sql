@@ -0,0 +1,11 @@
+create table refresh_tokens (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    family text not null,
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp,
+    revoked_at timestamp
+);
+
+create index refresh_tokens_family on refresh_tokens (family);
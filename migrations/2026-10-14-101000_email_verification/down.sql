@@ -0,0 +1,14 @@
+drop table verification_tokens;
+
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null
+);
+
+insert into users_old (id, email, name, pass)
+select id, email, name, pass from users;
+
+drop table users;
+alter table users_old rename to users;
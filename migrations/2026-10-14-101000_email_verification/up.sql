@@ -0,0 +1,11 @@
+alter table users add column email_verified boolean not null default 0;
+
+-- accounts created before verification existed are trusted as-is
+update users set email_verified = 1;
+
+create table verification_tokens (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    expires_at timestamp not null
+);
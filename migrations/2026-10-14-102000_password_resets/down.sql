@@ -0,0 +1,15 @@
+drop table password_resets;
+
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0
+);
+
+insert into users_old (id, email, name, pass, email_verified)
+select id, email, name, pass, email_verified from users;
+
+drop table users;
+alter table users_old rename to users;
@@ -0,0 +1,8 @@
+alter table users add column session_version integer not null default 0;
+
+create table password_resets (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    expires_at timestamp not null
+);
@@ -0,0 +1,9 @@
+create table user_identities (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    provider text not null,
+    subject text not null,
+    email text,
+    created_at timestamp not null default current_timestamp,
+    unique (provider, subject)
+);
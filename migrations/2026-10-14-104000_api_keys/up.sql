@@ -0,0 +1,9 @@
+create table api_keys (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    name text not null,
+    prefix text not null,
+    key_hash text not null unique,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp
+);
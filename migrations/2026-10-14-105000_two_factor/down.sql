@@ -0,0 +1,16 @@
+drop table recovery_codes;
+
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0,
+    session_version integer not null default 0
+);
+
+insert into users_old (id, email, name, pass, email_verified, session_version)
+select id, email, name, pass, email_verified, session_version from users;
+
+drop table users;
+alter table users_old rename to users;
@@ -0,0 +1,10 @@
+alter table users add column totp_secret text;
+alter table users add column totp_enabled boolean not null default 0;
+alter table users add column totp_last_step bigint;
+
+create table recovery_codes (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    code_hash text not null,
+    used_at timestamp
+);
@@ -0,0 +1,37 @@
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0,
+    session_version integer not null default 0,
+    totp_secret text,
+    totp_enabled boolean not null default 0,
+    totp_last_step bigint
+);
+
+insert into users_old (id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step)
+select id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step from users;
+
+drop table users;
+alter table users_old rename to users;
+
+create table refresh_tokens_old (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    family text not null,
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp,
+    revoked_at timestamp
+);
+
+insert into refresh_tokens_old (id, user_id, token_hash, family, expires_at, created_at, revoked_at)
+select id, user_id, token_hash, family, expires_at, created_at, revoked_at from refresh_tokens;
+
+drop table refresh_tokens;
+alter table refresh_tokens_old rename to refresh_tokens;
+
+create index refresh_tokens_family on refresh_tokens (family);
+
+drop table sessions;
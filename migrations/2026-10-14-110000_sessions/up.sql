@@ -0,0 +1,30 @@
+create table sessions (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    user_agent text,
+    ip text,
+    created_at timestamp not null default current_timestamp,
+    last_seen_at timestamp not null default current_timestamp,
+    revoked_at timestamp
+);
+
+alter table refresh_tokens add column session_id integer references sessions (id);
+
+-- sessions now live in their own table
+create table users_new (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0,
+    totp_secret text,
+    totp_enabled boolean not null default 0,
+    totp_last_step bigint
+);
+
+insert into users_new (id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step)
+select id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step from users;
+
+drop table users;
+alter table users_new rename to users;
@@ -0,0 +1,7 @@
+create table email_changes (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    new_email text not null,
+    token_hash text not null unique,
+    expires_at timestamp not null
+);
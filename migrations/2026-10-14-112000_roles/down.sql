@@ -0,0 +1,16 @@
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0,
+    totp_secret text,
+    totp_enabled boolean not null default 0,
+    totp_last_step bigint
+);
+
+insert into users_old (id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step)
+select id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step from users;
+
+drop table users;
+alter table users_old rename to users;
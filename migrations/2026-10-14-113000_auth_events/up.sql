@@ -0,0 +1,10 @@
+create table auth_events (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    kind text not null,
+    ip text,
+    user_agent text,
+    created_at timestamp not null default current_timestamp
+);
+
+create index auth_events_user_id on auth_events (user_id, created_at);
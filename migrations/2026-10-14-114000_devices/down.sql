@@ -0,0 +1,18 @@
+create table sessions_old (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    user_agent text,
+    ip text,
+    created_at timestamp not null default current_timestamp,
+    last_seen_at timestamp not null default current_timestamp,
+    revoked_at timestamp
+);
+
+insert into sessions_old (id, user_id, token_hash, user_agent, ip, created_at, last_seen_at, revoked_at)
+select id, user_id, token_hash, user_agent, ip, created_at, last_seen_at, revoked_at from sessions;
+
+drop table sessions;
+alter table sessions_old rename to sessions;
+
+drop table devices;
@@ -0,0 +1,13 @@
+create table devices (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    user_agent text,
+    ip text,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp not null default current_timestamp,
+    expires_at timestamp not null,
+    revoked_at timestamp
+);
+
+alter table sessions add column device_id integer references devices (id);
@@ -0,0 +1,6 @@
+create table magic_links (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    expires_at timestamp not null
+);
@@ -0,0 +1 @@
+alter table users add column is_guest boolean not null default 0;
@@ -0,0 +1,9 @@
+create table webauthn_credentials (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    credential_id text not null unique,
+    credential text not null,
+    name text not null,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp
+);
@@ -0,0 +1,15 @@
+create table api_keys_old (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    name text not null,
+    prefix text not null,
+    key_hash text not null unique,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp
+);
+
+insert into api_keys_old (id, user_id, name, prefix, key_hash, created_at, last_used_at)
+select id, user_id, name, prefix, key_hash, created_at, last_used_at from api_keys;
+
+drop table api_keys;
+alter table api_keys_old rename to api_keys;
@@ -0,0 +1,2 @@
+-- Keys created before scopes existed keep full access.
+alter table api_keys add column scopes text not null default 'loyalties:read loyalties:write account:read';
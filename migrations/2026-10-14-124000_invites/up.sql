@@ -0,0 +1,10 @@
+create table invites (
+    id integer primary key autoincrement not null,
+    code_hash text not null unique,
+    created_by integer not null references users (id),
+    max_uses integer not null default 1,
+    uses integer not null default 0,
+    expires_at timestamp,
+    revoked_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
@@ -0,0 +1,6 @@
+alter table sessions add column expires_at timestamp not null default '1970-01-01 00:00:00';
+
+-- Open sessions get a full idle period, capped by the absolute max age.
+update sessions
+set expires_at = min(datetime('now', '+14 days'), datetime(created_at, '+90 days'))
+where revoked_at is null;
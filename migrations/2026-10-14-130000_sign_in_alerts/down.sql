@@ -0,0 +1,38 @@
+drop table account_locks;
+
+create table users_old (
+    id integer primary key autoincrement not null,
+    email text not null unique,
+    name text not null,
+    pass text not null,
+    email_verified boolean not null default 0,
+    totp_secret text,
+    totp_enabled boolean not null default 0,
+    totp_last_step bigint,
+    role text not null default 'user',
+    deleted_at timestamp,
+    is_guest boolean not null default 0
+);
+
+insert into users_old (id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step, role, deleted_at, is_guest)
+select id, email, name, pass, email_verified, totp_secret, totp_enabled, totp_last_step, role, deleted_at, is_guest from users;
+
+drop table users;
+alter table users_old rename to users;
+
+create table auth_events_old (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    kind text not null,
+    ip text,
+    user_agent text,
+    created_at timestamp not null default current_timestamp
+);
+
+insert into auth_events_old (id, user_id, kind, ip, user_agent, created_at)
+select id, user_id, kind, ip, user_agent, created_at from auth_events;
+
+drop table auth_events;
+alter table auth_events_old rename to auth_events;
+
+create index auth_events_user_id on auth_events (user_id, created_at);
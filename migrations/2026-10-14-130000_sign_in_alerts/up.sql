@@ -0,0 +1,9 @@
+alter table auth_events add column country text;
+alter table users add column locked_at timestamp;
+
+create table account_locks (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    token_hash text not null unique,
+    expires_at timestamp not null
+);
@@ -0,0 +1,13 @@
+create table cards_old (
+    id integer primary key autoincrement not null,
+    name text not null,
+    color text,
+    code text not null,
+    user_id integer not null references users (id)
+);
+
+insert into cards_old (id, name, color, code, user_id)
+select id, name, color, code, user_id from cards;
+
+drop table cards;
+alter table cards_old rename to cards;
@@ -0,0 +1 @@
+alter table cards add column barcode_type text not null default 'code128';
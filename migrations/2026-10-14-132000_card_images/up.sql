@@ -0,0 +1,8 @@
+create table card_images (
+    id integer primary key autoincrement not null,
+    card_id integer not null unique references cards (id),
+    storage_key text not null,
+    content_type text not null,
+    size integer not null,
+    created_at timestamp not null default current_timestamp
+);
@@ -0,0 +1,16 @@
+create table card_images_old (
+    id integer primary key autoincrement not null,
+    card_id integer not null unique references cards (id),
+    storage_key text not null,
+    content_type text not null,
+    size integer not null,
+    created_at timestamp not null default current_timestamp
+);
+
+-- Back photos have nowhere to go.
+insert into card_images_old (id, card_id, storage_key, content_type, size, created_at)
+select id, card_id, storage_key, content_type, size, created_at from card_images
+where side = 'front';
+
+drop table card_images;
+alter table card_images_old rename to card_images;
@@ -0,0 +1,17 @@
+-- SQLite can't drop the unique constraint on card_id in place.
+create table card_images_new (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    side text not null default 'front',
+    storage_key text not null,
+    content_type text not null,
+    size integer not null,
+    created_at timestamp not null default current_timestamp,
+    unique (card_id, side)
+);
+
+insert into card_images_new (id, card_id, side, storage_key, content_type, size, created_at)
+select id, card_id, 'front', storage_key, content_type, size, created_at from card_images;
+
+drop table card_images;
+alter table card_images_new rename to card_images;
@@ -0,0 +1,2 @@
+drop table card_tags;
+drop table categories;
@@ -0,0 +1,15 @@
+create table categories (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users (id),
+    name text not null collate nocase,
+    created_at timestamp not null default current_timestamp,
+    unique (user_id, name)
+);
+
+create table card_tags (
+    card_id integer not null references cards (id),
+    category_id integer not null references categories (id),
+    primary key (card_id, category_id)
+);
+
+create index card_tags_category_id on card_tags (category_id);
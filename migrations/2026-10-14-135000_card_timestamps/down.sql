@@ -0,0 +1,14 @@
+create table cards_old (
+    id integer primary key autoincrement not null,
+    name text not null,
+    color text,
+    code text not null,
+    user_id integer not null references users (id),
+    barcode_type text not null default 'code128'
+);
+
+insert into cards_old (id, name, color, code, user_id, barcode_type)
+select id, name, color, code, user_id, barcode_type from cards;
+
+drop table cards;
+alter table cards_old rename to cards;
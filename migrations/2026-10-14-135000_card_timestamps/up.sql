@@ -0,0 +1,5 @@
+alter table cards add column created_at timestamp not null default '1970-01-01 00:00:00';
+alter table cards add column last_used_at timestamp;
+
+-- Existing cards keep their insertion order.
+update cards set created_at = datetime('now');
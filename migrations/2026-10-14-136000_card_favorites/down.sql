@@ -0,0 +1,16 @@
+create table cards_old (
+    id integer primary key autoincrement not null,
+    name text not null,
+    color text,
+    code text not null,
+    user_id integer not null references users (id),
+    barcode_type text not null default 'code128',
+    created_at timestamp not null default '1970-01-01 00:00:00',
+    last_used_at timestamp
+);
+
+insert into cards_old (id, name, color, code, user_id, barcode_type, created_at, last_used_at)
+select id, name, color, code, user_id, barcode_type, created_at, last_used_at from cards;
+
+drop table cards;
+alter table cards_old rename to cards;
@@ -0,0 +1 @@
+alter table cards add column is_favorite boolean not null default 0;
@@ -0,0 +1,4 @@
+alter table cards add column position integer not null default 0;
+
+-- Cards keep their insertion order until they are rearranged.
+update cards set position = id;
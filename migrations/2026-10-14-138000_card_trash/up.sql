@@ -0,0 +1 @@
+alter table cards add column deleted_at timestamp;
@@ -0,0 +1,21 @@
+create table cards_old (
+    id integer primary key autoincrement not null,
+    name text not null,
+    color text,
+    code text not null,
+    user_id integer not null references users (id),
+    barcode_type text not null default 'code128',
+    created_at timestamp not null default '1970-01-01 00:00:00',
+    last_used_at timestamp,
+    is_favorite boolean not null default 0,
+    position integer not null default 0,
+    deleted_at timestamp,
+    archived_at timestamp,
+    notes text
+);
+
+insert into cards_old (id, name, color, code, user_id, barcode_type, created_at, last_used_at, is_favorite, position, deleted_at, archived_at, notes)
+select id, name, color, code, user_id, barcode_type, created_at, last_used_at, is_favorite, position, deleted_at, archived_at, notes from cards;
+
+drop table cards;
+alter table cards_old rename to cards;
@@ -0,0 +1 @@
+alter table cards add column expires_at date;
@@ -0,0 +1,10 @@
+create table card_shares (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    access text not null,
+    created_at timestamp not null default current_timestamp,
+    unique (card_id, user_id)
+);
+
+create index card_shares_user_id on card_shares (user_id);
@@ -0,0 +1,28 @@
+create table "groups" (
+    id integer primary key autoincrement not null,
+    name text not null,
+    owner_id integer not null references users (id),
+    created_at timestamp not null default current_timestamp
+);
+
+create table group_members (
+    group_id integer not null references "groups" (id),
+    user_id integer not null references users (id),
+    role text not null default 'member',
+    joined_at timestamp not null default current_timestamp,
+    primary key (group_id, user_id)
+);
+
+create index group_members_user_id on group_members (user_id);
+
+create table group_invitations (
+    id integer primary key autoincrement not null,
+    group_id integer not null references "groups" (id),
+    email text not null,
+    token_hash text not null unique,
+    invited_by integer not null references users (id),
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp
+);
+
+alter table cards add column group_id integer references "groups" (id);
@@ -0,0 +1,12 @@
+create table share_links (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    token_hash text not null unique,
+    expires_at timestamp not null,
+    revoked_at timestamp,
+    view_count integer not null default 0,
+    last_viewed_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
+
+create index share_links_card_id on share_links (card_id);
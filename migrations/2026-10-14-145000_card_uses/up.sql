@@ -0,0 +1,11 @@
+alter table cards add column use_count integer not null default 0;
+
+create table card_uses (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    used_at timestamp not null default current_timestamp
+);
+
+create index card_uses_card_id on card_uses (card_id);
+create index card_uses_user_id_used_at on card_uses (user_id, used_at);
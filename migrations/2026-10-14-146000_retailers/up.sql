@@ -0,0 +1,26 @@
+create table retailers (
+    id integer primary key autoincrement not null,
+    name text not null unique collate nocase,
+    color text,
+    barcode_type text not null default 'code128',
+    logo_url text
+);
+
+insert into retailers (name, color, barcode_type) values
+    ('Boots', '#05054B', 'ean13'),
+    ('Carrefour', '#004E9F', 'ean13'),
+    ('Costco', '#E31837', 'code128'),
+    ('CVS ExtraCare', '#CC0000', 'code128'),
+    ('Decathlon', '#0082C3', 'ean13'),
+    ('IKEA Family', '#0058A3', 'qr'),
+    ('Kroger', '#21409A', 'upca'),
+    ('Lidl Plus', '#0050AA', 'qr'),
+    ('Safeway', '#E31837', 'upca'),
+    ('Sainsbury''s Nectar', '#7F2FB5', 'ean13'),
+    ('Sephora', '#000000', 'code128'),
+    ('Starbucks', '#00704A', 'qr'),
+    ('Target Circle', '#CC0000', 'pdf417'),
+    ('Tesco Clubcard', '#00539F', 'ean13'),
+    ('Walgreens', '#E31837', 'upca');
+
+alter table cards add column retailer_id integer references retailers (id);
@@ -0,0 +1 @@
+drop table retailer_logos;
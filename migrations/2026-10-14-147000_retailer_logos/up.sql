@@ -0,0 +1,7 @@
+-- Logos found for card names, keyed by the lowercased name. Names without a
+-- logo are kept too, with a null url, so they aren't looked up every time.
+create table retailer_logos (
+    name text primary key not null,
+    logo_url text,
+    fetched_at timestamp not null
+);
@@ -0,0 +1,16 @@
+-- The editable fields of a card as they were before each edit.
+create table card_revisions (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    changed_at timestamp not null default current_timestamp,
+    name text not null,
+    color text,
+    code text not null,
+    barcode_type text not null,
+    notes text,
+    expires_at date,
+    retailer_id integer references retailers (id)
+);
+
+create index card_revisions_card_id on card_revisions (card_id);
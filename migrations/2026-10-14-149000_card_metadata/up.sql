@@ -0,0 +1,7 @@
+-- Fields of a card the schema has no column for. Values are encrypted.
+create table card_metadata (
+    card_id integer not null references cards (id),
+    key text not null,
+    value text not null,
+    primary key (card_id, key)
+);
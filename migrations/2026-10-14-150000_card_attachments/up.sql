@@ -0,0 +1,11 @@
+create table card_attachments (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    file_name text not null,
+    storage_key text not null,
+    content_type text not null,
+    size integer not null,
+    created_at timestamp not null default current_timestamp
+);
+
+create index card_attachments_card_id on card_attachments (card_id);
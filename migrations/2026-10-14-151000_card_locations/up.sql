@@ -0,0 +1,10 @@
+create table card_locations (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    label text not null,
+    latitude double not null,
+    longitude double not null,
+    created_at timestamp not null default current_timestamp
+);
+
+create index card_locations_card_id on card_locations (card_id);
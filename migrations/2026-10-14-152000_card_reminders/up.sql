@@ -0,0 +1,12 @@
+create table card_reminders (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    message text not null,
+    remind_at timestamp not null,
+    sent_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
+
+create index card_reminders_card_id on card_reminders (card_id);
+create index card_reminders_due on card_reminders (sent_at, remind_at);
@@ -0,0 +1,2 @@
+alter table cards add column balance text;
+alter table cards add column balance_currency text;
@@ -0,0 +1,10 @@
+create table balance_entries (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    balance text,
+    balance_currency text,
+    recorded_at timestamp not null default current_timestamp
+);
+
+create index balance_entries_card_id on balance_entries (card_id);
@@ -0,0 +1,10 @@
+create table card_transfers (
+    id integer primary key autoincrement not null,
+    card_id integer not null unique references cards (id),
+    from_user_id integer not null references users (id),
+    to_user_id integer not null references users (id),
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp
+);
+
+create index card_transfers_to_user_id on card_transfers (to_user_id);
@@ -0,0 +1 @@
+alter table cards add column is_active boolean not null default 1;
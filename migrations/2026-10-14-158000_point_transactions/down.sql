@@ -0,0 +1,2 @@
+drop trigger point_transactions_append_only;
+drop table point_transactions;
@@ -0,0 +1,17 @@
+create table point_transactions (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    delta integer not null,
+    reason text not null,
+    occurred_at timestamp not null default current_timestamp
+);
+
+create index point_transactions_card_id on point_transactions (card_id);
+
+-- Entries are never edited: a mistake is undone by another entry. They only
+-- move to another card when cards are merged.
+create trigger point_transactions_append_only
+before update of delta, reason, occurred_at on point_transactions
+begin
+    select raise(abort, 'point transactions are append-only');
+end;
@@ -0,0 +1,10 @@
+create table rewards (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    name text not null,
+    cost integer not null,
+    stock integer,
+    created_at timestamp not null default current_timestamp
+);
+
+create index rewards_retailer_id on rewards (retailer_id);
@@ -0,0 +1,8 @@
+create table user_tiers (
+    user_id integer not null references users (id),
+    retailer_id integer not null references retailers (id),
+    tier text not null,
+    lifetime_points bigint not null,
+    updated_at timestamp not null default current_timestamp,
+    primary key (user_id, retailer_id)
+);
@@ -0,0 +1,2 @@
+drop table coupon_claims;
+drop table coupons;
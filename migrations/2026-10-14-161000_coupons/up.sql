@@ -0,0 +1,22 @@
+create table coupons (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    title text not null,
+    code text not null,
+    reusable boolean not null default 0,
+    expires_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
+
+create index coupons_retailer_id on coupons (retailer_id);
+
+create table coupon_claims (
+    id integer primary key autoincrement not null,
+    coupon_id integer not null references coupons (id),
+    user_id integer not null references users (id),
+    claimed_at timestamp not null default current_timestamp,
+    redeemed_at timestamp,
+    unique (coupon_id, user_id)
+);
+
+create index coupon_claims_user_id on coupon_claims (user_id);
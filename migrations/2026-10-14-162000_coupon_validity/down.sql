@@ -0,0 +1,20 @@
+drop index coupons_valid_until;
+alter table coupons rename column valid_until to expires_at;
+
+create table coupons_old (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    title text not null,
+    code text not null,
+    reusable boolean not null default 0,
+    expires_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
+
+insert into coupons_old (id, retailer_id, title, code, reusable, expires_at, created_at)
+select id, retailer_id, title, code, reusable, expires_at, created_at from coupons;
+
+drop table coupons;
+alter table coupons_old rename to coupons;
+
+create index coupons_retailer_id on coupons (retailer_id);
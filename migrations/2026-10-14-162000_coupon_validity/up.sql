@@ -0,0 +1,4 @@
+alter table coupons rename column expires_at to valid_until;
+alter table coupons add column valid_from timestamp;
+
+create index coupons_valid_until on coupons (valid_until);
@@ -0,0 +1,11 @@
+alter table cards add column stamps_required integer;
+alter table cards add column stamps integer not null default 0;
+
+create table stamp_rewards (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    issued_at timestamp not null default current_timestamp,
+    redeemed_at timestamp
+);
+
+create index stamp_rewards_card_id on stamp_rewards (card_id);
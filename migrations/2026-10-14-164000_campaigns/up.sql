@@ -0,0 +1,13 @@
+create table campaigns (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    name text not null,
+    multiplier_percent integer not null,
+    starts_at timestamp not null,
+    ends_at timestamp not null,
+    -- Comma-separated, such as `sat,sun`; every day when null.
+    weekdays text,
+    created_at timestamp not null default current_timestamp
+);
+
+create index campaigns_retailer_id on campaigns (retailer_id, ends_at);
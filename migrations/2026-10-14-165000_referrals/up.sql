@@ -0,0 +1,14 @@
+alter table users add column referral_code text;
+create unique index users_referral_code on users (referral_code);
+
+create table referrals (
+    id integer primary key autoincrement not null,
+    referrer_id integer not null references users (id),
+    referred_id integer not null unique references users (id),
+    created_at timestamp not null default current_timestamp,
+    -- Set once the bonus is on a card of each party.
+    referred_credited_at timestamp,
+    referrer_credited_at timestamp
+);
+
+create index referrals_referrer_id on referrals (referrer_id);
@@ -0,0 +1,23 @@
+drop index point_transactions_remaining;
+
+create table point_transactions_old (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    delta integer not null,
+    reason text not null,
+    occurred_at timestamp not null default current_timestamp
+);
+
+insert into point_transactions_old (id, card_id, delta, reason, occurred_at)
+select id, card_id, delta, reason, occurred_at from point_transactions;
+
+drop table point_transactions;
+alter table point_transactions_old rename to point_transactions;
+
+create index point_transactions_card_id on point_transactions (card_id);
+
+create trigger point_transactions_append_only
+before update of delta, reason, occurred_at on point_transactions
+begin
+    select raise(abort, 'point transactions are append-only');
+end;
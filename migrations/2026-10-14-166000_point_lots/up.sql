@@ -0,0 +1,24 @@
+-- What is left of the points each earning entry gave, spent oldest first;
+-- always 0 on spending entries.
+alter table point_transactions add column remaining integer not null default 0;
+
+-- Earlier spending is taken from the oldest entries.
+update point_transactions
+set remaining = max(0, min(delta,
+    (
+        select sum(earned.delta)
+        from point_transactions earned
+        where earned.card_id = point_transactions.card_id
+            and earned.delta > 0
+            and (earned.occurred_at < point_transactions.occurred_at
+                or (earned.occurred_at = point_transactions.occurred_at
+                    and earned.id <= point_transactions.id))
+    ) - coalesce((
+        select -sum(spent.delta)
+        from point_transactions spent
+        where spent.card_id = point_transactions.card_id and spent.delta < 0
+    ), 0)))
+where delta > 0;
+
+create index point_transactions_remaining on point_transactions (occurred_at)
+where remaining > 0;
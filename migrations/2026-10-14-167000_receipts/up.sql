@@ -0,0 +1,19 @@
+create table receipts (
+    id integer primary key autoincrement not null,
+    card_id integer not null references cards (id),
+    user_id integer not null references users (id),
+    storage_key text not null,
+    content_type text not null,
+    status text not null default 'pending',
+    -- Read by the OCR service, when one is configured.
+    total text,
+    suggested_points integer,
+    ocr_at timestamp,
+    -- Credited on approval.
+    points integer,
+    reviewed_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
+
+create index receipts_card_id on receipts (card_id);
+create index receipts_status on receipts (status, id);
@@ -0,0 +1,3 @@
+drop table purchases;
+drop table pos_keys;
+drop table earning_rules;
@@ -0,0 +1,44 @@
+-- How a merchant's customers earn points, replacing fixed point math. Every
+-- rule matching a purchase adds up.
+create table earning_rules (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    -- `amount`: `points` per `per_cents` spent; `item`: `points` per unit of
+    -- `sku` bought; `visit`: `points` once per purchase.
+    kind text not null,
+    points integer not null,
+    per_cents integer,
+    sku text,
+    -- Purchases of less are ignored by the rule.
+    min_cents integer,
+    created_at timestamp not null default current_timestamp
+);
+
+create index earning_rules_retailer_id on earning_rules (retailer_id);
+
+-- Keys the points of sale of a merchant post purchases with.
+create table pos_keys (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    name text not null,
+    prefix text not null,
+    key_hash text not null unique,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp
+);
+
+-- `reference` is the merchant's own id of the sale, so a retried post isn't
+-- credited twice.
+create table purchases (
+    id integer primary key autoincrement not null,
+    retailer_id integer not null references retailers (id),
+    card_id integer not null references cards (id),
+    reference text not null,
+    amount_cents integer not null,
+    points integer not null,
+    occurred_at timestamp not null,
+    created_at timestamp not null default current_timestamp,
+    unique (retailer_id, reference)
+);
+
+create index purchases_card_id on purchases (card_id);
@@ -0,0 +1 @@
+drop table point_transfers;
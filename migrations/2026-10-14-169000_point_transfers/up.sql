@@ -0,0 +1,16 @@
+-- Points users gave one another within a program, each recorded in the
+-- ledgers as a debit on the sender's card and a credit on the recipient's.
+create table point_transfers (
+    id integer primary key autoincrement not null,
+    from_user_id integer not null references users (id),
+    to_user_id integer not null references users (id),
+    from_card_id integer not null references cards (id),
+    to_card_id integer not null references cards (id),
+    points integer not null,
+    debit_id integer not null references point_transactions (id),
+    credit_id integer not null references point_transactions (id),
+    created_at timestamp not null default current_timestamp
+);
+
+create index point_transfers_from_user_id on point_transfers (from_user_id, created_at);
+create index point_transfers_to_user_id on point_transfers (to_user_id);
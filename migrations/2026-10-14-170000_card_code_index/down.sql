@@ -0,0 +1,33 @@
+drop index cards_code_index;
+
+create table cards_old (
+    id integer primary key autoincrement not null,
+    name text not null,
+    color text,
+    code text not null,
+    user_id integer not null references users (id),
+    barcode_type text not null default 'code128',
+    created_at timestamp not null default '1970-01-01 00:00:00',
+    last_used_at timestamp,
+    is_favorite boolean not null default 0,
+    position integer not null default 0,
+    deleted_at timestamp,
+    archived_at timestamp,
+    notes text,
+    expires_at date,
+    group_id integer references "groups" (id),
+    use_count integer not null default 0,
+    retailer_id integer references retailers (id),
+    balance text,
+    balance_currency text,
+    is_active boolean not null default 1,
+    icon text,
+    stamps_required integer,
+    stamps integer not null default 0
+);
+
+insert into cards_old (id, name, color, code, user_id, barcode_type, created_at, last_used_at, is_favorite, position, deleted_at, archived_at, notes, expires_at, group_id, use_count, retailer_id, balance, balance_currency, is_active, icon, stamps_required, stamps)
+select id, name, color, code, user_id, barcode_type, created_at, last_used_at, is_favorite, position, deleted_at, archived_at, notes, expires_at, group_id, use_count, retailer_id, balance, balance_currency, is_active, icon, stamps_required, stamps from cards;
+
+drop table cards;
+alter table cards_old rename to cards;
@@ -0,0 +1,5 @@
+-- Keyed digests of the card codes, to look cards up by code although the codes
+-- are encrypted. Filled in at startup for existing cards.
+alter table cards add column code_index text;
+
+create index cards_code_index on cards (retailer_id, code_index);
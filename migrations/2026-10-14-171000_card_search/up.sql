@@ -0,0 +1,9 @@
+-- Names and field names are indexed as they are. Codes, notes and field
+-- values are encrypted, so only blind tokens of their words are. Filled in
+-- at startup for existing cards.
+create virtual table card_search using fts5 (
+    name,
+    fields,
+    tokens,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
@@ -0,0 +1,23 @@
+create table point_transfers_old (
+    id integer primary key autoincrement not null,
+    from_user_id integer not null references users (id),
+    to_user_id integer not null references users (id),
+    from_card_id integer not null references cards (id),
+    to_card_id integer not null references cards (id),
+    points integer not null,
+    debit_id integer not null references point_transactions (id),
+    credit_id integer not null references point_transactions (id),
+    created_at timestamp not null default current_timestamp
+);
+
+insert into point_transfers_old (id, from_user_id, to_user_id, from_card_id, to_card_id, points, debit_id, credit_id, created_at)
+select id, from_user_id, to_user_id, from_card_id, to_card_id, points, debit_id, credit_id, created_at from point_transfers
+where from_user_id is not null and to_user_id is not null
+    and from_card_id is not null and to_card_id is not null
+    and debit_id is not null and credit_id is not null;
+
+drop table point_transfers;
+alter table point_transfers_old rename to point_transfers;
+
+create index point_transfers_from_user_id on point_transfers (from_user_id, created_at);
+create index point_transfers_to_user_id on point_transfers (to_user_id);
@@ -0,0 +1,22 @@
+-- Transfers outlive the cards and accounts on either side: the departing
+-- side is cleared rather than the transfer deleted for both parties.
+create table point_transfers_new (
+    id integer primary key autoincrement not null,
+    from_user_id integer references users (id),
+    to_user_id integer references users (id),
+    from_card_id integer references cards (id),
+    to_card_id integer references cards (id),
+    points integer not null,
+    debit_id integer unique references point_transactions (id),
+    credit_id integer unique references point_transactions (id),
+    created_at timestamp not null default current_timestamp
+);
+
+insert into point_transfers_new (id, from_user_id, to_user_id, from_card_id, to_card_id, points, debit_id, credit_id, created_at)
+select id, from_user_id, to_user_id, from_card_id, to_card_id, points, debit_id, credit_id, created_at from point_transfers;
+
+drop table point_transfers;
+alter table point_transfers_new rename to point_transfers;
+
+create index point_transfers_from_user_id on point_transfers (from_user_id, created_at);
+create index point_transfers_to_user_id on point_transfers (to_user_id);
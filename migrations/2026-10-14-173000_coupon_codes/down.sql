@@ -0,0 +1 @@
+drop index coupons_retailer_id_code;
@@ -0,0 +1,3 @@
+-- A code names one coupon of a retailer, so a new coupon can be read back
+-- by it.
+create unique index coupons_retailer_id_code on coupons (retailer_id, code);
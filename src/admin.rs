@@ -0,0 +1,936 @@
+//! Routes reserved to administrators. The first admin is promoted directly in
+//! the database: `update users set role = 'admin' where email = '...'`.
+
+use std::net::IpAddr;
+
+use diesel::prelude::*;
+use ipnet::IpNet;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::response::status;
+use rocket::{catch, delete, get, post, put, routes, Request, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::auth::{invites, token, AdminUser};
+use crate::db::{
+    self,
+    models::{
+        Campaign, Coupon, EarningRule, Invite, NewCampaign, NewCoupon, NewEarningRule, NewPosKey,
+        NewReward, PosKey, Receipt, Reward,
+    },
+};
+use crate::earning::RuleKind;
+use crate::rate_limit::RateLimiter;
+use crate::requests::{
+    invalid, ApproveReceipt, CampaignResponse, CouponResponse, CreateInvite, CreatePosKey,
+    CreatedInvite, CreatedPosKey, EarningRuleResponse, InviteResponse, IpStandingResponse,
+    PosKeyResponse, ReceiptResponse, RewardResponse, SetCampaign, SetCoupon, SetEarningRule,
+    SetReward, UpdateRole,
+};
+use crate::storage::Storage;
+use crate::tiers::TiersConfig;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_users,
+        update_role,
+        list_bans,
+        clear_ban,
+        list_invites,
+        create_invite,
+        revoke_invite,
+        create_reward,
+        update_reward,
+        delete_reward,
+        create_coupon,
+        update_coupon,
+        delete_coupon,
+        create_campaign,
+        update_campaign,
+        delete_campaign,
+        list_receipts,
+        get_receipt_photo,
+        approve_receipt,
+        reject_receipt,
+        create_earning_rule,
+        update_earning_rule,
+        delete_earning_rule,
+        list_pos_keys,
+        create_pos_key,
+        delete_pos_key
+    ]
+}
+
+/// The `admin` section of the Rocket configuration.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// CIDR ranges allowed to reach admin routes. Empty allows every address.
+    pub allowlist: Vec<IpNet>,
+}
+
+impl AdminConfig {
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowlist.is_empty() {
+            return true;
+        }
+
+        ip.map_or(false, |ip| {
+            self.allowlist.iter().any(|net| net.contains(&ip))
+        })
+    }
+}
+
+/// Set by `AdminUser` when it turns a request away for its address.
+pub struct IpRejected(pub bool);
+
+/// Request guards can't shape their failure response, so the 403 catcher
+/// gives the structured error to requests the allowlist rejected.
+#[catch(403)]
+pub fn forbidden(request: &Request<'_>) -> Result<status::Custom<()>, APIError> {
+    match request.local_cache(|| IpRejected(false)) {
+        IpRejected(true) => Err(APIError::IpNotAllowed),
+        IpRejected(false) => Ok(status::Custom(Status::Forbidden, ())),
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Admin Allowlist", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<AdminConfig>("admin") {
+            Ok(config) => config,
+            Err(e) if e.missing() => AdminConfig::default(),
+            Err(e) => {
+                log::error!("invalid admin configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
+
+#[get("/admin/users?<limit>&<offset>")]
+async fn list_users(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<db::models::User>>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(50)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let found = db
+        .run(move |c| {
+            users
+                .order(id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<db::models::User>(c)
+        })
+        .await?;
+
+    Ok(Json(found))
+}
+
+#[put("/admin/users/<user_id>/role", format = "json", data = "<body>")]
+async fn update_role(
+    db: LoyaltyDbConn,
+    admin: AdminUser,
+    user_id: String,
+    body: Json<UpdateRole>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let target: i32 = user_id.parse()?;
+
+    // Admins can't demote themselves and lock everyone out by accident.
+    if target == admin.0 {
+        return Err(APIError::Conflict);
+    }
+
+    let updated = db
+        .run(move |c| {
+            diesel::update(users.find(target))
+                .set(role.eq(body.0.role.name()))
+                .execute(c)
+        })
+        .await?;
+
+    match updated {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "role updated")),
+    }
+}
+
+/// IPs with strikes or an active ban. Bans live in memory and are lost on
+/// restart.
+#[get("/admin/bans")]
+async fn list_bans(
+    _admin: AdminUser,
+    limiter: State<'_, RateLimiter>,
+) -> Json<Vec<IpStandingResponse>> {
+    Json(
+        limiter
+            .standings()
+            .into_iter()
+            .map(|standing| IpStandingResponse {
+                ip: standing.ip,
+                strikes: standing.strikes,
+                banned_for: standing.banned_for,
+            })
+            .collect(),
+    )
+}
+
+#[delete("/admin/bans/<ip>")]
+async fn clear_ban(
+    _admin: AdminUser,
+    limiter: State<'_, RateLimiter>,
+    ip: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    match limiter.clear(&ip) {
+        true => Ok(status::Custom(Status::Ok, "ban cleared")),
+        false => Err(APIError::NotFound),
+    }
+}
+
+#[get("/admin/invites")]
+async fn list_invites(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+) -> Result<Json<Vec<InviteResponse>>, APIError> {
+    use db::schema::invites::dsl::*;
+
+    let found = db
+        .run(move |c| invites.order(id.desc()).load::<Invite>(c))
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|invite| InviteResponse {
+                id: invite.id,
+                created_by: invite.created_by,
+                max_uses: invite.max_uses,
+                uses: invite.uses,
+                expires_at: invite.expires_at,
+                revoked_at: invite.revoked_at,
+                created_at: invite.created_at,
+            })
+            .collect(),
+    ))
+}
+
+#[post("/admin/invites", format = "json", data = "<body>")]
+async fn create_invite(
+    db: LoyaltyDbConn,
+    admin: AdminUser,
+    body: Json<CreateInvite>,
+) -> Result<status::Custom<Json<CreatedInvite>>, APIError> {
+    body.0.validate()?;
+    let max_uses = body.0.max_uses.unwrap_or(1);
+
+    let (invite, code) = db
+        .run(move |c| invites::mint(c, admin.0, max_uses, body.0.expires_in_days))
+        .await?;
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(CreatedInvite {
+            id: invite.id,
+            code,
+            max_uses: invite.max_uses,
+            expires_at: invite.expires_at,
+        }),
+    ))
+}
+
+#[delete("/admin/invites/<invite_id>")]
+async fn revoke_invite(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    invite_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let invite_id: i32 = invite_id.parse()?;
+    let revoked = db.run(move |c| invites::revoke(c, invite_id)).await?;
+
+    match revoked {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "invite revoked")),
+    }
+}
+
+/// Rejects rewards, coupons, campaigns, earning rules and POS keys of
+/// retailers missing from the catalog.
+fn check_retailer(c: &SqliteConnection, retailer: i32) -> Result<(), APIError> {
+    use db::schema::retailers::dsl::*;
+
+    let found = retailers
+        .find(retailer)
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+    found
+        .map(|_| ())
+        .ok_or_else(|| crate::retailers::unknown().into())
+}
+
+/// Adds a reward to the catalog on behalf of a merchant.
+#[post("/admin/rewards", format = "json", data = "<body>")]
+async fn create_reward(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    body: Json<SetReward>,
+) -> Result<status::Custom<Json<RewardResponse>>, APIError> {
+    use db::schema::rewards::dsl::*;
+
+    body.0.validate()?;
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                diesel::insert_into(rewards)
+                    .values(&NewReward {
+                        retailer_id: body.0.retailer_id,
+                        name: body.0.name.trim(),
+                        cost: body.0.cost,
+                        stock: body.0.stock,
+                    })
+                    .execute(c)?;
+
+                Ok(rewards
+                    .filter(retailer_id.eq(body.0.retailer_id))
+                    .filter(name.eq(body.0.name.trim()))
+                    .filter(cost.eq(body.0.cost))
+                    .order(id.desc())
+                    .first::<Reward>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created.into())))
+}
+
+/// Replaces the settings of a reward, such as its stock once restocked.
+#[put("/admin/rewards/<reward_id>", format = "json", data = "<body>")]
+async fn update_reward(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    reward_id: String,
+    body: Json<SetReward>,
+) -> Result<Json<RewardResponse>, APIError> {
+    use db::schema::rewards::dsl::*;
+
+    let reward_id: i32 = reward_id.parse()?;
+    body.0.validate()?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                let found = diesel::update(rewards.find(reward_id))
+                    .set((
+                        retailer_id.eq(body.0.retailer_id),
+                        name.eq(body.0.name.trim()),
+                        cost.eq(body.0.cost),
+                        stock.eq(body.0.stock),
+                    ))
+                    .execute(c)?;
+                if found == 0 {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(rewards.find(reward_id).first::<Reward>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated.into()))
+}
+
+/// Takes a reward out of the catalog. Points spent on it stay spent.
+#[delete("/admin/rewards/<reward_id>")]
+async fn delete_reward(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    reward_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::rewards::dsl::*;
+
+    let reward_id: i32 = reward_id.parse()?;
+    let deleted = db
+        .run(move |c| diesel::delete(rewards.find(reward_id)).execute(c))
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "reward deleted")),
+    }
+}
+
+/// Rejects coupons that would never be valid.
+fn check_validity(body: &SetCoupon) -> Result<(), APIError> {
+    match (body.valid_from, body.valid_until) {
+        (Some(from), Some(until)) if from >= until => {
+            Err(invalid("valid_until", "valid_until must be after valid_from").into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Issues a coupon on behalf of a merchant, under a code none of its other
+/// coupons has.
+#[post("/admin/coupons", format = "json", data = "<body>")]
+async fn create_coupon(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    body: Json<SetCoupon>,
+) -> Result<status::Custom<Json<CouponResponse>>, APIError> {
+    use db::schema::coupons::dsl::*;
+
+    body.0.validate()?;
+    check_validity(&body.0)?;
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                diesel::insert_into(coupons)
+                    .values(&NewCoupon {
+                        retailer_id: body.0.retailer_id,
+                        title: body.0.title.trim(),
+                        code: body.0.code.trim(),
+                        reusable: body.0.reusable,
+                        valid_from: body.0.valid_from,
+                        valid_until: body.0.valid_until,
+                    })
+                    .execute(c)?;
+
+                Ok(coupons
+                    .filter(retailer_id.eq(body.0.retailer_id))
+                    .filter(code.eq(body.0.code.trim()))
+                    .first::<Coupon>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created.into())))
+}
+
+/// Replaces the settings of a coupon. Claims of it stay in the wallets.
+#[put("/admin/coupons/<coupon_id>", format = "json", data = "<body>")]
+async fn update_coupon(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    coupon_id: String,
+    body: Json<SetCoupon>,
+) -> Result<Json<CouponResponse>, APIError> {
+    use db::schema::coupons::dsl::*;
+
+    let coupon_id: i32 = coupon_id.parse()?;
+    body.0.validate()?;
+    check_validity(&body.0)?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                let found = diesel::update(coupons.find(coupon_id))
+                    .set((
+                        retailer_id.eq(body.0.retailer_id),
+                        title.eq(body.0.title.trim()),
+                        code.eq(body.0.code.trim()),
+                        reusable.eq(body.0.reusable),
+                        valid_from.eq(body.0.valid_from),
+                        valid_until.eq(body.0.valid_until),
+                    ))
+                    .execute(c)?;
+                if found == 0 {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(coupons.find(coupon_id).first::<Coupon>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated.into()))
+}
+
+/// Withdraws a coupon, taking it out of the wallets it was claimed into.
+#[delete("/admin/coupons/<coupon_id>")]
+async fn delete_coupon(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    coupon_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::{coupon_claims, coupons};
+
+    let coupon_id: i32 = coupon_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            c.transaction(|| {
+                diesel::delete(coupon_claims::table.filter(coupon_claims::coupon_id.eq(coupon_id)))
+                    .execute(c)?;
+                diesel::delete(coupons::table.find(coupon_id)).execute(c)
+            })
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "coupon deleted")),
+    }
+}
+
+/// Rejects campaigns that would never run.
+fn check_window(body: &SetCampaign) -> Result<(), APIError> {
+    if body.starts_at < body.ends_at {
+        return Ok(());
+    }
+
+    Err(invalid("ends_at", "ends_at must be after starts_at").into())
+}
+
+/// Starts a promotion on behalf of a merchant.
+#[post("/admin/campaigns", format = "json", data = "<body>")]
+async fn create_campaign(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    body: Json<SetCampaign>,
+) -> Result<status::Custom<Json<CampaignResponse>>, APIError> {
+    use db::schema::campaigns::dsl::*;
+
+    body.0.validate()?;
+    check_window(&body.0)?;
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                let days = body
+                    .0
+                    .weekdays
+                    .as_deref()
+                    .map(crate::campaigns::format_weekdays);
+                diesel::insert_into(campaigns)
+                    .values(&NewCampaign {
+                        retailer_id: body.0.retailer_id,
+                        name: body.0.name.trim(),
+                        multiplier_percent: body.0.multiplier_percent,
+                        starts_at: body.0.starts_at,
+                        ends_at: body.0.ends_at,
+                        weekdays: days.as_deref(),
+                    })
+                    .execute(c)?;
+
+                Ok(campaigns
+                    .filter(retailer_id.eq(body.0.retailer_id))
+                    .filter(name.eq(body.0.name.trim()))
+                    .filter(starts_at.eq(body.0.starts_at))
+                    .filter(ends_at.eq(body.0.ends_at))
+                    .order(id.desc())
+                    .first::<Campaign>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created.into())))
+}
+
+/// Replaces the settings of a campaign, such as to end it early. Points
+/// already earned keep their multiplier.
+#[put("/admin/campaigns/<campaign_id>", format = "json", data = "<body>")]
+async fn update_campaign(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    campaign_id: String,
+    body: Json<SetCampaign>,
+) -> Result<Json<CampaignResponse>, APIError> {
+    use db::schema::campaigns::dsl::*;
+
+    let campaign_id: i32 = campaign_id.parse()?;
+    body.0.validate()?;
+    check_window(&body.0)?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                let days = body
+                    .0
+                    .weekdays
+                    .as_deref()
+                    .map(crate::campaigns::format_weekdays);
+                let found = diesel::update(campaigns.find(campaign_id))
+                    .set((
+                        retailer_id.eq(body.0.retailer_id),
+                        name.eq(body.0.name.trim()),
+                        multiplier_percent.eq(body.0.multiplier_percent),
+                        starts_at.eq(body.0.starts_at),
+                        ends_at.eq(body.0.ends_at),
+                        weekdays.eq(days),
+                    ))
+                    .execute(c)?;
+                if found == 0 {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(campaigns.find(campaign_id).first::<Campaign>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated.into()))
+}
+
+/// Calls off a campaign. Points already earned keep their multiplier.
+#[delete("/admin/campaigns/<campaign_id>")]
+async fn delete_campaign(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    campaign_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::campaigns::dsl::*;
+
+    let campaign_id: i32 = campaign_id.parse()?;
+    let deleted = db
+        .run(move |c| diesel::delete(campaigns.find(campaign_id)).execute(c))
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "campaign deleted")),
+    }
+}
+
+fn admin_download(receipt: &Receipt) -> String {
+    format!("/admin/receipts/{}/photo", receipt.id)
+}
+
+/// Receipts waiting for review, or of another `status`, the oldest first.
+#[get("/admin/receipts?<status>&<limit>&<offset>")]
+async fn list_receipts(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    storage: State<'_, Storage>,
+    status: Option<String>,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<ReceiptResponse>>, APIError> {
+    use db::schema::receipts;
+
+    let wanted = status.unwrap_or_else(|| crate::receipts::PENDING.to_string());
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(50)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let found = db
+        .run(move |c| {
+            receipts::table
+                .filter(receipts::status.eq(wanted))
+                .order(receipts::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<Receipt>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|receipt| {
+                let download = admin_download(&receipt);
+                crate::receipts::describe(&storage, receipt, download)
+            })
+            .collect(),
+    ))
+}
+
+#[get("/admin/receipts/<receipt_id>/photo")]
+async fn get_receipt_photo(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    storage: State<'_, Storage>,
+    receipt_id: String,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::receipts::dsl::*;
+
+    let receipt_id: i32 = receipt_id.parse()?;
+    let receipt = db
+        .run(move |c| receipts.find(receipt_id).first::<Receipt>(c).optional())
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    crate::receipts::photo(&storage, &receipt).await
+}
+
+/// Approves a pending receipt, crediting the points given or else those
+/// suggested from its total.
+#[post(
+    "/admin/receipts/<receipt_id>/approve",
+    format = "json",
+    data = "<body>"
+)]
+async fn approve_receipt(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    storage: State<'_, Storage>,
+    tiers_config: State<'_, TiersConfig>,
+    receipt_id: String,
+    body: Json<ApproveReceipt>,
+) -> Result<Json<ReceiptResponse>, APIError> {
+    let receipt_id: i32 = receipt_id.parse()?;
+    body.0.validate()?;
+
+    let tiers_config = *tiers_config;
+    let approved = db
+        .run(move |c| crate::receipts::approve(c, tiers_config, receipt_id, body.0.points))
+        .await?;
+
+    let download = admin_download(&approved);
+    Ok(Json(crate::receipts::describe(
+        &storage, approved, download,
+    )))
+}
+
+#[post("/admin/receipts/<receipt_id>/reject")]
+async fn reject_receipt(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    storage: State<'_, Storage>,
+    receipt_id: String,
+) -> Result<Json<ReceiptResponse>, APIError> {
+    let receipt_id: i32 = receipt_id.parse()?;
+    let rejected = db
+        .run(move |c| crate::receipts::reject(c, receipt_id))
+        .await?;
+
+    let download = admin_download(&rejected);
+    Ok(Json(crate::receipts::describe(
+        &storage, rejected, download,
+    )))
+}
+
+/// Rejects rules missing what their kind is counted by.
+fn check_rule(body: &SetEarningRule) -> Result<(), APIError> {
+    let (field, message) = match body.kind {
+        RuleKind::Amount if body.per_cents.is_none() => {
+            ("per_cents", "amount rules need per_cents")
+        }
+        RuleKind::Item
+            if body
+                .sku
+                .as_deref()
+                .map_or(true, |sku| sku.trim().is_empty()) =>
+        {
+            ("sku", "item rules need a sku")
+        }
+        _ => return Ok(()),
+    };
+
+    Err(invalid(field, message).into())
+}
+
+/// Adds an earning rule to the program of a merchant.
+#[post("/admin/earning-rules", format = "json", data = "<body>")]
+async fn create_earning_rule(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    body: Json<SetEarningRule>,
+) -> Result<status::Custom<Json<EarningRuleResponse>>, APIError> {
+    use db::schema::earning_rules::dsl::*;
+
+    body.0.validate()?;
+    check_rule(&body.0)?;
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                diesel::insert_into(earning_rules)
+                    .values(&NewEarningRule {
+                        retailer_id: body.0.retailer_id,
+                        kind: body.0.kind.name(),
+                        points: body.0.points,
+                        per_cents: body.0.per_cents,
+                        sku: body.0.sku.as_deref().map(str::trim),
+                        min_cents: body.0.min_cents,
+                    })
+                    .execute(c)?;
+
+                Ok(earning_rules
+                    .filter(retailer_id.eq(body.0.retailer_id))
+                    .filter(kind.eq(body.0.kind.name()))
+                    .filter(points.eq(body.0.points))
+                    .order(id.desc())
+                    .first::<EarningRule>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created.into())))
+}
+
+/// Replaces the settings of an earning rule. Points already earned stay.
+#[put("/admin/earning-rules/<rule_id>", format = "json", data = "<body>")]
+async fn update_earning_rule(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    rule_id: String,
+    body: Json<SetEarningRule>,
+) -> Result<Json<EarningRuleResponse>, APIError> {
+    use db::schema::earning_rules::dsl::*;
+
+    let rule_id: i32 = rule_id.parse()?;
+    body.0.validate()?;
+    check_rule(&body.0)?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, body.0.retailer_id)?;
+                let found = diesel::update(earning_rules.find(rule_id))
+                    .set((
+                        retailer_id.eq(body.0.retailer_id),
+                        kind.eq(body.0.kind.name()),
+                        points.eq(body.0.points),
+                        per_cents.eq(body.0.per_cents),
+                        sku.eq(body.0.sku.as_deref().map(str::trim)),
+                        min_cents.eq(body.0.min_cents),
+                    ))
+                    .execute(c)?;
+                if found == 0 {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(earning_rules.find(rule_id).first::<EarningRule>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated.into()))
+}
+
+#[delete("/admin/earning-rules/<rule_id>")]
+async fn delete_earning_rule(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    rule_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::earning_rules::dsl::*;
+
+    let rule_id: i32 = rule_id.parse()?;
+    let deleted = db
+        .run(move |c| diesel::delete(earning_rules.find(rule_id)).execute(c))
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "earning rule deleted")),
+    }
+}
+
+#[get("/admin/retailers/<retailer_id>/pos-keys")]
+async fn list_pos_keys(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    retailer_id: String,
+) -> Result<Json<Vec<PosKeyResponse>>, APIError> {
+    use db::schema::pos_keys;
+
+    let retailer_id: i32 = retailer_id.parse()?;
+    let keys = db
+        .run(move |c| {
+            pos_keys::table
+                .filter(pos_keys::retailer_id.eq(retailer_id))
+                .order(pos_keys::id.asc())
+                .load::<PosKey>(c)
+        })
+        .await?;
+
+    Ok(Json(keys.into_iter().map(Into::into).collect()))
+}
+
+/// Issues a key for the points of sale of a merchant, shown only once.
+#[post(
+    "/admin/retailers/<retailer_id>/pos-keys",
+    format = "json",
+    data = "<body>"
+)]
+async fn create_pos_key(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    retailer_id: String,
+    body: Json<CreatePosKey>,
+) -> Result<status::Custom<Json<CreatedPosKey>>, APIError> {
+    use db::schema::pos_keys;
+
+    let retailer_id: i32 = retailer_id.parse()?;
+    body.0.validate()?;
+
+    let (raw, prefix) = crate::pos::generate();
+    let hashed = token::digest(&raw);
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                check_retailer(c, retailer_id)?;
+                diesel::insert_into(pos_keys::table)
+                    .values(&NewPosKey {
+                        retailer_id,
+                        name: body.0.name.trim(),
+                        prefix: &prefix,
+                        key_hash: &hashed,
+                    })
+                    .execute(c)?;
+
+                Ok(pos_keys::table
+                    .filter(pos_keys::key_hash.eq(&hashed))
+                    .first::<PosKey>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(CreatedPosKey {
+            id: created.id,
+            name: created.name,
+            key: raw,
+        }),
+    ))
+}
+
+#[delete("/admin/pos-keys/<key_id>")]
+async fn delete_pos_key(
+    db: LoyaltyDbConn,
+    _admin: AdminUser,
+    key_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::pos_keys::dsl::*;
+
+    let key_id: i32 = key_id.parse()?;
+    let deleted = db
+        .run(move |c| diesel::delete(pos_keys.find(key_id)).execute(c))
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "pos key deleted")),
+    }
+}
@@ -0,0 +1,289 @@
+//! Receipts and documents attached to cards, uploaded as
+//! `multipart/form-data` with the file in a `file` field. Like card photos,
+//! they are kept in the object storage and their type is sniffed from their
+//! content: PDFs and the image types of photos are accepted.
+
+use diesel::prelude::*;
+use rocket::data::Data;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Responder, Route, State};
+use rocket_contrib::json::Json;
+
+use crate::auth::{token, LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{CardAttachment, NewCardAttachment},
+};
+use crate::images;
+use crate::requests::AttachmentResponse;
+use crate::shares;
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const FIELD: &str = "file";
+const MAX_BYTES: usize = 10 * 1024 * 1024;
+const MAX_NAME_CHARS: usize = 255;
+/// Used when the client sent no usable file name.
+const DEFAULT_NAME: &str = "attachment";
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        upload_attachment,
+        get_attachments,
+        get_attachment,
+        delete_attachment
+    ]
+}
+
+#[derive(Responder)]
+struct Download {
+    inner: (ContentType, Vec<u8>),
+    disposition: Header<'static>,
+}
+
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    images::sniff(bytes)
+}
+
+/// Keeps the last component of the name the client gave, without control
+/// characters or the quotes and backslashes of `Content-Disposition`.
+fn sanitize_name(given: Option<&str>) -> String {
+    let base = given
+        .and_then(|name| name.rsplit(|c| c == '/' || c == '\\').next())
+        .unwrap_or_default();
+    let name: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .take(MAX_NAME_CHARS)
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => DEFAULT_NAME.to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn describe(storage: &Storage, attachment: CardAttachment) -> AttachmentResponse {
+    let url = storage.presign(&attachment.storage_key).unwrap_or_else(|| {
+        format!(
+            "/loyalties/{}/attachments/{}",
+            attachment.card_id, attachment.id
+        )
+    });
+
+    AttachmentResponse {
+        id: attachment.id,
+        file_name: attachment.file_name,
+        content_type: attachment.content_type,
+        size: attachment.size,
+        created_at: attachment.created_at,
+        url,
+    }
+}
+
+/// Removes the attachment rows of `card`, returning the keys of the objects
+/// to delete from storage.
+pub fn detach(c: &SqliteConnection, card: i32) -> QueryResult<Vec<String>> {
+    use db::schema::card_attachments::dsl::*;
+
+    let keys = card_attachments
+        .filter(card_id.eq(card))
+        .select(storage_key)
+        .load::<String>(c)?;
+    diesel::delete(card_attachments.filter(card_id.eq(card))).execute(c)?;
+
+    Ok(keys)
+}
+
+#[post("/loyalties/<loyalty_id>/attachments", data = "<data>")]
+async fn upload_attachment(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    content_type: &ContentType,
+    loyalty_id: String,
+    data: Data,
+) -> Result<status::Custom<Json<AttachmentResponse>>, APIError> {
+    use db::schema::card_attachments::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let owner = user.0;
+    if !db
+        .run(move |c| images::can_edit(c, owner, loyalty_id))
+        .await?
+    {
+        return Err(APIError::NotFound);
+    }
+
+    let (given, bytes) = images::read_upload(content_type, data, FIELD, MAX_BYTES).await?;
+    let kind = sniff(&bytes).ok_or(APIError::UnsupportedMediaType)?;
+    let name = sanitize_name(given.as_deref());
+    let length = bytes.len() as i32;
+
+    let key = format!("cards/{}/{}", loyalty_id, token::generate());
+    storage.put(&key, bytes, kind).await?;
+
+    let stored = key.clone();
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                // The card may have been deleted during the upload.
+                if !images::can_edit(c, owner, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                diesel::insert_into(card_attachments)
+                    .values(&NewCardAttachment {
+                        card_id: loyalty_id,
+                        file_name: &name,
+                        storage_key: &stored,
+                        content_type: kind,
+                        size: length,
+                    })
+                    .execute(c)?;
+
+                Ok(card_attachments
+                    .order(id.desc())
+                    .first::<CardAttachment>(c)?)
+            })
+        })
+        .await;
+
+    match created {
+        Ok(attachment) => Ok(status::Custom(
+            Status::Created,
+            Json(describe(&storage, attachment)),
+        )),
+        Err(e) => {
+            storage.discard(&[key]).await;
+            Err(e)
+        }
+    }
+}
+
+/// The attachments of a card, oldest first.
+#[get("/loyalties/<loyalty_id>/attachments")]
+async fn get_attachments(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<Vec<AttachmentResponse>>, APIError> {
+    use db::schema::{card_attachments, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let attachments = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let readable = cards::table
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .select(cards::id)
+                .first::<i32>(c)
+                .optional()?;
+            if readable.is_none() {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(card_attachments::table
+                .filter(card_attachments::card_id.eq(loyalty_id))
+                .order(card_attachments::id.asc())
+                .load::<CardAttachment>(c)?)
+        })
+        .await?;
+
+    Ok(Json(
+        attachments
+            .into_iter()
+            .map(|attachment| describe(&storage, attachment))
+            .collect(),
+    ))
+}
+
+#[get("/loyalties/<loyalty_id>/attachments/<attachment_id>")]
+async fn get_attachment(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    attachment_id: String,
+) -> Result<Download, APIError> {
+    use db::schema::{card_attachments, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let attachment_id: i32 = attachment_id.parse()?;
+    let attachment = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            card_attachments::table
+                .inner_join(cards::table)
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .filter(card_attachments::id.eq(attachment_id))
+                .select(card_attachments::all_columns)
+                .first::<CardAttachment>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    let bytes = storage
+        .get(&attachment.storage_key)
+        .await?
+        .ok_or(APIError::NotFound)?;
+    let content_type =
+        ContentType::parse_flexible(&attachment.content_type).unwrap_or(ContentType::Binary);
+
+    Ok(Download {
+        inner: (content_type, bytes),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", attachment.file_name),
+        ),
+    })
+}
+
+#[delete("/loyalties/<loyalty_id>/attachments/<attachment_id>")]
+async fn delete_attachment(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    attachment_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_attachments::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let attachment_id: i32 = attachment_id.parse()?;
+    let key = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if !images::can_edit(c, user.0, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                let target = card_attachments
+                    .filter(id.eq(attachment_id))
+                    .filter(card_id.eq(loyalty_id));
+                let key = target
+                    .clone()
+                    .select(storage_key)
+                    .first::<String>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                diesel::delete(target).execute(c)?;
+
+                Ok(key)
+            })
+        })
+        .await?;
+
+    storage.discard(&[key]).await;
+    Ok(status::Custom(Status::Ok, "attachment deleted"))
+}
@@ -0,0 +1,364 @@
+//! Account deletion. Deleted accounts are only marked at first; their data
+//! is purged by a background job once the grace period is over.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rocket::http::{CookieJar, Header, Status};
+use rocket::response::status;
+use rocket::{delete, get, routes, Responder, Route, State};
+use rocket_contrib::json::Json;
+
+use super::events::{self, Event};
+use super::sessions::ClientInfo;
+use super::{invalidate_sessions, remove_session_cookies, SessionUser};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::ApiKey, models::AuthEvent, models::Device, models::Session};
+use crate::requests::{
+    AccountExport, ApiKeyResponse, AuthEventResponse, CardFieldExport, CardTagExport,
+    DeviceResponse, LinkedAccountExport, SessionResponse,
+};
+use crate::{APIError, LoyaltyDbConn};
+
+pub fn routes() -> Vec<Route> {
+    routes![delete_account, export]
+}
+
+#[delete("/account")]
+async fn delete_account(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<status::Custom<&'static str>, APIError> {
+    db.run(move |c| {
+        c.transaction(|| {
+            {
+                use db::schema::users::dsl::*;
+                diesel::update(users.find(user.0).filter(deleted_at.is_null()))
+                    .set(deleted_at.eq(Utc::now().naive_utc()))
+                    .execute(c)?;
+            }
+
+            invalidate_sessions(c, user.0)?;
+            {
+                use db::schema::api_keys::dsl::*;
+                diesel::delete(api_keys.filter(user_id.eq(user.0))).execute(c)?;
+            }
+            events::record(c, user.0, Event::AccountDeleted, &client)?;
+            Ok::<_, diesel::result::Error>(())
+        })
+    })
+    .await?;
+
+    remove_session_cookies(cookies, &cookie_policy);
+    Ok(status::Custom(
+        Status::Accepted,
+        "account scheduled for deletion",
+    ))
+}
+
+#[derive(Responder)]
+struct Download {
+    inner: Json<AccountExport>,
+    disposition: Header<'static>,
+}
+
+fn collect_export(c: &SqliteConnection, user: i32) -> QueryResult<AccountExport> {
+    use db::schema::*;
+
+    let profile = users::table.find(user).first::<db::models::User>(c)?;
+    let cards = cards::table
+        .filter(cards::user_id.eq(user))
+        .order(cards::id.asc())
+        .load::<db::models::Loyalty>(c)?;
+    let owned: Vec<i32> = cards.iter().map(|card| card.id).collect();
+    let card_fields = crate::metadata::fields_of(c, &owned)?;
+    let categories = categories::table
+        .filter(categories::user_id.eq(user))
+        .order(categories::id.asc())
+        .load::<db::models::Category>(c)?;
+    let card_tags = card_tags::table
+        .filter(card_tags::card_id.eq_any(owned.clone()))
+        .select((card_tags::card_id, card_tags::category_id))
+        .load::<(i32, i32)>(c)?;
+    let attachments = card_attachments::table
+        .filter(card_attachments::card_id.eq_any(owned.clone()))
+        .order(card_attachments::id.asc())
+        .load::<db::models::CardAttachment>(c)?;
+    let locations = card_locations::table
+        .filter(card_locations::card_id.eq_any(owned.clone()))
+        .order(card_locations::id.asc())
+        .load::<db::models::CardLocation>(c)?;
+    let reminders = card_reminders::table
+        .filter(card_reminders::user_id.eq(user))
+        .order(card_reminders::id.asc())
+        .load::<db::models::CardReminder>(c)?;
+    let balance_history = balance_entries::table
+        .filter(balance_entries::user_id.eq(user))
+        .order(balance_entries::id.asc())
+        .load::<db::models::BalanceEntry>(c)?;
+    let points = point_transactions::table
+        .filter(point_transactions::card_id.eq_any(owned.clone()))
+        .order(point_transactions::id.asc())
+        .load::<db::models::PointTransaction>(c)?;
+    let point_transfers = point_transfers::table
+        .filter(
+            point_transfers::from_user_id
+                .eq(user)
+                .or(point_transfers::to_user_id.eq(user)),
+        )
+        .order(point_transfers::id.asc())
+        .load::<db::models::PointTransfer>(c)?;
+    let tiers = user_tiers::table
+        .filter(user_tiers::user_id.eq(user))
+        .load::<db::models::UserTier>(c)?;
+    let purchases = purchases::table
+        .filter(purchases::card_id.eq_any(owned.clone()))
+        .order(purchases::id.asc())
+        .load::<db::models::Purchase>(c)?;
+    let receipts = receipts::table
+        .filter(receipts::user_id.eq(user))
+        .order(receipts::id.asc())
+        .load::<db::models::Receipt>(c)?;
+    let stamp_rewards = stamp_rewards::table
+        .filter(stamp_rewards::card_id.eq_any(owned))
+        .order(stamp_rewards::id.asc())
+        .load::<db::models::StampReward>(c)?;
+    let coupons = coupon_claims::table
+        .filter(coupon_claims::user_id.eq(user))
+        .order(coupon_claims::id.asc())
+        .load::<db::models::CouponClaim>(c)?;
+    let card_transfers = card_transfers::table
+        .filter(
+            card_transfers::from_user_id
+                .eq(user)
+                .or(card_transfers::to_user_id.eq(user)),
+        )
+        .order(card_transfers::id.asc())
+        .load::<db::models::CardTransfer>(c)?;
+    let shares = card_shares::table
+        .filter(card_shares::user_id.eq(user))
+        .order(card_shares::id.asc())
+        .load::<db::models::CardShare>(c)?;
+    let referrals = referrals::table
+        .filter(
+            referrals::referrer_id
+                .eq(user)
+                .or(referrals::referred_id.eq(user)),
+        )
+        .order(referrals::id.asc())
+        .load::<db::models::Referral>(c)?;
+    let linked_accounts = user_identities::table
+        .filter(user_identities::user_id.eq(user))
+        .select((
+            user_identities::provider,
+            user_identities::email,
+            user_identities::created_at,
+        ))
+        .load::<(String, Option<String>, NaiveDateTime)>(c)?;
+    let keys = api_keys::table
+        .filter(api_keys::user_id.eq(user))
+        .load::<ApiKey>(c)?;
+    let active_sessions = sessions::table
+        .filter(sessions::user_id.eq(user))
+        .filter(sessions::revoked_at.is_null())
+        .filter(sessions::expires_at.gt(Utc::now().naive_utc()))
+        .load::<Session>(c)?;
+    let remembered = devices::table
+        .filter(devices::user_id.eq(user))
+        .filter(devices::revoked_at.is_null())
+        .load::<Device>(c)?;
+    let activity = auth_events::table
+        .filter(auth_events::user_id.eq(user))
+        .order(auth_events::id.asc())
+        .load::<AuthEvent>(c)?;
+
+    Ok(AccountExport {
+        exported_at: Utc::now().naive_utc(),
+        profile,
+        cards,
+        card_fields: card_fields
+            .into_iter()
+            .map(|(card_id, name, value)| CardFieldExport {
+                card_id,
+                name,
+                value,
+            })
+            .collect(),
+        categories,
+        card_tags: card_tags
+            .into_iter()
+            .map(|(card_id, category_id)| CardTagExport {
+                card_id,
+                category_id,
+            })
+            .collect(),
+        attachments,
+        locations,
+        reminders,
+        balance_history,
+        points,
+        point_transfers,
+        tiers,
+        purchases,
+        receipts,
+        stamp_rewards,
+        coupons,
+        card_transfers,
+        shares,
+        referrals,
+        linked_accounts: linked_accounts
+            .into_iter()
+            .map(|(provider, email, created_at)| LinkedAccountExport {
+                provider,
+                email,
+                created_at,
+            })
+            .collect(),
+        api_keys: keys
+            .into_iter()
+            .map(|key| ApiKeyResponse {
+                id: key.id,
+                name: key.name,
+                prefix: key.prefix,
+                scopes: super::api_keys::scope_names(&key.scopes),
+                created_at: key.created_at,
+                last_used_at: key.last_used_at,
+            })
+            .collect(),
+        sessions: active_sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                id: session.id,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created_at: session.created_at,
+                last_seen_at: session.last_seen_at,
+                expires_at: session.expires_at,
+                current: false,
+            })
+            .collect(),
+        devices: remembered
+            .into_iter()
+            .map(|device| DeviceResponse {
+                id: device.id,
+                user_agent: device.user_agent,
+                ip: device.ip,
+                created_at: device.created_at,
+                last_used_at: device.last_used_at,
+                expires_at: device.expires_at,
+                current: false,
+            })
+            .collect(),
+        activity: activity
+            .into_iter()
+            .map(|event| AuthEventResponse {
+                id: event.id,
+                kind: event.kind,
+                ip: event.ip,
+                country: event.country,
+                user_agent: event.user_agent,
+                created_at: event.created_at,
+            })
+            .collect(),
+    })
+}
+
+/// Downloads a JSON archive of everything stored about the user.
+#[get("/account/export")]
+async fn export(db: LoyaltyDbConn, user: SessionUser) -> Result<Download, APIError> {
+    let archive = db.run(move |c| collect_export(c, user.0)).await?;
+
+    Ok(Download {
+        inner: Json(archive),
+        disposition: Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"loyalty-export.json\"",
+        ),
+    })
+}
+
+/// Removes a user and everything that belongs to them, returning the keys of
+/// their stored objects.
+fn purge(c: &SqliteConnection, user: i32) -> QueryResult<Vec<String>> {
+    use db::schema::*;
+
+    c.transaction(|| {
+        // Children first, as refresh tokens point at sessions and sessions
+        // at devices.
+        diesel::delete(refresh_tokens::table.filter(refresh_tokens::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(sessions::table.filter(sessions::user_id.eq(user))).execute(c)?;
+        diesel::delete(devices::table.filter(devices::user_id.eq(user))).execute(c)?;
+        diesel::delete(account_locks::table.filter(account_locks::user_id.eq(user))).execute(c)?;
+        diesel::delete(api_keys::table.filter(api_keys::user_id.eq(user))).execute(c)?;
+        diesel::delete(auth_events::table.filter(auth_events::user_id.eq(user))).execute(c)?;
+        diesel::delete(email_changes::table.filter(email_changes::user_id.eq(user))).execute(c)?;
+        diesel::delete(invites::table.filter(invites::created_by.eq(user))).execute(c)?;
+        diesel::delete(magic_links::table.filter(magic_links::user_id.eq(user))).execute(c)?;
+        diesel::delete(password_resets::table.filter(password_resets::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(user_identities::table.filter(user_identities::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(verification_tokens::table.filter(verification_tokens::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(webauthn_credentials::table.filter(webauthn_credentials::user_id.eq(user)))
+            .execute(c)?;
+        let owned_cards = cards::table
+            .filter(cards::user_id.eq(user))
+            .select(cards::id)
+            .load::<i32>(c)?;
+        let mut objects = Vec::new();
+        for card in owned_cards {
+            objects.extend(crate::cards::remove(c, card)?);
+        }
+        diesel::delete(categories::table.filter(categories::user_id.eq(user))).execute(c)?;
+        crate::shares::forget(c, user)?;
+        crate::reminders::forget(c, user)?;
+        crate::transfers::forget(c, user)?;
+        crate::tiers::forget(c, user)?;
+        crate::coupons::forget(c, user)?;
+        crate::referrals::forget(c, user)?;
+        crate::point_transfers::forget(c, user)?;
+        objects.extend(crate::receipts::forget(c, user)?);
+        crate::groups::purge(c, user)?;
+        diesel::delete(card_uses::table.filter(card_uses::user_id.eq(user))).execute(c)?;
+        diesel::delete(card_revisions::table.filter(card_revisions::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(balance_entries::table.filter(balance_entries::user_id.eq(user)))
+            .execute(c)?;
+        diesel::delete(users::table.find(user)).execute(c)?;
+        Ok(objects)
+    })
+}
+
+/// What `purge_deleted` removed.
+pub struct Purged {
+    pub accounts: usize,
+    /// Stored objects of the accounts, to delete from storage.
+    pub objects: Vec<String>,
+}
+
+/// Purges the accounts deleted more than `grace_days` ago.
+pub fn purge_deleted(c: &SqliteConnection, grace_days: i64) -> QueryResult<Purged> {
+    use db::schema::users::dsl::*;
+
+    let cutoff: NaiveDateTime = Utc::now().naive_utc() - Duration::days(grace_days);
+    let expired = users
+        .filter(deleted_at.lt(cutoff))
+        .select(id)
+        .load::<i32>(c)?;
+
+    let mut objects = Vec::new();
+    for user in &expired {
+        objects.extend(purge(c, *user)?);
+    }
+
+    Ok(Purged {
+        accounts: expired.len(),
+        objects,
+    })
+}
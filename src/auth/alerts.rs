@@ -0,0 +1,174 @@
+//! Alerts for sign-ins from a device or country the account was never used
+//! from. The email carries a "this wasn't me" link that locks the account
+//! until its password is reset.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::sqlite::Sqlite;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, routes, Route};
+
+use super::events::{self, Event};
+use super::sessions::ClientInfo;
+use super::{invalidate_sessions, token};
+use crate::db::{self, models::NewAccountLock};
+use crate::mail::Mailer;
+use crate::{APIError, LoyaltyDbConn};
+
+const LOCK_TTL_DAYS: i64 = 7;
+
+pub fn routes() -> Vec<Route> {
+    routes![lock]
+}
+
+/// A suspicious sign-in, to be mailed once the sign-in went through.
+pub struct SignInAlert {
+    email: String,
+    lock_token: String,
+    ip: Option<String>,
+    country: Option<String>,
+    user_agent: Option<String>,
+}
+
+fn sign_ins(user: i32) -> db::schema::auth_events::BoxedQuery<'static, Sqlite> {
+    use db::schema::auth_events::dsl::*;
+
+    auth_events
+        .filter(user_id.eq(user))
+        .filter(kind.eq_any(vec![Event::SignIn.name(), Event::TokenIssued.name()]))
+        .into_boxed()
+}
+
+/// Refuses locked accounts, then compares the client with the previous
+/// sign-ins of `user`. Runs before the new sign-in is recorded.
+pub fn check(
+    c: &SqliteConnection,
+    user: i32,
+    client: &ClientInfo,
+) -> Result<Option<SignInAlert>, APIError> {
+    use db::schema::auth_events::dsl::*;
+
+    let account = db::schema::users::table
+        .find(user)
+        .first::<db::models::User>(c)?;
+    if account.locked_at.is_some() {
+        return Err(APIError::Locked);
+    }
+
+    // Guests have no address to alert, and a first sign-in has nothing to
+    // be compared with.
+    let signed_in_before = sign_ins(user)
+        .select(id)
+        .first::<i32>(c)
+        .optional()?
+        .is_some();
+    if account.is_guest || !signed_in_before {
+        return Ok(None);
+    }
+
+    let new_device = match &client.user_agent {
+        Some(agent) => sign_ins(user)
+            .filter(user_agent.eq(agent))
+            .select(id)
+            .first::<i32>(c)
+            .optional()?
+            .is_none(),
+        None => false,
+    };
+    let new_country = match &client.country {
+        Some(code) => sign_ins(user)
+            .filter(country.eq(code))
+            .select(id)
+            .first::<i32>(c)
+            .optional()?
+            .is_none(),
+        None => false,
+    };
+    if !new_device && !new_country {
+        return Ok(None);
+    }
+
+    let raw = token::generate();
+    diesel::insert_into(db::schema::account_locks::table)
+        .values(&NewAccountLock {
+            user_id: user,
+            token_hash: &token::digest(&raw),
+            expires_at: Utc::now().naive_utc() + Duration::days(LOCK_TTL_DAYS),
+        })
+        .execute(c)?;
+
+    Ok(Some(SignInAlert {
+        email: account.email,
+        lock_token: raw,
+        ip: client.ip.clone(),
+        country: client.country.clone(),
+        user_agent: client.user_agent.clone(),
+    }))
+}
+
+pub async fn send(mailer: &Mailer, alert: SignInAlert) {
+    let link = mailer.link(&format!("/security/lock/{}", alert.lock_token));
+    let body = format!(
+        "Your account was just signed in to from a new device or location:\n\n\
+         Address: {}\nCountry: {}\nBrowser: {}\n\n\
+         If this was you, there is nothing to do. Otherwise lock your account \
+         right away by opening the link below, then reset your password to unlock it:\n\n{}\n",
+        alert.ip.as_deref().unwrap_or("unknown"),
+        alert.country.as_deref().unwrap_or("unknown"),
+        alert.user_agent.as_deref().unwrap_or("unknown"),
+        link
+    );
+
+    if let Err(e) = mailer
+        .send(&alert.email, "New sign-in to your account", body)
+        .await
+    {
+        log::warn!("could not send sign-in alert: {}", e);
+    }
+}
+
+/// "This wasn't me": signs the account out everywhere, deletes its API keys
+/// and refuses sign-ins until the password is reset.
+#[get("/security/lock/<token>")]
+async fn lock(
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    token: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    db.run(move |c| {
+        use db::schema::account_locks::dsl::*;
+
+        c.transaction(|| {
+            let owner = account_locks
+                .filter(token_hash.eq(token::digest(&token)))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .select(user_id)
+                .first::<i32>(c)
+                .optional()?
+                .ok_or(APIError::NotAuthorized)?;
+
+            {
+                use db::schema::users::dsl::*;
+                diesel::update(users.find(owner))
+                    .set(locked_at.eq(Utc::now().naive_utc()))
+                    .execute(c)?;
+            }
+
+            diesel::delete(account_locks.filter(user_id.eq(owner))).execute(c)?;
+            diesel::delete(
+                db::schema::api_keys::table.filter(db::schema::api_keys::user_id.eq(owner)),
+            )
+            .execute(c)?;
+            invalidate_sessions(c, owner)?;
+            events::record(c, owner, Event::AccountLocked, &client)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(
+        Status::Ok,
+        "account locked, reset your password to unlock it",
+    ))
+}
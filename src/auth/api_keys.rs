@@ -0,0 +1,188 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Route};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+use super::{token, SessionUser};
+use crate::db::{self, models::ApiKey, models::NewApiKey};
+use crate::requests::{ApiKeyResponse, CreateApiKey, CreatedApiKey};
+use crate::{APIError, LoyaltyDbConn};
+
+pub const HEADER: &str = "X-Api-Key";
+
+const KEY_PREFIX: &str = "lk_";
+
+pub fn routes() -> Vec<Route> {
+    routes![list_api_keys, create_api_key, delete_api_key]
+}
+
+/// What an API key may do. Session and bearer credentials have every scope.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "loyalties:read")]
+    LoyaltiesRead,
+    #[serde(rename = "loyalties:write")]
+    LoyaltiesWrite,
+    #[serde(rename = "account:read")]
+    AccountRead,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [
+        Scope::LoyaltiesRead,
+        Scope::LoyaltiesWrite,
+        Scope::AccountRead,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "loyalties:read" => Some(Scope::LoyaltiesRead),
+            "loyalties:write" => Some(Scope::LoyaltiesWrite),
+            "account:read" => Some(Scope::AccountRead),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::LoyaltiesRead => "loyalties:read",
+            Scope::LoyaltiesWrite => "loyalties:write",
+            Scope::AccountRead => "account:read",
+        }
+    }
+}
+
+/// Scopes are stored space-separated, like OAuth scopes. Unknown names are
+/// ignored.
+fn parse_scopes(stored: &str) -> Vec<Scope> {
+    stored
+        .split_whitespace()
+        .filter_map(Scope::from_name)
+        .collect()
+}
+
+pub fn scope_names(stored: &str) -> Vec<String> {
+    parse_scopes(stored)
+        .into_iter()
+        .map(|scope| scope.name().to_string())
+        .collect()
+}
+
+/// Resolves a raw key to its owner and scopes, recording when it was last
+/// used.
+pub fn resolve(c: &SqliteConnection, raw: &str) -> QueryResult<Option<(i32, Vec<Scope>)>> {
+    use db::schema::api_keys::dsl::*;
+
+    let key = api_keys
+        .filter(key_hash.eq(token::digest(raw)))
+        .first::<ApiKey>(c)
+        .optional()?;
+
+    match key {
+        Some(key) => {
+            diesel::update(&key)
+                .set(last_used_at.eq(Utc::now().naive_utc()))
+                .execute(c)?;
+            Ok(Some((key.user_id, parse_scopes(&key.scopes))))
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/apikeys")]
+async fn list_api_keys(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<Json<Vec<ApiKeyResponse>>, APIError> {
+    use db::schema::api_keys::dsl::*;
+
+    let keys = db
+        .run(move |c| {
+            api_keys
+                .filter(user_id.eq(user.0))
+                .order(id.asc())
+                .load::<ApiKey>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        keys.into_iter()
+            .map(|key| ApiKeyResponse {
+                id: key.id,
+                name: key.name,
+                prefix: key.prefix,
+                scopes: scope_names(&key.scopes),
+                created_at: key.created_at,
+                last_used_at: key.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+#[post("/apikeys", format = "json", data = "<body>")]
+async fn create_api_key(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    body: Json<CreateApiKey>,
+) -> Result<Json<CreatedApiKey>, APIError> {
+    use db::schema::api_keys::dsl::*;
+
+    let raw = format!("{}{}", KEY_PREFIX, token::generate());
+    let hashed = token::digest(&raw);
+    let requested = body.0.scopes.clone().unwrap_or_else(|| Scope::ALL.to_vec());
+    let stored = Scope::ALL
+        .iter()
+        .filter(|scope| requested.contains(scope))
+        .map(|scope| scope.name())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let created = db
+        .run(move |c| {
+            diesel::insert_into(api_keys)
+                .values(&NewApiKey {
+                    user_id: user.0,
+                    name: &body.0.name,
+                    prefix: &raw[..KEY_PREFIX.len() + 6],
+                    key_hash: &hashed,
+                    scopes: &stored,
+                })
+                .execute(c)?;
+
+            let created = api_keys.filter(key_hash.eq(&hashed)).first::<ApiKey>(c)?;
+
+            Ok::<_, APIError>(CreatedApiKey {
+                id: created.id,
+                name: created.name,
+                key: raw,
+                scopes: scope_names(&created.scopes),
+            })
+        })
+        .await?;
+
+    Ok(Json(created))
+}
+
+#[delete("/apikeys/<key_id>")]
+async fn delete_api_key(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    key_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::api_keys::dsl::*;
+
+    let key_id: i32 = key_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            diesel::delete(api_keys.filter(id.eq(key_id).and(user_id.eq(user.0)))).execute(c)
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "api key deleted")),
+    }
+}
@@ -0,0 +1,206 @@
+//! "Remember this device": a long-lived cookie that opens a new session when
+//! the browser comes back without one.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::response::status;
+use rocket::{delete, get, routes, Route};
+use rocket_contrib::json::Json;
+
+use super::events::{self, Event};
+use super::sessions::{self, ClientInfo};
+use super::{token, SessionUser};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::Device, models::NewDevice};
+use crate::requests::DeviceResponse;
+use crate::{APIError, LoyaltyDbConn};
+
+pub const COOKIE: &str = "device";
+
+const TTL_DAYS: i64 = 90;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_devices, delete_device]
+}
+
+pub fn cookie(policy: &CookiePolicy, raw: String) -> Cookie<'static> {
+    let mut cookie = policy.build(COOKIE, raw);
+    cookie.set_max_age(time::Duration::days(TTL_DAYS));
+    cookie
+}
+
+/// Remembers the device the client signs in from and returns its id and the
+/// raw token to hand out.
+pub fn create(c: &SqliteConnection, user: i32, client: &ClientInfo) -> QueryResult<(i32, String)> {
+    use db::schema::devices::dsl::*;
+
+    let raw = token::generate();
+    let hashed = token::digest(&raw);
+
+    diesel::insert_into(devices)
+        .values(&NewDevice {
+            user_id: user,
+            token_hash: &hashed,
+            user_agent: client.user_agent.as_deref(),
+            ip: client.ip.as_deref(),
+            expires_at: Utc::now().naive_utc() + Duration::days(TTL_DAYS),
+        })
+        .execute(c)?;
+
+    let created = devices
+        .filter(token_hash.eq(&hashed))
+        .select(id)
+        .first::<i32>(c)?;
+
+    Ok((created, raw))
+}
+
+/// Opens a new session on a remembered device. Returns the owner and the raw
+/// session token.
+pub fn resume(
+    c: &SqliteConnection,
+    raw: &str,
+    client: &ClientInfo,
+) -> QueryResult<Option<(i32, String)>> {
+    use db::schema::devices::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let device = devices
+        .filter(token_hash.eq(token::digest(raw)))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(now))
+        .first::<Device>(c)
+        .optional()?;
+
+    let device = match device {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+
+    diesel::update(&device)
+        .set(last_used_at.eq(now))
+        .execute(c)?;
+
+    let (_, session) = sessions::create(c, device.user_id, client, Some(device.id))?;
+    events::record(c, device.user_id, Event::SignIn, client)?;
+
+    Ok(Some((device.user_id, session)))
+}
+
+/// Forgets one device of the user and signs out the sessions it opened.
+pub fn revoke(c: &SqliteConnection, user: i32, device: i32) -> QueryResult<usize> {
+    use db::schema::devices::dsl::*;
+
+    let revoked = diesel::update(
+        devices
+            .filter(id.eq(device))
+            .filter(user_id.eq(user))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(c)?;
+
+    let opened = {
+        use db::schema::sessions::dsl as s;
+        s::sessions
+            .filter(s::device_id.eq(device))
+            .filter(s::revoked_at.is_null())
+            .select(s::id)
+            .load::<i32>(c)?
+    };
+    for session in opened {
+        sessions::revoke(c, user, session)?;
+    }
+
+    Ok(revoked)
+}
+
+pub fn revoke_all(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::devices::dsl::*;
+
+    diesel::update(
+        devices
+            .filter(user_id.eq(user))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(c)
+}
+
+/// Forgets every device of the user except the one `session` was opened on.
+pub fn revoke_others(c: &SqliteConnection, user: i32, session: i32) -> QueryResult<usize> {
+    use db::schema::devices::dsl::*;
+
+    let keep = db::schema::sessions::table
+        .find(session)
+        .select(db::schema::sessions::device_id)
+        .first::<Option<i32>>(c)
+        .optional()?
+        .flatten();
+
+    match keep {
+        Some(keep) => diesel::update(
+            devices
+                .filter(user_id.eq(user))
+                .filter(id.ne(keep))
+                .filter(revoked_at.is_null()),
+        )
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(c),
+        None => revoke_all(c, user),
+    }
+}
+
+#[get("/devices")]
+async fn list_devices(
+    cookies: &CookieJar<'_>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<Json<Vec<DeviceResponse>>, APIError> {
+    use db::schema::devices::dsl::*;
+
+    let current = cookies
+        .get_private(COOKIE)
+        .map(|cookie| token::digest(cookie.value()));
+
+    let remembered = db
+        .run(move |c| {
+            devices
+                .filter(user_id.eq(user.0))
+                .filter(revoked_at.is_null())
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .order(last_used_at.desc())
+                .load::<Device>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        remembered
+            .into_iter()
+            .map(|device| DeviceResponse {
+                current: current.as_deref() == Some(device.token_hash.as_str()),
+                id: device.id,
+                user_agent: device.user_agent,
+                ip: device.ip,
+                created_at: device.created_at,
+                last_used_at: device.last_used_at,
+                expires_at: device.expires_at,
+            })
+            .collect(),
+    ))
+}
+
+#[delete("/devices/<device_id>")]
+async fn delete_device(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    device_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let device_id: i32 = device_id.parse()?;
+
+    match db.run(move |c| revoke(c, user.0, device_id)).await? {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "device revoked")),
+    }
+}
@@ -0,0 +1,221 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use super::events::{self, Event};
+use super::jwt::{EmailRevertClaims, JwtKeys};
+use super::sessions::ClientInfo;
+use super::{invalidate_sessions, password, token, SessionUser};
+use crate::db::{self, models::NewEmailChange};
+use crate::mail::Mailer;
+use crate::rate_limit::RateLimiter;
+use crate::requests::ChangeEmail;
+use crate::{APIError, LoyaltyDbConn};
+
+const TOKEN_TTL_HOURS: i64 = 24;
+const REVERT_TTL_DAYS: i64 = 7;
+
+pub fn routes() -> Vec<Route> {
+    routes![request_change, confirm_change, revert_change]
+}
+
+fn email_taken(c: &SqliteConnection, address: &str) -> QueryResult<bool> {
+    use db::schema::users::dsl::*;
+
+    let owner = users
+        .filter(email.eq(address))
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+
+    Ok(owner.is_some())
+}
+
+/// Stores the new address as pending until it is confirmed from its own
+/// inbox. The current address keeps working in the meantime.
+#[post("/email/change", format = "json", data = "<body>")]
+async fn request_change(
+    limiter: State<'_, RateLimiter>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    user: SessionUser,
+    body: Json<ChangeEmail>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    limiter.check_account(&format!("email:{}", user.0))?;
+    body.0.validate()?;
+
+    let (old_email, new_email, raw) = db
+        .run(move |c| {
+            use db::schema::email_changes::dsl::*;
+
+            let account = db::schema::users::table
+                .find(user.0)
+                .first::<db::models::User>(c)?;
+            if !password::verify(&account.pass, &body.0.pass) {
+                return Err(APIError::NotAuthorized);
+            }
+            if email_taken(c, &body.0.email)? {
+                return Err(APIError::Conflict);
+            }
+
+            diesel::delete(email_changes.filter(user_id.eq(user.0))).execute(c)?;
+
+            let raw = token::generate();
+            diesel::insert_into(email_changes)
+                .values(&NewEmailChange {
+                    user_id: user.0,
+                    new_email: &body.0.email,
+                    token_hash: &token::digest(&raw),
+                    expires_at: Utc::now().naive_utc() + Duration::hours(TOKEN_TTL_HOURS),
+                })
+                .execute(c)?;
+
+            Ok((account.email, body.0.email, raw))
+        })
+        .await?;
+
+    let link = mailer.link(&format!("/email/confirm?token={}", raw));
+    let confirmation = format!(
+        "Please confirm that you want to use this address for your account\n\
+         by opening the link below within {} hours:\n\n{}\n",
+        TOKEN_TTL_HOURS, link
+    );
+    if let Err(e) = mailer
+        .send(&new_email, "Confirm your new email address", confirmation)
+        .await
+    {
+        log::warn!("could not send email change confirmation: {}", e);
+    }
+
+    let notice = format!(
+        "Someone asked to change the email address of your account to {}.\n\n\
+         Nothing changes until the new address is confirmed. If it wasn't you,\n\
+         change your password.\n",
+        new_email
+    );
+    if let Err(e) = mailer
+        .send(&old_email, "Your email address is being changed", notice)
+        .await
+    {
+        log::warn!("could not send email change notice: {}", e);
+    }
+
+    Ok(status::Custom(Status::Ok, "confirmation email sent"))
+}
+
+/// Completes the change and tells the previous address, with a link to undo
+/// it in case the account was taken over.
+#[get("/email/confirm?<token>")]
+async fn confirm_change(
+    db: LoyaltyDbConn,
+    keys: State<'_, JwtKeys>,
+    mailer: State<'_, Mailer>,
+    token: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let (owner, old_email, new_email) = db
+        .run(move |c| {
+            use db::schema::email_changes::dsl::*;
+
+            c.transaction(|| {
+                let (owner, address) = email_changes
+                    .filter(token_hash.eq(token::digest(&token)))
+                    .filter(expires_at.gt(Utc::now().naive_utc()))
+                    .select((user_id, new_email))
+                    .first::<(i32, String)>(c)
+                    .optional()?
+                    .ok_or(APIError::NotAuthorized)?;
+
+                // Someone may have signed up with the address since the request.
+                if email_taken(c, &address)? {
+                    return Err(APIError::Conflict);
+                }
+
+                let previous = {
+                    use db::schema::users::dsl::*;
+                    let previous = users.find(owner).select(email).first::<String>(c)?;
+                    diesel::update(users.filter(id.eq(owner)))
+                        .set((email.eq(&address), email_verified.eq(true)))
+                        .execute(c)?;
+                    previous
+                };
+
+                diesel::delete(email_changes.filter(user_id.eq(owner))).execute(c)?;
+                Ok((owner, previous, address))
+            })
+        })
+        .await?;
+
+    let revert = keys.issue_email_revert(&EmailRevertClaims {
+        sub: owner,
+        exp: (Utc::now() + Duration::days(REVERT_TTL_DAYS)).timestamp(),
+        old_email: old_email.clone(),
+        new_email: new_email.clone(),
+    })?;
+    let link = mailer.link(&format!("/email/revert?token={}", revert));
+    let notice = format!(
+        "The email address of your account was changed to {}.\n\n\
+         If it wasn't you, open the link below within {} days to restore this\n\
+         address and sign out everywhere:\n\n{}\n",
+        new_email, REVERT_TTL_DAYS, link
+    );
+    if let Err(e) = mailer
+        .send(&old_email, "Your email address was changed", notice)
+        .await
+    {
+        log::warn!("could not send email change notification: {}", e);
+    }
+
+    Ok(status::Custom(Status::Ok, "email updated"))
+}
+
+/// Restores the previous address and signs out every session, as whoever
+/// changed it may still be signed in. The link stops working once the
+/// address is no longer the one it moved away to.
+#[get("/email/revert?<token>")]
+async fn revert_change(
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    keys: State<'_, JwtKeys>,
+    token: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let claims = keys
+        .verify_email_revert(&token)
+        .map_err(|_| APIError::NotAuthorized)?;
+
+    db.run(move |c| {
+        use db::schema::users::dsl::*;
+
+        c.transaction(|| {
+            let current = users.find(claims.sub).select(email).first::<String>(c)?;
+            if current != claims.new_email {
+                return Err(APIError::NotAuthorized);
+            }
+            if email_taken(c, &claims.old_email)? {
+                return Err(APIError::Conflict);
+            }
+
+            diesel::update(users.find(claims.sub))
+                .set((email.eq(&claims.old_email), email_verified.eq(true)))
+                .execute(c)?;
+            diesel::delete(
+                db::schema::email_changes::table
+                    .filter(db::schema::email_changes::user_id.eq(claims.sub)),
+            )
+            .execute(c)?;
+
+            invalidate_sessions(c, claims.sub)?;
+            events::record(c, claims.sub, Event::EmailChangeReverted, &client)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(
+        Status::Ok,
+        "email restored, please reset your password",
+    ))
+}
@@ -0,0 +1,154 @@
+//! Security audit log of authentication events, readable by their user.
+
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+
+use super::sessions::ClientInfo;
+use super::SessionUser;
+use crate::db::{self, models::AuthEvent, models::NewAuthEvent};
+use crate::requests::{AuthEventResponse, LoginResponse};
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_events, list_logins]
+}
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    SignIn,
+    SignInFailed,
+    SignOut,
+    SessionRevoked,
+    PasswordChanged,
+    PasswordReset,
+    TokenIssued,
+    TokenRefreshed,
+    AccountDeleted,
+    EmailChangeReverted,
+    AccountLocked,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::SignIn => "sign_in",
+            Event::SignInFailed => "sign_in_failed",
+            Event::SignOut => "sign_out",
+            Event::SessionRevoked => "session_revoked",
+            Event::PasswordChanged => "password_changed",
+            Event::PasswordReset => "password_reset",
+            Event::TokenIssued => "token_issued",
+            Event::TokenRefreshed => "token_refreshed",
+            Event::AccountDeleted => "account_deleted",
+            Event::EmailChangeReverted => "email_change_reverted",
+            Event::AccountLocked => "account_locked",
+        }
+    }
+}
+
+pub fn record(
+    c: &SqliteConnection,
+    user: i32,
+    event: Event,
+    client: &ClientInfo,
+) -> QueryResult<usize> {
+    diesel::insert_into(db::schema::auth_events::table)
+        .values(&NewAuthEvent {
+            user_id: user,
+            kind: event.name(),
+            ip: client.ip.as_deref(),
+            user_agent: client.user_agent.as_deref(),
+            country: client.country.as_deref(),
+        })
+        .execute(c)
+}
+
+#[get("/security/events?<limit>&<offset>")]
+async fn list_events(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<AuthEventResponse>>, APIError> {
+    use db::schema::auth_events::dsl::*;
+
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(50)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let events = db
+        .run(move |c| {
+            auth_events
+                .filter(user_id.eq(user.0))
+                .order(id.desc())
+                .limit(limit)
+                .offset(offset)
+                .load::<AuthEvent>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        events
+            .into_iter()
+            .map(|event| AuthEventResponse {
+                id: event.id,
+                kind: event.kind,
+                ip: event.ip,
+                country: event.country,
+                user_agent: event.user_agent,
+                created_at: event.created_at,
+            })
+            .collect(),
+    ))
+}
+
+/// Recent sign-in attempts, successful or not, so users can spot access
+/// they don't recognize.
+#[get("/security/logins?<limit>")]
+async fn list_logins(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    limit: Option<String>,
+) -> Result<Json<Vec<LoginResponse>>, APIError> {
+    use db::schema::auth_events::dsl::*;
+
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let logins: Vec<_> = [Event::SignIn, Event::TokenIssued, Event::SignInFailed]
+        .iter()
+        .map(|event| event.name())
+        .collect();
+
+    let events = db
+        .run(move |c| {
+            auth_events
+                .filter(user_id.eq(user.0))
+                .filter(kind.eq_any(logins))
+                .order(id.desc())
+                .limit(limit)
+                .load::<AuthEvent>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        events
+            .into_iter()
+            .map(|event| LoginResponse {
+                succeeded: event.kind != Event::SignInFailed.name(),
+                ip: event.ip,
+                country: event.country,
+                user_agent: event.user_agent,
+                at: event.created_at,
+            })
+            .collect(),
+    ))
+}
@@ -0,0 +1,130 @@
+//! Guest accounts: usable right away from the device that created them, and
+//! claimable later with an email and password without losing their cards.
+
+use diesel::prelude::*;
+use rocket::http::{CookieJar, Status};
+use rocket::response::status;
+use rocket::{post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use super::invites::SignupConfig;
+use super::policy::PasswordPolicy;
+use super::sessions::ClientInfo;
+use super::{open_session, password, set_session_cookies, token, verification, SessionUser};
+use crate::captcha::CaptchaVerified;
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewUser};
+use crate::mail::Mailer;
+use crate::rate_limit::IpThrottle;
+use crate::requests::ClaimAccount;
+use crate::{APIError, LoyaltyDbConn};
+
+/// Placeholder addresses use a reserved domain so they can never receive mail.
+const GUEST_DOMAIN: &str = "guest.invalid";
+
+pub fn routes() -> Vec<Route> {
+    routes![create_guest, claim]
+}
+
+/// Creates a guest and remembers the device, as the device cookie is the
+/// only way back into the account until it is claimed.
+#[post("/account/guest")]
+async fn create_guest(
+    _throttle: IpThrottle,
+    _captcha: CaptchaVerified,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    signup_config: State<'_, SignupConfig>,
+    db: LoyaltyDbConn,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    // Guests have no way to present an invite.
+    if signup_config.invite_only {
+        return Err(APIError::NotAuthorized);
+    }
+
+    let signed_in = db
+        .run(move |c| {
+            c.transaction(|| {
+                let placeholder = format!("{}@{}", token::generate().to_lowercase(), GUEST_DOMAIN);
+                let unusable = password::hash(&token::generate())?;
+
+                diesel::insert_into(users)
+                    .values(&NewUser {
+                        email: &placeholder,
+                        name: "Guest",
+                        pass: &unusable,
+                    })
+                    .execute(c)?;
+                diesel::update(users.filter(email.eq(&placeholder)))
+                    .set(is_guest.eq(true))
+                    .execute(c)?;
+
+                let guest = users
+                    .filter(email.eq(&placeholder))
+                    .select(id)
+                    .first::<i32>(c)?;
+
+                Ok::<_, APIError>(open_session(c, guest, &client, true)?)
+            })
+        })
+        .await?;
+
+    set_session_cookies(cookies, &cookie_policy, signed_in);
+    Ok(status::Custom(Status::Created, "guest created"))
+}
+
+#[post("/account/claim", format = "json", data = "<body>")]
+async fn claim(
+    policy: State<'_, PasswordPolicy>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    user: SessionUser,
+    body: Json<ClaimAccount>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    body.0.validate()?;
+    policy.check(&body.0.pass)?;
+
+    let (address, raw) = db
+        .run(move |c| {
+            c.transaction(|| {
+                let guest = users.find(user.0).select(is_guest).first::<bool>(c)?;
+                if !guest {
+                    return Err(APIError::Conflict);
+                }
+
+                let taken = users
+                    .filter(email.eq(&body.0.email))
+                    .select(id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if taken.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                let hashed = password::hash(&body.0.pass)?;
+                let display_name = body.0.name.as_deref().unwrap_or("Guest");
+                diesel::update(users.find(user.0))
+                    .set((
+                        email.eq(&body.0.email),
+                        name.eq(display_name),
+                        pass.eq(hashed),
+                        is_guest.eq(false),
+                        email_verified.eq(false),
+                    ))
+                    .execute(c)?;
+
+                let raw = verification::issue(c, user.0)?;
+                Ok((body.0.email, raw))
+            })
+        })
+        .await?;
+
+    verification::send(&mailer, &address, &raw).await;
+    Ok(status::Custom(Status::Ok, "account claimed"))
+}
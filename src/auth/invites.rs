@@ -0,0 +1,89 @@
+//! Invite codes for invite-only deployments, such as a private beta. Codes
+//! are minted by administrators and only stored as a digest.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use super::token;
+use crate::db::{self, models::Invite, models::NewInvite};
+use crate::APIError;
+
+/// The `signup` section of the Rocket configuration.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct SignupConfig {
+    /// Require an invite code to create an account.
+    pub invite_only: bool,
+}
+
+/// Mints an invite and returns it with the raw code to hand out.
+pub fn mint(
+    c: &SqliteConnection,
+    admin: i32,
+    max_uses: i32,
+    expires_in_days: Option<i64>,
+) -> QueryResult<(Invite, String)> {
+    use db::schema::invites::dsl::*;
+
+    let raw = token::generate();
+    let hashed = token::digest(&raw);
+
+    diesel::insert_into(invites)
+        .values(&NewInvite {
+            code_hash: &hashed,
+            created_by: admin,
+            max_uses,
+            expires_at: expires_in_days.map(|days| Utc::now().naive_utc() + Duration::days(days)),
+        })
+        .execute(c)?;
+
+    let created = invites.filter(code_hash.eq(&hashed)).first::<Invite>(c)?;
+    Ok((created, raw))
+}
+
+/// Uses up one redemption of `code`. Meant to run in the signup transaction
+/// so the use is given back if the account can't be created.
+pub fn redeem(c: &SqliteConnection, code: &str) -> Result<(), APIError> {
+    use db::schema::invites::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let redeemed = diesel::update(
+        invites
+            .filter(code_hash.eq(token::digest(code.trim())))
+            .filter(revoked_at.is_null())
+            .filter(expires_at.is_null().or(expires_at.gt(now)))
+            .filter(uses.lt(max_uses)),
+    )
+    .set(uses.eq(uses + 1))
+    .execute(c)?;
+
+    match redeemed {
+        0 => Err(APIError::NotAuthorized),
+        _ => Ok(()),
+    }
+}
+
+pub fn revoke(c: &SqliteConnection, invite: i32) -> QueryResult<usize> {
+    use db::schema::invites::dsl::*;
+
+    diesel::update(invites.find(invite).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(c)
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Signup Config", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<SignupConfig>("signup") {
+            Ok(config) => config,
+            Err(e) if e.missing() => SignupConfig::default(),
+            Err(e) => {
+                log::error!("invalid signup configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,135 @@
+use chrono::Utc;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+
+fn default_ttl() -> i64 {
+    15 * 60
+}
+
+fn default_refresh_ttl() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+/// The `jwt` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct JwtConfig {
+    pub secret: String,
+    /// Lifetime of an access token, in seconds.
+    #[serde(default = "default_ttl")]
+    pub ttl: i64,
+    /// Lifetime of a refresh token, in seconds.
+    #[serde(default = "default_refresh_ttl")]
+    pub refresh_ttl: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i32,
+    pub iat: i64,
+    pub exp: i64,
+    /// The session the token was issued for.
+    #[serde(default)]
+    pub sid: Option<i32>,
+}
+
+/// Lets the previous owner of an address undo an email change.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailRevertClaims {
+    pub sub: i32,
+    pub exp: i64,
+    pub old_email: String,
+    pub new_email: String,
+}
+
+pub struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey<'static>,
+    /// Revert tokens use a derived key so they can never pass as access
+    /// tokens, which share the `sub` and `exp` claims.
+    revert_encoding: EncodingKey,
+    revert_decoding: DecodingKey<'static>,
+    pub ttl: i64,
+    pub refresh_ttl: i64,
+}
+
+impl JwtKeys {
+    pub fn new(config: &JwtConfig) -> Self {
+        let revert_secret = format!("{}:email-revert", config.secret);
+
+        JwtKeys {
+            encoding: EncodingKey::from_secret(config.secret.as_bytes()),
+            decoding: DecodingKey::from_secret(config.secret.as_bytes()).into_static(),
+            revert_encoding: EncodingKey::from_secret(revert_secret.as_bytes()),
+            revert_decoding: DecodingKey::from_secret(revert_secret.as_bytes()).into_static(),
+            ttl: config.ttl,
+            refresh_ttl: config.refresh_ttl,
+        }
+    }
+
+    pub fn issue(
+        &self,
+        user_id: i32,
+        session: Option<i32>,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user_id,
+            iat: now,
+            exp: now + self.ttl,
+            sid: session,
+        };
+
+        jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::default())
+            .map(|data| data.claims)
+    }
+
+    pub fn issue_email_revert(
+        &self,
+        claims: &EmailRevertClaims,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        jsonwebtoken::encode(&Header::default(), claims, &self.revert_encoding)
+    }
+
+    pub fn verify_email_revert(
+        &self,
+        token: &str,
+    ) -> Result<EmailRevertClaims, jsonwebtoken::errors::Error> {
+        jsonwebtoken::decode::<EmailRevertClaims>(
+            token,
+            &self.revert_decoding,
+            &Validation::default(),
+        )
+        .map(|data| data.claims)
+    }
+}
+
+/// The secret of the sample configuration, only good for development.
+const PLACEHOLDER_SECRET: &str = "change-me-in-production";
+
+/// Reads the `jwt` configuration section and manages the resulting keys.
+/// Release builds refuse to start with an empty or the placeholder secret.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("JWT Keys", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<JwtConfig>("jwt") {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("invalid jwt configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let release = rocket.figment().profile() == rocket::Config::RELEASE_PROFILE;
+        let secret = config.secret.trim();
+        if release && (secret.is_empty() || secret == PLACEHOLDER_SECRET) {
+            log::error!("set a jwt secret of your own to run in release");
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(JwtKeys::new(&config)))
+    })
+}
@@ -0,0 +1,151 @@
+//! Passwordless sign-in through a single-use link sent by email.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::{CookieJar, Status};
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+
+use super::sessions::ClientInfo;
+use super::{complete_sign_in, open_session, token, totp};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewMagicLink};
+use crate::mail::Mailer;
+use crate::rate_limit::{IpThrottle, RateLimiter};
+use crate::requests::MagicLinkRequest;
+use crate::{APIError, LoyaltyDbConn};
+
+const TOKEN_TTL_MINUTES: i64 = 15;
+
+pub fn routes() -> Vec<Route> {
+    routes![request_link, sign_in]
+}
+
+/// Answers the same way whether or not the account exists, like
+/// `/password/forgot`.
+#[post("/signin/magic", format = "json", data = "<body>")]
+async fn request_link(
+    _throttle: IpThrottle,
+    limiter: State<'_, RateLimiter>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    body: Json<MagicLinkRequest>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    limiter.check_account(&body.0.email)?;
+
+    let requested = db
+        .run(move |c| {
+            use db::schema::users::dsl::*;
+
+            let owner = users
+                .filter(email.eq(&body.0.email))
+                .filter(deleted_at.is_null())
+                .select(id)
+                .first::<i32>(c)
+                .optional()?;
+
+            let owner = match owner {
+                Some(owner) => owner,
+                None => return Ok::<_, APIError>(None),
+            };
+
+            let raw = token::generate();
+            diesel::insert_into(db::schema::magic_links::table)
+                .values(&NewMagicLink {
+                    user_id: owner,
+                    token_hash: &token::digest(&raw),
+                    expires_at: Utc::now().naive_utc() + Duration::minutes(TOKEN_TTL_MINUTES),
+                })
+                .execute(c)?;
+
+            Ok(Some((body.0.email, raw)))
+        })
+        .await?;
+
+    if let Some((to, raw)) = requested {
+        let link = mailer.link(&format!("/signin/magic/{}", raw));
+        let body = format!(
+            "Open the link below within {} minutes to sign in:\n\n{}\n\n\
+             The link works once. If you didn't ask for it, you can ignore this email.\n",
+            TOKEN_TTL_MINUTES, link
+        );
+
+        if let Err(e) = mailer.send(&to, "Your sign-in link", body).await {
+            log::warn!("could not send magic link email: {}", e);
+        }
+    }
+
+    Ok(status::Custom(
+        Status::Ok,
+        "if the account exists, a sign-in link was sent",
+    ))
+}
+
+/// Consumes the link. Following it proves access to the inbox, so the email
+/// is marked verified; accounts with two-factor still need their code.
+#[get("/signin/magic/<token>")]
+async fn sign_in(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    token: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let (user, signed_in) = db
+        .run(move |c| {
+            use db::schema::magic_links::dsl::*;
+
+            c.transaction(|| {
+                let link = magic_links
+                    .filter(token_hash.eq(token::digest(&token)))
+                    .select((id, user_id, expires_at))
+                    .first::<(i32, i32, chrono::NaiveDateTime)>(c)
+                    .optional()?;
+
+                // Single use: the link is gone whether or not it expired.
+                let owner = match link {
+                    Some((link_id, owner, expiry)) => {
+                        diesel::delete(magic_links.find(link_id)).execute(c)?;
+                        if expiry < Utc::now().naive_utc() {
+                            return Ok(None);
+                        }
+                        owner
+                    }
+                    None => return Ok(None),
+                };
+
+                let user = {
+                    use db::schema::users::dsl::*;
+                    diesel::update(users.find(owner))
+                        .set(email_verified.eq(true))
+                        .execute(c)?;
+                    users.find(owner).first::<db::models::User>(c)?
+                };
+                if user.deleted_at.is_some() {
+                    return Ok(None);
+                }
+
+                if user.totp_enabled {
+                    return Ok(Some((user, None)));
+                }
+
+                let signed_in = open_session(c, user.id, &client, false)?;
+                Ok::<_, APIError>(Some((user, Some(signed_in))))
+            })
+        })
+        .await?
+        .ok_or(APIError::NotAuthorized)?;
+
+    match signed_in {
+        Some(signed_in) => {
+            complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+            Ok(status::Custom(Status::Ok, "connected"))
+        }
+        None => {
+            totp::start_pending(cookies, &cookie_policy, &user, false);
+            Ok(status::Custom(Status::Accepted, "2fa required"))
+        }
+    }
+}
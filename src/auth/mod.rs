@@ -0,0 +1,758 @@
+pub mod account;
+pub mod alerts;
+pub mod api_keys;
+pub mod devices;
+pub mod email_change;
+pub mod events;
+pub mod guest;
+pub mod invites;
+pub mod jwt;
+pub mod magic_link;
+pub mod oauth;
+pub mod passkeys;
+pub mod password;
+pub mod policy;
+pub mod refresh;
+pub mod reset;
+#[cfg(feature = "saml")]
+pub mod saml;
+pub mod sessions;
+pub mod token;
+pub mod totp;
+pub mod verification;
+
+use diesel::prelude::*;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{Form, FromRequest, Outcome};
+use rocket::response::status;
+use rocket::{post, routes, FromForm, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::admin::{AdminConfig, IpRejected};
+use crate::captcha::CaptchaVerified;
+use crate::cookie_policy::CookiePolicy;
+use crate::csrf;
+use crate::db::{self, models::NewUser};
+use crate::mail::Mailer;
+use crate::proxies;
+use crate::rate_limit::{IpThrottle, RateLimiter};
+use crate::requests::{RefreshRequest, TokenResponse, UserSignIn, UserSignup};
+use crate::{APIError, LoyaltyDbConn};
+use api_keys::Scope;
+use events::Event;
+use invites::SignupConfig;
+use jwt::JwtKeys;
+use policy::PasswordPolicy;
+use sessions::ClientInfo;
+
+pub fn routes() -> Vec<Route> {
+    let mut routes = routes![
+        signup,
+        signin,
+        sign_out,
+        sign_out_all,
+        token,
+        refresh_token,
+        revoke_token
+    ];
+    routes.extend(verification::routes());
+    routes.extend(reset::routes());
+    routes.extend(magic_link::routes());
+    routes.extend(email_change::routes());
+    routes.extend(oauth::routes());
+    routes.extend(api_keys::routes());
+    routes.extend(totp::routes());
+    routes.extend(passkeys::routes());
+    routes.extend(sessions::routes());
+    routes.extend(devices::routes());
+    routes.extend(events::routes());
+    routes.extend(alerts::routes());
+    routes.extend(account::routes());
+    routes.extend(guest::routes());
+    #[cfg(feature = "saml")]
+    routes.extend(saml::routes());
+    routes
+}
+
+/// Signs the user out of every session and device and revokes all their
+/// refresh tokens. Access tokens are short-lived and simply run out.
+pub fn invalidate_sessions(c: &SqliteConnection, user: i32) -> QueryResult<()> {
+    sessions::revoke_all(c, user)?;
+    devices::revoke_all(c, user)?;
+    refresh::revoke_all(c, user)?;
+
+    Ok(())
+}
+
+/// Like `invalidate_sessions`, but keeps `session`, the device it was opened
+/// on and its refresh tokens.
+pub fn invalidate_other_sessions(c: &SqliteConnection, user: i32, session: i32) -> QueryResult<()> {
+    sessions::revoke_others(c, user, session)?;
+    devices::revoke_others(c, user, session)?;
+    refresh::revoke_others(c, user, session)?;
+
+    Ok(())
+}
+
+fn session_cookie(policy: &CookiePolicy, raw: String) -> Cookie<'static> {
+    policy.build(sessions::COOKIE, raw)
+}
+
+/// Raw tokens of a cookie sign-in: the session and, with "remember me",
+/// the device. Comes with an alert when the client looks unfamiliar.
+struct SignedIn {
+    session: String,
+    device: Option<String>,
+    alert: Option<alerts::SignInAlert>,
+}
+
+/// Completes a cookie sign-in, remembering the device when asked to.
+fn open_session(
+    c: &SqliteConnection,
+    user: i32,
+    client: &ClientInfo,
+    remember: bool,
+) -> Result<SignedIn, APIError> {
+    let alert = alerts::check(c, user, client)?;
+    let device = match remember {
+        true => Some(devices::create(c, user, client)?),
+        false => None,
+    };
+
+    let (_, session) = sessions::create(c, user, client, device.as_ref().map(|(id, _)| *id))?;
+    events::record(c, user, Event::SignIn, client)?;
+
+    Ok(SignedIn {
+        session,
+        device: device.map(|(_, raw)| raw),
+        alert,
+    })
+}
+
+fn set_session_cookies(cookies: &CookieJar<'_>, policy: &CookiePolicy, signed_in: SignedIn) {
+    cookies.add_private(session_cookie(policy, signed_in.session));
+    if let Some(device) = signed_in.device {
+        cookies.add_private(devices::cookie(policy, device));
+    }
+}
+
+/// Hands out the cookies of a sign-in and sends its alert, if any.
+async fn complete_sign_in(
+    cookies: &CookieJar<'_>,
+    policy: &CookiePolicy,
+    mailer: &Mailer,
+    mut signed_in: SignedIn,
+) {
+    let alert = signed_in.alert.take();
+    set_session_cookies(cookies, policy, signed_in);
+
+    if let Some(alert) = alert {
+        alerts::send(mailer, alert).await;
+    }
+}
+
+fn remove_session_cookies(cookies: &CookieJar<'_>, policy: &CookiePolicy) {
+    cookies.remove_private(policy.named(sessions::COOKIE));
+    cookies.remove_private(policy.named(devices::COOKIE));
+}
+
+/// Looks up the user by email and checks the password against the stored hash.
+/// Wrong passwords for an existing account are recorded in its audit log, and
+/// locked accounts are only told so once the password is right.
+pub fn authenticate(
+    c: &SqliteConnection,
+    user_email: &str,
+    user_pass: &str,
+    client: &ClientInfo,
+) -> Result<db::models::User, APIError> {
+    use db::schema::users::dsl::*;
+
+    let user = users
+        .filter(email.eq(user_email))
+        .filter(deleted_at.is_null())
+        .first::<db::models::User>(c)
+        .optional()?;
+
+    match user {
+        Some(user) if password::verify(&user.pass, user_pass) => match user.locked_at {
+            Some(_) => Err(APIError::Locked),
+            None => Ok(user),
+        },
+        Some(user) => {
+            events::record(c, user.id, Event::SignInFailed, client)?;
+            Err(APIError::NotAuthorized)
+        }
+        None => Err(APIError::NotAuthorized),
+    }
+}
+
+/// Counts rejected credentials against the client IP.
+fn strike_on_failure<T>(
+    limiter: &RateLimiter,
+    ip: &str,
+    result: Result<T, APIError>,
+) -> Result<T, APIError> {
+    if let Err(APIError::NotAuthorized) = result {
+        limiter.strike(ip);
+    }
+
+    result
+}
+
+/// Runs the second-factor check of a password sign-in, recording rejected
+/// codes in the audit log.
+fn second_factor(
+    c: &SqliteConnection,
+    user: &db::models::User,
+    code: Option<&str>,
+    client: &ClientInfo,
+) -> Result<bool, APIError> {
+    totp::second_factor(c, user, code).or_else(|e| {
+        events::record(c, user.id, Event::SignInFailed, client)?;
+        Err(e)
+    })
+}
+
+#[derive(FromForm)]
+struct SignupQuery {
+    /// The referral code of the user who invited this one.
+    #[form(field = "ref")]
+    referral: Option<String>,
+}
+
+#[post("/signup?<query..>", format = "json", data = "<body>")]
+async fn signup(
+    _throttle: IpThrottle,
+    _captcha: CaptchaVerified,
+    limiter: State<'_, RateLimiter>,
+    policy: State<'_, PasswordPolicy>,
+    signup_config: State<'_, SignupConfig>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    query: Form<SignupQuery>,
+    body: Json<UserSignup>,
+) -> Result<(), APIError> {
+    use db::schema::users::dsl::*;
+
+    limiter.check_account(&body.0.email)?;
+    body.0.validate()?;
+    policy.check(&body.0.pass)?;
+
+    let invite_only = signup_config.invite_only;
+    let referral = query.into_inner().referral;
+    let (user_email, raw) = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if invite_only {
+                    let code = body
+                        .0
+                        .invite_code
+                        .as_deref()
+                        .ok_or(APIError::NotAuthorized)?;
+                    invites::redeem(c, code)?;
+                }
+
+                let hashed = password::hash(&body.0.pass)?;
+                let new_value = NewUser {
+                    email: &body.0.email,
+                    name: &body.0.name,
+                    pass: &hashed,
+                };
+
+                diesel::insert_into(users).values(&new_value).execute(c)?;
+
+                let user_id = users
+                    .filter(email.eq(&body.0.email))
+                    .select(id)
+                    .first::<i32>(c)?;
+                if let Some(code) = &referral {
+                    crate::referrals::record(c, user_id, code)?;
+                }
+                let raw = verification::issue(c, user_id)?;
+
+                Ok((body.0.email, raw))
+            })
+        })
+        .await?;
+
+    verification::send(&mailer, &user_email, &raw).await;
+    Ok(())
+}
+
+#[post("/signin", format = "json", data = "<body>")]
+async fn signin(
+    _throttle: IpThrottle,
+    _captcha: CaptchaVerified,
+    limiter: State<'_, RateLimiter>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    body: Json<UserSignIn>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    limiter.check_account(&body.0.email)?;
+
+    let ip = client.ip.clone().unwrap_or_default();
+    let remember = body.0.remember_me;
+    let signed_in = db
+        .run(move |c| {
+            let user = authenticate(c, &body.0.email, &body.0.pass, &client)?;
+            if !second_factor(c, &user, body.0.code.as_deref(), &client)? {
+                return Ok::<_, APIError>((user, None));
+            }
+
+            let signed_in = open_session(c, user.id, &client, remember)?;
+            Ok((user, Some(signed_in)))
+        })
+        .await;
+    let (user, signed_in) = strike_on_failure(&limiter, &ip, signed_in)?;
+
+    match signed_in {
+        Some(signed_in) => {
+            complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+            Ok(status::Custom(Status::Ok, "connected"))
+        }
+        None => {
+            totp::start_pending(cookies, &cookie_policy, &user, remember);
+            Ok(status::Custom(Status::Accepted, "2fa required"))
+        }
+    }
+}
+
+#[post("/signout")]
+async fn sign_out(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+) -> Result<status::Custom<&'static str>, APIError> {
+    if let Some(cookie) = cookies.get_private(sessions::COOKIE) {
+        let raw = cookie.value().to_string();
+        db.run(move |c| {
+            if let Some((session, user)) = sessions::resolve(c, &raw)? {
+                let device = db::schema::sessions::table
+                    .find(session)
+                    .select(db::schema::sessions::device_id)
+                    .first::<Option<i32>>(c)?;
+
+                sessions::revoke(c, user, session)?;
+                if let Some(device) = device {
+                    devices::revoke(c, user, device)?;
+                }
+                events::record(c, user, Event::SignOut, &client)?;
+            }
+            Ok::<_, diesel::result::Error>(())
+        })
+        .await?;
+    }
+
+    remove_session_cookies(cookies, &cookie_policy);
+    Ok(status::Custom(Status::Ok, "logged out"))
+}
+
+/// Signs the user out everywhere, this client included.
+#[post("/signout/all")]
+async fn sign_out_all(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<status::Custom<&'static str>, APIError> {
+    db.run(move |c| {
+        c.transaction(|| {
+            invalidate_sessions(c, user.0)?;
+            events::record(c, user.0, Event::SignOut, &client)?;
+            Ok::<_, diesel::result::Error>(())
+        })
+    })
+    .await?;
+
+    remove_session_cookies(cookies, &cookie_policy);
+    Ok(status::Custom(Status::Ok, "logged out everywhere"))
+}
+
+fn token_response(
+    keys: &JwtKeys,
+    user_id: i32,
+    session: Option<i32>,
+    refresh_token: String,
+) -> Result<Json<TokenResponse>, APIError> {
+    Ok(Json(TokenResponse {
+        access_token: keys.issue(user_id, session)?,
+        token_type: "Bearer",
+        expires_in: keys.ttl,
+        refresh_token,
+    }))
+}
+
+#[post("/token", format = "json", data = "<body>")]
+async fn token(
+    _throttle: IpThrottle,
+    limiter: State<'_, RateLimiter>,
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    keys: State<'_, JwtKeys>,
+    mailer: State<'_, Mailer>,
+    body: Json<UserSignIn>,
+) -> Result<Json<TokenResponse>, APIError> {
+    limiter.check_account(&body.0.email)?;
+
+    let ip = client.ip.clone().unwrap_or_default();
+    let ttl = keys.refresh_ttl;
+    let issued = db
+        .run(move |c| {
+            let user = authenticate(c, &body.0.email, &body.0.pass, &client)?;
+            if !second_factor(c, &user, body.0.code.as_deref(), &client)? {
+                return Err(APIError::TwoFactorRequired);
+            }
+
+            let alert = alerts::check(c, user.id, &client)?;
+            let (session, _) = sessions::create(c, user.id, &client, None)?;
+            let refresh = refresh::issue(c, user.id, None, Some(session), ttl)?;
+            events::record(c, user.id, Event::TokenIssued, &client)?;
+            Ok::<_, APIError>((user.id, session, refresh, alert))
+        })
+        .await;
+    let (user_id, session, refresh, alert) = strike_on_failure(&limiter, &ip, issued)?;
+
+    if let Some(alert) = alert {
+        alerts::send(&mailer, alert).await;
+    }
+
+    token_response(&keys, user_id, Some(session), refresh)
+}
+
+#[post("/token/refresh", format = "json", data = "<body>")]
+async fn refresh_token(
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    keys: State<'_, JwtKeys>,
+    body: Json<RefreshRequest>,
+) -> Result<Json<TokenResponse>, APIError> {
+    let ttl = keys.refresh_ttl;
+    let rotated = db
+        .run(move |c| {
+            let rotated = refresh::rotate(c, &body.0.refresh_token, ttl)?;
+            events::record(c, rotated.user_id, Event::TokenRefreshed, &client)?;
+            Ok::<_, APIError>(rotated)
+        })
+        .await?;
+
+    token_response(&keys, rotated.user_id, rotated.session_id, rotated.token)
+}
+
+#[post("/token/revoke", format = "json", data = "<body>")]
+async fn revoke_token(
+    db: LoyaltyDbConn,
+    body: Json<RefreshRequest>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    db.run(move |c| refresh::revoke(c, &body.0.refresh_token))
+        .await?;
+
+    Ok(status::Custom(Status::Ok, "token revoked"))
+}
+
+async fn request_db(request: &rocket::Request<'_>) -> Outcome<LoyaltyDbConn, APIError> {
+    match request.guard::<LoyaltyDbConn>().await {
+        Outcome::Success(db) => Outcome::Success(db),
+        _ => Outcome::Failure((Status::ServiceUnavailable, APIError::Unknown)),
+    }
+}
+
+/// The authenticated user, resolved from an `Authorization: Bearer` JWT, an
+/// `X-Api-Key` header or the private session cookie, in that order. Mutating
+/// requests using the cookie must also pass the CSRF check.
+#[derive(Debug)]
+pub struct User(pub i32);
+
+impl User {
+    /// A token outlives neither the session it was issued for nor the account.
+    async fn from_bearer(request: &rocket::Request<'_>, header: &str) -> Outcome<Self, APIError> {
+        let keys = request.managed_state::<JwtKeys>();
+
+        let claims = match (header.strip_prefix("Bearer "), keys) {
+            (Some(bearer), Some(keys)) => match keys.verify(bearer) {
+                Ok(claims) => claims,
+                Err(_) => return Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+            },
+            _ => return Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+        };
+
+        let db = try_outcome!(request_db(request).await);
+        let (user, session) = (claims.sub, claims.sid);
+        match db.run(move |c| sessions::accepts(c, user, session)).await {
+            Ok(true) => Outcome::Success(User(user)),
+            Ok(false) => Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+
+    async fn from_api_key(request: &rocket::Request<'_>, raw: &str) -> Outcome<Self, APIError> {
+        let db = try_outcome!(request_db(request).await);
+        let raw = raw.to_string();
+
+        match db.run(move |c| api_keys::resolve(c, &raw)).await {
+            Ok(Some((user, scopes))) => {
+                request.local_cache(|| KeyScopes(Some(scopes)));
+                Outcome::Success(User(user))
+            }
+            Ok(None) => Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+
+    /// Falls back to the device cookie when the session is gone, opening a
+    /// new session for the remembered device.
+    async fn from_cookie(request: &rocket::Request<'_>) -> Outcome<Self, APIError> {
+        if !csrf::verify(request) {
+            return Outcome::Failure((Status::Forbidden, APIError::NotAuthorized));
+        }
+
+        let cookies = request.cookies();
+        let session = cookies
+            .get_private(sessions::COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        let device = cookies
+            .get_private(devices::COOKIE)
+            .map(|cookie| cookie.value().to_string());
+
+        if session.is_none() && device.is_none() {
+            return Outcome::Failure((Status::Forbidden, APIError::NotAuthorized));
+        }
+        let presented_session = session.is_some();
+
+        let policy = match request.managed_state::<CookiePolicy>() {
+            Some(policy) => policy,
+            None => return Outcome::Failure((Status::InternalServerError, APIError::Unknown)),
+        };
+        let client = try_outcome!(request.guard::<ClientInfo>().await);
+        let db = try_outcome!(request_db(request).await);
+        let resolved = db
+            .run(move |c| {
+                if let Some(raw) = session {
+                    if let Some((_, user)) = sessions::resolve(c, &raw)? {
+                        return Ok::<_, diesel::result::Error>(Some((user, None)));
+                    }
+                }
+
+                match device {
+                    Some(raw) => Ok(devices::resume(c, &raw, &client)?
+                        .map(|(user, session)| (user, Some(session)))),
+                    None => Ok(None),
+                }
+            })
+            .await;
+
+        match resolved {
+            Ok(Some((user, resumed))) => {
+                if let Some(raw) = resumed {
+                    cookies.add_private(session_cookie(policy, raw));
+                }
+                Outcome::Success(User(user))
+            }
+            // The session expired or was revoked: the client should sign in again.
+            Ok(None) if presented_session => {
+                Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized))
+            }
+            Ok(None) => Outcome::Failure((Status::Forbidden, APIError::NotAuthorized)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for User {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        if let Some(header) = request.headers().get_one("Authorization") {
+            return User::from_bearer(request, header).await;
+        }
+
+        if let Some(raw) = request.headers().get_one(api_keys::HEADER) {
+            return User::from_api_key(request, raw).await;
+        }
+
+        User::from_cookie(request).await
+    }
+}
+
+/// A `User` authenticated with an interactive credential, i.e. anything but
+/// an API key. Used for routes a leaked key must not reach, like key management.
+#[derive(Debug)]
+pub struct SessionUser(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for SessionUser {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        if request.headers().contains(api_keys::HEADER) {
+            return Outcome::Failure((Status::Forbidden, APIError::NotAuthorized));
+        }
+
+        let user = try_outcome!(request.guard::<User>().await);
+        Outcome::Success(SessionUser(user.0))
+    }
+}
+
+/// Scopes of the API key a request was authenticated with. `None` for every
+/// other credential.
+struct KeyScopes(Option<Vec<Scope>>);
+
+async fn scoped_user(request: &rocket::Request<'_>, scope: Scope) -> Outcome<i32, APIError> {
+    let user = try_outcome!(request.guard::<User>().await);
+
+    match &request.local_cache(|| KeyScopes(None)).0 {
+        Some(scopes) if !scopes.contains(&scope) => {
+            Outcome::Failure((Status::Forbidden, APIError::NotAuthorized))
+        }
+        _ => Outcome::Success(user.0),
+    }
+}
+
+/// A `User` allowed to read cards: API keys need the `loyalties:read` scope.
+#[derive(Debug)]
+pub struct LoyaltiesReader(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for LoyaltiesReader {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        scoped_user(request, Scope::LoyaltiesRead)
+            .await
+            .map(LoyaltiesReader)
+    }
+}
+
+/// A `User` allowed to change cards: API keys need the `loyalties:write`
+/// scope.
+#[derive(Debug)]
+pub struct LoyaltiesWriter(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for LoyaltiesWriter {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        scoped_user(request, Scope::LoyaltiesWrite)
+            .await
+            .map(LoyaltiesWriter)
+    }
+}
+
+/// A `User` allowed to read their profile: API keys need the `account:read`
+/// scope.
+#[derive(Debug)]
+pub struct AccountReader(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AccountReader {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        scoped_user(request, Scope::AccountRead)
+            .await
+            .map(AccountReader)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// A `SessionUser` with the admin role, connecting from an address of the
+/// admin allowlist. API keys never grant admin access.
+#[derive(Debug)]
+pub struct AdminUser(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AdminUser {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        use db::schema::users::dsl::*;
+
+        // Checked first, so the rest of the world can't even probe credentials.
+        let allowed = request
+            .managed_state::<AdminConfig>()
+            .map_or(true, |config| config.allows(proxies::client_ip(request)));
+        if !allowed {
+            request.local_cache(|| IpRejected(true));
+            return Outcome::Failure((Status::Forbidden, APIError::IpNotAllowed));
+        }
+
+        let user = try_outcome!(request.guard::<SessionUser>().await);
+        let db = try_outcome!(request_db(request).await);
+
+        let found = db
+            .run(move |c| users.filter(id.eq(user.0)).select(role).first::<String>(c))
+            .await;
+
+        match found {
+            Ok(name) if Role::from_name(&name) == Some(Role::Admin) => {
+                Outcome::Success(AdminUser(user.0))
+            }
+            Ok(_) => Outcome::Failure((Status::Forbidden, APIError::NotAuthorized)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+}
+
+/// A `User` whose email address has been confirmed, or a guest: guests have
+/// no address yet but can use the app until they claim their account.
+#[derive(Debug)]
+pub struct VerifiedUser(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for VerifiedUser {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        use db::schema::users::dsl::*;
+
+        let user = try_outcome!(request.guard::<User>().await);
+        let db = try_outcome!(request_db(request).await);
+
+        let verified = db
+            .run(move |c| {
+                users
+                    .filter(id.eq(user.0))
+                    .select((email_verified, is_guest))
+                    .first::<(bool, bool)>(c)
+            })
+            .await;
+
+        match verified {
+            Ok((true, _)) | Ok((_, true)) => Outcome::Success(VerifiedUser(user.0)),
+            Ok(_) => Outcome::Failure((Status::Forbidden, APIError::EmailNotVerified)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+}
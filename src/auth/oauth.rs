@@ -0,0 +1,445 @@
+//! Authorization-code sign-in with external identity providers.
+//!
+//! The identity token is received directly from the provider's token endpoint
+//! over TLS, so only its claims are validated (OpenID Connect Core 3.1.3.7).
+
+use chrono::Utc;
+use diesel::prelude::*;
+use jsonwebtoken::{Algorithm, EncodingKey, Header, Validation};
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::Form;
+use rocket::response::{status, Redirect};
+use rocket::{get, post, routes, FromForm, Route, State};
+use serde::{Deserialize, Serialize};
+
+use super::invites::SignupConfig;
+use super::sessions::ClientInfo;
+use super::{complete_sign_in, open_session, password, token, SignedIn};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewUser, models::NewUserIdentity};
+use crate::mail::Mailer;
+use crate::{APIError, LoyaltyDbConn};
+
+const STATE_COOKIE: &str = "oauth_state";
+
+pub fn routes() -> Vec<Route> {
+    routes![authorize, callback, callback_form_post]
+}
+
+#[derive(Deserialize)]
+pub struct GoogleConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+}
+
+/// Apple has no static client secret: it is a short-lived ES256 JWT signed
+/// with the private key downloaded from the developer portal.
+#[derive(Deserialize)]
+pub struct AppleConfig {
+    pub client_id: String,
+    pub team_id: String,
+    pub key_id: String,
+    pub private_key: String,
+    pub redirect_uri: String,
+}
+
+/// The `oauth` section of the Rocket configuration. Providers without a
+/// section are disabled.
+#[derive(Default, Deserialize)]
+pub struct OAuthConfig {
+    pub google: Option<GoogleConfig>,
+    pub apple: Option<AppleConfig>,
+}
+
+pub struct OAuth {
+    config: OAuthConfig,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    email: Option<String>,
+    /// Google sends a boolean, Apple a string.
+    #[serde(default)]
+    email_verified: serde_json::Value,
+}
+
+impl IdClaims {
+    fn verified_email(&self) -> Option<&str> {
+        let verified = match &self.email_verified {
+            serde_json::Value::Bool(b) => *b,
+            serde_json::Value::String(s) => s == "true",
+            _ => false,
+        };
+
+        self.email.as_deref().filter(|_| verified)
+    }
+}
+
+#[derive(Serialize)]
+struct AppleSecretClaims<'a> {
+    iss: &'a str,
+    iat: i64,
+    exp: i64,
+    aud: &'a str,
+    sub: &'a str,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Provider {
+    Google,
+    Apple,
+}
+
+impl Provider {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "google" => Some(Provider::Google),
+            "apple" => Some(Provider::Apple),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Provider::Google => "google",
+            Provider::Apple => "apple",
+        }
+    }
+
+    fn issuers(self) -> &'static [&'static str] {
+        match self {
+            Provider::Google => &["https://accounts.google.com", "accounts.google.com"],
+            Provider::Apple => &["https://appleid.apple.com"],
+        }
+    }
+}
+
+impl OAuth {
+    fn authorize_url(&self, provider: Provider, state: &str) -> Option<String> {
+        let url = match provider {
+            Provider::Google => {
+                let google = self.config.google.as_ref()?;
+                url::Url::parse_with_params(
+                    "https://accounts.google.com/o/oauth2/v2/auth",
+                    &[
+                        ("client_id", google.client_id.as_str()),
+                        ("redirect_uri", &google.redirect_uri),
+                        ("response_type", "code"),
+                        ("scope", "openid email profile"),
+                        ("state", state),
+                    ],
+                )
+            }
+            Provider::Apple => {
+                let apple = self.config.apple.as_ref()?;
+                url::Url::parse_with_params(
+                    "https://appleid.apple.com/auth/authorize",
+                    &[
+                        ("client_id", apple.client_id.as_str()),
+                        ("redirect_uri", &apple.redirect_uri),
+                        ("response_type", "code"),
+                        ("response_mode", "form_post"),
+                        ("scope", "name email"),
+                        ("state", state),
+                    ],
+                )
+            }
+        };
+
+        url.ok().map(String::from)
+    }
+
+    fn apple_client_secret(apple: &AppleConfig) -> Result<String, APIError> {
+        let now = Utc::now().timestamp();
+        let claims = AppleSecretClaims {
+            iss: &apple.team_id,
+            iat: now,
+            exp: now + 5 * 60,
+            aud: "https://appleid.apple.com",
+            sub: &apple.client_id,
+        };
+        let header = Header {
+            kid: Some(apple.key_id.clone()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let key = EncodingKey::from_ec_pem(apple.private_key.as_bytes())?;
+
+        Ok(jsonwebtoken::encode(&header, &claims, &key)?)
+    }
+
+    /// Exchanges the authorization code and returns the validated identity claims.
+    async fn exchange(&self, provider: Provider, code: &str) -> Result<IdClaims, APIError> {
+        let (endpoint, client_id, form) = match provider {
+            Provider::Google => {
+                let google = self.config.google.as_ref().ok_or(APIError::NotFound)?;
+                (
+                    "https://oauth2.googleapis.com/token",
+                    google.client_id.as_str(),
+                    vec![
+                        ("code", code.to_string()),
+                        ("client_id", google.client_id.clone()),
+                        ("client_secret", google.client_secret.clone()),
+                        ("redirect_uri", google.redirect_uri.clone()),
+                        ("grant_type", "authorization_code".to_string()),
+                    ],
+                )
+            }
+            Provider::Apple => {
+                let apple = self.config.apple.as_ref().ok_or(APIError::NotFound)?;
+                (
+                    "https://appleid.apple.com/auth/token",
+                    apple.client_id.as_str(),
+                    vec![
+                        ("code", code.to_string()),
+                        ("client_id", apple.client_id.clone()),
+                        ("client_secret", Self::apple_client_secret(apple)?),
+                        ("redirect_uri", apple.redirect_uri.clone()),
+                        ("grant_type", "authorization_code".to_string()),
+                    ],
+                )
+            }
+        };
+
+        let response: TokenEndpointResponse = self
+            .http
+            .post(endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[client_id]);
+        let claims = jsonwebtoken::dangerous_insecure_decode_with_validation::<IdClaims>(
+            &response.id_token,
+            &validation,
+        )?
+        .claims;
+
+        if !provider.issuers().contains(&claims.iss.as_str()) {
+            return Err(APIError::NotAuthorized);
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Finds the local user for an external identity, linking it to an existing
+/// account with the same verified email or creating a new account when
+/// `allow_signup` is set. Also used by SAML sign-in.
+pub fn resolve_identity(
+    c: &SqliteConnection,
+    provider_name: &str,
+    external_subject: &str,
+    claimed_email: Option<&str>,
+    verified_email: Option<&str>,
+    allow_signup: bool,
+) -> Result<db::models::User, APIError> {
+    use db::schema::user_identities::dsl as identities;
+    use db::schema::users::dsl::*;
+
+    c.transaction(|| {
+        let linked = identities::user_identities
+            .filter(identities::provider.eq(provider_name))
+            .filter(identities::subject.eq(external_subject))
+            .select(identities::user_id)
+            .first::<i32>(c)
+            .optional()?;
+
+        if let Some(owner) = linked {
+            let user = users.find(owner).first::<db::models::User>(c)?;
+            return match user.deleted_at {
+                Some(_) => Err(APIError::NotAuthorized),
+                None => Ok(user),
+            };
+        }
+
+        let verified_email = verified_email.ok_or(APIError::NotAuthorized)?;
+        let existing = users
+            .filter(email.eq(verified_email))
+            .first::<db::models::User>(c)
+            .optional()?;
+
+        let user = match existing {
+            Some(user) if user.deleted_at.is_some() => return Err(APIError::NotAuthorized),
+            Some(user) => user,
+            None if !allow_signup => return Err(APIError::NotAuthorized),
+            None => {
+                // The account has no usable password until the user resets it.
+                let unusable = password::hash(&token::generate())?;
+                let display_name = verified_email.split('@').next().unwrap_or(verified_email);
+
+                diesel::insert_into(users)
+                    .values(&NewUser {
+                        email: verified_email,
+                        name: display_name,
+                        pass: &unusable,
+                    })
+                    .execute(c)?;
+
+                diesel::update(users.filter(email.eq(verified_email)))
+                    .set(email_verified.eq(true))
+                    .execute(c)?;
+                users
+                    .filter(email.eq(verified_email))
+                    .first::<db::models::User>(c)?
+            }
+        };
+
+        diesel::insert_into(identities::user_identities)
+            .values(&NewUserIdentity {
+                user_id: user.id,
+                provider: provider_name,
+                subject: external_subject,
+                email: claimed_email,
+            })
+            .execute(c)?;
+
+        Ok(user)
+    })
+}
+
+#[get("/auth/oauth/<provider>")]
+async fn authorize(
+    cookies: &CookieJar<'_>,
+    oauth: State<'_, OAuth>,
+    provider: String,
+) -> Result<Redirect, APIError> {
+    let provider = Provider::from_name(&provider).ok_or(APIError::NotFound)?;
+    let state = token::generate();
+    let url = oauth
+        .authorize_url(provider, &state)
+        .ok_or(APIError::NotFound)?;
+
+    // Apple posts the callback cross-site, which a lax cookie wouldn't survive.
+    let same_site = match provider {
+        Provider::Google => SameSite::Lax,
+        Provider::Apple => SameSite::None,
+    };
+    cookies.add_private(
+        Cookie::build(STATE_COOKIE, format!("{}:{}", provider.name(), state))
+            .same_site(same_site)
+            .secure(same_site == SameSite::None)
+            .http_only(true)
+            .finish(),
+    );
+
+    Ok(Redirect::to(url))
+}
+
+/// Checks the state against the cookie set by `authorize`.
+fn check_state(cookies: &CookieJar<'_>, provider: &str, state: &str) -> Result<Provider, APIError> {
+    let provider = Provider::from_name(provider).ok_or(APIError::NotFound)?;
+
+    let expected = cookies
+        .get_private(STATE_COOKIE)
+        .map(|c| c.value().to_string());
+    cookies.remove_private(Cookie::named(STATE_COOKIE));
+
+    if expected != Some(format!("{}:{}", provider.name(), state)) {
+        return Err(APIError::NotAuthorized);
+    }
+
+    Ok(provider)
+}
+
+/// Exchanges the code and opens a session, returning the raw session token.
+/// New accounts are only created when signup is open.
+async fn finish(
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    oauth: &OAuth,
+    signup: SignupConfig,
+    provider: Provider,
+    code: String,
+) -> Result<SignedIn, APIError> {
+    let claims = oauth.exchange(provider, &code).await?;
+    db.run(move |c| {
+        let user = resolve_identity(
+            c,
+            provider.name(),
+            &claims.sub,
+            claims.email.as_deref(),
+            claims.verified_email(),
+            !signup.invite_only,
+        )?;
+        open_session(c, user.id, &client, false)
+    })
+    .await
+}
+
+#[get("/auth/oauth/<provider>/callback?<code>&<state>")]
+async fn callback(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    oauth: State<'_, OAuth>,
+    signup: State<'_, SignupConfig>,
+    mailer: State<'_, Mailer>,
+    provider: String,
+    code: String,
+    state: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let provider = check_state(cookies, &provider, &state)?;
+    let signed_in = finish(client, db, &oauth, *signup, provider, code).await?;
+
+    complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+    Ok(status::Custom(Status::Ok, "connected"))
+}
+
+#[derive(FromForm)]
+struct CallbackForm {
+    code: String,
+    state: String,
+}
+
+#[post("/auth/oauth/<provider>/callback", data = "<form>")]
+async fn callback_form_post(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    oauth: State<'_, OAuth>,
+    signup: State<'_, SignupConfig>,
+    mailer: State<'_, Mailer>,
+    provider: String,
+    form: Form<CallbackForm>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let form = form.into_inner();
+    let provider = check_state(cookies, &provider, &form.state)?;
+    let signed_in = finish(client, db, &oauth, *signup, provider, form.code).await?;
+
+    complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+    Ok(status::Custom(Status::Ok, "connected"))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("OAuth Providers", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<OAuthConfig>("oauth") {
+            Ok(config) => config,
+            Err(e) if e.missing() => OAuthConfig::default(),
+            Err(e) => {
+                log::error!("invalid oauth configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(OAuth {
+            config,
+            http: reqwest::Client::new(),
+        }))
+    })
+}
@@ -0,0 +1,401 @@
+//! WebAuthn passkeys: platform authenticators registered by a signed-in user
+//! and usable instead of a password afterwards.
+//!
+//! Ceremony state lives in a short-lived private cookie between the two
+//! requests of each ceremony. Without a `webauthn` section in the
+//! configuration the routes answer 404.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::http::{CookieJar, Status};
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::proto::{
+    CreationChallengeResponse, Credential, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
+use webauthn_rs::{AuthenticationState, RegistrationState, Webauthn};
+
+use super::events::{self, Event};
+use super::sessions::ClientInfo;
+use super::{complete_sign_in, open_session, SessionUser};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewWebauthnCredential, models::WebauthnCredential};
+use crate::mail::Mailer;
+use crate::rate_limit::{IpThrottle, RateLimiter};
+use crate::requests::{PasskeyResponse, PasskeySignIn};
+use crate::{APIError, LoyaltyDbConn};
+
+const REGISTRATION_COOKIE: &str = "passkey_registration";
+const AUTHENTICATION_COOKIE: &str = "passkey_authentication";
+const CEREMONY_TTL_SECONDS: i64 = 5 * 60;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        start_registration,
+        finish_registration,
+        list_passkeys,
+        delete_passkey,
+        start_sign_in,
+        finish_sign_in
+    ]
+}
+
+/// The `webauthn` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct PasskeyConfig {
+    /// Usually the registrable domain, e.g. `example.com`.
+    pub rp_id: String,
+    pub rp_name: String,
+    /// Origin of the web app, e.g. `https://example.com`.
+    pub origin: String,
+}
+
+pub struct RelyingParty {
+    id: String,
+    name: String,
+    origin: url::Url,
+}
+
+impl webauthn_rs::WebauthnConfig for RelyingParty {
+    fn get_relying_party_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_origin(&self) -> &url::Url {
+        &self.origin
+    }
+
+    fn get_relying_party_id(&self) -> &str {
+        &self.id
+    }
+}
+
+pub struct Passkeys {
+    webauthn: Option<Webauthn<RelyingParty>>,
+}
+
+impl Passkeys {
+    fn webauthn(&self) -> Result<&Webauthn<RelyingParty>, APIError> {
+        self.webauthn.as_ref().ok_or(APIError::NotFound)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Ceremony<S> {
+    user: i32,
+    issued: i64,
+    state: S,
+}
+
+fn start_ceremony<S: Serialize>(
+    cookies: &CookieJar<'_>,
+    policy: &CookiePolicy,
+    name: &'static str,
+    user: i32,
+    state: S,
+) -> Result<(), APIError> {
+    let value = serde_json::to_string(&Ceremony {
+        user,
+        issued: Utc::now().timestamp(),
+        state,
+    })
+    .map_err(|_| APIError::Unknown)?;
+
+    let mut cookie = policy.build(name, value);
+    cookie.set_max_age(time::Duration::seconds(CEREMONY_TTL_SECONDS));
+    cookies.add_private(cookie);
+    Ok(())
+}
+
+/// Returns the pending ceremony, which can only be finished once.
+fn take_ceremony<S: DeserializeOwned>(
+    cookies: &CookieJar<'_>,
+    policy: &CookiePolicy,
+    name: &'static str,
+) -> Option<Ceremony<S>> {
+    let value = cookies.get_private(name)?.value().to_string();
+    cookies.remove_private(policy.named(name));
+
+    let ceremony: Ceremony<S> = serde_json::from_str(&value).ok()?;
+    if Utc::now().timestamp() - ceremony.issued > CEREMONY_TTL_SECONDS {
+        return None;
+    }
+
+    Some(ceremony)
+}
+
+fn load_credentials(c: &SqliteConnection, user: i32) -> QueryResult<Vec<WebauthnCredential>> {
+    use db::schema::webauthn_credentials::dsl::*;
+
+    webauthn_credentials
+        .filter(user_id.eq(user))
+        .order(id.asc())
+        .load::<WebauthnCredential>(c)
+}
+
+fn decode(stored: &WebauthnCredential) -> Option<Credential> {
+    serde_json::from_str(&stored.credential).ok()
+}
+
+#[post("/passkeys/register")]
+async fn start_registration(
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    passkeys: State<'_, Passkeys>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<Json<CreationChallengeResponse>, APIError> {
+    let webauthn = passkeys.webauthn()?;
+
+    let account = db
+        .run(move |c| {
+            use db::schema::users::dsl::*;
+            users.find(user.0).select(email).first::<String>(c)
+        })
+        .await?;
+
+    let (challenge, state) = webauthn
+        .generate_challenge_register(&account, false)
+        .map_err(|e| {
+            log::error!("could not start passkey registration: {:?}", e);
+            APIError::Unknown
+        })?;
+
+    start_ceremony(cookies, &cookie_policy, REGISTRATION_COOKIE, user.0, state)?;
+    Ok(Json(challenge))
+}
+
+#[post("/passkeys/register/finish?<name>", format = "json", data = "<body>")]
+async fn finish_registration(
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    passkeys: State<'_, Passkeys>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    name: Option<String>,
+    body: Json<RegisterPublicKeyCredential>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let webauthn = passkeys.webauthn()?;
+    let ceremony = take_ceremony::<RegistrationState>(cookies, &cookie_policy, REGISTRATION_COOKIE)
+        .filter(|ceremony| ceremony.user == user.0)
+        .ok_or(APIError::NotAuthorized)?;
+
+    // Duplicates are caught by the unique index on `credential_id`.
+    let (credential, _) = webauthn
+        .register_credential(&body.0, &ceremony.state, |_| Ok(false))
+        .map_err(|e| {
+            log::warn!("rejected passkey registration: {:?}", e);
+            APIError::NotAuthorized
+        })?;
+
+    let encoded = serde_json::to_string(&credential).map_err(|_| APIError::Unknown)?;
+    let label = name.unwrap_or_else(|| "Passkey".to_string());
+
+    db.run(move |c| {
+        diesel::insert_into(db::schema::webauthn_credentials::table)
+            .values(&NewWebauthnCredential {
+                user_id: user.0,
+                credential_id: &hex::encode(&credential.cred_id),
+                credential: &encoded,
+                name: &label,
+            })
+            .execute(c)
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Created, "passkey registered"))
+}
+
+#[get("/passkeys")]
+async fn list_passkeys(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+) -> Result<Json<Vec<PasskeyResponse>>, APIError> {
+    let stored = db.run(move |c| load_credentials(c, user.0)).await?;
+
+    Ok(Json(
+        stored
+            .into_iter()
+            .map(|passkey| PasskeyResponse {
+                id: passkey.id,
+                name: passkey.name,
+                created_at: passkey.created_at,
+                last_used_at: passkey.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+#[delete("/passkeys/<passkey_id>")]
+async fn delete_passkey(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    passkey_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::webauthn_credentials::dsl::*;
+
+    let passkey_id: i32 = passkey_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            diesel::delete(
+                webauthn_credentials
+                    .filter(id.eq(passkey_id))
+                    .filter(user_id.eq(user.0)),
+            )
+            .execute(c)
+        })
+        .await?;
+
+    if deleted == 0 {
+        return Err(APIError::NotFound);
+    }
+
+    Ok(status::Custom(Status::Ok, "passkey deleted"))
+}
+
+#[post("/signin/passkey", format = "json", data = "<body>")]
+async fn start_sign_in(
+    _throttle: IpThrottle,
+    limiter: State<'_, RateLimiter>,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    passkeys: State<'_, Passkeys>,
+    db: LoyaltyDbConn,
+    body: Json<PasskeySignIn>,
+) -> Result<Json<RequestChallengeResponse>, APIError> {
+    let webauthn = passkeys.webauthn()?;
+    limiter.check_account(&body.0.email)?;
+
+    let (owner, credentials) = db
+        .run(move |c| {
+            use db::schema::users::dsl::*;
+
+            let owner = users
+                .filter(email.eq(&body.0.email))
+                .filter(deleted_at.is_null())
+                .select(id)
+                .first::<i32>(c)
+                .optional()?
+                .ok_or(APIError::NotAuthorized)?;
+            let credentials = load_credentials(c, owner)?;
+
+            Ok::<_, APIError>((owner, credentials))
+        })
+        .await?;
+
+    let credentials: Vec<Credential> = credentials.iter().filter_map(decode).collect();
+    if credentials.is_empty() {
+        return Err(APIError::NotAuthorized);
+    }
+
+    let (challenge, state) = webauthn
+        .generate_challenge_authenticate(credentials)
+        .map_err(|e| {
+            log::error!("could not start passkey sign in: {:?}", e);
+            APIError::Unknown
+        })?;
+
+    start_ceremony(cookies, &cookie_policy, AUTHENTICATION_COOKIE, owner, state)?;
+    Ok(Json(challenge))
+}
+
+/// A passkey proves possession and user presence on its own, so accounts
+/// with two-factor enabled are not asked for their code here.
+#[post("/signin/passkey/finish", format = "json", data = "<body>")]
+async fn finish_sign_in(
+    _throttle: IpThrottle,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    passkeys: State<'_, Passkeys>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    body: Json<PublicKeyCredential>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let webauthn = passkeys.webauthn()?;
+    let ceremony =
+        take_ceremony::<AuthenticationState>(cookies, &cookie_policy, AUTHENTICATION_COOKIE)
+            .ok_or(APIError::NotAuthorized)?;
+    let owner = ceremony.user;
+
+    let verified = webauthn.authenticate_credential(&body.0, &ceremony.state);
+
+    let signed_in = db
+        .run(move |c| {
+            use db::schema::webauthn_credentials::dsl::*;
+
+            let (used, data) = match verified {
+                Ok(verified) => verified,
+                Err(e) => {
+                    log::warn!("rejected passkey sign in: {:?}", e);
+                    events::record(c, owner, Event::SignInFailed, &client)?;
+                    return Err(APIError::NotAuthorized);
+                }
+            };
+
+            let stored = webauthn_credentials
+                .filter(user_id.eq(owner))
+                .filter(credential_id.eq(hex::encode(&used)))
+                .first::<WebauthnCredential>(c)
+                .optional()?
+                .ok_or(APIError::NotAuthorized)?;
+            let mut passkey = decode(&stored).ok_or(APIError::Unknown)?;
+
+            // A counter going backwards means the authenticator was cloned.
+            if data.counter != 0 && data.counter <= passkey.counter {
+                log::warn!("passkey {} counter went backwards", stored.id);
+                events::record(c, owner, Event::SignInFailed, &client)?;
+                return Err(APIError::NotAuthorized);
+            }
+            passkey.counter = data.counter;
+            let encoded = serde_json::to_string(&passkey).map_err(|_| APIError::Unknown)?;
+
+            diesel::update(&stored)
+                .set((
+                    credential.eq(encoded),
+                    last_used_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(c)?;
+
+            Ok(open_session(c, owner, &client, false)?)
+        })
+        .await?;
+
+    complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+    Ok(status::Custom(Status::Ok, "connected"))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Passkeys", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<PasskeyConfig>("webauthn") {
+            Ok(config) => Some(config),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                log::error!("invalid webauthn configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let webauthn = match config {
+            Some(config) => match url::Url::parse(&config.origin) {
+                Ok(origin) => Some(Webauthn::new(RelyingParty {
+                    id: config.rp_id,
+                    name: config.rp_name,
+                    origin,
+                })),
+                Err(e) => {
+                    log::error!("invalid webauthn origin: {}", e);
+                    return Err(rocket);
+                }
+            },
+            None => None,
+        };
+
+        Ok(rocket.manage(Passkeys { webauthn }))
+    })
+}
@@ -0,0 +1,49 @@
+use argon2::{Config, Variant};
+use diesel::prelude::*;
+use rand::RngCore;
+
+use crate::APIError;
+
+/// Every hash produced by `hash` is stored in the PHC string format,
+/// which always starts with the algorithm identifier.
+const HASH_PREFIX: &str = "$argon2";
+
+fn config<'a>() -> Config<'a> {
+    Config {
+        variant: Variant::Argon2id,
+        ..Config::default()
+    }
+}
+
+pub fn hash(pass: &str) -> Result<String, argon2::Error> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    argon2::hash_encoded(pass.as_bytes(), &salt, &config())
+}
+
+pub fn verify(hash: &str, pass: &str) -> bool {
+    argon2::verify_encoded(hash, pass.as_bytes()).unwrap_or(false)
+}
+
+/// Hashes the passwords of rows created before hashing was introduced.
+///
+/// Returns the number of upgraded users.
+pub fn upgrade_plaintext(c: &SqliteConnection) -> Result<usize, APIError> {
+    use crate::db::schema::users::dsl::*;
+
+    let legacy = users
+        .filter(pass.not_like(format!("{}%", HASH_PREFIX)))
+        .select((id, pass))
+        .load::<(i32, String)>(c)?;
+
+    c.transaction::<_, APIError, _>(|| {
+        for (user_id, plain) in &legacy {
+            diesel::update(users.filter(id.eq(user_id)))
+                .set(pass.eq(hash(plain)?))
+                .execute(c)?;
+        }
+
+        Ok(legacy.len())
+    })
+}
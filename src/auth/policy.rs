@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+use validator::{ValidationError, ValidationErrors};
+
+/// The `password` section of the Rocket configuration.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// File listing breached passwords, one per line.
+    pub deny_list: Option<String>,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 10,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            deny_list: None,
+        }
+    }
+}
+
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    denied: HashSet<String>,
+}
+
+fn violation(code: &'static str, message: &'static str) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(Cow::Borrowed(message));
+    error
+}
+
+impl PasswordPolicy {
+    pub fn new(config: PasswordPolicyConfig) -> std::io::Result<Self> {
+        let denied = match &config.deny_list {
+            Some(path) => std::fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(PasswordPolicy { config, denied })
+    }
+
+    /// Checks `pass` against the policy, reporting every rule it breaks on
+    /// the `pass` field.
+    pub fn check(&self, pass: &str) -> Result<(), ValidationErrors> {
+        let config = &self.config;
+        let mut errors = ValidationErrors::new();
+        let mut fail = |error| errors.add("pass", error);
+
+        if pass.chars().count() < config.min_length {
+            let mut error = violation("min_length", "password is too short");
+            error.add_param(Cow::Borrowed("min"), &config.min_length);
+            fail(error);
+        }
+        if config.require_lowercase && !pass.chars().any(char::is_lowercase) {
+            fail(violation("lowercase", "password needs a lowercase letter"));
+        }
+        if config.require_uppercase && !pass.chars().any(char::is_uppercase) {
+            fail(violation("uppercase", "password needs an uppercase letter"));
+        }
+        if config.require_digit && !pass.chars().any(|ch| ch.is_ascii_digit()) {
+            fail(violation("digit", "password needs a digit"));
+        }
+        if config.require_symbol && pass.chars().all(char::is_alphanumeric) {
+            fail(violation("symbol", "password needs a symbol"));
+        }
+        if self.denied.contains(&pass.to_lowercase()) {
+            fail(violation("breached", "password appears in a breach"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Password Policy", |rocket| async move {
+        let config = match rocket
+            .figment()
+            .extract_inner::<PasswordPolicyConfig>("password")
+        {
+            Ok(config) => config,
+            Err(e) if e.missing() => PasswordPolicyConfig::default(),
+            Err(e) => {
+                log::error!("invalid password configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match PasswordPolicy::new(config) {
+            Ok(policy) => Ok(rocket.manage(policy)),
+            Err(e) => {
+                log::error!("could not read the password deny list: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,149 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+
+use super::{sessions, token};
+use crate::db::models::{NewRefreshToken, RefreshToken};
+use crate::APIError;
+
+pub struct Rotated {
+    pub user_id: i32,
+    pub session_id: Option<i32>,
+    pub token: String,
+}
+
+/// Issues a new refresh token for the user.
+///
+/// A token created on sign-in starts a new family; rotated tokens stay in the
+/// family of the token they replace so that reuse can revoke the whole chain.
+pub fn issue(
+    c: &SqliteConnection,
+    user: i32,
+    token_family: Option<&str>,
+    session: Option<i32>,
+    ttl: i64,
+) -> QueryResult<String> {
+    let raw = token::generate();
+    let new_family;
+    let token_family = match token_family {
+        Some(f) => f,
+        None => {
+            new_family = token::generate();
+            &new_family
+        }
+    };
+
+    diesel::insert_into(crate::db::schema::refresh_tokens::table)
+        .values(&NewRefreshToken {
+            user_id: user,
+            token_hash: &token::digest(&raw),
+            family: token_family,
+            expires_at: Utc::now().naive_utc() + Duration::seconds(ttl),
+            session_id: session,
+        })
+        .execute(c)?;
+
+    Ok(raw)
+}
+
+/// Exchanges a refresh token for a new one of the same family and session.
+///
+/// Presenting a token that was already rotated means it leaked: every token
+/// of its family is revoked and the request is rejected.
+pub fn rotate(c: &SqliteConnection, presented: &str, ttl: i64) -> Result<Rotated, APIError> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+
+    let rotated = c.transaction::<_, APIError, _>(|| {
+        let now = Utc::now().naive_utc();
+        let current = refresh_tokens
+            .filter(token_hash.eq(token::digest(presented)))
+            .first::<RefreshToken>(c)
+            .optional()?
+            .ok_or(APIError::NotAuthorized)?;
+
+        if current.revoked_at.is_some() {
+            revoke_family(c, &current.family)?;
+            return Ok(None);
+        }
+
+        if current.expires_at < now {
+            return Ok(None);
+        }
+
+        diesel::update(&current)
+            .set(revoked_at.eq(now))
+            .execute(c)?;
+
+        // Refresh tokens can't outlive the session they were issued for.
+        if let Some(session) = current.session_id {
+            if !sessions::touch(c, session)? {
+                return Ok(None);
+            }
+        }
+
+        let next = issue(
+            c,
+            current.user_id,
+            Some(&current.family),
+            current.session_id,
+            ttl,
+        )?;
+        Ok(Some(Rotated {
+            user_id: current.user_id,
+            session_id: current.session_id,
+            token: next,
+        }))
+    })?;
+
+    rotated.ok_or(APIError::NotAuthorized)
+}
+
+/// Revokes the family the presented token belongs to and its session, if
+/// it exists.
+pub fn revoke(c: &SqliteConnection, presented: &str) -> QueryResult<()> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+
+    let current = refresh_tokens
+        .filter(token_hash.eq(token::digest(presented)))
+        .first::<RefreshToken>(c)
+        .optional()?;
+
+    if let Some(current) = current {
+        revoke_family(c, &current.family)?;
+
+        if let Some(session) = current.session_id {
+            sessions::revoke(c, current.user_id, session)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Revokes every refresh token of the user.
+pub fn revoke_all(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+
+    diesel::update(refresh_tokens.filter(user_id.eq(user).and(revoked_at.is_null())))
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(c)
+}
+
+/// Revokes every refresh token of the user that doesn't belong to `session`.
+pub fn revoke_others(c: &SqliteConnection, user: i32, session: i32) -> QueryResult<usize> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+
+    diesel::update(
+        refresh_tokens
+            .filter(user_id.eq(user).and(revoked_at.is_null()))
+            .filter(session_id.is_null().or(session_id.ne(session))),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(c)
+}
+
+fn revoke_family(c: &SqliteConnection, token_family: &str) -> QueryResult<usize> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+
+    diesel::update(refresh_tokens.filter(family.eq(token_family).and(revoked_at.is_null())))
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(c)
+}
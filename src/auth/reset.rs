@@ -0,0 +1,167 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{post, routes, Route, State};
+use rocket_contrib::json::Json;
+
+use super::events::{self, Event};
+use super::policy::PasswordPolicy;
+use super::sessions::{ClientInfo, CurrentSession};
+use super::{invalidate_other_sessions, invalidate_sessions, password, token, SessionUser};
+use crate::db::{self, models::NewPasswordReset};
+use crate::mail::Mailer;
+use crate::rate_limit::RateLimiter;
+use crate::requests::{ChangePassword, ForgotPassword, ResetPassword};
+use crate::{APIError, LoyaltyDbConn};
+
+const TOKEN_TTL_MINUTES: i64 = 60;
+
+pub fn routes() -> Vec<Route> {
+    routes![forgot, reset, change]
+}
+
+/// Always answers the same way so the endpoint can't be used to find out
+/// which addresses have an account.
+#[post("/password/forgot", format = "json", data = "<body>")]
+async fn forgot(
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    body: Json<ForgotPassword>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let requested = db
+        .run(move |c| {
+            use db::schema::users::dsl::*;
+
+            let owner = users
+                .filter(email.eq(&body.0.email))
+                .select(id)
+                .first::<i32>(c)
+                .optional()?;
+
+            let owner = match owner {
+                Some(owner) => owner,
+                None => return Ok::<_, APIError>(None),
+            };
+
+            let raw = token::generate();
+            diesel::insert_into(db::schema::password_resets::table)
+                .values(&NewPasswordReset {
+                    user_id: owner,
+                    token_hash: &token::digest(&raw),
+                    expires_at: Utc::now().naive_utc() + Duration::minutes(TOKEN_TTL_MINUTES),
+                })
+                .execute(c)?;
+
+            Ok(Some((body.0.email, raw)))
+        })
+        .await?;
+
+    if let Some((to, raw)) = requested {
+        let link = mailer.link(&format!("/password/reset?token={}", raw));
+        let body = format!(
+            "Someone asked to reset the password of your account.\n\n\
+             Open the link below within {} minutes to choose a new one:\n\n{}\n\n\
+             If it wasn't you, you can ignore this email.\n",
+            TOKEN_TTL_MINUTES, link
+        );
+
+        if let Err(e) = mailer.send(&to, "Reset your password", body).await {
+            log::warn!("could not send password reset email: {}", e);
+        }
+    }
+
+    Ok(status::Custom(
+        Status::Ok,
+        "if the account exists, a reset link was sent",
+    ))
+}
+
+#[post("/password/reset", format = "json", data = "<body>")]
+async fn reset(
+    policy: State<'_, PasswordPolicy>,
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    body: Json<ResetPassword>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    policy.check(&body.0.pass)?;
+
+    db.run(move |c| {
+        use db::schema::password_resets::dsl::*;
+
+        c.transaction(|| {
+            let owner = password_resets
+                .filter(token_hash.eq(token::digest(&body.0.token)))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .select(user_id)
+                .first::<i32>(c)
+                .optional()?
+                .ok_or(APIError::NotAuthorized)?;
+
+            // Resetting the password is also how a locked account is unlocked.
+            {
+                use db::schema::users::dsl::*;
+                diesel::update(users.filter(id.eq(owner)))
+                    .set((
+                        pass.eq(password::hash(&body.0.pass)?),
+                        locked_at.eq(None::<chrono::NaiveDateTime>),
+                    ))
+                    .execute(c)?;
+            }
+
+            diesel::delete(password_resets.filter(user_id.eq(owner))).execute(c)?;
+            invalidate_sessions(c, owner)?;
+            events::record(c, owner, Event::PasswordReset, &client)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "password updated"))
+}
+
+/// Signed-in users change their password by proving they know the current
+/// one. Every other session is signed out; the current one stays valid.
+#[post("/password/change", format = "json", data = "<body>")]
+async fn change(
+    limiter: State<'_, RateLimiter>,
+    policy: State<'_, PasswordPolicy>,
+    client: ClientInfo,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    current: CurrentSession,
+    body: Json<ChangePassword>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    limiter.check_account(&format!("password:{}", user.0))?;
+    policy.check(&body.0.pass)?;
+
+    db.run(move |c| {
+        c.transaction(|| {
+            let account = users.find(user.0).first::<db::models::User>(c)?;
+            if !password::verify(&account.pass, &body.0.current_pass) {
+                return Err(APIError::NotAuthorized);
+            }
+
+            diesel::update(users.find(user.0))
+                .set(pass.eq(password::hash(&body.0.pass)?))
+                .execute(c)?;
+
+            {
+                use db::schema::password_resets::dsl::*;
+                diesel::delete(password_resets.filter(user_id.eq(user.0))).execute(c)?;
+            }
+
+            match current.0 {
+                Some(session) => invalidate_other_sessions(c, user.0, session)?,
+                None => invalidate_sessions(c, user.0)?,
+            }
+            events::record(c, user.0, Event::PasswordChanged, &client)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "password updated"))
+}
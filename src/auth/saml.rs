@@ -0,0 +1,251 @@
+//! SAML 2.0 service provider for enterprise tenants.
+//!
+//! Each tenant under `[global.saml.tenants]` points at the metadata of its
+//! identity provider. Employees start at `/auth/saml/<tenant>/login` and come
+//! back signed in through the assertion consumer service. Signature checks
+//! need libxmlsec1, which is why the module is behind the `saml` feature.
+
+use std::collections::HashMap;
+
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
+use rocket::request::Form;
+use rocket::response::{status, Redirect};
+use rocket::{get, post, routes, FromForm, Route, State};
+use samael::metadata::{EntityDescriptor, HTTP_REDIRECT_BINDING};
+use samael::schema::Assertion;
+use samael::service_provider::{ServiceProvider, ServiceProviderBuilder};
+use serde::Deserialize;
+
+use super::oauth::resolve_identity;
+use super::sessions::ClientInfo;
+use super::{complete_sign_in, open_session};
+use crate::cookie_policy::CookiePolicy;
+use crate::mail::Mailer;
+use crate::{APIError, LoyaltyDbConn};
+
+const REQUEST_COOKIE: &str = "saml_request";
+
+pub fn routes() -> Vec<Route> {
+    routes![metadata, login, consume]
+}
+
+#[derive(Deserialize)]
+pub struct TenantConfig {
+    /// Path to the XML metadata of the tenant's identity provider.
+    pub idp_metadata: String,
+    /// Attribute holding the email address. Defaults to the NameID.
+    pub email_attribute: Option<String>,
+}
+
+/// The `saml` section of the Rocket configuration.
+#[derive(Default, Deserialize)]
+pub struct SamlConfig {
+    /// Entity id of this service provider, shared by all tenants.
+    pub entity_id: String,
+    /// Base URL the identity providers send users back to.
+    pub public_url: String,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+}
+
+struct Tenant {
+    provider: ServiceProvider,
+    email_attribute: Option<String>,
+}
+
+pub struct Saml {
+    tenants: HashMap<String, Tenant>,
+}
+
+impl Saml {
+    fn new(config: SamlConfig) -> Result<Self, String> {
+        let base = config.public_url.trim_end_matches('/');
+        let mut tenants = HashMap::new();
+
+        for (name, tenant) in config.tenants {
+            let xml = std::fs::read_to_string(&tenant.idp_metadata)
+                .map_err(|e| format!("{}: {}", tenant.idp_metadata, e))?;
+            let idp_metadata: EntityDescriptor = xml
+                .parse()
+                .map_err(|e| format!("{}: {}", tenant.idp_metadata, e))?;
+
+            let provider = ServiceProviderBuilder::default()
+                .entity_id(config.entity_id.clone())
+                .idp_metadata(idp_metadata)
+                .acs_url(format!("{}/auth/saml/{}/acs", base, name))
+                .metadata_url(format!("{}/auth/saml/{}/metadata", base, name))
+                .allow_idp_initiated(false)
+                .build()?;
+
+            tenants.insert(
+                name,
+                Tenant {
+                    provider,
+                    email_attribute: tenant.email_attribute,
+                },
+            );
+        }
+
+        Ok(Saml { tenants })
+    }
+
+    fn tenant(&self, name: &str) -> Result<&Tenant, APIError> {
+        self.tenants.get(name).ok_or(APIError::NotFound)
+    }
+}
+
+impl Tenant {
+    fn email(&self, assertion: &Assertion, subject: &str) -> Option<String> {
+        let attribute = match &self.email_attribute {
+            Some(attribute) => attribute,
+            None => return Some(subject.to_string()).filter(|s| s.contains('@')),
+        };
+
+        assertion
+            .attribute_statements
+            .iter()
+            .flatten()
+            .flat_map(|statement| &statement.attributes)
+            .filter(|a| a.name.as_deref() == Some(attribute.as_str()))
+            .flat_map(|a| &a.values)
+            .find_map(|value| value.value.clone())
+    }
+}
+
+#[get("/auth/saml/<tenant>/metadata")]
+async fn metadata(
+    saml: State<'_, Saml>,
+    tenant: String,
+) -> Result<(ContentType, String), APIError> {
+    let tenant = saml.tenant(&tenant)?;
+    let xml = tenant
+        .provider
+        .metadata()
+        .and_then(|metadata| metadata.to_xml())
+        .map_err(|e| {
+            log::error!("could not build SAML metadata: {}", e);
+            APIError::Unknown
+        })?;
+
+    Ok((ContentType::XML, xml))
+}
+
+#[get("/auth/saml/<tenant>/login")]
+async fn login(
+    cookies: &CookieJar<'_>,
+    saml: State<'_, Saml>,
+    tenant: String,
+) -> Result<Redirect, APIError> {
+    let provider = &saml.tenant(&tenant)?.provider;
+
+    let url = provider
+        .sso_binding_location(HTTP_REDIRECT_BINDING)
+        .ok_or(APIError::NotFound)?;
+    let (request_id, redirect) = provider
+        .make_authentication_request(&url)
+        .and_then(|request| Ok((request.id.clone(), request.redirect("")?)))
+        .map_err(|e| {
+            log::error!("could not build SAML request: {}", e);
+            APIError::Unknown
+        })?;
+    let redirect = redirect.ok_or(APIError::Unknown)?;
+
+    // The identity provider posts the response back cross-site.
+    cookies.add_private(
+        Cookie::build(REQUEST_COOKIE, format!("{}:{}", tenant, request_id))
+            .same_site(SameSite::None)
+            .secure(true)
+            .http_only(true)
+            .finish(),
+    );
+
+    Ok(Redirect::to(String::from(redirect)))
+}
+
+#[derive(FromForm)]
+struct AcsForm {
+    #[form(field = "SAMLResponse")]
+    saml_response: String,
+}
+
+#[post("/auth/saml/<tenant>/acs", data = "<form>")]
+async fn consume(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    saml: State<'_, Saml>,
+    tenant: String,
+    form: Form<AcsForm>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let pending = cookies
+        .get_private(REQUEST_COOKIE)
+        .map(|c| c.value().to_string());
+    cookies.remove_private(Cookie::named(REQUEST_COOKIE));
+
+    let request_id = pending
+        .as_deref()
+        .and_then(|value| value.strip_prefix(&format!("{}:", tenant)))
+        .map(String::from)
+        .ok_or(APIError::NotAuthorized)?;
+
+    let config = saml.tenant(&tenant)?;
+    let assertion = config
+        .provider
+        .parse_response(&form.saml_response, &[request_id.as_str()])
+        .map_err(|e| {
+            log::warn!("rejected SAML response for {}: {}", tenant, e);
+            APIError::NotAuthorized
+        })?;
+
+    let subject = assertion
+        .subject
+        .as_ref()
+        .and_then(|subject| subject.name_id.as_ref())
+        .map(|name_id| name_id.value.clone())
+        .ok_or(APIError::NotAuthorized)?;
+    // Tenants vouch for the addresses of their employees, so accounts are
+    // created even when public signup needs an invite.
+    let email = config.email(&assertion, &subject);
+    let provider_name = format!("saml:{}", tenant);
+
+    let signed_in = db
+        .run(move |c| {
+            let user = resolve_identity(
+                c,
+                &provider_name,
+                &subject,
+                email.as_deref(),
+                email.as_deref(),
+                true,
+            )?;
+            Ok::<_, APIError>(open_session(c, user.id, &client, false)?)
+        })
+        .await?;
+
+    complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+    Ok(status::Custom(Status::Ok, "connected"))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("SAML Tenants", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<SamlConfig>("saml") {
+            Ok(config) => config,
+            Err(e) if e.missing() => SamlConfig::default(),
+            Err(e) => {
+                log::error!("invalid saml configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match Saml::new(config) {
+            Ok(saml) => Ok(rocket.manage(saml)),
+            Err(e) => {
+                log::error!("invalid SAML tenant: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,334 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rocket::http::{CookieJar, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status;
+use rocket::{delete, get, routes, Route, State};
+use rocket_contrib::json::Json;
+
+use super::events::{self, Event};
+use super::jwt::JwtKeys;
+use super::{request_db, token, SessionUser};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewSession, models::Session};
+use crate::geoip::GeoIp;
+use crate::proxies;
+use crate::requests::SessionResponse;
+use crate::{APIError, LoyaltyDbConn};
+
+pub const COOKIE: &str = "session";
+
+/// `last_seen_at` is only refreshed once per interval to avoid a write on
+/// every request.
+const TOUCH_INTERVAL_MINUTES: i64 = 5;
+
+/// A session expires after this long without activity...
+const IDLE_TIMEOUT_DAYS: i64 = 14;
+/// ...and this long after it was opened, however active it is.
+const MAX_AGE_DAYS: i64 = 90;
+
+fn renewed_expiry(now: NaiveDateTime, opened: NaiveDateTime) -> NaiveDateTime {
+    (now + Duration::days(IDLE_TIMEOUT_DAYS)).min(opened + Duration::days(MAX_AGE_DAYS))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_sessions, delete_session]
+}
+
+/// What the client tells about itself, recorded on new sessions.
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Looked up from the address, when a GeoIP database is configured.
+    pub country: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        let ip = proxies::client_ip(request);
+        let country = match (ip, request.managed_state::<GeoIp>()) {
+            (Some(ip), Some(geoip)) => geoip.country(ip),
+            _ => None,
+        };
+
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            ip: ip.map(|ip| ip.to_string()),
+            country,
+        })
+    }
+}
+
+/// Creates a session, optionally opened on a remembered device, and returns
+/// its id and the raw token to hand out.
+pub fn create(
+    c: &SqliteConnection,
+    user: i32,
+    client: &ClientInfo,
+    device: Option<i32>,
+) -> QueryResult<(i32, String)> {
+    use db::schema::sessions::dsl::*;
+
+    let raw = token::generate();
+    let hashed = token::digest(&raw);
+    let now = Utc::now().naive_utc();
+
+    diesel::insert_into(sessions)
+        .values(&NewSession {
+            user_id: user,
+            token_hash: &hashed,
+            user_agent: client.user_agent.as_deref(),
+            ip: client.ip.as_deref(),
+            device_id: device,
+            expires_at: renewed_expiry(now, now),
+        })
+        .execute(c)?;
+
+    let created = sessions
+        .filter(token_hash.eq(&hashed))
+        .select(id)
+        .first::<i32>(c)?;
+
+    Ok((created, raw))
+}
+
+/// Resolves a live session token to its id and owner, renewing it.
+pub fn resolve(c: &SqliteConnection, raw: &str) -> QueryResult<Option<(i32, i32)>> {
+    use db::schema::sessions::dsl::*;
+
+    let found = sessions
+        .filter(token_hash.eq(token::digest(raw)))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .select((id, user_id))
+        .first::<(i32, i32)>(c)
+        .optional()?;
+
+    if let Some((session, _)) = found {
+        touch(c, session)?;
+    }
+
+    Ok(found)
+}
+
+/// Whether an access token issued to `user` for `session` still holds: the
+/// account must be neither deleted nor locked, and the session, when the
+/// token names one, still live and the user's. The session is renewed.
+pub fn accepts(c: &SqliteConnection, user: i32, session: Option<i32>) -> QueryResult<bool> {
+    use db::schema::sessions::dsl::*;
+    use db::schema::users;
+
+    let active = users::table
+        .find(user)
+        .filter(users::deleted_at.is_null())
+        .filter(users::locked_at.is_null())
+        .select(users::id)
+        .first::<i32>(c)
+        .optional()?;
+    if active.is_none() {
+        return Ok(false);
+    }
+
+    match session {
+        Some(session) => {
+            let owned = sessions
+                .filter(id.eq(session))
+                .filter(user_id.eq(user))
+                .select(id)
+                .first::<i32>(c)
+                .optional()?;
+            Ok(owned.is_some() && touch(c, session)?)
+        }
+        None => Ok(true),
+    }
+}
+
+/// Records activity on a session and slides its expiry, up to the absolute
+/// max age. Returns false when the session is no longer live.
+pub fn touch(c: &SqliteConnection, session: i32) -> QueryResult<bool> {
+    use db::schema::sessions::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let live = sessions
+        .filter(id.eq(session))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(now))
+        .select((created_at, last_seen_at))
+        .first::<(NaiveDateTime, NaiveDateTime)>(c)
+        .optional()?;
+
+    let (opened, seen) = match live {
+        Some(live) => live,
+        None => return Ok(false),
+    };
+
+    if seen < now - Duration::minutes(TOUCH_INTERVAL_MINUTES) {
+        diesel::update(sessions.find(session))
+            .set((
+                last_seen_at.eq(now),
+                expires_at.eq(renewed_expiry(now, opened)),
+            ))
+            .execute(c)?;
+    }
+
+    Ok(true)
+}
+
+/// Revokes one session of the user along with its refresh tokens.
+pub fn revoke(c: &SqliteConnection, user: i32, session: i32) -> QueryResult<usize> {
+    use db::schema::sessions::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let revoked = diesel::update(
+        sessions
+            .filter(id.eq(session))
+            .filter(user_id.eq(user))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(now))
+    .execute(c)?;
+
+    {
+        use db::schema::refresh_tokens::dsl as refresh;
+        diesel::update(
+            refresh::refresh_tokens
+                .filter(refresh::session_id.eq(session))
+                .filter(refresh::revoked_at.is_null()),
+        )
+        .set(refresh::revoked_at.eq(now))
+        .execute(c)?;
+    }
+
+    Ok(revoked)
+}
+
+pub fn revoke_all(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::sessions::dsl::*;
+
+    diesel::update(
+        sessions
+            .filter(user_id.eq(user))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(c)
+}
+
+/// Revokes every session of the user except `keep`.
+pub fn revoke_others(c: &SqliteConnection, user: i32, keep: i32) -> QueryResult<usize> {
+    use db::schema::sessions::dsl::*;
+
+    diesel::update(
+        sessions
+            .filter(user_id.eq(user))
+            .filter(id.ne(keep))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(c)
+}
+
+/// The session the request was made with, if it was made with one.
+pub struct CurrentSession(pub Option<i32>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for CurrentSession {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        if let Some(bearer) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            let sid = request
+                .managed_state::<JwtKeys>()
+                .and_then(|keys| keys.verify(bearer).ok())
+                .and_then(|claims| claims.sid);
+            return Outcome::Success(CurrentSession(sid));
+        }
+
+        let raw = match request.cookies().get_private(COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Outcome::Success(CurrentSession(None)),
+        };
+
+        let db = try_outcome!(request_db(request).await);
+        match db.run(move |c| resolve(c, &raw)).await {
+            Ok(found) => Outcome::Success(CurrentSession(found.map(|(session, _)| session))),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+}
+
+#[get("/sessions")]
+async fn list_sessions(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    current: CurrentSession,
+) -> Result<Json<Vec<SessionResponse>>, APIError> {
+    use db::schema::sessions::dsl::*;
+
+    let active = db
+        .run(move |c| {
+            sessions
+                .filter(user_id.eq(user.0))
+                .filter(revoked_at.is_null())
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .order(last_seen_at.desc())
+                .load::<Session>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        active
+            .into_iter()
+            .map(|session| SessionResponse {
+                id: session.id,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created_at: session.created_at,
+                last_seen_at: session.last_seen_at,
+                expires_at: session.expires_at,
+                current: current.0 == Some(session.id),
+            })
+            .collect(),
+    ))
+}
+
+#[delete("/sessions/<session_id>")]
+async fn delete_session(
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    current: CurrentSession,
+    session_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let session_id: i32 = session_id.parse()?;
+
+    let revoked = db
+        .run(move |c| {
+            let revoked = revoke(c, user.0, session_id)?;
+            if revoked > 0 {
+                events::record(c, user.0, Event::SessionRevoked, &client)?;
+            }
+            Ok::<_, diesel::result::Error>(revoked)
+        })
+        .await?;
+
+    if revoked == 0 {
+        return Err(APIError::NotFound);
+    }
+
+    if current.0 == Some(session_id) {
+        cookies.remove_private(cookie_policy.named(COOKIE));
+    }
+
+    Ok(status::Custom(Status::Ok, "session revoked"))
+}
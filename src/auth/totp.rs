@@ -0,0 +1,328 @@
+//! Time-based one-time passwords (RFC 6238) and recovery codes.
+
+use base32::Alphabet;
+use chrono::Utc;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use rocket::http::{CookieJar, Status};
+use rocket::response::status;
+use rocket::{post, routes, Route, State};
+use rocket_contrib::json::Json;
+use sha1::Sha1;
+
+use super::events::{self, Event};
+use super::sessions::ClientInfo;
+use super::{complete_sign_in, open_session, token, SessionUser};
+use crate::cookie_policy::CookiePolicy;
+use crate::db::{self, models::NewRecoveryCode};
+use crate::mail::Mailer;
+use crate::rate_limit::{IpThrottle, RateLimiter};
+use crate::requests::{RecoveryCodes, TotpCode, TotpEnrollment};
+use crate::{APIError, LoyaltyDbConn};
+
+const ISSUER: &str = "Loyalty";
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Accepted clock drift, in steps on each side of the current one.
+const SKEW: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const PENDING_COOKIE: &str = "mfa_pending";
+const PENDING_TTL_SECONDS: i64 = 5 * 60;
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        enroll,
+        confirm_enrollment,
+        verify,
+        regenerate_recovery_codes
+    ]
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset],
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step matching `code`. Steps up to `last_step` were
+/// already used and are rejected to prevent replays.
+fn matching_step(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let secret = base32::decode(BASE32, secret)?;
+    let code: u32 = code.parse().ok()?;
+    let now = Utc::now().timestamp() / STEP_SECONDS;
+
+    (now - SKEW..=now + SKEW)
+        .filter(|step| last_step.map_or(true, |last| *step > last))
+        .find(|step| hotp(&secret, *step as u64) == code)
+}
+
+fn generate_recovery_codes(c: &SqliteConnection, user: i32) -> QueryResult<Vec<String>> {
+    use db::schema::recovery_codes::dsl::*;
+
+    diesel::delete(recovery_codes.filter(user_id.eq(user))).execute(c)?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw = token::generate().to_lowercase();
+            format!("{}-{}", &raw[..5], &raw[5..10])
+        })
+        .collect();
+
+    for code in &codes {
+        diesel::insert_into(recovery_codes)
+            .values(&NewRecoveryCode {
+                user_id: user,
+                code_hash: &token::digest(code),
+            })
+            .execute(c)?;
+    }
+
+    Ok(codes)
+}
+
+fn use_recovery_code(c: &SqliteConnection, user: i32, code: &str) -> QueryResult<bool> {
+    use db::schema::recovery_codes::dsl::*;
+
+    let used = diesel::update(
+        recovery_codes
+            .filter(user_id.eq(user))
+            .filter(code_hash.eq(token::digest(&code.trim().to_lowercase())))
+            .filter(used_at.is_null()),
+    )
+    .set(used_at.eq(Utc::now().naive_utc()))
+    .execute(c)?;
+
+    Ok(used > 0)
+}
+
+/// Checks a TOTP or recovery code for a user who has two-factor enabled.
+pub fn verify_code(
+    c: &SqliteConnection,
+    user: &db::models::User,
+    code: &str,
+) -> Result<bool, APIError> {
+    use db::schema::users::dsl::*;
+
+    let secret = match &user.totp_secret {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+
+    if let Some(step) = matching_step(secret, code.trim(), user.totp_last_step) {
+        diesel::update(users.find(user.id))
+            .set(totp_last_step.eq(step))
+            .execute(c)?;
+        return Ok(true);
+    }
+
+    Ok(use_recovery_code(c, user.id, code)?)
+}
+
+/// Decides whether a password sign-in is complete. Users with two-factor
+/// enabled need a valid `code`; `Ok(false)` means none was provided yet.
+pub fn second_factor(
+    c: &SqliteConnection,
+    user: &db::models::User,
+    code: Option<&str>,
+) -> Result<bool, APIError> {
+    if !user.totp_enabled {
+        return Ok(true);
+    }
+
+    match code {
+        Some(code) if verify_code(c, user, code)? => Ok(true),
+        Some(_) => Err(APIError::NotAuthorized),
+        None => Ok(false),
+    }
+}
+
+/// Remembers a sign-in whose password was checked but which still waits for
+/// its second factor. It is deliberately not a session cookie.
+pub fn start_pending(
+    cookies: &CookieJar<'_>,
+    policy: &CookiePolicy,
+    user: &db::models::User,
+    remember: bool,
+) {
+    let mut cookie = policy.build(
+        PENDING_COOKIE,
+        format!("{}:{}:{}", user.id, Utc::now().timestamp(), remember),
+    );
+    cookie.set_max_age(time::Duration::seconds(PENDING_TTL_SECONDS));
+    cookies.add_private(cookie);
+}
+
+/// Returns the user waiting for their second factor and whether they asked
+/// to be remembered on this device.
+fn take_pending(cookies: &CookieJar<'_>, policy: &CookiePolicy) -> Option<(i32, bool)> {
+    let value = cookies.get_private(PENDING_COOKIE)?.value().to_string();
+    cookies.remove_private(policy.named(PENDING_COOKIE));
+
+    let mut parts = value.splitn(3, ':');
+    let user = parts.next()?.parse().ok()?;
+    let issued: i64 = parts.next()?.parse().ok()?;
+    let remember = parts.next()?.parse().ok()?;
+
+    if Utc::now().timestamp() - issued > PENDING_TTL_SECONDS {
+        return None;
+    }
+
+    Some((user, remember))
+}
+
+#[post("/2fa/enroll")]
+async fn enroll(db: LoyaltyDbConn, user: SessionUser) -> Result<Json<TotpEnrollment>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = base32::encode(BASE32, &bytes);
+    let stored = secret.clone();
+
+    let account = db
+        .run(move |c| {
+            let current = users.find(user.0).first::<db::models::User>(c)?;
+            if current.totp_enabled {
+                return Err(APIError::Conflict);
+            }
+
+            diesel::update(users.find(user.0))
+                .set((totp_secret.eq(stored), totp_last_step.eq(None::<i64>)))
+                .execute(c)?;
+
+            Ok(current.email)
+        })
+        .await?;
+
+    let label = format!("{}:{}", ISSUER, account);
+    let mut otpauth = url::Url::parse("otpauth://totp/").expect("valid base url");
+    otpauth.set_path(&label);
+    otpauth
+        .query_pairs_mut()
+        .append_pair("secret", &secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+
+    Ok(Json(TotpEnrollment {
+        secret,
+        otpauth_url: otpauth.into(),
+    }))
+}
+
+#[post("/2fa/enroll/confirm", format = "json", data = "<body>")]
+async fn confirm_enrollment(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    body: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let codes = db
+        .run(move |c| {
+            c.transaction(|| {
+                let current = users.find(user.0).first::<db::models::User>(c)?;
+                let secret = match (&current.totp_secret, current.totp_enabled) {
+                    (Some(secret), false) => secret,
+                    _ => return Err(APIError::Conflict),
+                };
+
+                let step = matching_step(secret, body.0.code.trim(), None)
+                    .ok_or(APIError::NotAuthorized)?;
+
+                diesel::update(users.find(user.0))
+                    .set((totp_enabled.eq(true), totp_last_step.eq(step)))
+                    .execute(c)?;
+
+                Ok(generate_recovery_codes(c, user.0)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
+
+#[post("/2fa/verify", format = "json", data = "<body>")]
+async fn verify(
+    _throttle: IpThrottle,
+    limiter: State<'_, RateLimiter>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+    cookie_policy: State<'_, CookiePolicy>,
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    body: Json<TotpCode>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let (pending_user, remember) =
+        take_pending(cookies, &cookie_policy).ok_or(APIError::NotAuthorized)?;
+    limiter.check_account(&format!("2fa:{}", pending_user))?;
+
+    let signed_in = db
+        .run(move |c| {
+            let user = users.find(pending_user).first::<db::models::User>(c)?;
+
+            if !verify_code(c, &user, &body.0.code)? {
+                events::record(c, user.id, Event::SignInFailed, &client)?;
+                return Err(APIError::NotAuthorized);
+            }
+
+            Ok(open_session(c, user.id, &client, remember)?)
+        })
+        .await?;
+
+    complete_sign_in(cookies, &cookie_policy, &mailer, signed_in).await;
+    Ok(status::Custom(Status::Ok, "connected"))
+}
+
+#[post("/2fa/recovery-codes", format = "json", data = "<body>")]
+async fn regenerate_recovery_codes(
+    db: LoyaltyDbConn,
+    user: SessionUser,
+    body: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let codes = db
+        .run(move |c| {
+            c.transaction(|| {
+                let current = users.find(user.0).first::<db::models::User>(c)?;
+
+                if !current.totp_enabled {
+                    return Err(APIError::Conflict);
+                }
+                if !verify_code(c, &current, &body.0.code)? {
+                    return Err(APIError::NotAuthorized);
+                }
+
+                Ok(generate_recovery_codes(c, user.0)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
@@ -0,0 +1,114 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+
+use super::{token, User};
+use crate::db::{self, models::NewVerificationToken};
+use crate::mail::Mailer;
+use crate::{APIError, LoyaltyDbConn};
+
+const TOKEN_TTL_HOURS: i64 = 24;
+
+pub fn routes() -> Vec<Route> {
+    routes![verify, resend]
+}
+
+/// Creates a verification token for the user, replacing any previous one.
+pub fn issue(c: &SqliteConnection, user: i32) -> QueryResult<String> {
+    use db::schema::verification_tokens::dsl::*;
+
+    diesel::delete(verification_tokens.filter(user_id.eq(user))).execute(c)?;
+
+    let raw = token::generate();
+    diesel::insert_into(verification_tokens)
+        .values(&NewVerificationToken {
+            user_id: user,
+            token_hash: &token::digest(&raw),
+            expires_at: Utc::now().naive_utc() + Duration::hours(TOKEN_TTL_HOURS),
+        })
+        .execute(c)?;
+
+    Ok(raw)
+}
+
+/// Emails the verification link. Failures are only logged: the user can
+/// always ask for a new link through `/verify/resend`.
+pub async fn send(mailer: &Mailer, to: &str, raw: &str) {
+    let link = mailer.link(&format!("/verify?token={}", raw));
+    let body = format!(
+        "Welcome!\n\nPlease confirm your email address by opening the link below:\n\n{}\n",
+        link
+    );
+
+    if let Err(e) = mailer.send(to, "Confirm your email address", body).await {
+        log::warn!("could not send verification email: {}", e);
+    }
+}
+
+fn confirm(c: &SqliteConnection, presented: &str) -> Result<(), APIError> {
+    use db::schema::verification_tokens::dsl::*;
+
+    c.transaction(|| {
+        let owner = verification_tokens
+            .filter(token_hash.eq(token::digest(presented)))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .select(user_id)
+            .first::<i32>(c)
+            .optional()?
+            .ok_or(APIError::NotAuthorized)?;
+
+        {
+            use db::schema::users::dsl::*;
+            diesel::update(users.filter(id.eq(owner)))
+                .set(email_verified.eq(true))
+                .execute(c)?;
+        }
+
+        diesel::delete(verification_tokens.filter(user_id.eq(owner))).execute(c)?;
+        Ok(())
+    })
+}
+
+#[get("/verify?<token>")]
+async fn verify(
+    db: LoyaltyDbConn,
+    token: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    db.run(move |c| confirm(c, &token)).await?;
+
+    Ok(status::Custom(Status::Ok, "email verified"))
+}
+
+#[post("/verify/resend")]
+async fn resend(
+    db: LoyaltyDbConn,
+    mailer: State<'_, Mailer>,
+    user: User,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::users::dsl::*;
+
+    let pending = db
+        .run(move |c| {
+            let (user_email, verified) = users
+                .filter(id.eq(user.0))
+                .select((email, email_verified))
+                .first::<(String, bool)>(c)?;
+
+            if verified {
+                return Ok::<_, APIError>(None);
+            }
+
+            Ok(Some((user_email, issue(c, user.0)?)))
+        })
+        .await?;
+
+    match pending {
+        Some((user_email, raw)) => {
+            send(&mailer, &user_email, &raw).await;
+            Ok(status::Custom(Status::Ok, "verification email sent"))
+        }
+        None => Ok(status::Custom(Status::Ok, "email already verified")),
+    }
+}
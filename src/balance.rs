@@ -0,0 +1,205 @@
+//! What is left on gift and prepaid cards, entered by hand. Amounts are
+//! decimals such as `12.50`, kept as text so they are never rounded, with
+//! the ISO 4217 code of their currency.
+//!
+//! Each change of the balance is appended to the card's balance history,
+//! from which users can follow what they spent.
+
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::auth::LoyaltiesReader;
+use crate::db::{
+    self,
+    models::{BalanceEntry, Loyalty, NewBalanceEntry},
+};
+use crate::requests::{invalid, invalid_value, BalanceEntryResponse};
+use crate::shares;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+const MAX_UNITS_DIGITS: usize = 12;
+/// Enough for the currencies with thousandths, such as the Kuwaiti dinar.
+const MAX_FRACTION_DIGITS: usize = 3;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_balance_history]
+}
+
+/// Accepts non-negative decimals with a dot, without a sign or exponent.
+pub fn validate_amount(amount: &str) -> Result<(), ValidationError> {
+    let mut parts = amount.splitn(2, '.');
+    let units = parts.next().unwrap_or_default();
+    let fraction = parts.next();
+    let digits = |part: &str, max: usize| {
+        !part.is_empty() && part.len() <= max && part.bytes().all(|b| b.is_ascii_digit())
+    };
+
+    if !digits(units, MAX_UNITS_DIGITS)
+        || !fraction.map_or(true, |fraction| digits(fraction, MAX_FRACTION_DIGITS))
+    {
+        return Err(invalid_value("balances are amounts such as 12.50"));
+    }
+    Ok(())
+}
+
+/// Accepts three letters; they are stored uppercase.
+pub fn validate_currency(currency: &str) -> Result<(), ValidationError> {
+    if currency.len() != 3 || !currency.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(invalid_value("currencies are ISO 4217 codes such as EUR"));
+    }
+    Ok(())
+}
+
+/// The currency as stored.
+pub fn normalize_currency(currency: &str) -> String {
+    currency.to_ascii_uppercase()
+}
+
+/// A balance and its currency go together: one can't be set without the
+/// other.
+pub fn check_pair(amount: Option<&str>, currency: Option<&str>) -> Result<(), ValidationErrors> {
+    let (field, message) = match (amount, currency) {
+        (Some(_), None) => ("balance_currency", "a balance needs a currency"),
+        (None, Some(_)) => ("balance", "a currency needs a balance"),
+        _ => return Ok(()),
+    };
+
+    Err(invalid(field, message))
+}
+
+/// Appends the balance of `card` to its history when it differs from
+/// `before`, the card as it was before `editor` changed it. New cards have
+/// no `before`.
+pub fn record(
+    c: &SqliteConnection,
+    before: Option<&Loyalty>,
+    card: &Loyalty,
+    editor: i32,
+) -> QueryResult<usize> {
+    let unchanged = match before {
+        Some(before) => {
+            before.balance == card.balance && before.balance_currency == card.balance_currency
+        }
+        None => card.balance.is_none(),
+    };
+    if unchanged {
+        return Ok(0);
+    }
+
+    diesel::insert_into(db::schema::balance_entries::table)
+        .values(&NewBalanceEntry {
+            card_id: card.id,
+            user_id: editor,
+            balance: card.balance.as_deref(),
+            balance_currency: card.balance_currency.as_deref(),
+        })
+        .execute(c)
+}
+
+/// Removes the balance history of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::balance_entries::dsl::*;
+
+    diesel::delete(balance_entries.filter(card_id.eq(card))).execute(c)
+}
+
+/// An amount that passed `validate_amount`, in thousandths.
+fn thousandths(amount: &str) -> Option<i64> {
+    let mut parts = amount.splitn(2, '.');
+    let units: i64 = parts.next()?.parse().ok()?;
+    let fraction = parts.next().unwrap_or_default();
+    let padded = format!("{:0<width$}", fraction, width = MAX_FRACTION_DIGITS);
+
+    Some(units * 1000 + padded.parse::<i64>().ok()?)
+}
+
+fn fraction_digits(amount: &str) -> usize {
+    amount.splitn(2, '.').nth(1).map_or(0, str::len)
+}
+
+/// `after - before`, with as many decimals as the more precise of the two.
+fn difference(before: &str, after: &str) -> Option<String> {
+    let change = thousandths(after)? - thousandths(before)?;
+    let digits = fraction_digits(before).max(fraction_digits(after));
+
+    let sign = if change < 0 { "-" } else { "" };
+    let magnitude = change.abs();
+    let units = magnitude / 1000;
+    if digits == 0 {
+        return Some(format!("{}{}", sign, units));
+    }
+    let fraction = format!("{:03}", magnitude % 1000);
+    Some(format!("{}{}.{}", sign, units, &fraction[..digits]))
+}
+
+/// The balances of the card after each change, newest first.
+#[get("/loyalties/<loyalty_id>/balance-history?<limit>&<offset>")]
+async fn get_balance_history(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<BalanceEntryResponse>>, APIError> {
+    use db::schema::{balance_entries, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let entries = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let readable = cards::table
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .select(cards::id)
+                .first::<i32>(c)
+                .optional()?;
+            if readable.is_none() {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(balance_entries::table
+                .filter(balance_entries::card_id.eq(loyalty_id))
+                .order(balance_entries::id.asc())
+                .load::<BalanceEntry>(c)?)
+        })
+        .await?;
+
+    // Each entry is compared with the one before it, oldest first.
+    let mut history = Vec::new();
+    let mut previous: Option<&BalanceEntry> = None;
+    for entry in &entries {
+        let change = match (previous, &entry.balance) {
+            (Some(before), Some(after)) if before.balance_currency == entry.balance_currency => {
+                before
+                    .balance
+                    .as_deref()
+                    .and_then(|before| difference(before, after))
+            }
+            _ => None,
+        };
+        history.push(BalanceEntryResponse {
+            id: entry.id,
+            recorded_at: entry.recorded_at,
+            editor_id: entry.user_id,
+            balance: entry.balance.clone(),
+            balance_currency: entry.balance_currency.clone(),
+            change,
+        });
+        previous = Some(entry);
+    }
+
+    Ok(Json(
+        history.into_iter().rev().skip(offset).take(limit).collect(),
+    ))
+}
@@ -0,0 +1,192 @@
+//! Barcode formats a card can be displayed with, and the codes each accepts.
+
+use std::borrow::Cow;
+
+use barcoders::generators::image::{Color, Image, Rotation};
+use barcoders::generators::svg::SVG;
+use barcoders::sym::{code128::Code128, ean13::EAN13};
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use validator::{ValidationError, ValidationErrors};
+
+/// Longest payloads the 2D symbologies hold in byte mode.
+const QR_MAX_BYTES: usize = 2953;
+const PDF417_MAX_BYTES: usize = 1108;
+/// Longer Code 128 symbols don't fit a phone screen or most scanners.
+const CODE128_MAX_CHARS: usize = 80;
+/// Height in pixels of the rendered linear symbols.
+const SYMBOL_HEIGHT: u32 = 120;
+/// Width in pixels of a module in the PNG images; SVG ones scale.
+const PNG_MODULE_WIDTH: u32 = 3;
+/// Selects the Code 128 character set B, which covers printable ASCII.
+const CODE128_SET_B: char = 'Ɓ';
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum BarcodeType {
+    #[serde(rename = "ean13")]
+    Ean13,
+    #[serde(rename = "upca")]
+    UpcA,
+    #[serde(rename = "code128")]
+    Code128,
+    #[serde(rename = "qr")]
+    Qr,
+    #[serde(rename = "pdf417")]
+    Pdf417,
+}
+
+/// The format of cards created without one, and of cards created before
+/// formats existed.
+impl Default for BarcodeType {
+    fn default() -> Self {
+        BarcodeType::Code128
+    }
+}
+
+impl BarcodeType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ean13" => Some(BarcodeType::Ean13),
+            "upca" => Some(BarcodeType::UpcA),
+            "code128" => Some(BarcodeType::Code128),
+            "qr" => Some(BarcodeType::Qr),
+            "pdf417" => Some(BarcodeType::Pdf417),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BarcodeType::Ean13 => "ean13",
+            BarcodeType::UpcA => "upca",
+            BarcodeType::Code128 => "code128",
+            BarcodeType::Qr => "qr",
+            BarcodeType::Pdf417 => "pdf417",
+        }
+    }
+
+    fn accepts(self, code: &str) -> bool {
+        match self {
+            BarcodeType::Ean13 => gtin(code, 13),
+            BarcodeType::UpcA => gtin(code, 12),
+            BarcodeType::Code128 => {
+                !code.is_empty() && code.len() <= CODE128_MAX_CHARS && code.is_ascii()
+            }
+            BarcodeType::Qr => !code.is_empty() && code.len() <= QR_MAX_BYTES,
+            BarcodeType::Pdf417 => !code.is_empty() && code.len() <= PDF417_MAX_BYTES,
+        }
+    }
+
+    /// Checks that `code` can be encoded in this format, reporting on the
+    /// `code` field.
+    pub fn check(self, code: &str) -> Result<(), ValidationErrors> {
+        if self.accepts(code) {
+            return Ok(());
+        }
+
+        let mut error = ValidationError::new("barcode");
+        error.message = Some(Cow::Borrowed(
+            "code can't be encoded in this barcode format",
+        ));
+        error.add_param(Cow::Borrowed("barcode_type"), &self.name());
+
+        let mut errors = ValidationErrors::new();
+        errors.add("code", error);
+        Err(errors)
+    }
+
+    /// The modules of a linear symbol for `code`, 1 for a bar, surrounded by
+    /// the quiet zones scanners need: 11 modules before and 7 after EAN-13,
+    /// 9 on both sides of UPC-A and 10 on both sides of Code 128.
+    fn modules(self, code: &str) -> Option<Vec<u8>> {
+        let (encoded, before, after) = match self {
+            // The check digit is computed again from the data digits.
+            BarcodeType::Ean13 if gtin(code, 13) => (EAN13::new(&code[..12]).ok()?.encode(), 11, 7),
+            BarcodeType::UpcA if gtin(code, 12) => {
+                (EAN13::new(format!("0{}", &code[..11])).ok()?.encode(), 9, 9)
+            }
+            BarcodeType::Code128 if self.accepts(code) => (
+                Code128::new(format!("{}{}", CODE128_SET_B, code))
+                    .ok()?
+                    .encode(),
+                10,
+                10,
+            ),
+            _ => return None,
+        };
+
+        let mut modules = vec![0; before];
+        modules.extend(encoded);
+        modules.resize(modules.len() + after, 0);
+        Some(modules)
+    }
+
+    /// Renders `code` as an SVG image. PDF417 can't be rendered here and
+    /// gives `None`, as do codes that don't fit the format.
+    pub fn svg(self, code: &str) -> Option<String> {
+        if self == BarcodeType::Qr && self.accepts(code) {
+            let symbol = QrCode::new(code.as_bytes()).ok()?;
+            return Some(
+                symbol
+                    .render::<svg::Color>()
+                    .min_dimensions(240, 240)
+                    .build(),
+            );
+        }
+
+        SVG::new(SYMBOL_HEIGHT).generate(&self.modules(code)?).ok()
+    }
+
+    /// Renders `code` as a PNG image, with the same limits as `svg`.
+    pub fn png(self, code: &str) -> Option<Vec<u8>> {
+        if self == BarcodeType::Qr && self.accepts(code) {
+            return qr_png(code, 240);
+        }
+
+        Image::PNG {
+            height: SYMBOL_HEIGHT,
+            xdim: PNG_MODULE_WIDTH,
+            rotation: Rotation::Zero,
+            foreground: Color::black(),
+            background: Color::white(),
+        }
+        .generate(&self.modules(code)?)
+        .ok()
+    }
+}
+
+/// Renders `code` as a QR code in a PNG image of about `size` pixels a side,
+/// whatever the format of the card.
+pub fn qr_png(code: &str, size: u32) -> Option<Vec<u8>> {
+    let symbol = QrCode::new(code.as_bytes()).ok()?;
+    let rendered = symbol
+        .render::<Luma<u8>>()
+        .min_dimensions(size, size)
+        .build();
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(rendered)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .ok()?;
+    Some(png)
+}
+
+/// EAN-13 and UPC-A are GTINs: fixed-length digits ending with a mod-10
+/// check digit, weighted 3 from the rightmost data digit.
+fn gtin(code: &str, length: usize) -> bool {
+    if code.len() != length || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+
+    let digits: Vec<u32> = code.bytes().map(|b| u32::from(b - b'0')).collect();
+    let (data, check) = digits.split_at(length - 1);
+    let sum: u32 = data
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| if i % 2 == 0 { digit * 3 } else { *digit })
+        .sum();
+
+    (10 - sum % 10) % 10 == check[0]
+}
@@ -0,0 +1,115 @@
+//! Time-boxed promotions merchants run, such as double points on weekends.
+//! Points earned on a card while a campaign of its retailer runs are
+//! multiplied by it; when several run, the most generous one applies.
+
+use chrono::{Datelike, NaiveDateTime, Utc, Weekday};
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+
+use crate::auth::LoyaltiesReader;
+use crate::db::{self, models::Campaign};
+use crate::requests::CampaignResponse;
+use crate::{APIError, LoyaltyDbConn};
+
+pub fn routes() -> Vec<Route> {
+    routes![list_campaigns]
+}
+
+/// The days of a stored `weekdays` column, skipping any that don't parse.
+pub fn parse_weekdays(stored: &str) -> Vec<Weekday> {
+    stored
+        .split(',')
+        .filter_map(|day| day.trim().parse().ok())
+        .collect()
+}
+
+/// The `weekdays` column for `days`, such as `sat,sun`.
+pub fn format_weekdays(days: &[Weekday]) -> String {
+    let mut names: Vec<String> = Vec::with_capacity(days.len());
+    for day in days {
+        let name = format!("{:?}", day).to_lowercase();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.join(",")
+}
+
+fn runs_on(campaign: &Campaign, when: NaiveDateTime) -> bool {
+    match &campaign.weekdays {
+        Some(days) => parse_weekdays(days).contains(&when.weekday()),
+        None => true,
+    }
+}
+
+/// Multiplies `points` earned on `card` at `when` by the campaign of its
+/// retailer running then, returning them with the campaign's name.
+pub fn apply(
+    c: &SqliteConnection,
+    card: i32,
+    points: i32,
+    when: NaiveDateTime,
+) -> QueryResult<(i32, Option<String>)> {
+    use db::schema::{campaigns, cards};
+
+    let retailer = cards::table
+        .find(card)
+        .select(cards::retailer_id)
+        .first::<Option<i32>>(c)
+        .optional()?
+        .flatten();
+    let retailer = match retailer {
+        Some(retailer) => retailer,
+        None => return Ok((points, None)),
+    };
+
+    let best = campaigns::table
+        .filter(campaigns::retailer_id.eq(retailer))
+        .filter(campaigns::starts_at.le(when))
+        .filter(campaigns::ends_at.gt(when))
+        .load::<Campaign>(c)?
+        .into_iter()
+        .filter(|campaign| runs_on(campaign, when))
+        .max_by_key(|campaign| campaign.multiplier_percent);
+
+    Ok(match best {
+        Some(campaign) => {
+            let multiplied = i64::from(points) * i64::from(campaign.multiplier_percent) / 100;
+            (
+                multiplied.min(i64::from(i32::MAX)) as i32,
+                Some(campaign.name),
+            )
+        }
+        None => (points, None),
+    })
+}
+
+/// Campaigns running or to come, the soonest first.
+#[get("/campaigns?<retailer_id>")]
+async fn list_campaigns(
+    db: LoyaltyDbConn,
+    _user: LoyaltiesReader,
+    retailer_id: Option<i32>,
+) -> Result<Json<Vec<CampaignResponse>>, APIError> {
+    use db::schema::campaigns;
+
+    let found = db
+        .run(move |c| {
+            let mut query = campaigns::table
+                .filter(campaigns::ends_at.gt(Utc::now().naive_utc()))
+                .into_boxed();
+            if let Some(retailer) = retailer_id {
+                query = query.filter(campaigns::retailer_id.eq(retailer));
+            }
+
+            query
+                .order((campaigns::starts_at.asc(), campaigns::id.asc()))
+                .load::<Campaign>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        found.into_iter().map(CampaignResponse::from).collect(),
+    ))
+}
@@ -0,0 +1,129 @@
+//! Optional hCaptcha / reCAPTCHA check for public endpoints.
+//!
+//! Clients send the widget response in the `X-Captcha-Token` header. Without
+//! a `captcha` section in the configuration the check is disabled.
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::Deserialize;
+
+use crate::{proxies, APIError};
+
+pub const HEADER: &str = "X-Captcha-Token";
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    HCaptcha,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    fn verify_url(self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://hcaptcha.com/siteverify",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+/// The `captcha` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    pub secret: String,
+}
+
+pub struct Captcha {
+    config: Option<CaptchaConfig>,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    success: bool,
+}
+
+impl Captcha {
+    async fn verify(
+        &self,
+        config: &CaptchaConfig,
+        response: &str,
+        ip: Option<String>,
+    ) -> Result<bool, APIError> {
+        let mut form = vec![
+            ("secret", config.secret.clone()),
+            ("response", response.to_string()),
+        ];
+        if let Some(ip) = ip {
+            form.push(("remoteip", ip));
+        }
+
+        let verified: VerifyResponse = self
+            .http
+            .post(config.provider.verify_url())
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(verified.success)
+    }
+}
+
+/// Request guard passing when the captcha was solved, or when no captcha is
+/// configured.
+pub struct CaptchaVerified;
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for CaptchaVerified {
+    type Error = APIError;
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let captcha = match request.managed_state::<Captcha>() {
+            Some(captcha) => captcha,
+            None => return Outcome::Success(CaptchaVerified),
+        };
+        let config = match &captcha.config {
+            Some(config) => config,
+            None => return Outcome::Success(CaptchaVerified),
+        };
+
+        let response = match request.headers().get_one(HEADER) {
+            Some(response) if !response.is_empty() => response,
+            _ => return Outcome::Failure((Status::Forbidden, APIError::NotAuthorized)),
+        };
+
+        let ip = proxies::client_ip(request).map(|ip| ip.to_string());
+        match captcha.verify(config, response, ip).await {
+            Ok(true) => Outcome::Success(CaptchaVerified),
+            Ok(false) => Outcome::Failure((Status::Forbidden, APIError::NotAuthorized)),
+            Err(e) => {
+                log::warn!("captcha verification failed: {}", e);
+                Outcome::Failure((Status::BadGateway, e))
+            }
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Captcha", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<CaptchaConfig>("captcha") {
+            Ok(config) => Some(config),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                log::error!("invalid captcha configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(Captcha {
+            config,
+            http: reqwest::Client::new(),
+        }))
+    })
+}
@@ -0,0 +1,598 @@
+//! Card listing and responses, with what is stored beside the card rows:
+//! photo links, tags, custom fields and logos.
+
+use chrono::{Duration, Utc};
+use diesel::dsl::{count_star, sql};
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use diesel::sqlite::Sqlite;
+use validator::ValidationErrors;
+
+use crate::db::{
+    self,
+    crypto::{self, EncryptedString},
+    models::{Loyalty, NewCardTag, NewLoyalty},
+};
+use crate::images::{self, Side};
+use crate::requests::{invalid, AddLoyalty, AddLoyaltyResponse, StampProgress};
+use crate::storage::Storage;
+use crate::tags;
+use crate::{
+    attachments, locations, logos, metadata, point_transfers, points, pos, receipts, reminders,
+    retailers, revisions, search, share_links, shares, stamps, tiers, transfers, APIError,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sort {
+    Position,
+    Name,
+    CreatedAt,
+    LastUsed,
+    UseCount,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort::Position
+    }
+}
+
+impl Sort {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "position" => Some(Sort::Position),
+            "name" => Some(Sort::Name),
+            "created_at" => Some(Sort::CreatedAt),
+            "last_used" => Some(Sort::LastUsed),
+            "use_count" => Some(Sort::UseCount),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order::Asc
+    }
+}
+
+impl Order {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "asc" => Some(Order::Asc),
+            "desc" => Some(Order::Desc),
+            _ => None,
+        }
+    }
+}
+
+/// The `sort` and `order` query parameters, defaulting to the order the
+/// user arranged the cards in.
+pub fn sorting(sort: Option<&str>, order: Option<&str>) -> Result<(Sort, Order), ValidationErrors> {
+    let sort = match sort {
+        Some(name) => Sort::from_name(name).ok_or_else(|| {
+            invalid(
+                "sort",
+                "sort by position, name, created_at, last_used or use_count",
+            )
+        })?,
+        None => Sort::default(),
+    };
+    let order = match order {
+        Some(name) => {
+            Order::from_name(name).ok_or_else(|| invalid("order", "order is asc or desc"))?
+        }
+        None => Order::default(),
+    };
+
+    Ok((sort, order))
+}
+
+/// The filters of `GET /loyalties`.
+#[derive(Clone, Default)]
+pub struct CardFilter {
+    /// Name of a category the cards are tagged with.
+    pub tag: Option<String>,
+    /// Words to find in the name, code, notes or custom fields, ignoring
+    /// case. Each word must be found, in any of them: as a prefix of a word
+    /// of the name or of a field name, or as a whole word of the others.
+    pub q: Option<String>,
+    /// Lists the archived cards along with the others.
+    pub include_archived: bool,
+    /// Lists the deactivated cards along with the others.
+    pub include_inactive: bool,
+    pub sort: Sort,
+    pub order: Order,
+}
+
+impl CardFilter {
+    /// Cards of `user` matching the filter, in the requested order.
+    pub fn query(&self, user: i32) -> db::schema::cards::BoxedQuery<'static, Sqlite> {
+        use db::schema::cards::dsl::*;
+        use db::schema::{card_search, card_tags, categories, group_members};
+
+        // The cards of the family groups of `user` are listed with its own.
+        let joined = group_members::table
+            .filter(group_members::user_id.eq(user))
+            .select(group_members::group_id.nullable());
+        let mut query = cards
+            .filter(user_id.eq(user).or(group_id.eq_any(joined)))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        if !self.include_archived {
+            query = query.filter(archived_at.is_null());
+        }
+        if !self.include_inactive {
+            query = query.filter(is_active.eq(true));
+        }
+        if let Some(tag) = &self.tag {
+            query = query.filter(
+                id.eq_any(
+                    card_tags::table
+                        .inner_join(categories::table)
+                        .filter(categories::user_id.eq(user))
+                        .filter(categories::name.eq(tag.clone()))
+                        .select(card_tags::card_id),
+                ),
+            );
+        }
+        if let Some(expression) = self.q.as_deref().and_then(search::query) {
+            query = query.filter(
+                id.eq_any(
+                    card_search::table
+                        .filter(sql::<Bool>("card_search MATCH ").bind::<Text, _>(expression))
+                        .select(card_search::rowid),
+                ),
+            );
+        }
+
+        // Favorites come first, whatever the sort.
+        query = query.order(is_favorite.desc());
+        query = match (self.sort, self.order) {
+            (Sort::Position, Order::Asc) => query.then_order_by(position.asc()),
+            (Sort::Position, Order::Desc) => query.then_order_by(position.desc()),
+            (Sort::Name, Order::Asc) => query.then_order_by(name.asc()),
+            (Sort::Name, Order::Desc) => query.then_order_by(name.desc()),
+            (Sort::CreatedAt, Order::Asc) => query.then_order_by(created_at.asc()),
+            (Sort::CreatedAt, Order::Desc) => query.then_order_by(created_at.desc()),
+            (Sort::LastUsed, Order::Asc) => query.then_order_by(last_used_at.asc()),
+            (Sort::LastUsed, Order::Desc) => query.then_order_by(last_used_at.desc()),
+            (Sort::UseCount, Order::Asc) => query.then_order_by(use_count.asc()),
+            (Sort::UseCount, Order::Desc) => query.then_order_by(use_count.desc()),
+        };
+        // Ties, such as cards never used, stay in insertion order.
+        query.then_order_by(id.asc())
+    }
+
+    /// One page of the matching cards of `user`, with the number of matches.
+    pub fn page(
+        &self,
+        c: &SqliteConnection,
+        user: i32,
+        limit: i64,
+        offset: i64,
+    ) -> QueryResult<(i64, Vec<Loyalty>)> {
+        let count = self.query(user).select(count_star()).first(c)?;
+        let found = self.query(user).limit(limit).offset(offset).load(c)?;
+
+        Ok((count, found))
+    }
+}
+
+/// The card of `user` with this code, if any, leaving out the trash. Codes
+/// are sealed with a random nonce, so they are found by their blind index.
+pub fn find_duplicate(c: &SqliteConnection, user: i32, wanted: &str) -> QueryResult<Option<i32>> {
+    use db::schema::cards::dsl::*;
+
+    let digest = match crypto::blind_index(wanted) {
+        Some(digest) => digest,
+        None => return Ok(None),
+    };
+
+    cards
+        .filter(user_id.eq(user))
+        .filter(code_index.eq(digest))
+        .filter(deleted_at.is_null())
+        .select(id)
+        .first::<i32>(c)
+        .optional()
+}
+
+/// Codes of the cards of `user` as `(card, code)`, leaving out the trash.
+pub fn codes(c: &SqliteConnection, user: i32) -> QueryResult<Vec<(i32, String)>> {
+    use db::schema::cards::dsl::*;
+
+    let owned = cards
+        .filter(user_id.eq(user))
+        .filter(deleted_at.is_null())
+        .select((id, code))
+        .load::<(i32, EncryptedString)>(c)?;
+
+    Ok(owned
+        .into_iter()
+        .map(|(card, existing)| (card, existing.0))
+        .collect())
+}
+
+/// Inserts a card of `user` after the others, filling in what `body` left
+/// out from its retailer and spelling its name as the catalog does. Fails
+/// on codes the user already has a card for.
+pub fn create(c: &SqliteConnection, user: i32, body: &AddLoyalty) -> Result<Loyalty, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let (prefilled_color, prefilled_type) = retailers::prefill(c, body)?;
+    prefilled_type.check(&body.code)?;
+    crate::balance::check_pair(body.balance.as_deref(), body.balance_currency.as_deref())?;
+    let currency = body
+        .balance_currency
+        .as_deref()
+        .map(crate::balance::normalize_currency);
+    if let Some(existing) = find_duplicate(c, user, &body.code)? {
+        return Err(APIError::DuplicateCard(existing));
+    }
+    let canonical = if body.keep_name {
+        None
+    } else {
+        retailers::canonical_name(c, &body.name)?
+    };
+
+    let last_position = cards
+        .filter(user_id.eq(user))
+        .select(diesel::dsl::max(position))
+        .first::<Option<i32>>(c)?;
+
+    diesel::insert_into(cards)
+        .values(&NewLoyalty {
+            name: canonical.as_deref().unwrap_or(&body.name),
+            color: prefilled_color.as_deref(),
+            code: EncryptedString(body.code.clone()),
+            user_id: user,
+            barcode_type: prefilled_type.name(),
+            created_at: Utc::now().naive_utc(),
+            position: last_position.map_or(0, |last| last + 1),
+            notes: body.notes.clone().map(EncryptedString),
+            expires_at: body.expires_at,
+            retailer_id: body.retailer_id,
+            balance: body.balance.as_deref(),
+            balance_currency: currency.as_deref(),
+            icon: body.icon.as_deref(),
+            stamps_required: body.stamps_required,
+            code_index: crypto::blind_index(&body.code),
+        })
+        .execute(c)?;
+
+    let created = cards.order(id.desc()).first::<Loyalty>(c)?;
+    crate::balance::record(c, None, &created, user)?;
+    search::index(c, created.id)?;
+    Ok(created)
+}
+
+/// Deletes `card` with its tags, custom fields, locations, reminders,
+/// shares, links, pending transfer, uses, history, balance history, points
+/// and their transfers, stamp rewards, purchases, receipts and search entry,
+/// returning the keys of its photos, attachments and receipts to delete from
+/// storage.
+pub fn remove(c: &SqliteConnection, card: i32) -> QueryResult<Vec<String>> {
+    let mut objects = images::detach(c, card, None)?;
+    objects.extend(attachments::detach(c, card)?);
+    objects.extend(receipts::detach(c, card)?);
+    tags::untag(c, card)?;
+    shares::unshare(c, card)?;
+    share_links::unlink(c, card)?;
+    transfers::cancel(c, card)?;
+    revisions::forget(c, card)?;
+    crate::balance::forget(c, card)?;
+    point_transfers::detach(c, card)?;
+    points::forget(c, card)?;
+    pos::forget(c, card)?;
+    stamps::forget(c, card)?;
+    metadata::clear(c, card)?;
+    locations::clear(c, card)?;
+    reminders::clear(c, card)?;
+    search::forget(c, card)?;
+    diesel::delete(db::schema::card_uses::table.filter(db::schema::card_uses::card_id.eq(card)))
+        .execute(c)?;
+    diesel::delete(db::schema::cards::table.find(card)).execute(c)?;
+
+    Ok(objects)
+}
+
+/// Folds `other` into `kept`, then deletes it: notes are joined, uses,
+/// tags, custom fields, attachments, locations, reminders, points, stamp
+/// rewards, point transfers, purchases and receipts added up, and photos
+/// moved for the sides `kept` has none of. Returns the keys of the photos
+/// left over, to delete from storage.
+pub fn merge(c: &SqliteConnection, kept: &Loyalty, other: &Loyalty) -> QueryResult<Vec<String>> {
+    use db::schema::{
+        card_attachments, card_images, card_locations, card_metadata, card_reminders, card_tags,
+        card_uses, cards, point_transactions, point_transfers, purchases, receipts, stamp_rewards,
+    };
+
+    let notes = match (&kept.notes, &other.notes) {
+        (Some(first), Some(second)) if first.0 != second.0 => {
+            Some(EncryptedString(format!("{}\n\n{}", first.0, second.0)))
+        }
+        (Some(first), _) => Some(first.clone()),
+        (None, second) => second.clone(),
+    };
+    let last_used = kept.last_used_at.max(other.last_used_at);
+    diesel::update(cards::table.find(kept.id))
+        .set((
+            cards::notes.eq(notes),
+            cards::use_count.eq(kept.use_count + other.use_count),
+            cards::last_used_at.eq(last_used),
+            cards::is_favorite.eq(kept.is_favorite || other.is_favorite),
+            cards::created_at.eq(kept.created_at.min(other.created_at)),
+            cards::expires_at.eq(kept.expires_at.or(other.expires_at)),
+        ))
+        .execute(c)?;
+
+    diesel::update(card_uses::table.filter(card_uses::card_id.eq(other.id)))
+        .set(card_uses::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(card_attachments::table.filter(card_attachments::card_id.eq(other.id)))
+        .set(card_attachments::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(card_locations::table.filter(card_locations::card_id.eq(other.id)))
+        .set(card_locations::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(card_reminders::table.filter(card_reminders::card_id.eq(other.id)))
+        .set(card_reminders::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(point_transactions::table.filter(point_transactions::card_id.eq(other.id)))
+        .set(point_transactions::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(stamp_rewards::table.filter(stamp_rewards::card_id.eq(other.id)))
+        .set(stamp_rewards::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(point_transfers::table.filter(point_transfers::from_card_id.eq(other.id)))
+        .set(point_transfers::from_card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(point_transfers::table.filter(point_transfers::to_card_id.eq(other.id)))
+        .set(point_transfers::to_card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(purchases::table.filter(purchases::card_id.eq(other.id)))
+        .set(purchases::card_id.eq(kept.id))
+        .execute(c)?;
+    diesel::update(receipts::table.filter(receipts::card_id.eq(other.id)))
+        .set(receipts::card_id.eq(kept.id))
+        .execute(c)?;
+
+    let kept_sides = card_images::table
+        .filter(card_images::card_id.eq(kept.id))
+        .select(card_images::side)
+        .load::<String>(c)?;
+    diesel::update(
+        card_images::table
+            .filter(card_images::card_id.eq(other.id))
+            .filter(card_images::side.ne_all(kept_sides)),
+    )
+    .set(card_images::card_id.eq(kept.id))
+    .execute(c)?;
+
+    let kept_tags = card_tags::table
+        .filter(card_tags::card_id.eq(kept.id))
+        .select(card_tags::category_id)
+        .load::<i32>(c)?;
+    diesel::update(
+        card_tags::table
+            .filter(card_tags::card_id.eq(other.id))
+            .filter(card_tags::category_id.ne_all(kept_tags)),
+    )
+    .set(card_tags::card_id.eq(kept.id))
+    .execute(c)?;
+
+    let kept_keys = card_metadata::table
+        .filter(card_metadata::card_id.eq(kept.id))
+        .select(card_metadata::key)
+        .load::<String>(c)?;
+    diesel::update(
+        card_metadata::table
+            .filter(card_metadata::card_id.eq(other.id))
+            .filter(card_metadata::key.ne_all(kept_keys)),
+    )
+    .set(card_metadata::card_id.eq(kept.id))
+    .execute(c)?;
+    search::index(c, kept.id)?;
+
+    remove(c, other.id)
+}
+
+/// Copies `card` into a new card of `user`, after the others, with the same
+/// code and custom fields. Tags are copied when `user` owns the card, as
+/// they are the owner's; photos, uses, the balance and favorite, archive or
+/// group settings are not.
+pub fn duplicate(c: &SqliteConnection, user: i32, card: &Loyalty) -> QueryResult<Loyalty> {
+    use db::schema::{card_tags, cards};
+
+    let last_position = cards::table
+        .filter(cards::user_id.eq(user))
+        .select(diesel::dsl::max(cards::position))
+        .first::<Option<i32>>(c)?;
+    diesel::insert_into(cards::table)
+        .values(&NewLoyalty {
+            name: &card.name,
+            color: card.color.as_deref(),
+            code: card.code.clone(),
+            user_id: user,
+            barcode_type: &card.barcode_type,
+            created_at: Utc::now().naive_utc(),
+            position: last_position.map_or(0, |last| last + 1),
+            notes: card.notes.clone(),
+            expires_at: card.expires_at,
+            retailer_id: card.retailer_id,
+            balance: None,
+            balance_currency: None,
+            icon: card.icon.as_deref(),
+            stamps_required: card.stamps_required,
+            code_index: card.code_index.clone(),
+        })
+        .execute(c)?;
+    let copy = cards::table.order(cards::id.desc()).first::<Loyalty>(c)?;
+    let fields = metadata::fields_of(c, &[card.id])?
+        .into_iter()
+        .map(|(_, name, text)| (name, text))
+        .collect();
+    metadata::replace(c, copy.id, &fields)?;
+
+    if card.user_id == user {
+        let tagged = card_tags::table
+            .filter(card_tags::card_id.eq(card.id))
+            .select(card_tags::category_id)
+            .load::<i32>(c)?;
+        let copied: Vec<NewCardTag> = tagged
+            .into_iter()
+            .map(|category_id| NewCardTag {
+                card_id: copy.id,
+                category_id,
+            })
+            .collect();
+        diesel::insert_into(card_tags::table)
+            .values(&copied)
+            .execute(c)?;
+    }
+
+    Ok(copy)
+}
+
+/// What `purge_trashed` removed.
+pub struct Purged {
+    pub cards: usize,
+    /// Photos of the cards, to delete from storage.
+    pub objects: Vec<String>,
+}
+
+/// Purges the cards moved to the trash more than `retention_days` ago.
+pub fn purge_trashed(c: &SqliteConnection, retention_days: i64) -> QueryResult<Purged> {
+    use db::schema::cards::dsl::*;
+
+    let cutoff = Utc::now().naive_utc() - Duration::days(retention_days);
+    let expired = cards
+        .filter(deleted_at.lt(cutoff))
+        .select(id)
+        .load::<i32>(c)?;
+
+    let objects = c.transaction(|| {
+        let mut objects = Vec::new();
+        for card in &expired {
+            objects.extend(remove(c, *card)?);
+        }
+        Ok::<_, diesel::result::Error>(objects)
+    })?;
+
+    Ok(Purged {
+        cards: expired.len(),
+        objects,
+    })
+}
+
+pub fn describe(
+    c: &SqliteConnection,
+    storage: &Storage,
+    cards: Vec<Loyalty>,
+) -> QueryResult<Vec<AddLoyaltyResponse>> {
+    use db::schema::retailers;
+
+    let ids: Vec<i32> = cards.iter().map(|card| card.id).collect();
+    let links = images::links(c, storage, &ids)?;
+    let tags = tags::tags_of(c, &ids)?;
+    let fields = metadata::fields_of(c, &ids)?;
+
+    // The logo of the retailer the card was made from wins over the one
+    // looked up from its name.
+    let catalog = retailers::table
+        .filter(
+            retailers::id.eq_any(
+                cards
+                    .iter()
+                    .filter_map(|card| card.retailer_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .filter(retailers::logo_url.is_not_null())
+        .select((retailers::id, retailers::logo_url))
+        .load::<(i32, Option<String>)>(c)?;
+    let names: Vec<String> = cards.iter().map(|card| card.name.clone()).collect();
+    let cached = logos::cached(c, &names)?;
+    let owners: Vec<i32> = cards.iter().map(|card| card.user_id).collect();
+    let programs: Vec<i32> = cards.iter().filter_map(|card| card.retailer_id).collect();
+    let held = tiers::tiers_of(c, &owners, &programs)?;
+    let stamp_cards: Vec<i32> = cards
+        .iter()
+        .filter(|card| card.stamps_required.is_some())
+        .map(|card| card.id)
+        .collect();
+    let earned = stamps::unredeemed(c, &stamp_cards)?;
+
+    Ok(cards
+        .into_iter()
+        .map(|card| {
+            let link = |wanted: Side| {
+                links
+                    .iter()
+                    .find(|(owner, side, _)| *owner == card.id && *side == wanted)
+                    .map(|(_, _, url)| url.clone())
+            };
+            let front_image_url = link(Side::Front);
+            let back_image_url = link(Side::Back);
+            let card_tags = tags
+                .iter()
+                .filter(|(owner, _)| *owner == card.id)
+                .map(|(_, name)| name.clone())
+                .collect();
+            let card_fields = fields
+                .iter()
+                .filter(|(owner, _, _)| *owner == card.id)
+                .map(|(_, name, text)| (name.clone(), text.clone()))
+                .collect();
+            let logo_url = catalog
+                .iter()
+                .find(|(retailer, _)| Some(*retailer) == card.retailer_id)
+                .and_then(|(_, url)| url.clone())
+                .or_else(|| {
+                    let wanted = card.name.trim().to_lowercase();
+                    cached
+                        .iter()
+                        .find(|(found, _)| *found == wanted)
+                        .map(|(_, url)| url.clone())
+                });
+            let tier = held
+                .iter()
+                .find(|stored| {
+                    stored.user_id == card.user_id && Some(stored.retailer_id) == card.retailer_id
+                })
+                .map(|stored| stored.tier.clone());
+            let rewards = earned.iter().filter(|card_id| **card_id == card.id).count() as i64;
+            let progress = card.stamps_required.map(|required| StampProgress {
+                collected: card.stamps,
+                required,
+                rewards,
+            });
+
+            AddLoyaltyResponse {
+                front_image_url,
+                back_image_url,
+                tags: card_tags,
+                metadata: card_fields,
+                logo_url,
+                tier,
+                stamps: progress,
+                ..card.into()
+            }
+        })
+        .collect())
+}
+
+pub fn describe_one(
+    c: &SqliteConnection,
+    storage: &Storage,
+    card: Loyalty,
+) -> QueryResult<AddLoyaltyResponse> {
+    Ok(describe(c, storage, vec![card])?.remove(0))
+}
@@ -0,0 +1,101 @@
+//! Card colors: hex codes such as `#1e88e5` or `#fff`, or HTML color names,
+//! stored as `#RRGGBB`.
+
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+use validator::ValidationError;
+
+use crate::requests::PaletteColor;
+
+/// The colors named in HTML 4, with `orange` from CSS 2.1.
+const NAMED: &[(&str, &str)] = &[
+    ("aqua", "#00FFFF"),
+    ("black", "#000000"),
+    ("blue", "#0000FF"),
+    ("fuchsia", "#FF00FF"),
+    ("gray", "#808080"),
+    ("green", "#008000"),
+    ("lime", "#00FF00"),
+    ("maroon", "#800000"),
+    ("navy", "#000080"),
+    ("olive", "#808000"),
+    ("orange", "#FFA500"),
+    ("purple", "#800080"),
+    ("red", "#FF0000"),
+    ("silver", "#C0C0C0"),
+    ("teal", "#008080"),
+    ("white", "#FFFFFF"),
+    ("yellow", "#FFFF00"),
+];
+
+/// Suggested to users picking a color, in display order.
+const PALETTE: &[(&str, &str)] = &[
+    ("red", "#E53935"),
+    ("pink", "#D81B60"),
+    ("purple", "#8E24AA"),
+    ("indigo", "#3949AB"),
+    ("blue", "#1E88E5"),
+    ("teal", "#00897B"),
+    ("green", "#43A047"),
+    ("lime", "#C0CA33"),
+    ("yellow", "#FDD835"),
+    ("orange", "#FB8C00"),
+    ("brown", "#6D4C41"),
+    ("gray", "#757575"),
+    ("black", "#212121"),
+];
+
+pub fn routes() -> Vec<Route> {
+    routes![palette]
+}
+
+/// `color` as `#RRGGBB`, or `None` when it isn't a color.
+pub fn normalize(color: &str) -> Option<String> {
+    let color = color.trim();
+    if let Some((_, hex)) = NAMED
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(color))
+    {
+        return Some(hex.to_string());
+    }
+
+    let digits = color.strip_prefix('#').unwrap_or(color);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match digits.len() {
+        3 => Some(
+            digits
+                .chars()
+                .fold(String::from("#"), |mut hex, c| {
+                    hex.push(c);
+                    hex.push(c);
+                    hex
+                })
+                .to_uppercase(),
+        ),
+        6 => Some(format!("#{}", digits.to_uppercase())),
+        _ => None,
+    }
+}
+
+/// Validator of the `color` fields.
+pub fn validate(color: &str) -> Result<(), ValidationError> {
+    if normalize(color).is_some() {
+        return Ok(());
+    }
+
+    let mut error = ValidationError::new("color");
+    error.message = Some("color is a hex code such as #1E88E5 or an HTML color name".into());
+    Err(error)
+}
+
+#[get("/colors/palette")]
+fn palette() -> Json<Vec<PaletteColor>> {
+    Json(
+        PALETTE
+            .iter()
+            .map(|(name, hex)| PaletteColor { name, hex })
+            .collect(),
+    )
+}
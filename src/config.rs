@@ -0,0 +1,107 @@
+//! Startup configuration. Secrets are layered over `Rocket.toml` so they
+//! don't have to live in the file, from lowest to highest precedence:
+//!
+//! 1. `Rocket.toml` and the usual `ROCKET_*` variables,
+//! 2. a HashiCorp Vault KV v2 secret, when `VAULT_ADDR`, `VAULT_TOKEN` and
+//!    `VAULT_SECRET_PATH` are set,
+//! 3. the variables listed in `SECRETS`, or a file named by the same variable
+//!    with a `_FILE` suffix, as mounted by Docker and Kubernetes secrets.
+//!
+//! Vault entries use the same names as the variables.
+
+use std::collections::HashMap;
+
+use rocket::figment::providers::Serialized;
+use rocket::figment::Figment;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Secret names and the configuration keys they set.
+const SECRETS: &[(&str, &str)] = &[
+    ("DATABASE_URL", "databases.loyalty_db.url"),
+    ("SECRET_KEY", "secret_key"),
+    ("JWT_SECRET", "jwt.secret"),
+    ("ENCRYPTION_KEY", "encryption.key"),
+    ("SMTP_PASSWORD", "mail.smtp.password"),
+    ("S3_SECRET_KEY", "storage.secret_key"),
+    ("CAPTCHA_SECRET", "captcha.secret"),
+    ("GOOGLE_CLIENT_SECRET", "oauth.google.client_secret"),
+    ("APPLE_PRIVATE_KEY", "oauth.apple.private_key"),
+];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read {0}: {1}")]
+    File(String, std::io::Error),
+    #[error("could not read secrets from vault: {0}")]
+    Vault(#[from] reqwest::Error),
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+    data: VaultData,
+}
+
+#[derive(Deserialize)]
+struct VaultData {
+    data: HashMap<String, String>,
+}
+
+/// Reads a KV v2 secret, e.g. `secret/data/loyalty-api`.
+async fn vault_secrets() -> Result<HashMap<String, String>, ConfigError> {
+    let (addr, token, path) = match (
+        std::env::var("VAULT_ADDR"),
+        std::env::var("VAULT_TOKEN"),
+        std::env::var("VAULT_SECRET_PATH"),
+    ) {
+        (Ok(addr), Ok(token), Ok(path)) => (addr, token, path),
+        _ => return Ok(HashMap::new()),
+    };
+
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let response: VaultResponse = reqwest::Client::new()
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response.data.data)
+}
+
+fn env_secret(name: &str) -> Result<Option<String>, ConfigError> {
+    if let Ok(value) = std::env::var(name) {
+        return Ok(Some(value));
+    }
+
+    match std::env::var(format!("{}_FILE", name)) {
+        Ok(path) => std::fs::read_to_string(&path)
+            .map(|value| Some(value.trim_end().to_string()))
+            .map_err(|e| ConfigError::File(path, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Builds the figment Rocket is started with.
+pub async fn figment() -> Result<Figment, ConfigError> {
+    let mut figment = rocket::Config::figment();
+    let vault = vault_secrets().await?;
+
+    for (name, key) in SECRETS {
+        // Global, so they win over the `[global]` tables of the file.
+        if let Some(value) = vault.get(*name) {
+            figment = figment.merge(Serialized::global(key, value));
+        }
+        if let Some(value) = env_secret(name)? {
+            figment = figment.merge(Serialized::global(key, value));
+        }
+    }
+
+    Ok(figment)
+}
@@ -0,0 +1,83 @@
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, SameSite};
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+/// The `cookies` section of the Rocket configuration, applied to every
+/// cookie the API sets for authentication.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CookiePolicy {
+    pub same_site: SameSitePolicy,
+    pub secure: bool,
+    pub path: String,
+    pub domain: Option<String>,
+    /// Lifetime in seconds. Without it Rocket's default of one week applies.
+    pub max_age: Option<i64>,
+}
+
+impl Default for CookiePolicy {
+    fn default() -> Self {
+        CookiePolicy {
+            same_site: SameSitePolicy::Strict,
+            secure: false,
+            path: "/".to_string(),
+            domain: None,
+            max_age: None,
+        }
+    }
+}
+
+impl CookiePolicy {
+    /// Builds an HTTP-only cookie with the configured attributes.
+    pub fn build(&self, name: &'static str, value: String) -> Cookie<'static> {
+        let mut cookie = self.named(name);
+        cookie.set_value(value);
+        cookie.set_http_only(true);
+        cookie.set_same_site(match self.same_site {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        });
+        // Browsers drop `SameSite=None` cookies that aren't secure.
+        cookie.set_secure(self.secure || matches!(self.same_site, SameSitePolicy::None));
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(time::Duration::seconds(max_age));
+        }
+
+        cookie
+    }
+
+    /// A cookie matching the configured path and domain, as needed to remove it.
+    pub fn named(&self, name: &'static str) -> Cookie<'static> {
+        let mut cookie = Cookie::named(name);
+        cookie.set_path(self.path.clone());
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        cookie
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Cookie Policy", |rocket| async move {
+        let policy = match rocket.figment().extract_inner::<CookiePolicy>("cookies") {
+            Ok(policy) => policy,
+            Err(e) if e.missing() => CookiePolicy::default(),
+            Err(e) => {
+                log::error!("invalid cookies configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(policy))
+    })
+}
@@ -0,0 +1,262 @@
+//! Coupons merchants issue, such as 10% off, that users claim into their
+//! wallet. Their code is only shown once claimed, and redeeming a coupon
+//! spends it unless it is reusable.
+//!
+//! Coupons may be claimed ahead of their `valid_from`, but only redeemed
+//! from then. Once past `valid_until` they leave the listings and wallets,
+//! and the background jobs purge them after a while.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, post, routes, Route};
+use rocket_contrib::json::Json;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{Coupon, CouponClaim, NewCouponClaim},
+};
+use crate::requests::{invalid, ClaimResponse, CouponResponse};
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_coupons, list_claimed, claim_coupon, redeem_coupon]
+}
+
+/// Removes the claims of `user`, before the account is purged.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::coupon_claims::dsl::*;
+
+    diesel::delete(coupon_claims.filter(user_id.eq(user))).execute(c)
+}
+
+/// Removes the coupons that expired more than `retention_days` ago, along
+/// with their claims.
+pub fn purge_expired(c: &SqliteConnection, retention_days: i64) -> QueryResult<usize> {
+    use db::schema::{coupon_claims, coupons};
+
+    let cutoff = Utc::now().naive_utc() - Duration::days(retention_days);
+    c.transaction(|| {
+        let expired = coupons::table
+            .filter(coupons::valid_until.lt(cutoff))
+            .select(coupons::id)
+            .load::<i32>(c)?;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        diesel::delete(coupon_claims::table.filter(coupon_claims::coupon_id.eq_any(&expired)))
+            .execute(c)?;
+        diesel::delete(coupons::table.filter(coupons::id.eq_any(&expired))).execute(c)
+    })
+}
+
+fn claimed(coupon: Coupon, claim: CouponClaim) -> ClaimResponse {
+    ClaimResponse {
+        coupon: coupon.into(),
+        claimed_at: claim.claimed_at,
+        redeemed_at: claim.redeemed_at,
+    }
+}
+
+/// Coupons that haven't expired, including upcoming ones, the newest first,
+/// without their code.
+#[get("/coupons?<retailer_id>&<limit>&<offset>")]
+async fn list_coupons(
+    db: LoyaltyDbConn,
+    _user: LoyaltiesReader,
+    retailer_id: Option<i32>,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<CouponResponse>>, APIError> {
+    use db::schema::coupons;
+
+    let limit = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .min(MAX_LIMIT);
+    let offset = offset.and_then(|p| p.parse().ok()).unwrap_or(0);
+
+    let found = db
+        .run(move |c| {
+            let now = Utc::now().naive_utc();
+            let mut query = coupons::table
+                .filter(
+                    coupons::valid_until
+                        .is_null()
+                        .or(coupons::valid_until.gt(now)),
+                )
+                .into_boxed();
+            if let Some(retailer) = retailer_id {
+                query = query.filter(coupons::retailer_id.eq(retailer));
+            }
+
+            query
+                .order(coupons::id.desc())
+                .limit(limit)
+                .offset(offset)
+                .load::<Coupon>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|coupon| CouponResponse {
+                code: None,
+                ..coupon.into()
+            })
+            .collect(),
+    ))
+}
+
+/// The caller's wallet, the last claimed first, spent coupons included but
+/// not expired ones.
+#[get("/coupons/claimed")]
+async fn list_claimed(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<Vec<ClaimResponse>>, APIError> {
+    use db::schema::{coupon_claims, coupons};
+
+    let found = db
+        .run(move |c| {
+            let now = Utc::now().naive_utc();
+            coupon_claims::table
+                .inner_join(coupons::table)
+                .filter(coupon_claims::user_id.eq(user.0))
+                .filter(
+                    coupons::valid_until
+                        .is_null()
+                        .or(coupons::valid_until.gt(now)),
+                )
+                .order(coupon_claims::id.desc())
+                .select((coupons::all_columns, coupon_claims::all_columns))
+                .load::<(Coupon, CouponClaim)>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|(coupon, claim)| claimed(coupon, claim))
+            .collect(),
+    ))
+}
+
+/// Adds a coupon that hasn't expired to the caller's wallet, once.
+#[post("/coupons/<coupon_id>/claim")]
+async fn claim_coupon(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    coupon_id: String,
+) -> Result<status::Custom<Json<ClaimResponse>>, APIError> {
+    use db::schema::{coupon_claims, coupons};
+
+    let coupon_id: i32 = coupon_id.parse()?;
+    let claim = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let now = Utc::now().naive_utc();
+                let coupon = coupons::table
+                    .find(coupon_id)
+                    .filter(
+                        coupons::valid_until
+                            .is_null()
+                            .or(coupons::valid_until.gt(now)),
+                    )
+                    .first::<Coupon>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                let existing = coupon_claims::table
+                    .filter(coupon_claims::coupon_id.eq(coupon.id))
+                    .filter(coupon_claims::user_id.eq(user.0))
+                    .select(coupon_claims::id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if existing.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                diesel::insert_into(coupon_claims::table)
+                    .values(&NewCouponClaim {
+                        coupon_id: coupon.id,
+                        user_id: user.0,
+                    })
+                    .execute(c)?;
+                let claim = coupon_claims::table
+                    .order(coupon_claims::id.desc())
+                    .first::<CouponClaim>(c)?;
+
+                Ok(claimed(coupon, claim))
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(claim)))
+}
+
+/// Redeems a coupon of the caller's wallet at the till. A single-use coupon
+/// is spent by the first redemption, even when two race.
+#[post("/coupons/<coupon_id>/redeem")]
+async fn redeem_coupon(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    coupon_id: String,
+) -> Result<Json<ClaimResponse>, APIError> {
+    use db::schema::{coupon_claims, coupons};
+
+    let coupon_id: i32 = coupon_id.parse()?;
+    let redeemed = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let now = Utc::now().naive_utc();
+                let coupon = coupons::table
+                    .find(coupon_id)
+                    .first::<Coupon>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                let claim = coupon_claims::table
+                    .filter(coupon_claims::coupon_id.eq(coupon.id))
+                    .filter(coupon_claims::user_id.eq(user.0))
+                    .first::<CouponClaim>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if coupon.valid_from.map_or(false, |from| from > now) {
+                    return Err(invalid("valid_from", "the coupon isn't valid yet").into());
+                }
+                if coupon.valid_until.map_or(false, |until| until <= now) {
+                    return Err(invalid("valid_until", "the coupon has expired").into());
+                }
+
+                let taken = if coupon.reusable {
+                    diesel::update(coupon_claims::table.find(claim.id))
+                        .set(coupon_claims::redeemed_at.eq(now))
+                        .execute(c)?
+                } else {
+                    diesel::update(
+                        coupon_claims::table
+                            .find(claim.id)
+                            .filter(coupon_claims::redeemed_at.is_null()),
+                    )
+                    .set(coupon_claims::redeemed_at.eq(now))
+                    .execute(c)?
+                };
+                if taken == 0 {
+                    return Err(APIError::Conflict);
+                }
+
+                let claim = coupon_claims::table
+                    .find(claim.id)
+                    .first::<CouponClaim>(c)?;
+                Ok(claimed(coupon, claim))
+            })
+        })
+        .await?;
+
+    Ok(Json(redeemed))
+}
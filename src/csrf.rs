@@ -0,0 +1,60 @@
+//! Double-submit CSRF protection for cookie-authenticated requests.
+//!
+//! Every response to a client without one sets a random `csrf_token` cookie
+//! that scripts on our origin can read. Mutating requests authenticated with
+//! the session cookie must echo it in the `X-CSRF-Token` header: other sites
+//! can make the browser send the cookie, but can't read it to copy it.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Request, Response};
+
+use crate::auth::token;
+use crate::cookie_policy::CookiePolicy;
+
+pub const COOKIE: &str = "csrf_token";
+pub const HEADER: &str = "X-CSRF-Token";
+
+/// Whether a cookie-authenticated request may go through.
+pub fn verify(request: &Request<'_>) -> bool {
+    if matches!(
+        request.method(),
+        Method::Get | Method::Head | Method::Options
+    ) {
+        return true;
+    }
+
+    match (
+        request.cookies().get(COOKIE),
+        request.headers().get_one(HEADER),
+    ) {
+        (Some(cookie), Some(header)) => !header.is_empty() && cookie.value() == header,
+        _ => false,
+    }
+}
+
+pub struct Csrf;
+
+#[rocket::async_trait]
+impl Fairing for Csrf {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF Token",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if request.cookies().get(COOKIE).is_some() {
+            return;
+        }
+
+        // Scripts need to read it, so it can't be HTTP-only.
+        let mut cookie = match request.managed_state::<CookiePolicy>() {
+            Some(policy) => policy.build(COOKIE, token::generate()),
+            None => CookiePolicy::default().build(COOKIE, token::generate()),
+        };
+        cookie.set_http_only(false);
+        response.adjoin_header(cookie);
+    }
+}
@@ -0,0 +1,234 @@
+//! Column-level encryption for sensitive values such as card codes.
+//!
+//! Columns of type `Encrypted` only accept an `EncryptedString`, which is
+//! sealed with AES-256-GCM on the way in and opened on the way out, so
+//! plaintext can't be written by accident. The key comes from the
+//! `encryption` section of the configuration, usually set through the
+//! `ENCRYPTION_KEY` secret.
+//!
+//! Sealed values never compare equal, so card codes are also stored as a
+//! blind index, a keyed digest of the code, to look cards up by code.
+
+use std::io::Write;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::{Bool, Text};
+use diesel::sqlite::Sqlite;
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::Sha256;
+
+use crate::LoyaltyDbConn;
+
+/// Marks sealed values, and the key that sealed them.
+const PREFIX: &str = "enc:v1:";
+const NONCE_BYTES: usize = 12;
+
+/// Sets the blind index key apart from the encryption key it derives from.
+const INDEX_CONTEXT: &[u8] = b"loyalty-api card code index";
+
+static CIPHER: OnceCell<Aes256Gcm> = OnceCell::new();
+static INDEX_KEY: OnceCell<Vec<u8>> = OnceCell::new();
+
+#[derive(SqlType)]
+#[sqlite_type = "Text"]
+pub struct Encrypted;
+
+/// A plaintext value stored encrypted.
+#[derive(AsExpression, FromSqlRow, Clone, Debug, PartialEq)]
+#[sql_type = "Encrypted"]
+pub struct EncryptedString(pub String);
+
+impl Serialize for EncryptedString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+fn cipher() -> Result<&'static Aes256Gcm, &'static str> {
+    CIPHER.get().ok_or("encryption key not loaded")
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The blind index of a card code, `None` until the key is loaded. Codes
+/// are trimmed first, as scanners and users may add whitespace around them.
+pub fn blind_index(plaintext: &str) -> Option<String> {
+    let key = INDEX_KEY.get()?;
+    Some(hex::encode(hmac(key, plaintext.trim().as_bytes())))
+}
+
+fn seal(plaintext: &str) -> Result<String, &'static str> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let sealed = cipher()?
+        .encrypt(GenericArray::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| "encryption failed")?;
+
+    Ok(format!(
+        "{}{}{}",
+        PREFIX,
+        hex::encode(nonce),
+        hex::encode(sealed)
+    ))
+}
+
+/// Values written before encryption existed are returned as they are until
+/// `encrypt_plaintext` has rewritten them.
+fn open(stored: &str) -> Result<String, &'static str> {
+    let encoded = match stored.strip_prefix(PREFIX) {
+        Some(encoded) => encoded,
+        None => return Ok(stored.to_string()),
+    };
+
+    let bytes = hex::decode(encoded).map_err(|_| "malformed encrypted value")?;
+    if bytes.len() < NONCE_BYTES {
+        return Err("malformed encrypted value");
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_BYTES);
+
+    let plaintext = cipher()?
+        .decrypt(GenericArray::from_slice(nonce), sealed)
+        .map_err(|_| "decryption failed")?;
+
+    String::from_utf8(plaintext).map_err(|_| "decrypted value is not UTF-8")
+}
+
+impl ToSql<Encrypted, Sqlite> for EncryptedString {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        let sealed = seal(&self.0)?;
+        ToSql::<Text, Sqlite>::to_sql(&sealed, out)
+    }
+}
+
+impl FromSql<Encrypted, Sqlite> for EncryptedString {
+    fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let stored = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        Ok(EncryptedString(open(&stored)?))
+    }
+}
+
+/// Encrypts the card codes stored before encryption was enabled. Returns how
+/// many were rewritten.
+pub fn encrypt_plaintext(c: &SqliteConnection) -> QueryResult<usize> {
+    use super::schema::cards::dsl::*;
+
+    c.transaction(|| {
+        let legacy = cards
+            .filter(diesel::dsl::sql::<Bool>(&format!(
+                "code not like '{}%'",
+                PREFIX
+            )))
+            .select((id, code))
+            .load::<(i32, EncryptedString)>(c)?;
+
+        for (card, plaintext) in &legacy {
+            diesel::update(cards.find(*card))
+                .set(code.eq(plaintext))
+                .execute(c)?;
+        }
+
+        Ok(legacy.len())
+    })
+}
+
+/// Fills the blind index of the card codes stored before it existed. Returns
+/// how many were indexed.
+pub fn index_codes(c: &SqliteConnection) -> QueryResult<usize> {
+    use super::schema::cards::dsl::*;
+
+    c.transaction(|| {
+        let unindexed = cards
+            .filter(code_index.is_null())
+            .select((id, code))
+            .load::<(i32, EncryptedString)>(c)?;
+
+        for (card, plaintext) in &unindexed {
+            diesel::update(cards.find(*card))
+                .set(code_index.eq(blind_index(&plaintext.0)))
+                .execute(c)?;
+        }
+
+        Ok(unindexed.len())
+    })
+}
+
+/// The `encryption` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct EncryptionConfig {
+    /// 32 bytes, hex encoded.
+    pub key: String,
+}
+
+/// Loads the key, then encrypts leftover plaintext and indexes the codes. The
+/// server refuses to start without a valid key rather than store codes in the
+/// clear, and release builds also refuse the all-zero development key.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Column Encryption", |rocket| async move {
+        let config = match rocket
+            .figment()
+            .extract_inner::<EncryptionConfig>("encryption")
+        {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("invalid encryption configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let key = match hex::decode(config.key.trim()) {
+            Ok(key) if key.len() == 32 => key,
+            _ => {
+                log::error!("encryption key must be 32 hex-encoded bytes");
+                return Err(rocket);
+            }
+        };
+        let release = rocket.figment().profile() == rocket::Config::RELEASE_PROFILE;
+        if release && key.iter().all(|byte| *byte == 0) {
+            log::error!("the development encryption key can't be used in release");
+            return Err(rocket);
+        }
+        let _ = CIPHER.set(Aes256Gcm::new(GenericArray::from_slice(&key)));
+        let _ = INDEX_KEY.set(hmac(&key, INDEX_CONTEXT));
+
+        let conn = match LoyaltyDbConn::get_one(&rocket).await {
+            Some(conn) => conn,
+            None => return Err(rocket),
+        };
+
+        match conn.run(|c| encrypt_plaintext(c)).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("encrypted {} plaintext card code(s)", count),
+            Err(e) => {
+                log::error!("failed to encrypt card codes: {}", e);
+                return Err(rocket);
+            }
+        }
+
+        match conn.run(|c| index_codes(c)).await {
+            Ok(0) => Ok(rocket),
+            Ok(count) => {
+                log::info!("indexed {} card code(s)", count);
+                Ok(rocket)
+            }
+            Err(e) => {
+                log::error!("failed to index card codes: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -1,2 +1,3 @@
+pub mod crypto;
 pub mod models;
 pub mod schema;
@@ -1,5 +1,53 @@
+use super::crypto::EncryptedString;
+use super::schema::account_locks;
+use super::schema::api_keys;
+use super::schema::auth_events;
+use super::schema::balance_entries;
+use super::schema::campaigns;
+use super::schema::card_attachments;
+use super::schema::card_images;
+use super::schema::card_locations;
+use super::schema::card_metadata;
+use super::schema::card_reminders;
+use super::schema::card_revisions;
+use super::schema::card_search;
+use super::schema::card_shares;
+use super::schema::card_tags;
+use super::schema::card_transfers;
+use super::schema::card_uses;
 use super::schema::cards;
+use super::schema::categories;
+use super::schema::coupon_claims;
+use super::schema::coupons;
+use super::schema::devices;
+use super::schema::earning_rules;
+use super::schema::email_changes;
+use super::schema::group_invitations;
+use super::schema::group_members;
+use super::schema::groups;
+use super::schema::invites;
+use super::schema::magic_links;
+use super::schema::password_resets;
+use super::schema::point_transactions;
+use super::schema::point_transfers;
+use super::schema::pos_keys;
+use super::schema::purchases;
+use super::schema::receipts;
+use super::schema::recovery_codes;
+use super::schema::referrals;
+use super::schema::refresh_tokens;
+use super::schema::retailer_logos;
+use super::schema::retailers;
+use super::schema::rewards;
+use super::schema::sessions;
+use super::schema::share_links;
+use super::schema::stamp_rewards;
+use super::schema::user_identities;
+use super::schema::user_tiers;
 use super::schema::users;
+use super::schema::verification_tokens;
+use super::schema::webauthn_credentials;
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::Serialize;
 #[derive(Insertable)]
 #[table_name = "users"]
@@ -14,7 +62,23 @@ pub struct User {
     pub id: i32,
     pub email: String,
     pub name: String,
+    #[serde(skip_serializing)]
     pub pass: String,
+    pub email_verified: bool,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+    pub role: String,
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<NaiveDateTime>,
+    pub is_guest: bool,
+    #[serde(skip_serializing)]
+    pub locked_at: Option<NaiveDateTime>,
+    /// Given out to refer friends, made the first time it is asked for.
+    #[serde(skip_serializing)]
+    pub referral_code: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -22,8 +86,19 @@ pub struct User {
 pub struct NewLoyalty<'a> {
     pub name: &'a str,
     pub color: Option<&'a str>,
-    pub code: &'a str,
+    pub code: EncryptedString,
     pub user_id: i32,
+    pub barcode_type: &'a str,
+    pub created_at: NaiveDateTime,
+    pub position: i32,
+    pub notes: Option<EncryptedString>,
+    pub expires_at: Option<NaiveDate>,
+    pub retailer_id: Option<i32>,
+    pub balance: Option<&'a str>,
+    pub balance_currency: Option<&'a str>,
+    pub icon: Option<&'a str>,
+    pub stamps_required: Option<i32>,
+    pub code_index: Option<String>,
 }
 
 #[derive(Identifiable, Serialize, Queryable)]
@@ -32,12 +107,858 @@ pub struct Loyalty {
     pub id: i32,
     pub name: String,
     pub color: Option<String>,
+    pub code: EncryptedString,
+    pub user_id: i32,
+    pub barcode_type: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub is_favorite: bool,
+    pub position: i32,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub archived_at: Option<NaiveDateTime>,
+    pub notes: Option<EncryptedString>,
+    pub expires_at: Option<NaiveDate>,
+    pub group_id: Option<i32>,
+    pub use_count: i32,
+    pub retailer_id: Option<i32>,
+    pub balance: Option<String>,
+    pub balance_currency: Option<String>,
+    pub is_active: bool,
+    pub icon: Option<String>,
+    /// Stamps that complete the card, `None` for cards that aren't stamp
+    /// cards.
+    pub stamps_required: Option<i32>,
+    pub stamps: i32,
+    /// The blind index of `code`, see `crypto::blind_index`.
+    #[serde(skip_serializing)]
+    pub code_index: Option<String>,
+}
+
+/// The balance of a card after an edit by `user_id`; `None` when it was
+/// cleared.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "balance_entries"]
+pub struct BalanceEntry {
+    pub id: i32,
+    pub card_id: i32,
+    pub user_id: i32,
+    pub balance: Option<String>,
+    pub balance_currency: Option<String>,
+    pub recorded_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "balance_entries"]
+pub struct NewBalanceEntry<'a> {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub balance: Option<&'a str>,
+    pub balance_currency: Option<&'a str>,
+}
+
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "card_attachments"]
+pub struct CardAttachment {
+    pub id: i32,
+    pub card_id: i32,
+    pub file_name: String,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub size: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_attachments"]
+pub struct NewCardAttachment<'a> {
+    pub card_id: i32,
+    pub file_name: &'a str,
+    pub storage_key: &'a str,
+    pub content_type: &'a str,
+    pub size: i32,
+}
+
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "card_locations"]
+pub struct CardLocation {
+    pub id: i32,
+    pub card_id: i32,
+    pub label: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_locations"]
+pub struct NewCardLocation<'a> {
+    pub card_id: i32,
+    pub label: &'a str,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_images"]
+pub struct NewCardImage<'a> {
+    pub card_id: i32,
+    pub side: &'a str,
+    pub storage_key: &'a str,
+    pub content_type: &'a str,
+    pub size: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "categories"]
+pub struct NewCategory<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+}
+
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "categories"]
+pub struct Category {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_shares"]
+pub struct NewCardShare<'a> {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub access: &'a str,
+}
+
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "card_shares"]
+pub struct CardShare {
+    pub id: i32,
+    pub card_id: i32,
+    pub user_id: i32,
+    pub access: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// The search index entry of card `rowid`.
+#[derive(Insertable)]
+#[table_name = "card_search"]
+pub struct NewCardSearch<'a> {
+    pub rowid: i32,
+    pub name: &'a str,
+    pub fields: String,
+    pub tokens: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_tags"]
+pub struct NewCardTag {
+    pub card_id: i32,
+    pub category_id: i32,
+}
+
+#[derive(Identifiable, Queryable)]
+#[table_name = "retailers"]
+pub struct Retailer {
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+    pub barcode_type: String,
+    pub logo_url: Option<String>,
+}
+
+/// An item of a retailer that its cards can be redeemed for. `stock` is
+/// `None` for items that don't run out.
+#[derive(Identifiable, Queryable)]
+#[table_name = "rewards"]
+pub struct Reward {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    pub cost: i32,
+    pub stock: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "rewards"]
+pub struct NewReward<'a> {
+    pub retailer_id: i32,
+    pub name: &'a str,
+    pub cost: i32,
+    pub stock: Option<i32>,
+}
+
+/// A promotion of a retailer, such as double points on weekends, applied to
+/// the points earned between `starts_at` and `ends_at` on `weekdays`.
+#[derive(Identifiable, Queryable)]
+#[table_name = "campaigns"]
+pub struct Campaign {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    /// `200` doubles points.
+    pub multiplier_percent: i32,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    /// Such as `sat,sun`, `None` for every day.
+    pub weekdays: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "campaigns"]
+pub struct NewCampaign<'a> {
+    pub retailer_id: i32,
+    pub name: &'a str,
+    pub multiplier_percent: i32,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    pub weekdays: Option<&'a str>,
+}
+
+/// An offer of a retailer, such as 10% off, claimed into wallets. Its code
+/// is shown at the till, between `valid_from` and `valid_until` when set.
+#[derive(Identifiable, Queryable)]
+#[table_name = "coupons"]
+pub struct Coupon {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub title: String,
     pub code: String,
+    pub reusable: bool,
+    pub valid_until: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub valid_from: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "coupons"]
+pub struct NewCoupon<'a> {
+    pub retailer_id: i32,
+    pub title: &'a str,
+    pub code: &'a str,
+    pub reusable: bool,
+    pub valid_from: Option<NaiveDateTime>,
+    pub valid_until: Option<NaiveDateTime>,
+}
+
+/// A coupon in the wallet of a user, spent once redeemed unless it is
+/// reusable.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "coupon_claims"]
+pub struct CouponClaim {
+    pub id: i32,
+    pub coupon_id: i32,
     pub user_id: i32,
+    pub claimed_at: NaiveDateTime,
+    pub redeemed_at: Option<NaiveDateTime>,
 }
 
-pub struct LoyaltyUpdate<'a> {
+#[derive(Insertable)]
+#[table_name = "coupon_claims"]
+pub struct NewCouponClaim {
+    pub coupon_id: i32,
+    pub user_id: i32,
+}
+
+/// A reward a stamp card earned when completed, spent once redeemed.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "stamp_rewards"]
+pub struct StampReward {
+    pub id: i32,
+    pub card_id: i32,
+    pub issued_at: NaiveDateTime,
+    pub redeemed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "stamp_rewards"]
+pub struct NewStampReward {
+    pub card_id: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "retailer_logos"]
+pub struct NewRetailerLogo<'a> {
+    pub name: &'a str,
+    pub logo_url: Option<&'a str>,
+    pub fetched_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "share_links"]
+pub struct NewShareLink<'a> {
+    pub card_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Identifiable, Queryable)]
+#[table_name = "share_links"]
+pub struct ShareLink {
+    pub id: i32,
+    pub card_id: i32,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub view_count: i32,
+    pub last_viewed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "groups"]
+pub struct NewGroup<'a> {
+    pub name: &'a str,
+    pub owner_id: i32,
+}
+
+#[derive(Identifiable, Queryable)]
+#[table_name = "groups"]
+pub struct Group {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "group_members"]
+pub struct NewGroupMember<'a> {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub role: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "group_invitations"]
+pub struct NewGroupInvitation<'a> {
+    pub group_id: i32,
+    pub email: &'a str,
+    pub token_hash: &'a str,
+    pub invited_by: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_metadata"]
+pub struct NewCardMetadata<'a> {
+    pub card_id: i32,
+    pub key: &'a str,
+    pub value: EncryptedString,
+}
+
+/// A reminder `user_id` set on a card, mailed at `remind_at`.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "card_reminders"]
+pub struct CardReminder {
+    pub id: i32,
+    pub card_id: i32,
+    pub user_id: i32,
+    pub message: EncryptedString,
+    pub remind_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_reminders"]
+pub struct NewCardReminder {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub message: EncryptedString,
+    pub remind_at: NaiveDateTime,
+}
+
+/// The editable fields of a card before an edit by `user_id`.
+#[derive(Identifiable, Queryable)]
+#[table_name = "card_revisions"]
+pub struct CardRevision {
+    pub id: i32,
+    pub card_id: i32,
+    pub user_id: i32,
+    pub changed_at: NaiveDateTime,
+    pub name: String,
+    pub color: Option<String>,
+    pub code: EncryptedString,
+    pub barcode_type: String,
+    pub notes: Option<EncryptedString>,
+    pub expires_at: Option<NaiveDate>,
+    pub retailer_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_revisions"]
+pub struct NewCardRevision<'a> {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub changed_at: NaiveDateTime,
     pub name: &'a str,
     pub color: Option<&'a str>,
-    pub code: &'a str,
+    pub code: EncryptedString,
+    pub barcode_type: &'a str,
+    pub notes: Option<EncryptedString>,
+    pub expires_at: Option<NaiveDate>,
+    pub retailer_id: Option<i32>,
+}
+
+/// A card offered by `from_user_id` to `to_user_id`, who has to accept it.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "card_transfers"]
+pub struct CardTransfer {
+    pub id: i32,
+    pub card_id: i32,
+    pub from_user_id: i32,
+    pub to_user_id: i32,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_transfers"]
+pub struct NewCardTransfer {
+    pub card_id: i32,
+    pub from_user_id: i32,
+    pub to_user_id: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "card_uses"]
+pub struct NewCardUse {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub used_at: NaiveDateTime,
+}
+
+/// Points earned (`delta > 0`) or spent on a card. Only `remaining` is ever
+/// updated.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "point_transactions"]
+pub struct PointTransaction {
+    pub id: i32,
+    pub card_id: i32,
+    pub delta: i32,
+    pub reason: String,
+    pub occurred_at: NaiveDateTime,
+    /// What is left of the points earned by this entry, which expire with
+    /// it; always 0 on entries spending points.
+    pub remaining: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "point_transactions"]
+pub struct NewPointTransaction<'a> {
+    pub card_id: i32,
+    pub delta: i32,
+    pub reason: &'a str,
+    pub occurred_at: NaiveDateTime,
+    pub remaining: i32,
+}
+
+/// The tier of a user in the program of a retailer, from the points earned
+/// on their cards of that retailer.
+#[derive(Queryable, Serialize)]
+pub struct UserTier {
+    pub user_id: i32,
+    pub retailer_id: i32,
+    pub tier: String,
+    pub lifetime_points: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_tiers"]
+pub struct NewUserTier<'a> {
+    pub user_id: i32,
+    pub retailer_id: i32,
+    pub tier: &'a str,
+    pub lifetime_points: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A partial update of a card: `None` fields are left as they are.
+#[derive(AsChangeset)]
+#[table_name = "cards"]
+pub struct LoyaltyUpdate<'a> {
+    pub name: Option<&'a str>,
+    pub color: Option<&'a str>,
+    pub code: Option<EncryptedString>,
+    pub barcode_type: Option<&'a str>,
+    /// `Some(None)` clears the notes.
+    pub notes: Option<Option<EncryptedString>>,
+    pub expires_at: Option<Option<NaiveDate>>,
+    pub balance: Option<Option<&'a str>>,
+    pub balance_currency: Option<Option<&'a str>>,
+    pub icon: Option<Option<&'a str>>,
+    pub stamps_required: Option<Option<i32>>,
+    /// Set along with `code`.
+    pub code_index: Option<Option<String>>,
+}
+
+/// Points `from_user_id` gave `to_user_id`, debited by ledger entry
+/// `debit_id` and credited by `credit_id`. A side is cleared once its card or
+/// account is deleted, leaving the other side its record.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "point_transfers"]
+pub struct PointTransfer {
+    pub id: i32,
+    pub from_user_id: Option<i32>,
+    pub to_user_id: Option<i32>,
+    pub from_card_id: Option<i32>,
+    pub to_card_id: Option<i32>,
+    pub points: i32,
+    pub debit_id: Option<i32>,
+    pub credit_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "point_transfers"]
+pub struct NewPointTransfer {
+    pub from_user_id: i32,
+    pub to_user_id: i32,
+    pub from_card_id: i32,
+    pub to_card_id: i32,
+    pub points: i32,
+    pub debit_id: i32,
+    pub credit_id: i32,
+}
+
+/// How the customers of a retailer earn points, by `kind`: `points` per
+/// `per_cents` spent, per unit of `sku` bought, or once per visit.
+#[derive(Identifiable, Queryable)]
+#[table_name = "earning_rules"]
+pub struct EarningRule {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub kind: String,
+    pub points: i32,
+    pub per_cents: Option<i32>,
+    pub sku: Option<String>,
+    /// Purchases of less are ignored by the rule.
+    pub min_cents: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "earning_rules"]
+pub struct NewEarningRule<'a> {
+    pub retailer_id: i32,
+    pub kind: &'a str,
+    pub points: i32,
+    pub per_cents: Option<i32>,
+    pub sku: Option<&'a str>,
+    pub min_cents: Option<i32>,
+}
+
+/// A key the points of sale of a retailer post purchases with, stored as a
+/// digest.
+#[derive(Identifiable, Queryable)]
+#[table_name = "pos_keys"]
+pub struct PosKey {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "pos_keys"]
+pub struct NewPosKey<'a> {
+    pub retailer_id: i32,
+    pub name: &'a str,
+    pub prefix: &'a str,
+    pub key_hash: &'a str,
+}
+
+/// A sale a point of sale posted for a card, with the points it earned.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "purchases"]
+pub struct Purchase {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub card_id: i32,
+    pub reference: String,
+    pub amount_cents: i32,
+    pub points: i32,
+    pub occurred_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "purchases"]
+pub struct NewPurchase<'a> {
+    pub retailer_id: i32,
+    pub card_id: i32,
+    pub reference: &'a str,
+    pub amount_cents: i32,
+    pub points: i32,
+    pub occurred_at: NaiveDateTime,
+}
+
+/// A receipt photo uploaded by `user_id` to earn points on a card, waiting
+/// for review while `status` is `pending`.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "receipts"]
+pub struct Receipt {
+    pub id: i32,
+    pub card_id: i32,
+    pub user_id: i32,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub status: String,
+    pub total: Option<String>,
+    pub suggested_points: Option<i32>,
+    pub ocr_at: Option<NaiveDateTime>,
+    pub points: Option<i32>,
+    pub reviewed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "receipts"]
+pub struct NewReceipt<'a> {
+    pub card_id: i32,
+    pub user_id: i32,
+    pub storage_key: &'a str,
+    pub content_type: &'a str,
+}
+
+/// `referred_id` signed up with the referral code of `referrer_id`.
+#[derive(Identifiable, Queryable, Serialize)]
+#[table_name = "referrals"]
+pub struct Referral {
+    pub id: i32,
+    pub referrer_id: i32,
+    pub referred_id: i32,
+    pub created_at: NaiveDateTime,
+    pub referred_credited_at: Option<NaiveDateTime>,
+    pub referrer_credited_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "referrals"]
+pub struct NewReferral {
+    pub referrer_id: i32,
+    pub referred_id: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+pub struct NewRefreshToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub family: &'a str,
+    pub expires_at: NaiveDateTime,
+    pub session_id: Option<i32>,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub family: String,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub session_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "verification_tokens"]
+pub struct NewVerificationToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "password_resets"]
+pub struct NewPasswordReset<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "magic_links"]
+pub struct NewMagicLink<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "email_changes"]
+pub struct NewEmailChange<'a> {
+    pub user_id: i32,
+    pub new_email: &'a str,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_identities"]
+pub struct NewUserIdentity<'a> {
+    pub user_id: i32,
+    pub provider: &'a str,
+    pub subject: &'a str,
+    pub email: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub prefix: &'a str,
+    pub key_hash: &'a str,
+    pub scopes: &'a str,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub scopes: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code_hash: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+pub struct NewSession<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub device_id: Option<i32>,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub device_id: Option<i32>,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "devices"]
+pub struct NewDevice<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct Device {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "auth_events"]
+pub struct NewAuthEvent<'a> {
+    pub user_id: i32,
+    pub kind: &'a str,
+    pub ip: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub country: Option<&'a str>,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct AuthEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub country: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "webauthn_credentials"]
+pub struct NewWebauthnCredential<'a> {
+    pub user_id: i32,
+    pub credential_id: &'a str,
+    pub credential: &'a str,
+    pub name: &'a str,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub credential: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "invites"]
+pub struct NewInvite<'a> {
+    pub code_hash: &'a str,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct Invite {
+    pub id: i32,
+    pub code_hash: String,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "account_locks"]
+pub struct NewAccountLock<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
 }
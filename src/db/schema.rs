@@ -1,10 +1,533 @@
 table! {
+    account_locks (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    api_keys (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        prefix -> Text,
+        key_hash -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        scopes -> Text,
+    }
+}
+
+table! {
+    auth_events (id) {
+        id -> Integer,
+        user_id -> Integer,
+        kind -> Text,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamp,
+        country -> Nullable<Text>,
+    }
+}
+
+table! {
+    balance_entries (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        balance -> Nullable<Text>,
+        balance_currency -> Nullable<Text>,
+        recorded_at -> Timestamp,
+    }
+}
+
+table! {
+    campaigns (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        name -> Text,
+        multiplier_percent -> Integer,
+        starts_at -> Timestamp,
+        ends_at -> Timestamp,
+        weekdays -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    card_attachments (id) {
+        id -> Integer,
+        card_id -> Integer,
+        file_name -> Text,
+        storage_key -> Text,
+        content_type -> Text,
+        size -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    card_images (id) {
+        id -> Integer,
+        card_id -> Integer,
+        side -> Text,
+        storage_key -> Text,
+        content_type -> Text,
+        size -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    card_locations (id) {
+        id -> Integer,
+        card_id -> Integer,
+        label -> Text,
+        latitude -> Double,
+        longitude -> Double,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::crypto::Encrypted;
+
+    card_metadata (card_id, key) {
+        card_id -> Integer,
+        key -> Text,
+        value -> Encrypted,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::crypto::Encrypted;
+
+    card_reminders (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        message -> Encrypted,
+        remind_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::crypto::Encrypted;
+
+    card_revisions (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        changed_at -> Timestamp,
+        name -> Text,
+        color -> Nullable<Text>,
+        code -> Encrypted,
+        barcode_type -> Text,
+        notes -> Nullable<Encrypted>,
+        expires_at -> Nullable<Date>,
+        retailer_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    card_search (rowid) {
+        rowid -> Integer,
+        name -> Text,
+        fields -> Text,
+        tokens -> Text,
+    }
+}
+
+table! {
+    card_shares (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        access -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    card_tags (card_id, category_id) {
+        card_id -> Integer,
+        category_id -> Integer,
+    }
+}
+
+table! {
+    card_transfers (id) {
+        id -> Integer,
+        card_id -> Integer,
+        from_user_id -> Integer,
+        to_user_id -> Integer,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    card_uses (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        used_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::crypto::Encrypted;
+
     cards (id) {
         id -> Integer,
         name -> Text,
         color -> Nullable<Text>,
+        code -> Encrypted,
+        user_id -> Integer,
+        barcode_type -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        is_favorite -> Bool,
+        position -> Integer,
+        deleted_at -> Nullable<Timestamp>,
+        archived_at -> Nullable<Timestamp>,
+        notes -> Nullable<Encrypted>,
+        expires_at -> Nullable<Date>,
+        group_id -> Nullable<Integer>,
+        use_count -> Integer,
+        retailer_id -> Nullable<Integer>,
+        balance -> Nullable<Text>,
+        balance_currency -> Nullable<Text>,
+        is_active -> Bool,
+        icon -> Nullable<Text>,
+        stamps_required -> Nullable<Integer>,
+        stamps -> Integer,
+        code_index -> Nullable<Text>,
+    }
+}
+
+table! {
+    categories (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    coupon_claims (id) {
+        id -> Integer,
+        coupon_id -> Integer,
+        user_id -> Integer,
+        claimed_at -> Timestamp,
+        redeemed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    coupons (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        title -> Text,
         code -> Text,
+        reusable -> Bool,
+        valid_until -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        valid_from -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    devices (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    earning_rules (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        kind -> Text,
+        points -> Integer,
+        per_cents -> Nullable<Integer>,
+        sku -> Nullable<Text>,
+        min_cents -> Nullable<Integer>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    email_changes (id) {
+        id -> Integer,
         user_id -> Integer,
+        new_email -> Text,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    group_invitations (id) {
+        id -> Integer,
+        group_id -> Integer,
+        email -> Text,
+        token_hash -> Text,
+        invited_by -> Integer,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    group_members (group_id, user_id) {
+        group_id -> Integer,
+        user_id -> Integer,
+        role -> Text,
+        joined_at -> Timestamp,
+    }
+}
+
+table! {
+    groups (id) {
+        id -> Integer,
+        name -> Text,
+        owner_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    invites (id) {
+        id -> Integer,
+        code_hash -> Text,
+        created_by -> Integer,
+        max_uses -> Integer,
+        uses -> Integer,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    magic_links (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    point_transactions (id) {
+        id -> Integer,
+        card_id -> Integer,
+        delta -> Integer,
+        reason -> Text,
+        occurred_at -> Timestamp,
+        remaining -> Integer,
+    }
+}
+
+table! {
+    point_transfers (id) {
+        id -> Integer,
+        from_user_id -> Nullable<Integer>,
+        to_user_id -> Nullable<Integer>,
+        from_card_id -> Nullable<Integer>,
+        to_card_id -> Nullable<Integer>,
+        points -> Integer,
+        debit_id -> Nullable<Integer>,
+        credit_id -> Nullable<Integer>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    pos_keys (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        name -> Text,
+        prefix -> Text,
+        key_hash -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    purchases (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        card_id -> Integer,
+        reference -> Text,
+        amount_cents -> Integer,
+        points -> Integer,
+        occurred_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    receipts (id) {
+        id -> Integer,
+        card_id -> Integer,
+        user_id -> Integer,
+        storage_key -> Text,
+        content_type -> Text,
+        status -> Text,
+        total -> Nullable<Text>,
+        suggested_points -> Nullable<Integer>,
+        ocr_at -> Nullable<Timestamp>,
+        points -> Nullable<Integer>,
+        reviewed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    referrals (id) {
+        id -> Integer,
+        referrer_id -> Integer,
+        referred_id -> Integer,
+        created_at -> Timestamp,
+        referred_credited_at -> Nullable<Timestamp>,
+        referrer_credited_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        family -> Text,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        session_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    retailer_logos (name) {
+        name -> Text,
+        logo_url -> Nullable<Text>,
+        fetched_at -> Timestamp,
+    }
+}
+
+table! {
+    retailers (id) {
+        id -> Integer,
+        name -> Text,
+        color -> Nullable<Text>,
+        barcode_type -> Text,
+        logo_url -> Nullable<Text>,
+    }
+}
+
+table! {
+    rewards (id) {
+        id -> Integer,
+        retailer_id -> Integer,
+        name -> Text,
+        cost -> Integer,
+        stock -> Nullable<Integer>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        device_id -> Nullable<Integer>,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    share_links (id) {
+        id -> Integer,
+        card_id -> Integer,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        view_count -> Integer,
+        last_viewed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    stamp_rewards (id) {
+        id -> Integer,
+        card_id -> Integer,
+        issued_at -> Timestamp,
+        redeemed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Integer,
+        user_id -> Integer,
+        provider -> Text,
+        subject -> Text,
+        email -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_tiers (user_id, retailer_id) {
+        user_id -> Integer,
+        retailer_id -> Integer,
+        tier -> Text,
+        lifetime_points -> BigInt,
+        updated_at -> Timestamp,
     }
 }
 
@@ -14,12 +537,145 @@ table! {
         email -> Text,
         name -> Text,
         pass -> Text,
+        email_verified -> Bool,
+        totp_secret -> Nullable<Text>,
+        totp_enabled -> Bool,
+        totp_last_step -> Nullable<BigInt>,
+        role -> Text,
+        deleted_at -> Nullable<Timestamp>,
+        is_guest -> Bool,
+        locked_at -> Nullable<Timestamp>,
+        referral_code -> Nullable<Text>,
+    }
+}
+
+table! {
+    verification_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    webauthn_credentials (id) {
+        id -> Integer,
+        user_id -> Integer,
+        credential_id -> Text,
+        credential -> Text,
+        name -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
     }
 }
 
+joinable!(account_locks -> users (user_id));
+joinable!(api_keys -> users (user_id));
+joinable!(auth_events -> users (user_id));
+joinable!(balance_entries -> cards (card_id));
+joinable!(balance_entries -> users (user_id));
+joinable!(campaigns -> retailers (retailer_id));
+joinable!(card_attachments -> cards (card_id));
+joinable!(card_images -> cards (card_id));
+joinable!(card_locations -> cards (card_id));
+joinable!(card_metadata -> cards (card_id));
+joinable!(card_reminders -> cards (card_id));
+joinable!(card_reminders -> users (user_id));
+joinable!(card_revisions -> cards (card_id));
+joinable!(card_revisions -> retailers (retailer_id));
+joinable!(card_revisions -> users (user_id));
+joinable!(card_shares -> cards (card_id));
+joinable!(card_shares -> users (user_id));
+joinable!(card_tags -> cards (card_id));
+joinable!(card_tags -> categories (category_id));
+joinable!(card_transfers -> cards (card_id));
+joinable!(card_uses -> cards (card_id));
+joinable!(card_uses -> users (user_id));
+joinable!(cards -> groups (group_id));
+joinable!(cards -> retailers (retailer_id));
 joinable!(cards -> users (user_id));
+joinable!(categories -> users (user_id));
+joinable!(coupon_claims -> coupons (coupon_id));
+joinable!(coupon_claims -> users (user_id));
+joinable!(coupons -> retailers (retailer_id));
+joinable!(devices -> users (user_id));
+joinable!(earning_rules -> retailers (retailer_id));
+joinable!(email_changes -> users (user_id));
+joinable!(group_invitations -> groups (group_id));
+joinable!(group_members -> groups (group_id));
+joinable!(group_members -> users (user_id));
+joinable!(groups -> users (owner_id));
+joinable!(invites -> users (created_by));
+joinable!(magic_links -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(point_transactions -> cards (card_id));
+joinable!(pos_keys -> retailers (retailer_id));
+joinable!(purchases -> cards (card_id));
+joinable!(purchases -> retailers (retailer_id));
+joinable!(receipts -> cards (card_id));
+joinable!(receipts -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(refresh_tokens -> sessions (session_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(rewards -> retailers (retailer_id));
+joinable!(sessions -> devices (device_id));
+joinable!(sessions -> users (user_id));
+joinable!(share_links -> cards (card_id));
+joinable!(stamp_rewards -> cards (card_id));
+joinable!(user_identities -> users (user_id));
+joinable!(user_tiers -> retailers (retailer_id));
+joinable!(user_tiers -> users (user_id));
+joinable!(verification_tokens -> users (user_id));
+joinable!(webauthn_credentials -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    account_locks,
+    api_keys,
+    auth_events,
+    balance_entries,
+    campaigns,
+    card_attachments,
+    card_images,
+    card_locations,
+    card_metadata,
+    card_reminders,
+    card_revisions,
+    card_search,
+    card_shares,
+    card_tags,
+    card_transfers,
+    card_uses,
     cards,
+    categories,
+    coupon_claims,
+    coupons,
+    devices,
+    earning_rules,
+    email_changes,
+    group_invitations,
+    group_members,
+    groups,
+    invites,
+    magic_links,
+    password_resets,
+    point_transactions,
+    point_transfers,
+    pos_keys,
+    purchases,
+    receipts,
+    recovery_codes,
+    referrals,
+    refresh_tokens,
+    retailer_logos,
+    retailers,
+    rewards,
+    sessions,
+    share_links,
+    stamp_rewards,
+    user_identities,
+    user_tiers,
     users,
+    verification_tokens,
+    webauthn_credentials,
 );
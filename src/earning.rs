@@ -0,0 +1,114 @@
+//! The rules merchants earn points by, which administrators keep for them:
+//! points per amount spent, per unit of an item bought, or per visit. Every
+//! rule of the retailer matching a purchase adds up; campaigns then multiply
+//! the sum.
+
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+use crate::auth::LoyaltiesReader;
+use crate::db::{self, models::EarningRule};
+use crate::requests::{EarningRuleResponse, PurchaseItem};
+use crate::{APIError, LoyaltyDbConn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    Amount,
+    Item,
+    Visit,
+}
+
+impl RuleKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "amount" => Some(RuleKind::Amount),
+            "item" => Some(RuleKind::Item),
+            "visit" => Some(RuleKind::Visit),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RuleKind::Amount => "amount",
+            RuleKind::Item => "item",
+            RuleKind::Visit => "visit",
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_rules]
+}
+
+/// The points `rule` awards for a purchase of `amount_cents` with `items`.
+fn earned_by(rule: &EarningRule, amount_cents: i32, items: &[PurchaseItem]) -> i64 {
+    if rule.min_cents.map_or(false, |min| amount_cents < min) {
+        return 0;
+    }
+
+    let points = i64::from(rule.points);
+    match RuleKind::from_name(&rule.kind) {
+        Some(RuleKind::Amount) => match rule.per_cents {
+            Some(per) if per > 0 => i64::from(amount_cents / per) * points,
+            _ => 0,
+        },
+        Some(RuleKind::Item) => {
+            let bought: i64 = items
+                .iter()
+                .filter(|item| Some(item.sku.as_str()) == rule.sku.as_deref())
+                .map(|item| i64::from(item.quantity))
+                .sum();
+            bought * points
+        }
+        Some(RuleKind::Visit) => points,
+        None => 0,
+    }
+}
+
+/// The points the rules of `retailer` award for a purchase of
+/// `amount_cents` with `items`, before campaigns.
+pub fn points_for(
+    c: &SqliteConnection,
+    retailer: i32,
+    amount_cents: i32,
+    items: &[PurchaseItem],
+) -> QueryResult<i32> {
+    use db::schema::earning_rules::dsl::*;
+
+    let rules = earning_rules
+        .filter(retailer_id.eq(retailer))
+        .load::<EarningRule>(c)?;
+    let total: i64 = rules
+        .iter()
+        .map(|rule| earned_by(rule, amount_cents, items))
+        .sum();
+
+    Ok(total.min(i64::from(i32::MAX)) as i32)
+}
+
+/// The earning rules of a retailer, or of every retailer.
+#[get("/earning-rules?<retailer_id>")]
+async fn list_rules(
+    db: LoyaltyDbConn,
+    _user: LoyaltiesReader,
+    retailer_id: Option<i32>,
+) -> Result<Json<Vec<EarningRuleResponse>>, APIError> {
+    use db::schema::earning_rules;
+
+    let found = db
+        .run(move |c| {
+            let mut query = earning_rules::table.into_boxed();
+            if let Some(retailer) = retailer_id {
+                query = query.filter(earning_rules::retailer_id.eq(retailer));
+            }
+
+            query.order(earning_rules::id.asc()).load::<EarningRule>(c)
+        })
+        .await?;
+
+    Ok(Json(found.into_iter().map(Into::into).collect()))
+}
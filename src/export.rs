@@ -0,0 +1,103 @@
+//! Export of every card of the user, archived ones included, to back up or
+//! move a wallet. The CSV columns start with those `POST /loyalties/import`
+//! reads, so an export can be imported back.
+
+use csv::Writer;
+use rocket::http::ContentType;
+use rocket::{get, routes, Route, State};
+
+use crate::auth::LoyaltiesReader;
+use crate::cards::CardFilter;
+use crate::requests::{invalid, AddLoyaltyResponse};
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+pub fn routes() -> Vec<Route> {
+    routes![export_cards]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+fn to_csv(cards: &[AddLoyaltyResponse]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = Writer::from_writer(Vec::new());
+    writer.write_record(&[
+        "name",
+        "code",
+        "color",
+        "barcode_type",
+        "notes",
+        "expires_at",
+        "tags",
+    ])?;
+
+    for card in cards {
+        writer.write_record(&[
+            card.name.clone(),
+            card.code.clone(),
+            card.color.clone().unwrap_or_default(),
+            card.barcode_type.clone(),
+            card.notes.clone().unwrap_or_default(),
+            card.expires_at
+                .map(|date| date.to_string())
+                .unwrap_or_default(),
+            card.tags.join(";"),
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))
+}
+
+#[get("/loyalties/export?<format>")]
+async fn export_cards(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    format: Option<String>,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    let format = match format.as_deref() {
+        None => Format::Json,
+        Some(name) => {
+            Format::from_name(name).ok_or_else(|| invalid("format", "format is csv or json"))?
+        }
+    };
+
+    let storage = storage.inner().clone();
+    let filter = CardFilter {
+        include_archived: true,
+        include_inactive: true,
+        ..CardFilter::default()
+    };
+    let cards = db
+        .run(move |c| {
+            let found = filter.query(user.0).load(c)?;
+            crate::cards::describe(c, &storage, found)
+        })
+        .await?;
+
+    match format {
+        Format::Json => {
+            let body = serde_json::to_vec(&cards).map_err(|_| APIError::Unknown)?;
+            Ok((ContentType::JSON, body))
+        }
+        Format::Csv => {
+            let body = to_csv(&cards).map_err(|_| APIError::Unknown)?;
+            Ok((ContentType::CSV, body))
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Country lookup of client addresses, from a MaxMind GeoLite2 or GeoIP2
+//! Country database. Without a database configured every lookup comes back
+//! empty.
+
+use std::net::IpAddr;
+
+use maxminddb::{geoip2, Reader};
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+/// The `geoip` section of the Rocket configuration.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// Path to a `.mmdb` country database.
+    pub database: Option<String>,
+}
+
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// The ISO 3166-1 code of the country `ip` is located in, when known.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let found = reader.lookup::<geoip2::Country>(ip).ok()?;
+
+        found
+            .country
+            .and_then(|country| country.iso_code)
+            .map(String::from)
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("GeoIP", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<GeoIpConfig>("geoip") {
+            Ok(config) => config,
+            Err(e) if e.missing() => GeoIpConfig::default(),
+            Err(e) => {
+                log::error!("invalid geoip configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let reader = match config.database {
+            Some(path) => match Reader::open_readfile(&path) {
+                Ok(reader) => Some(reader),
+                Err(e) => {
+                    log::error!("could not open the geoip database {}: {}", path, e);
+                    return Err(rocket);
+                }
+            },
+            None => None,
+        };
+
+        Ok(rocket.manage(GeoIp { reader }))
+    })
+}
@@ -0,0 +1,477 @@
+//! Family groups sharing a wallet. Members attach their own cards to a
+//! group, and every member then sees them in `GET /loyalties`. Members join
+//! through an emailed invitation; the owner manages the group.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, put, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::{token, LoyaltiesReader, LoyaltiesWriter, VerifiedUser};
+use crate::db::models::{NewGroupInvitation, NewGroupMember};
+use crate::db::{self, models::Group, models::NewGroup};
+use crate::mail::Mailer;
+use crate::requests::{
+    AddLoyaltyResponse, CreateGroup, GroupMemberResponse, GroupResponse, InviteToGroup,
+    SetCardGroup,
+};
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const INVITATION_TTL_DAYS: i64 = 7;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_groups,
+        create_group,
+        get_group,
+        delete_group,
+        invite,
+        join,
+        remove_member,
+        set_card_group
+    ]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupRole {
+    Owner,
+    Member,
+}
+
+impl GroupRole {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "owner" => Some(GroupRole::Owner),
+            "member" => Some(GroupRole::Member),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupRole::Owner => "owner",
+            GroupRole::Member => "member",
+        }
+    }
+}
+
+/// Cards in use attached to the groups of `user`, its own included.
+pub fn card_ids(c: &SqliteConnection, user: i32) -> QueryResult<Vec<i32>> {
+    use db::schema::{cards, group_members};
+
+    cards::table
+        .inner_join(group_members::table.on(cards::group_id.eq(group_members::group_id.nullable())))
+        .filter(group_members::user_id.eq(user))
+        .filter(cards::deleted_at.is_null())
+        .select(cards::id)
+        .load(c)
+}
+
+fn role_of(c: &SqliteConnection, group: i32, user: i32) -> QueryResult<Option<GroupRole>> {
+    use db::schema::group_members::dsl::*;
+
+    let found = group_members
+        .filter(group_id.eq(group).and(user_id.eq(user)))
+        .select(role)
+        .first::<String>(c)
+        .optional()?;
+
+    Ok(found.and_then(|name| GroupRole::from_name(&name)))
+}
+
+fn describe(c: &SqliteConnection, group: Group) -> QueryResult<GroupResponse> {
+    use db::schema::{group_members, users};
+
+    let members = group_members::table
+        .inner_join(users::table)
+        .filter(group_members::group_id.eq(group.id))
+        .order(group_members::joined_at.asc())
+        .select((
+            group_members::user_id,
+            users::name,
+            group_members::role,
+            group_members::joined_at,
+        ))
+        .load::<(i32, String, String, chrono::NaiveDateTime)>(c)?;
+
+    Ok(GroupResponse {
+        id: group.id,
+        name: group.name,
+        owner_id: group.owner_id,
+        created_at: group.created_at,
+        members: members
+            .into_iter()
+            .map(|(user_id, name, role, joined_at)| GroupMemberResponse {
+                user_id,
+                name,
+                role,
+                joined_at,
+            })
+            .collect(),
+    })
+}
+
+/// Takes the cards of `user` out of `group`, once it left.
+fn detach_cards(c: &SqliteConnection, group: i32, user: i32) -> QueryResult<usize> {
+    use db::schema::cards::dsl::*;
+
+    diesel::update(cards.filter(group_id.eq(group)).filter(user_id.eq(user)))
+        .set(group_id.eq(None::<i32>))
+        .execute(c)
+}
+
+/// Deletes `group`, keeping the cards that were attached to it.
+fn dissolve(c: &SqliteConnection, group: i32) -> QueryResult<()> {
+    use db::schema::{cards, group_invitations, group_members, groups};
+
+    diesel::update(cards::table.filter(cards::group_id.eq(group)))
+        .set(cards::group_id.eq(None::<i32>))
+        .execute(c)?;
+    diesel::delete(group_invitations::table.filter(group_invitations::group_id.eq(group)))
+        .execute(c)?;
+    diesel::delete(group_members::table.filter(group_members::group_id.eq(group))).execute(c)?;
+    diesel::delete(groups::table.find(group)).execute(c)?;
+
+    Ok(())
+}
+
+/// Dissolves the groups `user` owns and leaves the others, before the
+/// account is purged.
+pub fn purge(c: &SqliteConnection, user: i32) -> QueryResult<()> {
+    use db::schema::{group_invitations, group_members, groups};
+
+    let owned = groups::table
+        .filter(groups::owner_id.eq(user))
+        .select(groups::id)
+        .load::<i32>(c)?;
+    for group in owned {
+        dissolve(c, group)?;
+    }
+
+    let joined = group_members::table
+        .filter(group_members::user_id.eq(user))
+        .select(group_members::group_id)
+        .load::<i32>(c)?;
+    for group in joined {
+        detach_cards(c, group, user)?;
+    }
+    diesel::delete(group_members::table.filter(group_members::user_id.eq(user))).execute(c)?;
+    diesel::delete(group_invitations::table.filter(group_invitations::invited_by.eq(user)))
+        .execute(c)?;
+
+    Ok(())
+}
+
+#[get("/groups")]
+async fn list_groups(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<Vec<GroupResponse>>, APIError> {
+    use db::schema::{group_members, groups};
+
+    let found = db
+        .run(move |c| {
+            let joined = groups::table
+                .inner_join(group_members::table)
+                .filter(group_members::user_id.eq(user.0))
+                .order(groups::name.asc())
+                .select(groups::all_columns)
+                .load::<Group>(c)?;
+
+            joined
+                .into_iter()
+                .map(|group| describe(c, group))
+                .collect::<QueryResult<Vec<_>>>()
+        })
+        .await?;
+
+    Ok(Json(found))
+}
+
+#[post("/groups", format = "json", data = "<body>")]
+async fn create_group(
+    db: LoyaltyDbConn,
+    _scope: LoyaltiesWriter,
+    user: VerifiedUser,
+    body: Json<CreateGroup>,
+) -> Result<status::Custom<Json<GroupResponse>>, APIError> {
+    use db::schema::{group_members, groups};
+
+    body.0.validate()?;
+    let wanted = body.0.name.trim().to_string();
+
+    let created = db
+        .run(move |c| {
+            c.transaction(|| {
+                diesel::insert_into(groups::table)
+                    .values(&NewGroup {
+                        name: &wanted,
+                        owner_id: user.0,
+                    })
+                    .execute(c)?;
+                let group = groups::table
+                    .filter(groups::owner_id.eq(user.0))
+                    .order(groups::id.desc())
+                    .first::<Group>(c)?;
+
+                diesel::insert_into(group_members::table)
+                    .values(&NewGroupMember {
+                        group_id: group.id,
+                        user_id: user.0,
+                        role: GroupRole::Owner.name(),
+                    })
+                    .execute(c)?;
+
+                describe(c, group)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created)))
+}
+
+#[get("/groups/<group_id>")]
+async fn get_group(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    group_id: String,
+) -> Result<Json<GroupResponse>, APIError> {
+    use db::schema::groups;
+
+    let group_id: i32 = group_id.parse()?;
+
+    let found = db
+        .run(move |c| {
+            role_of(c, group_id, user.0)?.ok_or(APIError::NotFound)?;
+            let group = groups::table.find(group_id).first::<Group>(c)?;
+            Ok::<_, APIError>(describe(c, group)?)
+        })
+        .await?;
+
+    Ok(Json(found))
+}
+
+/// Deletes the group. The cards attached to it stay with their owners.
+#[delete("/groups/<group_id>")]
+async fn delete_group(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    group_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let group_id: i32 = group_id.parse()?;
+
+    db.run(move |c| {
+        c.transaction(|| {
+            match role_of(c, group_id, user.0)? {
+                Some(GroupRole::Owner) => {}
+                Some(GroupRole::Member) => return Err(APIError::NotAuthorized),
+                None => return Err(APIError::NotFound),
+            }
+
+            Ok(dissolve(c, group_id)?)
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "group deleted"))
+}
+
+#[post("/groups/<group_id>/invitations", format = "json", data = "<body>")]
+async fn invite(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    mailer: State<'_, Mailer>,
+    group_id: String,
+    body: Json<InviteToGroup>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::{group_invitations, groups, users};
+
+    let group_id: i32 = group_id.parse()?;
+    body.0.validate()?;
+
+    let email = body.0.email.trim().to_lowercase();
+    let raw = token::generate();
+    let hashed = token::digest(&raw);
+    let invited = email.clone();
+    let (group_name, inviter) = db
+        .run(move |c| {
+            match role_of(c, group_id, user.0)? {
+                Some(GroupRole::Owner) => {}
+                Some(GroupRole::Member) => return Err(APIError::NotAuthorized),
+                None => return Err(APIError::NotFound),
+            }
+
+            diesel::insert_into(group_invitations::table)
+                .values(&NewGroupInvitation {
+                    group_id,
+                    email: &invited,
+                    token_hash: &hashed,
+                    invited_by: user.0,
+                    expires_at: Utc::now().naive_utc() + Duration::days(INVITATION_TTL_DAYS),
+                })
+                .execute(c)?;
+
+            let group_name = groups::table
+                .find(group_id)
+                .select(groups::name)
+                .first::<String>(c)?;
+            let inviter = users::table
+                .find(user.0)
+                .select(users::name)
+                .first::<String>(c)?;
+            Ok((group_name, inviter))
+        })
+        .await?;
+
+    let body_text = format!(
+        "{} invited you to the family group \"{}\", to share loyalty cards.\n\n\
+         Open the link below while signed in to join. It expires in {} days.\n\n{}\n",
+        inviter,
+        group_name,
+        INVITATION_TTL_DAYS,
+        mailer.link(&format!("/groups/join/{}", raw))
+    );
+    if let Err(e) = mailer
+        .send(&email, "You were invited to a family group", body_text)
+        .await
+    {
+        log::warn!("could not send group invitation: {}", e);
+    }
+
+    Ok(status::Custom(Status::Created, "invitation sent"))
+}
+
+/// Accepts an invitation sent to the address of the signed-in user.
+#[post("/groups/join/<token>")]
+async fn join(
+    db: LoyaltyDbConn,
+    _scope: LoyaltiesWriter,
+    user: VerifiedUser,
+    token: String,
+) -> Result<Json<GroupResponse>, APIError> {
+    use db::schema::{group_invitations, group_members, groups, users};
+
+    let joined = db
+        .run(move |c| {
+            c.transaction(|| {
+                let (invitation, group, invited) = group_invitations::table
+                    .filter(group_invitations::token_hash.eq(token::digest(&token)))
+                    .filter(group_invitations::expires_at.gt(Utc::now().naive_utc()))
+                    .select((
+                        group_invitations::id,
+                        group_invitations::group_id,
+                        group_invitations::email,
+                    ))
+                    .first::<(i32, i32, String)>(c)
+                    .optional()?
+                    .ok_or(APIError::NotAuthorized)?;
+
+                let email = users::table
+                    .find(user.0)
+                    .select(users::email)
+                    .first::<String>(c)?;
+                if email.to_lowercase() != invited {
+                    return Err(APIError::NotAuthorized);
+                }
+
+                if role_of(c, group, user.0)?.is_none() {
+                    diesel::insert_into(group_members::table)
+                        .values(&NewGroupMember {
+                            group_id: group,
+                            user_id: user.0,
+                            role: GroupRole::Member.name(),
+                        })
+                        .execute(c)?;
+                }
+                diesel::delete(group_invitations::table.find(invitation)).execute(c)?;
+
+                let group = groups::table.find(group).first::<Group>(c)?;
+                Ok(describe(c, group)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(joined))
+}
+
+/// Lets the owner remove a member, or a member leave. The owner can't
+/// leave: it deletes the group instead.
+#[delete("/groups/<group_id>/members/<member_id>")]
+async fn remove_member(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    group_id: String,
+    member_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::group_members;
+
+    let group_id: i32 = group_id.parse()?;
+    let member_id: i32 = member_id.parse()?;
+
+    db.run(move |c| {
+        c.transaction(|| {
+            let caller = role_of(c, group_id, user.0)?.ok_or(APIError::NotFound)?;
+            let member = role_of(c, group_id, member_id)?.ok_or(APIError::NotFound)?;
+            match (caller, member) {
+                (_, GroupRole::Owner) => return Err(APIError::Conflict),
+                (GroupRole::Member, _) if member_id != user.0 => {
+                    return Err(APIError::NotAuthorized)
+                }
+                _ => {}
+            }
+
+            detach_cards(c, group_id, member_id)?;
+            diesel::delete(
+                group_members::table
+                    .filter(group_members::group_id.eq(group_id))
+                    .filter(group_members::user_id.eq(member_id)),
+            )
+            .execute(c)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "member removed"))
+}
+
+/// Attaches one of the caller's cards to a group it belongs to.
+#[put("/loyalties/<loyalty_id>/group", format = "json", data = "<body>")]
+async fn set_card_group(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    body: Json<SetCardGroup>,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let wanted = body.0.group_id;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            if let Some(group) = wanted {
+                role_of(c, group, user.0)?.ok_or(APIError::NotFound)?;
+            }
+
+            let target = cards
+                .filter(id.eq(loyalty_id).and(user_id.eq(user.0)))
+                .filter(deleted_at.is_null());
+            let updated = diesel::update(target).set(group_id.eq(wanted)).execute(c)?;
+            if updated == 0 {
+                return Err(APIError::NotFound);
+            }
+
+            let card = target.first::<db::models::Loyalty>(c)?;
+            Ok(crate::cards::describe_one(c, &storage, card)?)
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
@@ -0,0 +1,114 @@
+//! Card icons, shown in place of the logo when the retailer has none: a
+//! single emoji such as `☕`, or one of the identifiers of `ICONS` that the
+//! apps draw themselves.
+
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+use validator::ValidationError;
+
+/// Icons the apps ship, in display order.
+const ICONS: &[&str] = &[
+    "cart",
+    "bag",
+    "coffee",
+    "restaurant",
+    "fuel",
+    "car",
+    "pharmacy",
+    "book",
+    "clothing",
+    "gift",
+    "pet",
+    "fitness",
+    "beauty",
+    "plane",
+    "hotel",
+    "ticket",
+    "film",
+    "music",
+    "game",
+    "star",
+];
+
+/// Longer than any emoji, down to the tag sequences of subdivision flags.
+const MAX_EMOJI_BYTES: usize = 32;
+
+const ZWJ: char = '\u{200D}';
+const VARIATION_SELECTOR: char = '\u{FE0F}';
+const KEYCAP: char = '\u{20E3}';
+
+pub fn routes() -> Vec<Route> {
+    routes![list_icons]
+}
+
+fn pictographic(c: char) -> bool {
+    match c as u32 {
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 => true,
+        0x2194..=0x21AA | 0x231A..=0x23FF | 0x24C2 | 0x25AA..=0x25FE => true,
+        0x2600..=0x27BF | 0x2934 | 0x2935 | 0x2B05..=0x2B55 => true,
+        0x3030 | 0x303D | 0x3297 | 0x3299 => true,
+        // Leaving out regional indicators and skin tones, which only modify.
+        0x1F000..=0x1F1E5 | 0x1F200..=0x1F3FA | 0x1F400..=0x1FAFF => true,
+        _ => false,
+    }
+}
+
+fn regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+fn skin_tone(c: char) -> bool {
+    ('\u{1F3FB}'..='\u{1F3FF}').contains(&c)
+}
+
+fn tag(c: char) -> bool {
+    ('\u{E0020}'..='\u{E007F}').contains(&c)
+}
+
+/// A pictograph with its presentation selector, skin tone or tags.
+fn emoji_part(part: &str) -> bool {
+    let mut chars = part.chars();
+    match chars.next() {
+        Some(base) if pictographic(base) => {}
+        _ => return false,
+    }
+    chars.all(|c| c == VARIATION_SELECTOR || skin_tone(c) || tag(c))
+}
+
+/// Whether `icon` is one emoji: a flag, a keycap such as `1️⃣`, or
+/// pictographs joined with zero-width joiners, such as `👩‍💻`.
+fn is_emoji(icon: &str) -> bool {
+    if icon.is_empty() || icon.len() > MAX_EMOJI_BYTES {
+        return false;
+    }
+
+    let chars: Vec<char> = icon.chars().collect();
+    if chars.len() == 2 && chars.iter().all(|c| regional_indicator(*c)) {
+        return true;
+    }
+    if let [key, rest @ ..] = chars.as_slice() {
+        if (key.is_ascii_digit() || *key == '#' || *key == '*')
+            && matches!(rest, [KEYCAP] | [VARIATION_SELECTOR, KEYCAP])
+        {
+            return true;
+        }
+    }
+
+    icon.split(ZWJ).all(emoji_part)
+}
+
+/// Validator of the `icon` fields.
+pub fn validate(icon: &str) -> Result<(), ValidationError> {
+    if ICONS.contains(&icon) || is_emoji(icon) {
+        return Ok(());
+    }
+
+    let mut error = ValidationError::new("icon");
+    error.message = Some("icon is a single emoji or one of GET /icons".into());
+    Err(error)
+}
+
+#[get("/icons")]
+fn list_icons() -> Json<Vec<&'static str>> {
+    Json(ICONS.to_vec())
+}
@@ -0,0 +1,249 @@
+//! Photos of the front and back of cards, uploaded as `multipart/form-data`
+//! with the file in an `image` field. The file type is sniffed from its
+//! content, whatever the client claims.
+
+use diesel::prelude::*;
+use multer::Multipart;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+
+use crate::auth::{token, LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{self, models::NewCardImage};
+use crate::shares;
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const FIELD: &str = "image";
+const MAX_BYTES: usize = 5 * 1024 * 1024;
+/// Room for the multipart headers and boundaries around the file.
+const ENVELOPE_BYTES: usize = 64 * 1024;
+
+pub fn routes() -> Vec<Route> {
+    routes![upload_image, get_image]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Front,
+    Back,
+}
+
+impl Side {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "front" => Some(Side::Front),
+            "back" => Some(Side::Back),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Side::Front => "front",
+            Side::Back => "back",
+        }
+    }
+}
+
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("image/png"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Reads the file of the `field` part of a multipart upload, with the name
+/// the client gave it.
+pub async fn read_upload(
+    content_type: &ContentType,
+    data: Data,
+    field: &str,
+    max_bytes: usize,
+) -> Result<(Option<String>, Vec<u8>), APIError> {
+    let boundary =
+        multer::parse_boundary(content_type.to_string()).map_err(|_| APIError::InvalidUpload)?;
+    let stream = data.open((max_bytes + ENVELOPE_BYTES).bytes());
+    let mut multipart = Multipart::with_reader(stream, boundary);
+
+    while let Some(part) = multipart
+        .next_field()
+        .await
+        .map_err(|_| APIError::InvalidUpload)?
+    {
+        if part.name() != Some(field) {
+            continue;
+        }
+
+        let file_name = part.file_name().map(String::from);
+        let bytes = part.bytes().await.map_err(|_| APIError::InvalidUpload)?;
+        if bytes.len() > max_bytes {
+            return Err(APIError::PayloadTooLarge);
+        }
+        return Ok((file_name, bytes.to_vec()));
+    }
+
+    Err(APIError::InvalidUpload)
+}
+
+/// Removes the image rows of `card`, or only the one of `only`, returning
+/// the keys of the objects to delete from storage.
+pub fn detach(c: &SqliteConnection, card: i32, only: Option<Side>) -> QueryResult<Vec<String>> {
+    use db::schema::card_images::dsl::*;
+
+    let mut query = diesel::delete(card_images.filter(card_id.eq(card))).into_boxed();
+    let mut keys = card_images
+        .filter(card_id.eq(card))
+        .select(storage_key)
+        .into_boxed();
+    if let Some(only) = only {
+        query = query.filter(side.eq(only.name()));
+        keys = keys.filter(side.eq(only.name()));
+    }
+
+    let keys = keys.load::<String>(c)?;
+    query.execute(c)?;
+
+    Ok(keys)
+}
+
+/// Links to the photos of `cards` as `(card, side, url)`: presigned when
+/// the storage backend can sign them, the image routes otherwise.
+pub fn links(
+    c: &SqliteConnection,
+    storage: &Storage,
+    cards: &[i32],
+) -> QueryResult<Vec<(i32, Side, String)>> {
+    use db::schema::card_images::dsl::*;
+
+    let stored = card_images
+        .filter(card_id.eq_any(cards.to_vec()))
+        .select((card_id, side, storage_key))
+        .load::<(i32, String, String)>(c)?;
+
+    Ok(stored
+        .into_iter()
+        .filter_map(|(card, name, key)| {
+            let wanted = Side::from_name(&name)?;
+            let url = storage
+                .presign(&key)
+                .unwrap_or_else(|| format!("/loyalties/{}/images/{}", card, wanted.name()));
+            Some((card, wanted, url))
+        })
+        .collect())
+}
+
+/// Whether `user` owns the card or was shared it with full access.
+pub fn can_edit(c: &SqliteConnection, user: i32, card: i32) -> QueryResult<bool> {
+    use db::schema::cards::dsl::*;
+
+    let shared = shares::shared_ids(c, user, true)?;
+    let found = cards
+        .filter(id.eq(card))
+        .filter(user_id.eq(user).or(id.eq_any(shared)))
+        .filter(deleted_at.is_null())
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+
+    Ok(found.is_some())
+}
+
+/// Replaces the photo of one side of a card.
+#[post("/loyalties/<loyalty_id>/images/<side>", data = "<data>")]
+async fn upload_image(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    content_type: &ContentType,
+    loyalty_id: String,
+    side: String,
+    data: Data,
+) -> Result<status::Custom<&'static str>, APIError> {
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let side = Side::from_name(&side).ok_or(APIError::NotFound)?;
+    let owner = user.0;
+    if !db.run(move |c| can_edit(c, owner, loyalty_id)).await? {
+        return Err(APIError::NotFound);
+    }
+
+    let (_, bytes) = read_upload(content_type, data, FIELD, MAX_BYTES).await?;
+    let kind = sniff(&bytes).ok_or(APIError::UnsupportedMediaType)?;
+    let size = bytes.len() as i32;
+
+    let key = format!("cards/{}/{}", loyalty_id, token::generate());
+    storage.put(&key, bytes, kind).await?;
+
+    let stored = key.clone();
+    let replaced = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                // The card may have been deleted during the upload.
+                if !can_edit(c, owner, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                let previous = detach(c, loyalty_id, Some(side))?;
+                diesel::insert_into(db::schema::card_images::table)
+                    .values(&NewCardImage {
+                        card_id: loyalty_id,
+                        side: side.name(),
+                        storage_key: &stored,
+                        content_type: kind,
+                        size,
+                    })
+                    .execute(c)?;
+
+                Ok(previous)
+            })
+        })
+        .await;
+
+    match replaced {
+        Ok(previous) => {
+            storage.discard(&previous).await;
+            Ok(status::Custom(Status::Ok, "image uploaded"))
+        }
+        Err(e) => {
+            storage.discard(&[key]).await;
+            Err(e)
+        }
+    }
+}
+
+#[get("/loyalties/<loyalty_id>/images/<side>")]
+async fn get_image(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    side: String,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::{card_images, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let side = Side::from_name(&side).ok_or(APIError::NotFound)?;
+    let (key, stored_type) = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            card_images::table
+                .inner_join(cards::table)
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .filter(card_images::side.eq(side.name()))
+                .select((card_images::storage_key, card_images::content_type))
+                .first::<(String, String)>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    let bytes = storage.get(&key).await?.ok_or(APIError::NotFound)?;
+    let content_type = ContentType::parse_flexible(&stored_type).unwrap_or(ContentType::Binary);
+
+    Ok((content_type, bytes))
+}
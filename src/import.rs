@@ -0,0 +1,415 @@
+//! Bulk import of cards. Valid entries are inserted together and the others
+//! are reported one by one. The format is detected from the content:
+//!
+//! * our CSV, with a `name,code,color,barcode_type,notes` header where all
+//!   but `name` and `code` may be left out or empty;
+//! * the CSV export of Klarna, with `Card name`, `Card number` and
+//!   `Barcode format` columns and optional `Notes`;
+//! * the JSON export of Stocard: a list of cards, alone or under
+//!   `loyalty_cards`, with the number in `input_id`, its format in
+//!   `input_barcode_format` and the store in `store_name` or
+//!   `custom_store.name`.
+//!
+//! Card names are looked up in the retailer catalog, which fills in the
+//! color and format the file left out and, unless `keep_names` is set, the
+//! spelling of the name.
+
+use chrono::Utc;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use diesel::prelude::*;
+use rocket::data::{Data, ToByteUnit};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{post, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use validator::{Validate, ValidationErrors};
+
+use crate::auth::{LoyaltiesWriter, VerifiedUser};
+use crate::barcode::BarcodeType;
+use crate::colors;
+use crate::db::{
+    self,
+    crypto::{self, EncryptedString},
+    models::{NewLoyalty, Retailer},
+};
+use crate::quota::{self, QuotaConfig};
+use crate::referrals::{self, ReferralsConfig};
+use crate::requests::{ImportReport, ImportedRow};
+use crate::retailers;
+use crate::search;
+use crate::tiers::TiersConfig;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_BYTES: usize = 1024 * 1024;
+
+pub fn routes() -> Vec<Route> {
+    routes![import_cards]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Csv,
+    Klarna,
+    Stocard,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Klarna => "klarna",
+            Format::Stocard => "stocard",
+        }
+    }
+}
+
+/// The same rules as `PATCH /loyalties/<id>`.
+#[derive(Validate)]
+struct Row {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    #[validate(length(min = 1, max = 255))]
+    code: String,
+    #[validate(length(min = 1, max = 32), custom = "crate::colors::validate")]
+    color: Option<String>,
+    barcode_type: Option<String>,
+    #[validate(length(max = 500))]
+    notes: Option<String>,
+}
+
+/// An entry ready to be matched with the catalog.
+struct Card {
+    name: String,
+    code: String,
+    color: Option<String>,
+    barcode_type: Option<BarcodeType>,
+    notes: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StocardStore {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StocardCard {
+    input_id: String,
+    input_barcode_format: Option<String>,
+    store_name: Option<String>,
+    custom_store: Option<StocardStore>,
+    notes: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StocardExport {
+    Cards(Vec<StocardCard>),
+    Wrapped { loyalty_cards: Vec<StocardCard> },
+}
+
+fn explain(errors: &ValidationErrors) -> String {
+    let mut fields: Vec<String> = errors
+        .field_errors()
+        .into_iter()
+        .map(
+            |(field, errors)| match errors.first().and_then(|e| e.message.as_ref()) {
+                Some(message) => format!("{}: {}", field, message),
+                None => format!("invalid {}", field),
+            },
+        )
+        .collect();
+    fields.sort();
+    fields.join(", ")
+}
+
+/// Reads our format names as well as those other apps use, such as
+/// `EAN_13` or `QR_CODE`.
+fn barcode_type(name: &str) -> Option<BarcodeType> {
+    let compact: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match compact.as_str() {
+        "qrcode" => Some(BarcodeType::Qr),
+        other => BarcodeType::from_name(other),
+    }
+}
+
+fn check(row: Row) -> Result<Card, String> {
+    row.validate().map_err(|e| explain(&e))?;
+
+    let barcode_type = match row.barcode_type.as_deref() {
+        None | Some("") => None,
+        Some(name) => {
+            Some(barcode_type(name).ok_or_else(|| format!("unknown barcode type {}", name))?)
+        }
+    };
+
+    Ok(Card {
+        name: row.name,
+        code: row.code,
+        color: row.color.as_deref().and_then(colors::normalize),
+        barcode_type,
+        notes: row.notes.filter(|notes| !notes.is_empty()),
+    })
+}
+
+/// Column names of each field in the CSV formats, lowercase.
+const NAME_COLUMNS: &[&str] = &["name", "card name"];
+const CODE_COLUMNS: &[&str] = &["code", "card number"];
+const COLOR_COLUMNS: &[&str] = &["color"];
+const BARCODE_TYPE_COLUMNS: &[&str] = &["barcode_type", "barcode format"];
+const NOTES_COLUMNS: &[&str] = &["notes"];
+
+fn parse_csv(bytes: &[u8]) -> Result<(Format, Vec<(u64, Result<Card, String>)>), APIError> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(bytes);
+    let headers = reader
+        .headers()
+        .map_err(|_| APIError::InvalidUpload)?
+        .clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|header| names.contains(&header.to_lowercase().as_str()))
+    };
+    let (name, code) = match (column(NAME_COLUMNS), column(CODE_COLUMNS)) {
+        (Some(name), Some(code)) => (name, code),
+        _ => return Err(APIError::InvalidUpload),
+    };
+    let (color, kind, notes) = (
+        column(COLOR_COLUMNS),
+        column(BARCODE_TYPE_COLUMNS),
+        column(NOTES_COLUMNS),
+    );
+    let format = if headers[code].eq_ignore_ascii_case("card number") {
+        Format::Klarna
+    } else {
+        Format::Csv
+    };
+
+    let field = |record: &StringRecord, at: Option<usize>| {
+        at.and_then(|at| record.get(at))
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+    };
+    let lines = reader
+        .records()
+        .map(|record| match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |p| p.line());
+                let row = Row {
+                    name: field(&record, Some(name)).unwrap_or_default(),
+                    code: field(&record, Some(code)).unwrap_or_default(),
+                    color: field(&record, color),
+                    barcode_type: field(&record, kind),
+                    notes: field(&record, notes),
+                };
+                (line, check(row))
+            }
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                (line, Err(format!("unreadable line: {}", e)))
+            }
+        })
+        .collect();
+
+    Ok((format, lines))
+}
+
+/// Entries are numbered by their position in the list, from 1.
+fn parse_stocard(bytes: &[u8]) -> Result<Vec<(u64, Result<Card, String>)>, APIError> {
+    let export: StocardExport =
+        serde_json::from_slice(bytes).map_err(|_| APIError::InvalidUpload)?;
+    let cards = match export {
+        StocardExport::Cards(cards) => cards,
+        StocardExport::Wrapped { loyalty_cards } => loyalty_cards,
+    };
+
+    Ok(cards
+        .into_iter()
+        .zip(1..)
+        .map(|(card, position)| {
+            let name = card
+                .store_name
+                .or_else(|| card.custom_store.and_then(|store| store.name))
+                .unwrap_or_default();
+            let row = Row {
+                name: name.trim().to_string(),
+                code: card.input_id.trim().to_string(),
+                color: None,
+                barcode_type: card.input_barcode_format,
+                notes: card.notes.map(|notes| notes.trim().to_string()),
+            };
+            (position, check(row))
+        })
+        .collect())
+}
+
+/// Reads the entries, keeping those that can't be checked as errors.
+fn parse(bytes: &[u8]) -> Result<(Format, Vec<(u64, Result<Card, String>)>), APIError> {
+    let json = bytes
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map_or(false, |first| *first == b'[' || *first == b'{');
+    if json {
+        return Ok((Format::Stocard, parse_stocard(bytes)?));
+    }
+
+    parse_csv(bytes)
+}
+
+/// Fills in what `card` left out from its retailer, checking the code
+/// against the format it ends up with.
+fn prefill<'a>(
+    catalog: &'a [Retailer],
+    card: &mut Card,
+    keep_name: bool,
+) -> Result<(Option<&'a Retailer>, BarcodeType), String> {
+    let retailer = retailers::find_by_name(catalog, &card.name);
+    if let (Some(retailer), false) = (retailer, keep_name) {
+        card.name = retailer.name.clone();
+    }
+    if card.color.is_none() {
+        card.color = retailer.and_then(|r| r.color.clone());
+    }
+    let kind = card
+        .barcode_type
+        .or_else(|| retailer.and_then(|r| BarcodeType::from_name(&r.barcode_type)))
+        .unwrap_or_default();
+    kind.check(&card.code).map_err(|e| explain(&e))?;
+
+    Ok((retailer, kind))
+}
+
+/// Takes our CSV and the exports of other apps; see the module docs.
+#[post("/loyalties/import?<keep_names>", data = "<data>")]
+async fn import_cards(
+    db: LoyaltyDbConn,
+    _scope: LoyaltiesWriter,
+    user: VerifiedUser,
+    quota: State<'_, QuotaConfig>,
+    referrals_config: State<'_, ReferralsConfig>,
+    tiers_config: State<'_, TiersConfig>,
+    keep_names: Option<bool>,
+    data: Data,
+) -> Result<Json<ImportReport>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let mut bytes = Vec::new();
+    data.open((MAX_BYTES + 1).bytes())
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| APIError::InvalidUpload)?;
+    if bytes.len() > MAX_BYTES {
+        return Err(APIError::PayloadTooLarge);
+    }
+    let (format, lines) = parse(&bytes)?;
+
+    let quota = *quota;
+    let (referrals_config, tiers_config) = (*referrals_config, *tiers_config);
+    let keep_names = keep_names.unwrap_or(false);
+    let rows = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                // A full account gets the same error as when adding a card;
+                // otherwise the entries past the quota are reported.
+                quota::check(c, quota, user.0, 1)?;
+                let mut left = quota::remaining(c, quota, user.0)?;
+                let catalog = db::schema::retailers::table.load::<Retailer>(c)?;
+                let mut existing = crate::cards::codes(c, user.0)?;
+                let mut next_position = cards
+                    .filter(user_id.eq(user.0))
+                    .select(diesel::dsl::max(position))
+                    .first::<Option<i32>>(c)?
+                    .map_or(0, |last| last + 1);
+
+                let mut rows = Vec::with_capacity(lines.len());
+                for (line, checked) in lines {
+                    let prefilled = checked.and_then(|mut card| {
+                        let (retailer, kind) = prefill(&catalog, &mut card, keep_names)?;
+                        Ok((card, retailer.map(|r| r.id), kind))
+                    });
+                    let (card, retailer, kind) = match prefilled {
+                        Ok(prefilled) => prefilled,
+                        Err(error) => {
+                            rows.push(ImportedRow {
+                                line,
+                                id: None,
+                                error: Some(error),
+                            });
+                            continue;
+                        }
+                    };
+
+                    // Also catches the same code twice in the file.
+                    if let Some((duplicate, _)) =
+                        existing.iter().find(|(_, known)| *known == card.code)
+                    {
+                        rows.push(ImportedRow {
+                            line,
+                            id: None,
+                            error: Some(format!("duplicate of card {}", duplicate)),
+                        });
+                        continue;
+                    }
+                    if left == Some(0) {
+                        rows.push(ImportedRow {
+                            line,
+                            id: None,
+                            error: Some("card quota exceeded".to_string()),
+                        });
+                        continue;
+                    }
+
+                    diesel::insert_into(cards)
+                        .values(&NewLoyalty {
+                            name: &card.name,
+                            color: card.color.as_deref(),
+                            code: EncryptedString(card.code.clone()),
+                            user_id: user.0,
+                            barcode_type: kind.name(),
+                            created_at: Utc::now().naive_utc(),
+                            position: next_position,
+                            notes: card.notes.clone().map(EncryptedString),
+                            expires_at: None,
+                            retailer_id: retailer,
+                            balance: None,
+                            balance_currency: None,
+                            icon: None,
+                            stamps_required: None,
+                            code_index: crypto::blind_index(&card.code),
+                        })
+                        .execute(c)?;
+                    let created = cards.order(id.desc()).select(id).first::<i32>(c)?;
+                    search::index(c, created)?;
+
+                    next_position += 1;
+                    left = left.map(|left| left - 1);
+                    existing.push((created, card.code));
+                    rows.push(ImportedRow {
+                        line,
+                        id: Some(created),
+                        error: None,
+                    });
+                }
+                if let Some(first) = rows.iter().find_map(|row| row.id) {
+                    referrals::credit(c, referrals_config, tiers_config, user.0, first)?;
+                }
+
+                Ok(rows)
+            })
+        })
+        .await?;
+
+    let imported = rows.iter().filter(|row| row.id.is_some()).count();
+    Ok(Json(ImportReport {
+        format: format.name(),
+        imported,
+        failed: rows.len() - imported,
+        rows,
+    }))
+}
@@ -0,0 +1,191 @@
+//! Periodic maintenance running in the background for the lifetime of the
+//! server.
+
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use serde::Deserialize;
+
+use crate::auth;
+use crate::cards;
+use crate::coupons;
+use crate::mail::Mailer;
+use crate::points::{self, PointsConfig};
+use crate::receipts::Ocr;
+use crate::reminders;
+use crate::storage::Storage;
+use crate::LoyaltyDbConn;
+
+/// The `jobs` section of the Rocket configuration.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Seconds between two runs.
+    pub interval: u64,
+    /// Seconds between two checks for due reminders, which are sent late by
+    /// up to that much.
+    pub reminder_interval: u64,
+    /// Seconds between two runs of the OCR service over queued receipts,
+    /// when one is configured.
+    pub receipt_interval: u64,
+    /// Days a deleted account is kept before being purged.
+    pub deletion_grace_days: i64,
+    /// Days a card stays in the trash before being purged.
+    pub trash_retention_days: i64,
+    /// Days expired coupons are kept before being purged.
+    pub coupon_retention_days: i64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            interval: 60 * 60,
+            reminder_interval: 60,
+            receipt_interval: 60,
+            deletion_grace_days: 30,
+            trash_retention_days: 30,
+            coupon_retention_days: 30,
+        }
+    }
+}
+
+async fn run(
+    conn: &LoyaltyDbConn,
+    storage: &Storage,
+    mailer: &Mailer,
+    config: JobsConfig,
+    points_config: PointsConfig,
+) {
+    let grace = config.deletion_grace_days;
+    match conn
+        .run(move |c| auth::account::purge_deleted(c, grace))
+        .await
+    {
+        Ok(purged) if purged.accounts == 0 => {}
+        Ok(purged) => {
+            storage.discard(&purged.objects).await;
+            log::info!("purged {} deleted account(s)", purged.accounts);
+        }
+        Err(e) => log::error!("failed to purge deleted accounts: {}", e),
+    }
+
+    let retention = config.trash_retention_days;
+    match conn.run(move |c| cards::purge_trashed(c, retention)).await {
+        Ok(purged) if purged.cards == 0 => {}
+        Ok(purged) => {
+            storage.discard(&purged.objects).await;
+            log::info!("purged {} trashed card(s)", purged.cards);
+        }
+        Err(e) => log::error!("failed to purge trashed cards: {}", e),
+    }
+
+    let retention = config.coupon_retention_days;
+    match conn
+        .run(move |c| coupons::purge_expired(c, retention))
+        .await
+    {
+        Ok(0) => {}
+        Ok(purged) => log::info!("purged {} expired coupon(s)", purged),
+        Err(e) => log::error!("failed to purge expired coupons: {}", e),
+    }
+
+    match points::expire_due(conn, mailer, points_config).await {
+        Ok(0) => {}
+        Ok(cards) => log::info!("expired points on {} card(s)", cards),
+        Err(e) => log::error!("failed to expire points: {}", e),
+    }
+}
+
+async fn send_reminders(conn: &LoyaltyDbConn, mailer: &Mailer) {
+    match reminders::send_due(conn, mailer).await {
+        Ok(0) => {}
+        Ok(sent) => log::info!("sent {} reminder(s)", sent),
+        Err(e) => log::error!("failed to send reminders: {}", e),
+    }
+}
+
+async fn read_receipts(conn: &LoyaltyDbConn, storage: &Storage, ocr: &Ocr) {
+    match ocr.read_queued(conn, storage).await {
+        Ok(0) => {}
+        Ok(read) => log::info!("read {} receipt(s)", read),
+        Err(e) => log::error!("failed to read receipts: {}", e),
+    }
+}
+
+/// Spawns the job loops: maintenance, reminders which need to run more
+/// often, and receipt reading when an OCR service is configured. Each keeps
+/// one database connection for itself.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Background Jobs", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<JobsConfig>("jobs") {
+            Ok(config) => config,
+            Err(e) if e.missing() => JobsConfig::default(),
+            Err(e) => {
+                log::error!("invalid jobs configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let (conn, reminder_conn) = match (
+            LoyaltyDbConn::get_one(&rocket).await,
+            LoyaltyDbConn::get_one(&rocket).await,
+        ) {
+            (Some(conn), Some(reminder_conn)) => (conn, reminder_conn),
+            _ => return Err(rocket),
+        };
+        // Managed by the storage, mail, points and receipts fairings,
+        // attached before this one.
+        let storage = match rocket.state::<Storage>() {
+            Some(storage) => storage.clone(),
+            None => return Err(rocket),
+        };
+        let mailer = match rocket.state::<Mailer>() {
+            Some(mailer) => mailer.clone(),
+            None => return Err(rocket),
+        };
+        let points_config = match rocket.state::<PointsConfig>() {
+            Some(points_config) => *points_config,
+            None => return Err(rocket),
+        };
+        let ocr = match rocket.state::<Ocr>() {
+            Some(ocr) => ocr.clone(),
+            None => return Err(rocket),
+        };
+        let reminder_mailer = mailer.clone();
+
+        if ocr.is_configured() {
+            let receipt_conn = match LoyaltyDbConn::get_one(&rocket).await {
+                Some(receipt_conn) => receipt_conn,
+                None => return Err(rocket),
+            };
+            let receipt_storage = storage.clone();
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.receipt_interval.max(1)));
+                loop {
+                    interval.tick().await;
+                    read_receipts(&receipt_conn, &receipt_storage, &ocr).await;
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+            loop {
+                interval.tick().await;
+                run(&conn, &storage, &mailer, config, points_config).await;
+            }
+        });
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config.reminder_interval.max(1)));
+            loop {
+                interval.tick().await;
+                send_reminders(&reminder_conn, &reminder_mailer).await;
+            }
+        });
+
+        Ok(rocket)
+    })
+}
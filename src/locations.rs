@@ -0,0 +1,390 @@
+//! The stores a card is used at, with their coordinates, so the apps can
+//! suggest the card when the user is close to one.
+//!
+//! Distances are great-circle distances in meters. Nearby cards are first
+//! narrowed to the locations in a box around the caller, which the database
+//! can compare, then sorted by distance.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, put, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{CardLocation, NewCardLocation},
+};
+use crate::images;
+use crate::requests::{LocationResponse, NearbyCard, SetLocation};
+use crate::shares;
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LOCATIONS: i64 = 50;
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+/// Meters of a degree of latitude, and of longitude at the equator.
+const M_PER_DEGREE: f64 = 111_320.0;
+const DEFAULT_RADIUS_M: f64 = 1_000.0;
+const MAX_RADIUS_M: f64 = 50_000.0;
+const MAX_NEARBY: usize = 50;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_nearby,
+        get_locations,
+        add_location,
+        update_location,
+        delete_location
+    ]
+}
+
+/// Removes the locations of `card`, before it is deleted.
+pub fn clear(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_locations::dsl::*;
+
+    diesel::delete(card_locations.filter(card_id.eq(card))).execute(c)
+}
+
+fn describe(location: CardLocation) -> LocationResponse {
+    LocationResponse {
+        id: location.id,
+        label: location.label,
+        latitude: location.latitude,
+        longitude: location.longitude,
+        created_at: location.created_at,
+    }
+}
+
+/// The great-circle distance in meters between two points.
+fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_M * a.sqrt().min(1.0).asin()
+}
+
+/// Parses the `field` query parameter as a coordinate of at most `max`
+/// degrees either way.
+fn coordinate(value: Option<String>, field: &'static str, max: f64) -> Result<f64, APIError> {
+    match value.and_then(|value| value.parse::<f64>().ok()) {
+        Some(degrees) if degrees.abs() <= max => Ok(degrees),
+        _ => {
+            let mut error = ValidationError::new("range");
+            error.message = Some(Cow::Borrowed("a coordinate in degrees is required"));
+
+            let mut errors = ValidationErrors::new();
+            errors.add(field, error);
+            Err(errors.into())
+        }
+    }
+}
+
+/// Validates `body`, returning its label trimmed.
+fn check(body: &SetLocation) -> Result<String, APIError> {
+    body.validate()?;
+
+    let label = body.label.trim();
+    if label.is_empty() {
+        let mut error = ValidationError::new("length");
+        error.message = Some(Cow::Borrowed("locations need a label"));
+
+        let mut errors = ValidationErrors::new();
+        errors.add("label", error);
+        return Err(errors.into());
+    }
+
+    Ok(label.to_string())
+}
+
+/// Cards the caller can read with a location within `radius` meters of
+/// `lat`/`lon`, closest first. Archived cards and the trash are left out.
+#[get("/loyalties/nearby?<lat>&<lon>&<radius>&<limit>")]
+async fn get_nearby(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    lat: Option<String>,
+    lon: Option<String>,
+    radius: Option<String>,
+    limit: Option<String>,
+) -> Result<Json<Vec<NearbyCard>>, APIError> {
+    use db::schema::{card_locations, cards};
+
+    let here = (
+        coordinate(lat, "lat", 90.0)?,
+        coordinate(lon, "lon", 180.0)?,
+    );
+    let radius = radius
+        .and_then(|p| p.parse::<f64>().ok())
+        .filter(|radius| radius.is_finite())
+        .unwrap_or(DEFAULT_RADIUS_M)
+        .max(0.0)
+        .min(MAX_RADIUS_M);
+    let limit = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(10)
+        .min(MAX_NEARBY);
+
+    let lat_span = radius / M_PER_DEGREE;
+    // Longitude degrees shrink towards the poles, where the box covers
+    // every longitude.
+    let lon_span = match here.0.to_radians().cos() * M_PER_DEGREE {
+        shrunk if shrunk > radius => radius / shrunk,
+        _ => 180.0,
+    };
+
+    let storage = storage.inner().clone();
+    let nearby = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let mut query = card_locations::table
+                .inner_join(cards::table)
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .filter(cards::archived_at.is_null())
+                .filter(cards::is_active.eq(true))
+                .filter(card_locations::latitude.between(here.0 - lat_span, here.0 + lat_span))
+                .select(card_locations::all_columns)
+                .into_boxed();
+            if lon_span < 180.0 {
+                let (west, east) = (here.1 - lon_span, here.1 + lon_span);
+                // The box may cross the antimeridian.
+                query = if west < -180.0 {
+                    query.filter(
+                        card_locations::longitude
+                            .ge(west + 360.0)
+                            .or(card_locations::longitude.le(east)),
+                    )
+                } else if east > 180.0 {
+                    query.filter(
+                        card_locations::longitude
+                            .ge(west)
+                            .or(card_locations::longitude.le(east - 360.0)),
+                    )
+                } else {
+                    query.filter(card_locations::longitude.between(west, east))
+                };
+            }
+            let candidates = query.load::<CardLocation>(c)?;
+
+            // The closest location of each card within the radius.
+            let mut closest: HashMap<i32, (f64, CardLocation)> = HashMap::new();
+            for location in candidates {
+                let meters = distance(here, (location.latitude, location.longitude));
+                if meters > radius {
+                    continue;
+                }
+                match closest.get(&location.card_id) {
+                    Some((best, _)) if *best <= meters => {}
+                    _ => {
+                        closest.insert(location.card_id, (meters, location));
+                    }
+                }
+            }
+            let mut ranked: Vec<(f64, CardLocation)> =
+                closest.into_iter().map(|(_, best)| best).collect();
+            ranked.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            ranked.truncate(limit);
+
+            let ids: Vec<i32> = ranked
+                .iter()
+                .map(|(_, location)| location.card_id)
+                .collect();
+            let found = cards::table
+                .filter(cards::id.eq_any(ids))
+                .load::<db::models::Loyalty>(c)?;
+            let mut described: HashMap<i32, _> = crate::cards::describe(c, &storage, found)?
+                .into_iter()
+                .map(|card| (card.id, card))
+                .collect();
+
+            Ok::<_, APIError>(
+                ranked
+                    .into_iter()
+                    .filter_map(|(meters, location)| {
+                        Some(NearbyCard {
+                            distance: meters.round(),
+                            card: described.remove(&location.card_id)?,
+                            location: describe(location),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await?;
+
+    Ok(Json(nearby))
+}
+
+/// The locations of a card, in the order they were added.
+#[get("/loyalties/<loyalty_id>/locations")]
+async fn get_locations(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+) -> Result<Json<Vec<LocationResponse>>, APIError> {
+    use db::schema::{card_locations, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let locations = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let readable = cards::table
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .select(cards::id)
+                .first::<i32>(c)
+                .optional()?;
+            if readable.is_none() {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(card_locations::table
+                .filter(card_locations::card_id.eq(loyalty_id))
+                .order(card_locations::id.asc())
+                .load::<CardLocation>(c)?)
+        })
+        .await?;
+
+    Ok(Json(locations.into_iter().map(describe).collect()))
+}
+
+#[post("/loyalties/<loyalty_id>/locations", format = "json", data = "<body>")]
+async fn add_location(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    body: Json<SetLocation>,
+) -> Result<status::Custom<Json<LocationResponse>>, APIError> {
+    use db::schema::card_locations::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let wanted = check(&body.0)?;
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if !images::can_edit(c, user.0, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                let stored = card_locations
+                    .filter(card_id.eq(loyalty_id))
+                    .count()
+                    .get_result::<i64>(c)?;
+                if stored >= MAX_LOCATIONS {
+                    let mut error = ValidationError::new("length");
+                    error.message = Some(Cow::Borrowed("cards have up to 50 locations"));
+
+                    let mut errors = ValidationErrors::new();
+                    errors.add("locations", error);
+                    return Err(errors.into());
+                }
+
+                diesel::insert_into(card_locations)
+                    .values(&NewCardLocation {
+                        card_id: loyalty_id,
+                        label: &wanted,
+                        latitude: body.latitude,
+                        longitude: body.longitude,
+                    })
+                    .execute(c)?;
+
+                Ok(card_locations.order(id.desc()).first::<CardLocation>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(describe(created))))
+}
+
+#[put(
+    "/loyalties/<loyalty_id>/locations/<location_id>",
+    format = "json",
+    data = "<body>"
+)]
+async fn update_location(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    location_id: String,
+    body: Json<SetLocation>,
+) -> Result<Json<LocationResponse>, APIError> {
+    use db::schema::card_locations::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let location_id: i32 = location_id.parse()?;
+    let wanted = check(&body.0)?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if !images::can_edit(c, user.0, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                let target = card_locations
+                    .filter(id.eq(location_id))
+                    .filter(card_id.eq(loyalty_id));
+                let changed = diesel::update(target.clone())
+                    .set((
+                        label.eq(&wanted),
+                        latitude.eq(body.latitude),
+                        longitude.eq(body.longitude),
+                    ))
+                    .execute(c)?;
+                if changed == 0 {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(target.first::<CardLocation>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(describe(updated)))
+}
+
+#[delete("/loyalties/<loyalty_id>/locations/<location_id>")]
+async fn delete_location(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    location_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_locations::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let location_id: i32 = location_id.parse()?;
+
+    let deleted = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if !images::can_edit(c, user.0, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                Ok(diesel::delete(
+                    card_locations
+                        .filter(id.eq(location_id))
+                        .filter(card_id.eq(loyalty_id)),
+                )
+                .execute(c)?)
+            })
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "location deleted")),
+    }
+}
@@ -0,0 +1,163 @@
+//! Logos shown beside cards: the one of the card's retailer in the catalog,
+//! or else one looked up from the card name with a Clearbit-style
+//! autocomplete API and cached in `retailer_logos`. Without a `logos`
+//! section in the configuration only the catalog logos are used.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::db::{self, models::NewRetailerLogo};
+use crate::{APIError, LoyaltyDbConn};
+
+/// Lookups slower than this are given up, so card edits don't hang on them.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+fn default_refresh_days() -> i64 {
+    30
+}
+
+/// The `logos` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct LogosConfig {
+    /// Endpoint taking the name in a `query` parameter and answering with a
+    /// JSON array of `{ "logo": url }` suggestions, best first, such as
+    /// `https://autocomplete.clearbit.com/v1/companies/suggest`.
+    pub lookup_url: String,
+    /// Days before a cached logo, or the lack of one, is looked up again.
+    #[serde(default = "default_refresh_days")]
+    pub refresh_days: i64,
+}
+
+pub struct Logos {
+    config: Option<LogosConfig>,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct Suggestion {
+    logo: Option<String>,
+}
+
+/// Cache key of a card name.
+fn key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Cached logos of the card `names`, as `(lowercased name, url)`.
+pub fn cached(c: &SqliteConnection, names: &[String]) -> QueryResult<Vec<(String, String)>> {
+    use db::schema::retailer_logos::dsl::*;
+
+    let keys: Vec<String> = names.iter().map(|wanted| key(wanted)).collect();
+    let found = retailer_logos
+        .filter(name.eq_any(keys))
+        .filter(logo_url.is_not_null())
+        .select((name, logo_url))
+        .load::<(String, Option<String>)>(c)?;
+
+    Ok(found
+        .into_iter()
+        .filter_map(|(found, url)| Some((found, url?)))
+        .collect())
+}
+
+impl Logos {
+    async fn lookup(&self, config: &LogosConfig, wanted: &str) -> Result<Option<String>, APIError> {
+        let suggestions: Vec<Suggestion> = self
+            .http
+            .get(&config.lookup_url)
+            .query(&[("query", wanted)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(suggestions.into_iter().find_map(|s| s.logo))
+    }
+
+    /// Looks up the logo of the card name `wanted` unless it was looked up
+    /// recently. Failures are only logged: the card is shown without a logo.
+    pub async fn refresh(&self, conn: &LoyaltyDbConn, wanted: &str) {
+        use db::schema::retailer_logos::dsl::*;
+
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+        let wanted = key(wanted);
+        if wanted.is_empty() {
+            return;
+        }
+
+        let cutoff = Utc::now().naive_utc() - chrono::Duration::days(config.refresh_days);
+        let looked_up = wanted.clone();
+        let fresh = conn
+            .run(move |c| {
+                retailer_logos
+                    .filter(name.eq(looked_up))
+                    .filter(fetched_at.gt(cutoff))
+                    .select(name)
+                    .first::<String>(c)
+                    .optional()
+            })
+            .await;
+        match fresh {
+            Ok(Some(_)) => return,
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("failed to read the logo cache: {}", e);
+                return;
+            }
+        }
+
+        let found = match self.lookup(config, &wanted).await {
+            Ok(found) => found,
+            Err(e) => {
+                log::warn!("logo lookup failed for {:?}: {}", wanted, e);
+                return;
+            }
+        };
+
+        let stored = conn
+            .run(move |c| {
+                diesel::replace_into(retailer_logos)
+                    .values(&NewRetailerLogo {
+                        name: &wanted,
+                        logo_url: found.as_deref(),
+                        fetched_at: Utc::now().naive_utc(),
+                    })
+                    .execute(c)
+            })
+            .await;
+        if let Err(e) = stored {
+            log::warn!("failed to cache a logo: {}", e);
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Logos", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<LogosConfig>("logos") {
+            Ok(config) => Some(config),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                log::error!("invalid logos configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let http = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+            Ok(http) => http,
+            Err(e) => {
+                log::error!("failed to build the logo lookup client: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(Logos { config, http }))
+    })
+}
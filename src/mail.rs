@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("invalid address")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("invalid message")]
+    Message(#[from] lettre::error::Error),
+    #[error("smtp error")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("mail task failed")]
+    Task,
+}
+
+#[derive(Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// The `mail` section of the Rocket configuration.
+///
+/// Without an `smtp` table messages are only logged, which is what you want
+/// during development.
+#[derive(Deserialize)]
+pub struct MailConfig {
+    pub from: String,
+    /// Base URL used to build the links sent in emails.
+    pub public_url: String,
+    pub smtp: Option<SmtpConfig>,
+}
+
+struct Inner {
+    from: String,
+    public_url: String,
+    transport: Option<SmtpTransport>,
+}
+
+#[derive(Clone)]
+pub struct Mailer(Arc<Inner>);
+
+impl Mailer {
+    pub fn new(config: MailConfig) -> Result<Self, MailError> {
+        let transport = match config.smtp {
+            Some(smtp) => Some(
+                SmtpTransport::relay(&smtp.host)?
+                    .credentials(Credentials::new(smtp.username, smtp.password))
+                    .build(),
+            ),
+            None => None,
+        };
+
+        Ok(Mailer(Arc::new(Inner {
+            from: config.from,
+            public_url: config.public_url,
+            transport,
+        })))
+    }
+
+    /// Builds an absolute link to `path` on the public URL of the API.
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.0.public_url.trim_end_matches('/'), path)
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.0.from.parse()?)
+            .to(to.parse()?)
+            .subject(subject)
+            .body(body)?;
+
+        let inner = self.0.clone();
+        rocket::tokio::task::spawn_blocking(move || match &inner.transport {
+            Some(transport) => transport
+                .send(&message)
+                .map(|_| ())
+                .map_err(MailError::from),
+            None => {
+                log::info!(
+                    "mail (not sent):\n{}",
+                    String::from_utf8_lossy(&message.formatted())
+                );
+                Ok(())
+            }
+        })
+        .await
+        .map_err(|_| MailError::Task)?
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Mailer", |rocket| async move {
+        let mailer = rocket
+            .figment()
+            .extract_inner::<MailConfig>("mail")
+            .map_err(|e| e.to_string())
+            .and_then(|config| Mailer::new(config).map_err(|e| e.to_string()));
+
+        match mailer {
+            Ok(mailer) => Ok(rocket.manage(mailer)),
+            Err(e) => {
+                log::error!("invalid mail configuration: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
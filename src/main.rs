@@ -1,39 +1,134 @@
 #[macro_use]
 extern crate diesel;
+mod admin;
+mod attachments;
+mod auth;
+mod balance;
+mod barcode;
+mod campaigns;
+mod captcha;
+mod cards;
+mod colors;
+mod config;
+mod cookie_policy;
+mod coupons;
+mod csrf;
 mod db;
+mod earning;
+mod export;
+mod geoip;
+mod groups;
+mod icons;
+mod images;
+mod import;
+mod jobs;
+mod locations;
+mod logos;
+mod mail;
+mod metadata;
+mod point_transfers;
+mod points;
+mod pos;
+mod proxies;
+mod quota;
+mod rate_limit;
+mod receipts;
+mod referrals;
+mod reminders;
 mod requests;
+mod retailers;
+mod revisions;
+mod rewards;
+mod search;
+mod share_links;
+mod shares;
+mod stamps;
+mod stats;
+mod storage;
+mod tags;
+mod tiers;
+mod transfers;
+mod wallet;
+use std::io::Cursor;
 use std::num::ParseIntError;
 
-use diesel::{dsl::count_star, prelude::*, result::DatabaseErrorKind};
+use chrono::Utc;
+use diesel::{prelude::*, result::DatabaseErrorKind};
 
-use db::models::{NewLoyalty, NewUser};
+use auth::{AccountReader, LoyaltiesReader, LoyaltiesWriter, VerifiedUser};
+use barcode::BarcodeType;
+use cards::CardFilter;
+use db::crypto::EncryptedString;
+use db::models::{LoyaltyUpdate, NewCardUse};
 use diesel::RunQueryDsl;
-use requests::{AddLoyalty, AddLoyaltyResponse, PageResponse, UserSignIn, UserSignup};
+use logos::Logos;
+use quota::QuotaConfig;
+use referrals::ReferralsConfig;
+use requests::{
+    invalid, AddLoyalty, AddLoyaltyResponse, BatchResponse, CardOrder, DeleteCards, MergeCards,
+    PageResponse, UpdateLoyalty,
+};
+use storage::Storage;
+use tiers::TiersConfig;
 
-use rocket::http::Cookie;
+use rocket::fairing::AdHoc;
 use rocket::{
-    delete, get,
-    http::Status,
-    launch, post, put,
-    request::Outcome,
+    catchers, delete, get,
+    http::{ContentType, Header, Status},
+    launch, patch, post, put,
     response::{status, Responder},
-    routes, Response,
+    routes, Response, State,
 };
-use rocket::{http::CookieJar, request::FromRequest};
 use rocket_contrib::{database, json::Json};
 use thiserror::Error;
-use validator::{Validate, ValidationErrors};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+const MAX_EXPIRY_WINDOW_DAYS: i64 = 366;
+const MAX_BATCH_CARDS: usize = 500;
+const MAX_LIMIT: i64 = 100;
 
 #[derive(Debug, Error)]
-enum APIError {
+pub enum APIError {
     #[error("error during sign in")]
     SignError(#[from] ValidationErrors),
     #[error("query error")]
     DieselError(#[from] diesel::result::Error),
     #[error("not authorised")]
     NotAuthorized,
+    #[error("not found")]
+    NotFound,
+    #[error("email not verified")]
+    EmailNotVerified,
+    #[error("second factor required")]
+    TwoFactorRequired,
+    #[error("conflict")]
+    Conflict,
+    #[error("duplicate card")]
+    DuplicateCard(i32),
     #[error("parsing error")]
     ParsingError(#[from] ParseIntError),
+    #[error("password hashing error")]
+    HashError(#[from] argon2::Error),
+    #[error("token error")]
+    JwtError(#[from] jsonwebtoken::errors::Error),
+    #[error("too many requests")]
+    RateLimited(u64),
+    #[error("upstream request error")]
+    HttpError(#[from] reqwest::Error),
+    #[error("address not allowed")]
+    IpNotAllowed,
+    #[error("account locked")]
+    Locked,
+    #[error("invalid upload")]
+    InvalidUpload,
+    #[error("unsupported file type")]
+    UnsupportedMediaType,
+    #[error("file too large")]
+    PayloadTooLarge,
+    #[error("card quota exceeded")]
+    QuotaExceeded(i64),
+    #[error("storage error")]
+    StorageError(#[from] storage::StorageError),
     #[error("unknown eerror")]
     Unknown,
 }
@@ -53,6 +148,52 @@ impl<'a> Responder<'a, 'static> for APIError {
                 _ => Status::InternalServerError,
             },
             APIError::ParsingError(..) => Status::BadRequest,
+            APIError::NotAuthorized => Status::Unauthorized,
+            APIError::EmailNotVerified => Status::Forbidden,
+            APIError::TwoFactorRequired => Status::Unauthorized,
+            APIError::Conflict => Status::Conflict,
+            APIError::DuplicateCard(existing) => {
+                let body = serde_json::json!({
+                    "error": "duplicate_card",
+                    "message": "a card with this code already exists",
+                    "existing_id": existing,
+                })
+                .to_string();
+                resp.header(ContentType::JSON)
+                    .sized_body(body.len(), Cursor::new(body));
+                Status::Conflict
+            }
+            APIError::QuotaExceeded(max) => {
+                let body = serde_json::json!({
+                    "error": "quota_exceeded",
+                    "message": "no more cards can be added to this account",
+                    "max_cards": max,
+                })
+                .to_string();
+                resp.header(ContentType::JSON)
+                    .sized_body(body.len(), Cursor::new(body));
+                Status::UnprocessableEntity
+            }
+            APIError::NotFound => Status::NotFound,
+            APIError::Locked => Status::Locked,
+            APIError::InvalidUpload => Status::BadRequest,
+            APIError::UnsupportedMediaType => Status::UnsupportedMediaType,
+            APIError::PayloadTooLarge => Status::PayloadTooLarge,
+            APIError::HttpError(..) => Status::BadGateway,
+            APIError::RateLimited(wait) => {
+                resp.header(Header::new("Retry-After", wait.to_string()));
+                Status::TooManyRequests
+            }
+            APIError::IpNotAllowed => {
+                let body = serde_json::json!({
+                    "error": "ip_not_allowed",
+                    "message": "this address may not use admin routes",
+                })
+                .to_string();
+                resp.header(ContentType::JSON)
+                    .sized_body(body.len(), Cursor::new(body));
+                Status::Forbidden
+            }
             _ => Status::InternalServerError,
         };
 
@@ -61,100 +202,132 @@ impl<'a> Responder<'a, 'static> for APIError {
 }
 
 #[database("loyalty_db")]
-struct LoyaltyDbConn(diesel::SqliteConnection);
+pub struct LoyaltyDbConn(diesel::SqliteConnection);
 
 #[launch]
-fn rocket() -> rocket::Rocket {
-    rocket::ignite().attach(LoyaltyDbConn::fairing()).mount(
-        "/",
-        routes![
-            signup,
-            signin,
-            get_user,
-            sign_out,
-            update_loyalty,
-            add_loyalty,
-            get_loyalties,
-            delete_loyalty
-        ],
-    )
-}
-
-#[post("/signup", format = "json", data = "<body>")]
-async fn signup(db: LoyaltyDbConn, body: Json<UserSignup>) -> Result<(), APIError> {
-    body.0.validate()?;
-
-    db.run(move |c| {
-        let new_value = NewUser {
-            email: &body.0.email,
-            name: &body.0.name,
-            pass: &body.0.pass,
-        };
-
-        diesel::insert_into(db::schema::users::table)
-            .values(&new_value)
-            .execute(c)?;
-
-        Ok(())
-    })
-    .await
-}
-
-#[post("/signin", format = "json", data = "<body>")]
-async fn signin(
-    cookies: &CookieJar<'_>,
-    db: LoyaltyDbConn,
-    body: Json<UserSignIn>,
-) -> Result<status::Custom<&'static str>, APIError> {
-    use db::schema::users::dsl::*;
-
-    let user = db
-        .run(move |c| {
-            let req = body.0;
+async fn rocket() -> rocket::Rocket {
+    let figment = config::figment()
+        .await
+        .expect("could not load the configuration");
 
-            users
-                .filter(email.eq(req.email).and(pass.eq(req.pass)))
-                .first::<db::models::User>(c)
-        })
-        .await?;
+    let rocket = rocket::custom(figment)
+        .attach(LoyaltyDbConn::fairing())
+        .attach(db::crypto::fairing())
+        .attach(search::fairing())
+        .attach(proxies::fairing())
+        .attach(admin::fairing())
+        .attach(auth::jwt::fairing())
+        .attach(auth::policy::fairing())
+        .attach(auth::invites::fairing())
+        .attach(mail::fairing())
+        .attach(cookie_policy::fairing())
+        .attach(auth::oauth::fairing())
+        .attach(auth::passkeys::fairing())
+        .attach(captcha::fairing())
+        .attach(geoip::fairing())
+        .attach(storage::fairing())
+        .attach(logos::fairing())
+        .attach(quota::fairing())
+        .attach(points::fairing())
+        .attach(tiers::fairing())
+        .attach(referrals::fairing())
+        .attach(receipts::fairing())
+        .attach(wallet::google::fairing())
+        .attach(rate_limit::RateLimit)
+        .attach(csrf::Csrf)
+        .attach(AdHoc::on_attach("Password Upgrade", |rocket| async move {
+            let conn = match LoyaltyDbConn::get_one(&rocket).await {
+                Some(conn) => conn,
+                None => return Err(rocket),
+            };
 
-    cookies.add_private(Cookie::new("user_id", user.id.to_string()));
-    Ok(status::Custom(Status::Ok, "connected"))
-}
+            match conn.run(|c| auth::password::upgrade_plaintext(c)).await {
+                Ok(0) => Ok(rocket),
+                Ok(count) => {
+                    log::info!("hashed {} plaintext password(s)", count);
+                    Ok(rocket)
+                }
+                Err(e) => {
+                    log::error!("failed to hash plaintext passwords: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(jobs::fairing());
 
-#[post("/signout")]
-async fn sign_out(cookies: &CookieJar<'_>) -> status::Custom<&'static str> {
-    cookies.remove_private(Cookie::named("user_id"));
-    status::Custom(Status::Ok, "logged out")
-}
+    #[cfg(feature = "saml")]
+    let rocket = rocket.attach(auth::saml::fairing());
+    #[cfg(feature = "apple_wallet")]
+    let rocket = rocket
+        .attach(wallet::apple::fairing())
+        .mount("/", wallet::apple::routes());
 
-#[derive(Debug)]
-struct User(i32);
-
-use rocket::async_trait;
-
-#[crate::async_trait]
-impl<'a, 'r> FromRequest<'a, 'r> for User {
-    type Error = APIError;
-
-    async fn from_request(
-        request: &'a rocket::Request<'r>,
-    ) -> rocket::request::Outcome<Self, Self::Error> {
-        if let Some(user) = request
-            .cookies()
-            .get_private("user_id")
-            .and_then(|c| c.value().parse().ok())
-            .map(|id| User(id))
-        {
-            Outcome::Success(user)
-        } else {
-            Outcome::Failure((Status::Forbidden, APIError::NotAuthorized))
-        }
-    }
+    rocket
+        .register(catchers![admin::forbidden])
+        .mount("/", auth::routes())
+        .mount("/", admin::routes())
+        .mount("/", images::routes())
+        .mount("/", attachments::routes())
+        .mount("/", locations::routes())
+        .mount("/", tags::routes())
+        .mount("/", import::routes())
+        .mount("/", export::routes())
+        .mount("/", shares::routes())
+        .mount("/", groups::routes())
+        .mount("/", share_links::routes())
+        .mount("/", stats::routes())
+        .mount("/", retailers::routes())
+        .mount("/", colors::routes())
+        .mount("/", icons::routes())
+        .mount("/", revisions::routes())
+        .mount("/", metadata::routes())
+        .mount("/", balance::routes())
+        .mount("/", points::routes())
+        .mount("/", point_transfers::routes())
+        .mount("/", stamps::routes())
+        .mount("/", rewards::routes())
+        .mount("/", coupons::routes())
+        .mount("/", campaigns::routes())
+        .mount("/", earning::routes())
+        .mount("/", pos::routes())
+        .mount("/", referrals::routes())
+        .mount("/", receipts::routes())
+        .mount("/", tiers::routes())
+        .mount("/", transfers::routes())
+        .mount("/", reminders::routes())
+        .mount("/", wallet::google::routes())
+        .mount(
+            "/",
+            routes![
+                get_user,
+                update_loyalty,
+                patch_loyalty,
+                order_loyalties,
+                merge_loyalties,
+                duplicate_loyalty,
+                add_loyalty,
+                add_loyalties,
+                get_loyalties,
+                get_loyalty,
+                get_loyalty_qr,
+                get_loyalty_barcode,
+                get_trash,
+                get_expiring,
+                restore_loyalty,
+                use_loyalty,
+                toggle_favorite,
+                archive_loyalty,
+                unarchive_loyalty,
+                deactivate_loyalty,
+                activate_loyalty,
+                delete_loyalty,
+                delete_loyalties
+            ],
+        )
 }
 
 #[get("/userinfo")]
-async fn get_user(db: LoyaltyDbConn, user: User) -> Option<Json<db::models::User>> {
+async fn get_user(db: LoyaltyDbConn, user: AccountReader) -> Option<Json<db::models::User>> {
     use db::schema::users::dsl::*;
     let fetched = db
         .run(move |c| {
@@ -182,130 +355,933 @@ async fn get_user(db: LoyaltyDbConn, user: User) -> Option<Json<db::models::User
 #[put("/loyalties", format = "json", data = "<body>")]
 async fn add_loyalty(
     db: LoyaltyDbConn,
-    user: User,
+    _scope: LoyaltiesWriter,
+    user: VerifiedUser,
+    storage: State<'_, Storage>,
+    logos: State<'_, Logos>,
+    quota: State<'_, QuotaConfig>,
+    referrals_config: State<'_, ReferralsConfig>,
+    tiers_config: State<'_, TiersConfig>,
     body: Json<AddLoyalty>,
-) -> Option<Json<AddLoyaltyResponse>> {
-    use db::schema::cards::dsl::*;
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    body.0.validate()?;
+    logos.refresh(&db, &body.0.name).await;
 
-    let last = db
+    let storage = storage.inner().clone();
+    let quota = *quota;
+    let (referrals_config, tiers_config) = (*referrals_config, *tiers_config);
+    let created = db
         .run(move |c| {
-            let new_value = NewLoyalty {
-                name: &body.0.name,
-                color: body.0.color.as_deref(),
-                code: &body.0.code,
-                user_id: user.0,
-            };
+            c.transaction::<_, APIError, _>(|| {
+                quota::check(c, quota, user.0, 1)?;
+                let created = crate::cards::create(c, user.0, &body.0)?;
+                referrals::credit(c, referrals_config, tiers_config, user.0, created.id)?;
+                Ok(crate::cards::describe_one(c, &storage, created)?)
+            })
+        })
+        .await?;
 
-            diesel::insert_into(db::schema::cards::table)
-                .values(&new_value)
-                .execute(c)
-                .ok()?;
+    Ok(Json(created))
+}
 
-            Ok(cards
-                .order(id.desc())
-                .first::<db::models::Loyalty>(c)
-                .ok()?)
+/// Adds many cards at once, such as on the first sync of a device: either
+/// all of them are added, or none. The ids come back in the order given.
+#[post("/loyalties/batch", format = "json", data = "<body>")]
+async fn add_loyalties(
+    db: LoyaltyDbConn,
+    _scope: LoyaltiesWriter,
+    user: VerifiedUser,
+    logos: State<'_, Logos>,
+    quota: State<'_, QuotaConfig>,
+    referrals_config: State<'_, ReferralsConfig>,
+    tiers_config: State<'_, TiersConfig>,
+    body: Json<Vec<AddLoyalty>>,
+) -> Result<status::Custom<Json<BatchResponse>>, APIError> {
+    let batch = body.into_inner();
+    if batch.is_empty() || batch.len() > MAX_BATCH_CARDS {
+        let mut error = ValidationError::new("length");
+        error.message = Some("send 1 to 500 cards".into());
+
+        let mut errors = ValidationErrors::new();
+        errors.add("cards", error);
+        return Err(errors.into());
+    }
+    ValidationErrors::merge_all(
+        Ok(()),
+        "cards",
+        batch.iter().map(Validate::validate).collect(),
+    )?;
+    // Caught here, a code repeated in the batch would otherwise be reported
+    // as a duplicate of a card that is then rolled back.
+    if batch.iter().enumerate().any(|(index, card)| {
+        batch[..index]
+            .iter()
+            .any(|earlier| earlier.code == card.code)
+    }) {
+        let mut error = ValidationError::new("duplicate");
+        error.message = Some("two cards have the same code".into());
+
+        let mut errors = ValidationErrors::new();
+        errors.add("cards", error);
+        return Err(errors.into());
+    }
+
+    let names: Vec<String> = batch.iter().map(|card| card.name.clone()).collect();
+    let quota = *quota;
+    let (referrals_config, tiers_config) = (*referrals_config, *tiers_config);
+    let ids = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                quota::check(c, quota, user.0, batch.len() as i64)?;
+                let mut ids = Vec::new();
+                for (index, card) in batch.iter().enumerate() {
+                    let created = crate::cards::create(c, user.0, card).map_err(|e| match e {
+                        // Tell which card was rejected.
+                        APIError::SignError(errors) => {
+                            let mut results = vec![Ok(()); index];
+                            results.push(Err(errors));
+                            ValidationErrors::merge_all(Ok(()), "cards", results)
+                                .unwrap_err()
+                                .into()
+                        }
+                        e => e,
+                    })?;
+                    ids.push(created.id);
+                }
+                referrals::credit(c, referrals_config, tiers_config, user.0, ids[0])?;
+                Ok(ids)
+            })
         })
         .await?;
 
-    Some(Json(AddLoyaltyResponse {
-        id: last.id,
-        name: last.name,
-        color: last.color,
-        code: last.code,
-    }))
+    let mut looked_up: Vec<&str> = Vec::new();
+    for name in &names {
+        if !looked_up.contains(&name.as_str()) {
+            logos.refresh(&db, name).await;
+            looked_up.push(name);
+        }
+    }
+
+    Ok(status::Custom(Status::Created, Json(BatchResponse { ids })))
 }
 
 #[put("/loyalties/<loyalty_id>", format = "json", data = "<body>")]
 async fn update_loyalty(
     db: LoyaltyDbConn,
-    user: User,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    logos: State<'_, Logos>,
     body: Json<AddLoyalty>,
     loyalty_id: String,
 ) -> Result<Json<AddLoyaltyResponse>, APIError> {
     use db::schema::cards::dsl::*;
 
-    db.run(move |c| {
-        let loyalty_id_int: i32 = loyalty_id.parse()?;
-        let target = cards.filter(id.eq(loyalty_id_int).and(user_id.eq(user.0)));
-
-        let result = diesel::update(target)
-            .set((
-                name.eq(&body.0.name),
-                code.eq(&body.0.code),
-                color.eq(&body.0.color),
-            ))
-            .execute(c);
-
-        match result {
-            Ok(size) if size > 0 => Ok(Json(AddLoyaltyResponse {
-                id: loyalty_id_int,
-                name: body.0.name,
-                color: body.0.color,
-                code: body.0.code,
-            })),
-            Err(e) => Err(APIError::DieselError(e)),
-            _ => Err(APIError::Unknown),
-        }
-    })
-    .await
+    body.0.validate()?;
+    logos.refresh(&db, &body.0.name).await;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let (prefilled_color, prefilled_type) = retailers::prefill(c, &body.0)?;
+                prefilled_type.check(&body.0.code)?;
+                crate::balance::check_pair(
+                    body.0.balance.as_deref(),
+                    body.0.balance_currency.as_deref(),
+                )?;
+                let currency = body
+                    .0
+                    .balance_currency
+                    .as_deref()
+                    .map(crate::balance::normalize_currency);
+                let shared = shares::shared_ids(c, user.0, true)?;
+                let target = cards
+                    .filter(id.eq(loyalty_id))
+                    .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                    .filter(deleted_at.is_null());
+                let current = target
+                    .clone()
+                    .first::<db::models::Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+
+                revisions::record(c, &current, user.0)?;
+                diesel::update(target.clone())
+                    .set((
+                        name.eq(&body.0.name),
+                        code.eq(EncryptedString(body.0.code.clone())),
+                        code_index.eq(db::crypto::blind_index(&body.0.code)),
+                        color.eq(&prefilled_color),
+                        barcode_type.eq(prefilled_type.name()),
+                        notes.eq(body.0.notes.clone().map(EncryptedString)),
+                        expires_at.eq(body.0.expires_at),
+                        retailer_id.eq(body.0.retailer_id),
+                        balance.eq(&body.0.balance),
+                        balance_currency.eq(&currency),
+                        icon.eq(&body.0.icon),
+                        stamps_required.eq(body.0.stamps_required),
+                    ))
+                    .execute(c)?;
+
+                let updated = target.first::<db::models::Loyalty>(c)?;
+                crate::balance::record(c, Some(&current), &updated, user.0)?;
+                search::index(c, updated.id)?;
+                Ok(crate::cards::describe_one(c, &storage, updated)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
+
+#[patch("/loyalties/<loyalty_id>", format = "json", data = "<body>")]
+async fn patch_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    logos: State<'_, Logos>,
+    body: Json<UpdateLoyalty>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+    if let Some(renamed) = &body.0.name {
+        logos.refresh(&db, renamed).await;
+    }
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, true)?;
+            let target = cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null());
+            let current = target
+                .clone()
+                .first::<db::models::Loyalty>(c)
+                .optional()?
+                .ok_or(APIError::NotFound)?;
+
+            // Changing either the code or the format can make them disagree.
+            if body.0.code.is_some() || body.0.barcode_type.is_some() {
+                let kind = body
+                    .0
+                    .barcode_type
+                    .or_else(|| BarcodeType::from_name(&current.barcode_type))
+                    .unwrap_or_default();
+                kind.check(body.0.code.as_deref().unwrap_or(&current.code.0))?;
+            }
+
+            // Clearing the balance clears its currency, unless a new one is
+            // given with it.
+            let new_currency = body
+                .0
+                .balance_currency
+                .as_ref()
+                .map(|currency| currency.as_deref().map(crate::balance::normalize_currency));
+            let new_balance = body.0.balance.as_ref().map(|amount| amount.as_deref());
+            let new_currency = match (new_balance, new_currency) {
+                (Some(None), None) => Some(None),
+                (_, currency) => currency,
+            };
+            crate::balance::check_pair(
+                new_balance.unwrap_or_else(|| current.balance.as_deref()),
+                new_currency
+                    .as_ref()
+                    .map(|currency| currency.as_deref())
+                    .unwrap_or_else(|| current.balance_currency.as_deref()),
+            )?;
+
+            let normalized_color = body.0.color.as_deref().and_then(colors::normalize);
+            let changes = LoyaltyUpdate {
+                name: body.0.name.as_deref(),
+                color: normalized_color.as_deref(),
+                code: body.0.code.clone().map(EncryptedString),
+                barcode_type: body.0.barcode_type.map(BarcodeType::name),
+                notes: body.0.notes.as_ref().map(|text| match text.as_str() {
+                    "" => None,
+                    text => Some(EncryptedString(text.to_string())),
+                }),
+                expires_at: body.0.expires_at,
+                balance: new_balance,
+                balance_currency: new_currency.as_ref().map(|currency| currency.as_deref()),
+                icon: body.0.icon.as_ref().map(|icon| icon.as_deref()),
+                stamps_required: body.0.stamps_required,
+                code_index: body.0.code.as_deref().map(db::crypto::blind_index),
+            };
+
+            // An empty changeset is not a valid UPDATE: just return the card.
+            if changes.name.is_none()
+                && changes.color.is_none()
+                && changes.code.is_none()
+                && changes.barcode_type.is_none()
+                && changes.notes.is_none()
+                && changes.expires_at.is_none()
+                && changes.balance.is_none()
+                && changes.balance_currency.is_none()
+                && changes.icon.is_none()
+                && changes.stamps_required.is_none()
+            {
+                return Ok(crate::cards::describe_one(c, &storage, current)?);
+            }
+
+            let updated = c.transaction(|| {
+                revisions::record(c, &current, user.0)?;
+                diesel::update(target.clone()).set(&changes).execute(c)?;
+                let updated = target.first::<db::models::Loyalty>(c)?;
+                crate::balance::record(c, Some(&current), &updated, user.0)?;
+                search::index(c, updated.id)?;
+                Ok::<_, diesel::result::Error>(updated)
+            })?;
+            Ok::<_, APIError>(crate::cards::describe_one(c, &storage, updated)?)
+        })
+        .await?;
+
+    Ok(Json(updated))
 }
 
-#[get("/loyalties?<limit>&<offset>")]
+#[get("/loyalties?<limit>&<offset>&<tag>&<q>&<include_archived>&<include_inactive>&<sort>&<order>")]
 async fn get_loyalties(
     db: LoyaltyDbConn,
-    user: User,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
     limit: Option<String>,
     offset: Option<String>,
-) -> Option<Json<PageResponse>> {
-    use db::schema::cards::dsl::*;
-
+    tag: Option<String>,
+    q: Option<String>,
+    include_archived: Option<bool>,
+    include_inactive: Option<bool>,
+    sort: Option<String>,
+    order: Option<String>,
+) -> Result<Json<PageResponse>, APIError> {
+    let storage = storage.inner().clone();
     let limit = limit.and_then(|p| p.parse().ok()).unwrap_or(10);
     let offset = offset.and_then(|p| p.parse().ok()).unwrap_or(0);
+    let (sort, order) = crate::cards::sorting(sort.as_deref(), order.as_deref())?;
+    let filter = CardFilter {
+        tag,
+        q,
+        include_archived: include_archived.unwrap_or(false),
+        include_inactive: include_inactive.unwrap_or(false),
+        sort,
+        order,
+    };
 
     let elements = db
         .run(move |c| {
-            // We first count the elenments
+            let (element_count, elements) = filter.page(c, user.0, limit, offset)?;
+            let new = crate::cards::describe(c, &storage, elements)?;
+
+            Ok::<_, diesel::result::Error>(PageResponse {
+                count: element_count,
+                cards: new,
+            })
+        })
+        .await?;
+
+    Ok(Json(elements))
+}
+
+#[get("/loyalties/<loyalty_id>")]
+async fn get_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let found = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let card = cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null())
+                .first::<db::models::Loyalty>(c)
+                .optional()?;
+
+            card.map(|card| crate::cards::describe_one(c, &storage, card))
+                .transpose()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    Ok(Json(found))
+}
+
+/// The code of the card as a QR code, for clients without a barcode library.
+#[get("/loyalties/<loyalty_id>/qr.png?<size>")]
+async fn get_loyalty_qr(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+    size: Option<String>,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let size = size
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(256)
+        .max(64)
+        .min(1024);
+
+    let stored = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null())
+                .select(code)
+                .first::<EncryptedString>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    let png = barcode::qr_png(&stored.0, size).ok_or(APIError::Unknown)?;
+    Ok((ContentType::PNG, png))
+}
+
+/// The card's code drawn in its own format, as `svg` (the default) or `png`.
+/// PDF417 cards can't be drawn and answer 404.
+#[get("/loyalties/<loyalty_id>/barcode?<format>")]
+async fn get_loyalty_barcode(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+    format: Option<String>,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let png = match format.as_deref() {
+        None | Some("svg") => false,
+        Some("png") => true,
+        Some(_) => {
+            return Err(invalid("format", "format is svg or png").into());
+        }
+    };
+
+    let (stored, kind) = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null())
+                .select((code, barcode_type))
+                .first::<(EncryptedString, String)>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    let kind = BarcodeType::from_name(&kind).unwrap_or_default();
+    if png {
+        let image = kind.png(&stored.0).ok_or(APIError::NotFound)?;
+        Ok((ContentType::PNG, image))
+    } else {
+        let image = kind.svg(&stored.0).ok_or(APIError::NotFound)?;
+        Ok((ContentType::SVG, image.into_bytes()))
+    }
+}
+
+/// Merges a duplicate into the card, which is kept, and deletes the
+/// duplicate.
+#[post("/loyalties/<loyalty_id>/merge", format = "json", data = "<body>")]
+async fn merge_loyalties(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    body: Json<MergeCards>,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let other_id = body.0.other_id;
+    if other_id == loyalty_id {
+        return Err(APIError::Conflict);
+    }
+
+    let describer = storage.inner().clone();
+    let (merged, objects) = db
+        .run(move |c| {
+            c.transaction(|| {
+                let owned = |card: i32| {
+                    cards
+                        .filter(id.eq(card).and(user_id.eq(user.0)))
+                        .filter(deleted_at.is_null())
+                        .first::<db::models::Loyalty>(c)
+                        .optional()
+                };
+                let kept = owned(loyalty_id)?.ok_or(APIError::NotFound)?;
+                let other = owned(other_id)?.ok_or(APIError::NotFound)?;
+
+                let objects = crate::cards::merge(c, &kept, &other)?;
+                let merged = cards.find(kept.id).first::<db::models::Loyalty>(c)?;
+                Ok::<_, APIError>((crate::cards::describe_one(c, &describer, merged)?, objects))
+            })
+        })
+        .await?;
+
+    storage.discard(&objects).await;
+    Ok(Json(merged))
+}
+
+/// Copies a card, code included, such as for a second card number of the
+/// same retailer to edit afterwards. Cards shared with the user can be copied
+/// too.
+#[post("/loyalties/<loyalty_id>/duplicate")]
+async fn duplicate_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    quota: State<'_, QuotaConfig>,
+    loyalty_id: String,
+) -> Result<status::Custom<Json<AddLoyaltyResponse>>, APIError> {
+    use db::schema::cards::dsl::*;
 
-            let element_count = cards.select(count_star()).first(c).ok()?;
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let quota = *quota;
+    let copy = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let shared = shares::shared_ids(c, user.0, false)?;
+                let card = cards
+                    .filter(id.eq(loyalty_id))
+                    .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                    .filter(deleted_at.is_null())
+                    .first::<db::models::Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
 
-            let elements = cards
+                quota::check(c, quota, user.0, 1)?;
+                let copy = crate::cards::duplicate(c, user.0, &card)?;
+                Ok(crate::cards::describe_one(c, &storage, copy)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(copy)))
+}
+
+/// Saves the order the user arranged their cards in.
+#[put("/loyalties/order", format = "json", data = "<body>")]
+async fn order_loyalties(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    body: Json<CardOrder>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    db.run(move |c| {
+        c.transaction(|| {
+            let owned = cards
                 .filter(user_id.eq(user.0))
-                .limit(limit)
-                .offset(offset)
-                .load::<db::models::Loyalty>(c)
-                .ok()?;
-
-            let new: Vec<_> = elements
-                .into_iter()
-                .map(|last| AddLoyaltyResponse {
-                    id: last.id,
-                    name: last.name,
-                    color: last.color,
-                    code: last.code,
-                })
-                .collect();
+                .filter(deleted_at.is_null())
+                .order((position.asc(), id.asc()))
+                .select(id)
+                .load::<i32>(c)?;
+            if body.0.ids.iter().any(|card| !owned.contains(card)) {
+                return Err(APIError::NotFound);
+            }
 
-            Ok(PageResponse {
-                count: element_count,
-                cards: new,
+            let mut arranged: Vec<i32> = Vec::with_capacity(owned.len());
+            for card in body.0.ids.iter().chain(owned.iter()) {
+                if !arranged.contains(card) {
+                    arranged.push(*card);
+                }
+            }
+
+            for (index, card) in arranged.into_iter().enumerate() {
+                diesel::update(cards.find(card))
+                    .set(position.eq(index as i32))
+                    .execute(c)?;
+            }
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "cards ordered"))
+}
+
+/// Adds the card to the favorites, or takes it out of them.
+#[post("/loyalties/<loyalty_id>/favorite")]
+async fn toggle_favorite(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            let target = cards
+                .filter(id.eq(loyalty_id).and(user_id.eq(user.0)))
+                .filter(deleted_at.is_null());
+            let updated = diesel::update(target)
+                .set(is_favorite.eq(diesel::dsl::not(is_favorite)))
+                .execute(c)?;
+            if updated == 0 {
+                return Err(APIError::NotFound);
+            }
+
+            let card = target.first::<db::models::Loyalty>(c)?;
+            Ok(crate::cards::describe_one(c, &storage, card)?)
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
+
+/// Hides the card from the main list, without moving it to the trash.
+#[post("/loyalties/<loyalty_id>/archive")]
+async fn archive_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    set_archived(db, user, storage, loyalty_id, true).await
+}
+
+#[post("/loyalties/<loyalty_id>/unarchive")]
+async fn unarchive_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    set_archived(db, user, storage, loyalty_id, false).await
+}
+
+async fn set_archived(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    archived: bool,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            let target = cards
+                .filter(id.eq(loyalty_id).and(user_id.eq(user.0)))
+                .filter(deleted_at.is_null());
+            let card = target
+                .first::<db::models::Loyalty>(c)
+                .optional()?
+                .ok_or(APIError::NotFound)?;
+
+            // Archiving again keeps the original date.
+            if archived != card.archived_at.is_some() {
+                let stamp = if archived {
+                    Some(Utc::now().naive_utc())
+                } else {
+                    None
+                };
+                diesel::update(target)
+                    .set(archived_at.eq(stamp))
+                    .execute(c)?;
+            }
+
+            let card = target.first::<db::models::Loyalty>(c)?;
+            Ok::<_, APIError>(crate::cards::describe_one(c, &storage, card)?)
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
+
+/// Keeps a card that can't be used anymore, such as a lost one, for the
+/// records: it is left out of the main list and can't be added to wallets.
+#[post("/loyalties/<loyalty_id>/deactivate")]
+async fn deactivate_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    set_active(db, user, storage, loyalty_id, false).await
+}
+
+#[post("/loyalties/<loyalty_id>/activate")]
+async fn activate_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    set_active(db, user, storage, loyalty_id, true).await
+}
+
+async fn set_active(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    active: bool,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            let target = cards
+                .filter(id.eq(loyalty_id).and(user_id.eq(user.0)))
+                .filter(deleted_at.is_null());
+            let found = diesel::update(target)
+                .set(is_active.eq(active))
+                .execute(c)?;
+            if found == 0 {
+                return Err(APIError::NotFound);
+            }
+
+            let card = target.first::<db::models::Loyalty>(c)?;
+            Ok(crate::cards::describe_one(c, &storage, card)?)
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
+
+/// Records that the card was just shown at a till, for `sort=last_used`
+/// and `sort=use_count`. Cards shared with the caller can be used too.
+#[post("/loyalties/<loyalty_id>/use")]
+async fn use_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let updated = db
+        .run(move |c| {
+            c.transaction(|| {
+                let now = Utc::now().naive_utc();
+                let shared = shares::shared_ids(c, user.0, false)?;
+                let updated = diesel::update(
+                    cards
+                        .filter(id.eq(loyalty_id))
+                        .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                        .filter(deleted_at.is_null()),
+                )
+                .set((last_used_at.eq(now), use_count.eq(use_count + 1)))
+                .execute(c)?;
+
+                if updated > 0 {
+                    diesel::insert_into(db::schema::card_uses::table)
+                        .values(&NewCardUse {
+                            card_id: loyalty_id,
+                            user_id: user.0,
+                            used_at: now,
+                        })
+                        .execute(c)?;
+                }
+                Ok::<_, diesel::result::Error>(updated)
             })
         })
         .await?;
 
-    Some(Json(elements))
+    match updated {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "card use recorded")),
+    }
 }
 
 #[delete("/loyalties/<loyalty_id>")]
 async fn delete_loyalty(
     db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
     loyalty_id: String,
 ) -> Result<status::Custom<&'static str>, APIError> {
     use db::schema::cards::dsl::*;
 
     let loyalty_id: i32 = loyalty_id.parse()?;
 
-    db.run(move |c| diesel::delete(cards.filter(id.eq(loyalty_id))).execute(c))
-        .await?;
+    db.run(move |c| {
+        diesel::update(
+            cards
+                .filter(id.eq(loyalty_id).and(user_id.eq(user.0)))
+                .filter(deleted_at.is_null()),
+        )
+        .set(deleted_at.eq(Utc::now().naive_utc()))
+        .execute(c)
+    })
+    .await?;
+
     Ok(status::Custom(Status::Ok, "loyalty deleted"))
 }
+
+#[delete("/loyalties", format = "json", data = "<body>")]
+async fn delete_loyalties(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    body: Json<DeleteCards>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    body.0.validate()?;
+    let mut wanted = body.into_inner().ids;
+    wanted.sort_unstable();
+    wanted.dedup();
+
+    db.run(move |c| {
+        c.transaction(|| {
+            let owned = cards
+                .filter(id.eq_any(wanted.clone()))
+                .filter(user_id.eq(user.0))
+                .filter(deleted_at.is_null());
+            let found = owned.clone().select(id).load::<i32>(c)?;
+            if found.len() != wanted.len() {
+                return Err(APIError::NotFound);
+            }
+
+            diesel::update(owned)
+                .set(deleted_at.eq(Utc::now().naive_utc()))
+                .execute(c)?;
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(status::Custom(Status::Ok, "loyalties deleted"))
+}
+
+#[get("/loyalties/trash?<limit>&<offset>")]
+async fn get_trash(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<PageResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let storage = storage.inner().clone();
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(10)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let trashed = db
+        .run(move |c| {
+            let trashed = cards
+                .filter(user_id.eq(user.0))
+                .filter(deleted_at.is_not_null());
+            let count = trashed.count().get_result(c)?;
+            let elements = trashed
+                .order((deleted_at.desc(), id.desc()))
+                .limit(limit)
+                .offset(offset)
+                .load::<db::models::Loyalty>(c)?;
+
+            Ok::<_, diesel::result::Error>(PageResponse {
+                count,
+                cards: crate::cards::describe(c, &storage, elements)?,
+            })
+        })
+        .await?;
+
+    Ok(Json(trashed))
+}
+
+/// Cards expiring in the next `within_days` days, 30 by default, soonest
+/// first. Archived cards are left out.
+#[get("/loyalties/expiring?<within_days>")]
+async fn get_expiring(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    within_days: Option<String>,
+) -> Result<Json<Vec<AddLoyaltyResponse>>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let storage = storage.inner().clone();
+    let within_days: i64 = within_days
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(30)
+        .max(0)
+        .min(MAX_EXPIRY_WINDOW_DAYS);
+    let today = Utc::now().naive_utc().date();
+    let until = today + chrono::Duration::days(within_days);
+
+    let expiring = db
+        .run(move |c| {
+            let found = cards
+                .filter(user_id.eq(user.0))
+                .filter(deleted_at.is_null())
+                .filter(archived_at.is_null())
+                .filter(is_active.eq(true))
+                .filter(expires_at.between(today, until))
+                .order((expires_at.asc(), id.asc()))
+                .load::<db::models::Loyalty>(c)?;
+
+            crate::cards::describe(c, &storage, found)
+        })
+        .await?;
+
+    Ok(Json(expiring))
+}
+
+/// Takes the card out of the trash.
+#[post("/loyalties/<loyalty_id>/restore")]
+async fn restore_loyalty(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    quota: State<'_, QuotaConfig>,
+    loyalty_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let quota = *quota;
+    let restored = db
+        .run(move |c| {
+            let target = cards.filter(id.eq(loyalty_id).and(user_id.eq(user.0)));
+            quota::check(c, quota, user.0, 1)?;
+            let restored = diesel::update(target.filter(deleted_at.is_not_null()))
+                .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
+                .execute(c)?;
+            if restored == 0 {
+                return Err(APIError::NotFound);
+            }
+
+            let card = target.first::<db::models::Loyalty>(c)?;
+            Ok(crate::cards::describe_one(c, &storage, card)?)
+        })
+        .await?;
+
+    Ok(Json(restored))
+}
@@ -0,0 +1,135 @@
+//! Custom fields of cards, such as a membership tier or the phone number on
+//! file, stored as encrypted name/value pairs.
+
+use std::collections::BTreeMap;
+
+use diesel::prelude::*;
+use rocket::{put, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::ValidationErrors;
+
+use crate::auth::LoyaltiesWriter;
+use crate::db::{self, crypto::EncryptedString, models::NewCardMetadata};
+use crate::requests::{invalid, AddLoyaltyResponse, SetMetadata};
+use crate::search;
+use crate::shares;
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_FIELDS: usize = 20;
+const MAX_KEY_CHARS: usize = 50;
+const MAX_VALUE_CHARS: usize = 500;
+
+pub fn routes() -> Vec<Route> {
+    routes![set_metadata]
+}
+
+/// Custom fields of `cards` as `(card, key, value)`.
+pub fn fields_of(c: &SqliteConnection, cards: &[i32]) -> QueryResult<Vec<(i32, String, String)>> {
+    use db::schema::card_metadata::dsl::*;
+
+    let stored = card_metadata
+        .filter(card_id.eq_any(cards.to_vec()))
+        .select((card_id, key, value))
+        .load::<(i32, String, EncryptedString)>(c)?;
+
+    Ok(stored
+        .into_iter()
+        .map(|(card, name, stored)| (card, name, stored.0))
+        .collect())
+}
+
+/// Replaces the custom fields of `card`, and its search entry with them.
+pub fn replace(
+    c: &SqliteConnection,
+    card: i32,
+    fields: &BTreeMap<String, String>,
+) -> QueryResult<usize> {
+    clear(c, card)?;
+
+    let rows: Vec<NewCardMetadata> = fields
+        .iter()
+        .map(|(name, text)| NewCardMetadata {
+            card_id: card,
+            key: name,
+            value: EncryptedString(text.clone()),
+        })
+        .collect();
+    let stored = diesel::insert_into(db::schema::card_metadata::table)
+        .values(&rows)
+        .execute(c)?;
+    search::index(c, card)?;
+
+    Ok(stored)
+}
+
+/// Removes the custom fields of `card`, before it is deleted.
+pub fn clear(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_metadata::dsl::*;
+
+    diesel::delete(card_metadata.filter(card_id.eq(card))).execute(c)
+}
+
+/// Trims the names, rejecting blank or long names and long values on the
+/// `metadata` field.
+fn normalize(
+    fields: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, ValidationErrors> {
+    if fields.len() > MAX_FIELDS {
+        return Err(invalid("metadata", "cards have up to 20 custom fields"));
+    }
+
+    let mut normalized = BTreeMap::new();
+    for (name, text) in fields {
+        let name = name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_KEY_CHARS {
+            return Err(invalid("metadata", "field names need 1 to 50 characters"));
+        }
+        if text.chars().count() > MAX_VALUE_CHARS {
+            return Err(invalid(
+                "metadata",
+                "field values have up to 500 characters",
+            ));
+        }
+        if normalized.insert(name, text).is_some() {
+            return Err(invalid("metadata", "field names must differ"));
+        }
+    }
+
+    Ok(normalized)
+}
+
+#[put("/loyalties/<loyalty_id>/metadata", format = "json", data = "<body>")]
+async fn set_metadata(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    body: Json<SetMetadata>,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::cards;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let fields = normalize(body.into_inner().metadata)?;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let shared = shares::shared_ids(c, user.0, true)?;
+                let card = cards::table
+                    .filter(cards::id.eq(loyalty_id))
+                    .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                    .filter(cards::deleted_at.is_null())
+                    .first::<db::models::Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+
+                replace(c, card.id, &fields)?;
+                Ok(crate::cards::describe_one(c, &storage, card)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
@@ -0,0 +1,307 @@
+//! Points users give one another within the program of a retailer, from a
+//! card of theirs to the favorite or most used card the recipient holds of
+//! the same retailer. Each transfer is written to both ledgers in one
+//! transaction, a debit and a credit, and kept as an audit trail.
+//!
+//! Transfers are capped by the `points` section of the configuration, and
+//! the points received don't count towards tiers. They make a new lot on the
+//! recipient's card, expiring from the day of the transfer.
+
+use chrono::{Duration, Utc};
+use diesel::dsl::sum;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{Loyalty, NewPointTransfer, PointTransfer},
+};
+use crate::mail::Mailer;
+use crate::points::{self, PointsConfig};
+use crate::requests::{invalid, PointTransferResponse, TransferPoints};
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![transfer_points, list_transfers]
+}
+
+/// Clears the side of the transfers `card` took part in, before it and its
+/// ledger are deleted. The other side keeps its record.
+pub fn detach(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::point_transfers::dsl::*;
+
+    let sent = diesel::update(point_transfers.filter(from_card_id.eq(card)))
+        .set((from_card_id.eq(None::<i32>), debit_id.eq(None::<i32>)))
+        .execute(c)?;
+    let received = diesel::update(point_transfers.filter(to_card_id.eq(card)))
+        .set((to_card_id.eq(None::<i32>), credit_id.eq(None::<i32>)))
+        .execute(c)?;
+
+    Ok(sent + received)
+}
+
+/// Clears the side of the transfers `user` took part in, before the account
+/// is purged. The other side keeps its record.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::point_transfers::dsl::*;
+
+    let sent = diesel::update(point_transfers.filter(from_user_id.eq(user)))
+        .set(from_user_id.eq(None::<i32>))
+        .execute(c)?;
+    let received = diesel::update(point_transfers.filter(to_user_id.eq(user)))
+        .set(to_user_id.eq(None::<i32>))
+        .execute(c)?;
+
+    Ok(sent + received)
+}
+
+/// The card of `user` in the program of `retailer` to credit transfers on.
+fn receiving_card(c: &SqliteConnection, user: i32, retailer: i32) -> QueryResult<Option<i32>> {
+    use db::schema::cards::dsl::*;
+
+    cards
+        .filter(user_id.eq(user))
+        .filter(retailer_id.eq(retailer))
+        .filter(deleted_at.is_null())
+        .filter(archived_at.is_null())
+        .filter(is_active.eq(true))
+        .order((is_favorite.desc(), use_count.desc(), id.asc()))
+        .select(id)
+        .first::<i32>(c)
+        .optional()
+}
+
+/// Points `user` gave in the last 24 hours.
+fn sent_today(c: &SqliteConnection, user: i32) -> QueryResult<i64> {
+    use db::schema::point_transfers::dsl::*;
+
+    let since = Utc::now().naive_utc() - Duration::days(1);
+    let sent = point_transfers
+        .filter(from_user_id.eq(user))
+        .filter(created_at.gt(since))
+        .select(sum(points))
+        .first::<Option<i64>>(c)?;
+
+    Ok(sent.unwrap_or(0))
+}
+
+/// Gives points from a card the caller owns to another user holding a card
+/// of the same retailer, within the transfer limits.
+#[post("/points/transfer", format = "json", data = "<body>")]
+async fn transfer_points(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    mailer: State<'_, Mailer>,
+    config: State<'_, PointsConfig>,
+    body: Json<TransferPoints>,
+) -> Result<status::Custom<Json<PointTransferResponse>>, APIError> {
+    use db::schema::{cards, point_transfers, users};
+
+    body.0.validate()?;
+    let config = *config;
+    if body.0.points > config.max_transfer {
+        return Err(invalid("points", "more points than one transfer allows").into());
+    }
+
+    let (transfer, recipient_email, sender_name, card_name) = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let card = cards::table
+                    .filter(cards::id.eq(body.0.card_id))
+                    .filter(cards::user_id.eq(user.0))
+                    .filter(cards::deleted_at.is_null())
+                    .first::<Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                let retailer = card.retailer_id.ok_or_else(|| {
+                    invalid(
+                        "card_id",
+                        "only cards of a retailer's program hold points to give",
+                    )
+                })?;
+                let recipient = users::table
+                    .filter(users::email.eq(&body.0.email))
+                    .filter(users::deleted_at.is_null())
+                    .filter(users::is_guest.eq(false))
+                    .select(users::id)
+                    .first::<i32>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if recipient == user.0 {
+                    return Err(APIError::Conflict);
+                }
+                let target = receiving_card(c, recipient, retailer)?
+                    .ok_or_else(|| invalid("email", "the recipient has no card of this program"))?;
+
+                let wanted = body.0.points;
+                if sent_today(c, user.0)? + i64::from(wanted) > config.daily_transfer_limit {
+                    return Err(
+                        invalid("points", "more points than a day of transfers allows").into(),
+                    );
+                }
+                if points::balance_of(c, card.id)? < i64::from(wanted) {
+                    return Err(invalid("points", "not enough points on the card").into());
+                }
+
+                let now = Utc::now().naive_utc();
+                let sender_name = users::table
+                    .find(user.0)
+                    .select(users::name)
+                    .first::<String>(c)?;
+                let (recipient_name, recipient_email) = users::table
+                    .find(recipient)
+                    .select((users::name, users::email))
+                    .first::<(String, String)>(c)?;
+                let target_name = cards::table
+                    .find(target)
+                    .select(cards::name)
+                    .first::<String>(c)?;
+                let debit = points::record(
+                    c,
+                    card.id,
+                    -wanted,
+                    &format!("Transfer to {}", recipient_name),
+                    now,
+                )?;
+                let credit = points::record(
+                    c,
+                    target,
+                    wanted,
+                    &format!("Transfer from {}", sender_name),
+                    now,
+                )?;
+
+                diesel::insert_into(point_transfers::table)
+                    .values(&NewPointTransfer {
+                        from_user_id: user.0,
+                        to_user_id: recipient,
+                        from_card_id: card.id,
+                        to_card_id: target,
+                        points: wanted,
+                        debit_id: debit.id,
+                        credit_id: credit.id,
+                    })
+                    .execute(c)?;
+                let transfer = point_transfers::table
+                    .filter(point_transfers::debit_id.eq(debit.id))
+                    .first::<PointTransfer>(c)?;
+
+                Ok((transfer, recipient_email, sender_name, target_name))
+            })
+        })
+        .await?;
+
+    let body_text = format!(
+        "{} gave you {} points on your {} card.\n\n{}\n",
+        sender_name,
+        transfer.points,
+        card_name,
+        mailer.link("/points/transfers")
+    );
+    if let Err(e) = mailer
+        .send(&recipient_email, "You received points", body_text)
+        .await
+    {
+        log::warn!("could not send points transfer notification: {}", e);
+    }
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(PointTransferResponse {
+            id: transfer.id,
+            sent: true,
+            email: Some(recipient_email),
+            card_id: transfer.from_card_id,
+            points: transfer.points,
+            created_at: transfer.created_at,
+        }),
+    ))
+}
+
+/// Transfers the caller gave or received, newest first.
+#[get("/points/transfers?<limit>&<offset>")]
+async fn list_transfers(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<PointTransferResponse>>, APIError> {
+    use db::schema::{point_transfers, users};
+
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let found = db
+        .run(move |c| {
+            let transfers = point_transfers::table
+                .filter(
+                    point_transfers::from_user_id
+                        .eq(user.0)
+                        .or(point_transfers::to_user_id.eq(user.0)),
+                )
+                .order(point_transfers::id.desc())
+                .limit(limit)
+                .offset(offset)
+                .load::<PointTransfer>(c)?;
+
+            let others: Vec<i32> = transfers
+                .iter()
+                .filter_map(|transfer| {
+                    if transfer.from_user_id == Some(user.0) {
+                        transfer.to_user_id
+                    } else {
+                        transfer.from_user_id
+                    }
+                })
+                .collect();
+            let emails = users::table
+                .filter(users::id.eq_any(others))
+                .select((users::id, users::email))
+                .load::<(i32, String)>(c)?;
+
+            Ok::<_, diesel::result::Error>(
+                transfers
+                    .into_iter()
+                    .map(|transfer| {
+                        let sent = transfer.from_user_id == Some(user.0);
+                        let other = if sent {
+                            transfer.to_user_id
+                        } else {
+                            transfer.from_user_id
+                        };
+                        let email = emails
+                            .iter()
+                            .find(|(id, _)| Some(*id) == other)
+                            .map(|(_, email)| email.clone());
+
+                        PointTransferResponse {
+                            id: transfer.id,
+                            sent,
+                            email,
+                            card_id: if sent {
+                                transfer.from_card_id
+                            } else {
+                                transfer.to_card_id
+                            },
+                            points: transfer.points,
+                            created_at: transfer.created_at,
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await?;
+
+    Ok(Json(found))
+}
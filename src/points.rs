@@ -0,0 +1,638 @@
+//! The points of a card, as a ledger of what was earned and redeemed.
+//! Entries are only ever appended, a trigger refusing edits; a mistake is
+//! undone by recording the opposite change. The balance is their sum.
+//!
+//! Each earning entry is a lot that spending draws from, oldest first. When
+//! the configuration sets `expiry_months`, what is left of a lot expires that
+//! many months after it was earned: the background jobs record it as spent
+//! and mail the owner.
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use diesel::dsl::sum;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::response::status;
+use rocket::{get, post, routes, FromForm, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{NewPointTransaction, PointTransaction},
+};
+use crate::mail::Mailer;
+use crate::requests::{
+    invalid, ChangePoints, ExpiringPoints, PointTransactionResponse, PointsResponse,
+    TransactionsPage,
+};
+use crate::tiers::{self, TiersConfig};
+use crate::{campaigns, images, shares, APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+/// The most ledger entries a page of transactions walks its balances through.
+const MAX_SPANNED: i64 = 1000;
+
+/// The `points` section of the Rocket configuration.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct PointsConfig {
+    /// Months after which earned points expire; never when left out.
+    pub expiry_months: Option<u32>,
+    /// Points a user may give in one transfer.
+    pub max_transfer: i32,
+    /// Points a user may give in transfers over 24 hours.
+    pub daily_transfer_limit: i64,
+}
+
+impl Default for PointsConfig {
+    fn default() -> Self {
+        PointsConfig {
+            expiry_months: None,
+            max_transfer: 10_000,
+            daily_transfer_limit: 20_000,
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![earn_points, redeem_points, get_points, list_transactions]
+}
+
+/// `when` moved by `months`, on the last day of the month when it has fewer
+/// days, such as from March 31st to February 28th.
+fn add_months(when: NaiveDateTime, months: i32) -> NaiveDateTime {
+    let index = when.year() * 12 + when.month0() as i32 + months;
+    let (year, month) = (index.div_euclid(12), index.rem_euclid(12) as u32 + 1);
+    let days_in_month = match NaiveDate::from_ymd_opt(year, month + 1, 1) {
+        Some(next) => next.pred().day(),
+        // December.
+        None => 31,
+    };
+
+    NaiveDate::from_ymd(year, month, when.day().min(days_in_month)).and_time(when.time())
+}
+
+/// Takes `amount` spent points from the lots of `card`, oldest first.
+fn consume(c: &SqliteConnection, card: i32, mut amount: i32) -> QueryResult<()> {
+    use db::schema::point_transactions::dsl::*;
+
+    let lots = point_transactions
+        .filter(card_id.eq(card))
+        .filter(remaining.gt(0))
+        .order((occurred_at.asc(), id.asc()))
+        .load::<PointTransaction>(c)?;
+    for lot in lots {
+        if amount == 0 {
+            break;
+        }
+        let taken = lot.remaining.min(amount);
+        diesel::update(point_transactions.find(lot.id))
+            .set(remaining.eq(lot.remaining - taken))
+            .execute(c)?;
+        amount -= taken;
+    }
+
+    Ok(())
+}
+
+/// Records an entry in the ledger of `card`, earned points making a new lot.
+/// Access isn't checked, nor is the balance when spending.
+pub fn record(
+    c: &SqliteConnection,
+    card: i32,
+    change: i32,
+    wanted: &str,
+    when: NaiveDateTime,
+) -> QueryResult<PointTransaction> {
+    use db::schema::point_transactions::dsl::*;
+
+    if change < 0 {
+        consume(c, card, -change)?;
+    }
+    diesel::insert_into(point_transactions)
+        .values(&NewPointTransaction {
+            card_id: card,
+            delta: change,
+            reason: wanted,
+            occurred_at: when,
+            remaining: change.max(0),
+        })
+        .execute(c)?;
+
+    point_transactions
+        .filter(card_id.eq(card))
+        .order(id.desc())
+        .first::<PointTransaction>(c)
+}
+
+/// The points on `card`.
+pub fn balance_of(c: &SqliteConnection, card: i32) -> QueryResult<i64> {
+    use db::schema::point_transactions::dsl::*;
+
+    let total = point_transactions
+        .filter(card_id.eq(card))
+        .select(sum(delta))
+        .first::<Option<i64>>(c)?;
+
+    Ok(total.unwrap_or(0))
+}
+
+/// Removes the ledger of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::point_transactions::dsl::*;
+
+    diesel::delete(point_transactions.filter(card_id.eq(card))).execute(c)
+}
+
+/// The reason and date of the change, checked.
+fn entry(body: &ChangePoints) -> Result<(&str, NaiveDateTime), APIError> {
+    let wanted = body.reason.trim();
+    let now = Utc::now().naive_utc();
+    let when = body.occurred_at.unwrap_or(now);
+    if wanted.is_empty() {
+        return Err(invalid("reason", "points need a reason").into());
+    }
+    if when > now {
+        return Err(invalid("occurred_at", "points can't be recorded ahead").into());
+    }
+
+    Ok((wanted, when))
+}
+
+/// Appends `delta` points to the ledger of `card`, which `user` must be able
+/// to edit, in one transaction. Spending draws from the oldest lots and
+/// can't take the balance below zero.
+pub fn append(
+    c: &SqliteConnection,
+    user: i32,
+    card: i32,
+    delta: i32,
+    wanted: &str,
+    when: NaiveDateTime,
+) -> Result<PointTransactionResponse, APIError> {
+    c.transaction(|| {
+        if !images::can_edit(c, user, card)? {
+            return Err(APIError::NotFound);
+        }
+
+        let before = balance_of(c, card)?;
+        if before + i64::from(delta) < 0 {
+            return Err(invalid("points", "not enough points on the card").into());
+        }
+
+        let created = record(c, card, delta, wanted, when)?;
+
+        Ok(PointTransactionResponse {
+            id: created.id,
+            delta: created.delta,
+            reason: created.reason,
+            occurred_at: created.occurred_at,
+            balance: before + i64::from(created.delta),
+        })
+    })
+}
+
+/// Adds points to a card the caller can edit, multiplied by the campaign of
+/// its retailer running when they were earned. This may move its owner up a
+/// tier.
+#[post(
+    "/loyalties/<loyalty_id>/points/earn",
+    format = "json",
+    data = "<body>"
+)]
+async fn earn_points(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    tiers_config: State<'_, TiersConfig>,
+    loyalty_id: String,
+    body: Json<ChangePoints>,
+) -> Result<status::Custom<Json<PointTransactionResponse>>, APIError> {
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+
+    let tiers_config = *tiers_config;
+    let earned = db
+        .run(move |c| {
+            let (wanted, when) = entry(&body.0)?;
+            c.transaction::<_, APIError, _>(|| {
+                let (points, campaign) = campaigns::apply(c, loyalty_id, body.0.points, when)?;
+                let reason = match campaign {
+                    Some(campaign) => format!("{} ({})", wanted, campaign),
+                    None => wanted.to_string(),
+                };
+                let earned = append(c, user.0, loyalty_id, points, &reason, when)?;
+                tiers::refresh(c, tiers_config, loyalty_id)?;
+                Ok(earned)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(earned)))
+}
+
+/// Spends points of a card the caller can edit, when it has enough.
+#[post(
+    "/loyalties/<loyalty_id>/points/redeem",
+    format = "json",
+    data = "<body>"
+)]
+async fn redeem_points(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    body: Json<ChangePoints>,
+) -> Result<status::Custom<Json<PointTransactionResponse>>, APIError> {
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+
+    let redeemed = db
+        .run(move |c| {
+            let (wanted, when) = entry(&body.0)?;
+            append(c, user.0, loyalty_id, -body.0.points, wanted, when)
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(redeemed)))
+}
+
+/// The balance of a card the caller can read, with one page of its ledger.
+#[get("/loyalties/<loyalty_id>/points?<limit>&<offset>")]
+async fn get_points(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    config: State<'_, PointsConfig>,
+    loyalty_id: String,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<PointsResponse>, APIError> {
+    use db::schema::point_transactions;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+    let expiry_months = config.expiry_months;
+
+    let (balance, next_expiry, transactions) = db
+        .run(move |c| {
+            if !shares::can_read(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            let balance = balance_of(c, loyalty_id)?;
+
+            // Lots are spent in the order of the ledger, so the first one
+            // left expires first.
+            let next_expiry = match expiry_months {
+                Some(months) => point_transactions::table
+                    .filter(point_transactions::card_id.eq(loyalty_id))
+                    .filter(point_transactions::remaining.gt(0))
+                    .order((
+                        point_transactions::occurred_at.asc(),
+                        point_transactions::id.asc(),
+                    ))
+                    .first::<PointTransaction>(c)
+                    .optional()?
+                    .map(|lot| ExpiringPoints {
+                        points: lot.remaining,
+                        expires_at: add_months(lot.occurred_at, months as i32),
+                    }),
+                None => None,
+            };
+
+            let page = point_transactions::table
+                .filter(point_transactions::card_id.eq(loyalty_id))
+                .order((
+                    point_transactions::occurred_at.desc(),
+                    point_transactions::id.desc(),
+                ))
+                .limit(limit)
+                .offset(offset)
+                .load::<PointTransaction>(c)?;
+
+            // The page is contiguous, so the running balance is walked back
+            // from the sum up to its newest entry.
+            let mut running = match page.first() {
+                Some(newest) => balance_up_to(c, loyalty_id, newest)?,
+                None => 0,
+            };
+            let mut transactions = Vec::with_capacity(page.len());
+            for entry in page {
+                let after = running;
+                running -= i64::from(entry.delta);
+                transactions.push(PointTransactionResponse {
+                    id: entry.id,
+                    delta: entry.delta,
+                    reason: entry.reason,
+                    occurred_at: entry.occurred_at,
+                    balance: after,
+                });
+            }
+
+            Ok::<_, APIError>((balance, next_expiry, transactions))
+        })
+        .await?;
+
+    Ok(Json(PointsResponse {
+        balance,
+        next_expiry,
+        transactions,
+    }))
+}
+
+/// The balance of `card` right after `entry`, in ledger order.
+fn balance_up_to(c: &SqliteConnection, card: i32, entry: &PointTransaction) -> QueryResult<i64> {
+    use db::schema::point_transactions::dsl::*;
+
+    let total = point_transactions
+        .filter(card_id.eq(card))
+        .filter(
+            occurred_at
+                .lt(entry.occurred_at)
+                .or(occurred_at.eq(entry.occurred_at).and(id.le(entry.id))),
+        )
+        .select(sum(delta))
+        .first::<Option<i64>>(c)?;
+
+    Ok(total.unwrap_or(0))
+}
+
+#[derive(FromForm)]
+struct TransactionFilter {
+    /// `earn` or `redeem`.
+    #[form(field = "type")]
+    kind: Option<String>,
+    /// First day included, such as `2021-01-31`.
+    from: Option<String>,
+    /// Last day included.
+    to: Option<String>,
+}
+
+fn day(field: &'static str, value: Option<&str>) -> Result<Option<NaiveDate>, APIError> {
+    value
+        .map(|value| {
+            value
+                .parse::<NaiveDate>()
+                .map_err(|_| invalid(field, "dates are written YYYY-MM-DD").into())
+        })
+        .transpose()
+}
+
+/// The ledger of a card the caller can read, newest first, a page at a time:
+/// `next_cursor` is passed as `cursor` for the next one, and is `null` on
+/// the last.
+#[get("/loyalties/<loyalty_id>/transactions?<limit>&<cursor>&<filter..>")]
+async fn list_transactions(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+    limit: Option<String>,
+    cursor: Option<String>,
+    filter: Form<TransactionFilter>,
+) -> Result<Json<TransactionsPage>, APIError> {
+    use db::schema::point_transactions::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let limit = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let cursor: Option<i32> = cursor.map(|after| after.parse()).transpose()?;
+    let filter = filter.into_inner();
+    let earned = match filter.kind.as_deref() {
+        None => None,
+        Some("earn") => Some(true),
+        Some("redeem") => Some(false),
+        Some(_) => return Err(invalid("type", "type is earn or redeem").into()),
+    };
+    let from = day("from", filter.from.as_deref())?.map(|from| from.and_hms(0, 0, 0));
+    // Up to the end of the day.
+    let until =
+        day("to", filter.to.as_deref())?.map(|to| (to + Duration::days(1)).and_hms(0, 0, 0));
+
+    let (page, balances, more) = db
+        .run(move |c| {
+            if !shares::can_read(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            let mut query = point_transactions
+                .filter(card_id.eq(loyalty_id))
+                .into_boxed();
+            if let Some(after) = cursor {
+                let (last_at, last_id) = point_transactions
+                    .filter(card_id.eq(loyalty_id))
+                    .filter(id.eq(after))
+                    .select((occurred_at, id))
+                    .first::<(NaiveDateTime, i32)>(c)
+                    .optional()?
+                    .ok_or_else(|| invalid("cursor", "unknown cursor"))?;
+                query = query.filter(
+                    occurred_at
+                        .lt(last_at)
+                        .or(occurred_at.eq(last_at).and(id.lt(last_id))),
+                );
+            }
+            match earned {
+                Some(true) => query = query.filter(delta.gt(0)),
+                Some(false) => query = query.filter(delta.lt(0)),
+                None => {}
+            }
+            if let Some(from) = from {
+                query = query.filter(occurred_at.ge(from));
+            }
+            if let Some(until) = until {
+                query = query.filter(occurred_at.lt(until));
+            }
+
+            // One more tells whether there is a next page.
+            let mut page = query
+                .order((occurred_at.desc(), id.desc()))
+                .limit(limit + 1)
+                .load::<PointTransaction>(c)?;
+            let more = page.len() as i64 > limit;
+            page.truncate(limit as usize);
+
+            // The running balances count the entries filtered out too: they
+            // are walked back from the sum up to the newest entry of the page,
+            // through every entry the page spans. Pages spanning too many are
+            // summed entry by entry instead.
+            let mut balances = Vec::new();
+            if let (Some(newest), Some(oldest)) = (page.first(), page.last()) {
+                let spanned = point_transactions
+                    .filter(card_id.eq(loyalty_id))
+                    .filter(
+                        occurred_at
+                            .lt(newest.occurred_at)
+                            .or(occurred_at.eq(newest.occurred_at).and(id.le(newest.id))),
+                    )
+                    .filter(
+                        occurred_at
+                            .gt(oldest.occurred_at)
+                            .or(occurred_at.eq(oldest.occurred_at).and(id.ge(oldest.id))),
+                    )
+                    .order((occurred_at.desc(), id.desc()))
+                    .select((id, delta))
+                    .limit(MAX_SPANNED + 1)
+                    .load::<(i32, i32)>(c)?;
+                if spanned.len() as i64 > MAX_SPANNED {
+                    for entry in &page {
+                        balances.push((entry.id, balance_up_to(c, loyalty_id, entry)?));
+                    }
+                } else {
+                    let mut running = balance_up_to(c, loyalty_id, newest)?;
+                    for (entry, change) in spanned {
+                        balances.push((entry, running));
+                        running -= i64::from(change);
+                    }
+                }
+            }
+
+            Ok((page, balances, more))
+        })
+        .await?;
+
+    let next_cursor = match page.last() {
+        Some(last) if more => Some(last.id.to_string()),
+        _ => None,
+    };
+    let transactions = page
+        .into_iter()
+        .map(|entry| PointTransactionResponse {
+            balance: balances
+                .iter()
+                .find(|(found, _)| *found == entry.id)
+                .map_or(0, |(_, balance)| *balance),
+            id: entry.id,
+            delta: entry.delta,
+            reason: entry.reason,
+            occurred_at: entry.occurred_at,
+        })
+        .collect();
+
+    Ok(Json(TransactionsPage {
+        transactions,
+        next_cursor,
+    }))
+}
+
+/// Points that expired on a card, to tell its owner about.
+struct Expired {
+    email: String,
+    card_name: String,
+    points: i64,
+}
+
+/// Records as spent what is left of the lots earned more than `months` ago,
+/// returning what to mail. Owners of deleted accounts or of cards in the
+/// trash aren't told.
+fn expire(c: &SqliteConnection, months: u32) -> QueryResult<Vec<Expired>> {
+    use db::schema::{cards, point_transactions, users};
+
+    c.transaction(|| {
+        let cutoff = add_months(Utc::now().naive_utc(), -(months as i32));
+        let lots = point_transactions::table
+            .filter(point_transactions::remaining.gt(0))
+            .filter(point_transactions::occurred_at.le(cutoff))
+            .order((
+                point_transactions::card_id.asc(),
+                point_transactions::occurred_at.asc(),
+            ))
+            .load::<PointTransaction>(c)?;
+
+        let mut expired: Vec<(i32, i64)> = Vec::new();
+        for lot in lots {
+            // Spending draws from this lot first, being the oldest.
+            record(
+                c,
+                lot.card_id,
+                -lot.remaining,
+                "Points expired",
+                Utc::now().naive_utc(),
+            )?;
+            match expired.iter_mut().find(|(card, _)| *card == lot.card_id) {
+                Some((_, points)) => *points += i64::from(lot.remaining),
+                None => expired.push((lot.card_id, i64::from(lot.remaining))),
+            }
+        }
+
+        let mut notices = Vec::with_capacity(expired.len());
+        for (card, points) in expired {
+            let owner = cards::table
+                .inner_join(users::table)
+                .filter(cards::id.eq(card))
+                .filter(cards::deleted_at.is_null())
+                .filter(users::deleted_at.is_null())
+                .select((users::email, cards::name))
+                .first::<(String, String)>(c)
+                .optional()?;
+            if let Some((email, card_name)) = owner {
+                notices.push(Expired {
+                    email,
+                    card_name,
+                    points,
+                });
+            }
+        }
+
+        Ok(notices)
+    })
+}
+
+/// Expires the points due to, mailing their owners. Returns on how many
+/// cards points expired that were told about.
+pub async fn expire_due(
+    conn: &LoyaltyDbConn,
+    mailer: &Mailer,
+    config: PointsConfig,
+) -> QueryResult<usize> {
+    let months = match config.expiry_months {
+        Some(months) => months,
+        None => return Ok(0),
+    };
+    let expired = conn.run(move |c| expire(c, months)).await?;
+
+    for notice in &expired {
+        let body = format!(
+            "{} points on your {} card expired, {} months after they were earned.\n",
+            notice.points, notice.card_name, months
+        );
+        let subject = format!("Points expired: {}", notice.card_name);
+        if let Err(e) = mailer.send(&notice.email, &subject, body).await {
+            log::warn!("could not send points expiry notice: {}", e);
+        }
+    }
+
+    Ok(expired.len())
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Points Config", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<PointsConfig>("points") {
+            Ok(config) if config.expiry_months == Some(0) => {
+                log::error!("invalid points configuration: expiry_months must be positive");
+                return Err(rocket);
+            }
+            Ok(config) if config.max_transfer <= 0 || config.daily_transfer_limit <= 0 => {
+                log::error!("invalid points configuration: transfer limits must be positive");
+                return Err(rocket);
+            }
+            Ok(config) => config,
+            Err(e) if e.missing() => PointsConfig::default(),
+            Err(e) => {
+                log::error!("invalid points configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,196 @@
+//! Points of sale of merchants, posting the purchases of cards scanned at the
+//! till to `POST /pos/purchases` with a key administrators issue for the
+//! retailer, in the `X-Pos-Key` header. Purchases earn points by the earning
+//! rules of the retailer, multiplied by its running campaign.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status;
+use rocket::{post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::token;
+use crate::db::{
+    self, crypto,
+    models::{NewPurchase, PosKey, Purchase},
+};
+use crate::requests::{invalid, PurchaseEvent, PurchaseResponse};
+use crate::tiers::{self, TiersConfig};
+use crate::{campaigns, earning, points, APIError, LoyaltyDbConn};
+
+pub const HEADER: &str = "X-Pos-Key";
+
+pub const KEY_PREFIX: &str = "pk_";
+
+pub fn routes() -> Vec<Route> {
+    routes![post_purchase]
+}
+
+/// A new raw key, with the prefix shown in listings to tell keys apart.
+pub fn generate() -> (String, String) {
+    let raw = format!("{}{}", KEY_PREFIX, token::generate());
+    let prefix = raw[..KEY_PREFIX.len() + 6].to_string();
+    (raw, prefix)
+}
+
+/// Resolves a raw key to its retailer, recording when it was last used.
+fn resolve(c: &SqliteConnection, raw: &str) -> QueryResult<Option<i32>> {
+    use db::schema::pos_keys::dsl::*;
+
+    let key = pos_keys
+        .filter(key_hash.eq(token::digest(raw)))
+        .first::<PosKey>(c)
+        .optional()?;
+
+    match key {
+        Some(key) => {
+            diesel::update(&key)
+                .set(last_used_at.eq(Utc::now().naive_utc()))
+                .execute(c)?;
+            Ok(Some(key.retailer_id))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A point of sale, authenticated with a key of the retailer it holds.
+#[derive(Debug)]
+pub struct PosTerminal(pub i32);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for PosTerminal {
+    type Error = APIError;
+
+    async fn from_request(request: &'a rocket::Request<'r>) -> Outcome<Self, Self::Error> {
+        let raw = match request.headers().get_one(HEADER) {
+            Some(raw) => raw.to_string(),
+            None => return Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+        };
+        let db = match request.guard::<LoyaltyDbConn>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Failure((Status::ServiceUnavailable, APIError::Unknown)),
+        };
+
+        match db.run(move |c| resolve(c, &raw)).await {
+            Ok(Some(retailer)) => Outcome::Success(PosTerminal(retailer)),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, APIError::NotAuthorized)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, APIError::DieselError(e))),
+        }
+    }
+}
+
+/// The card in use of `retailer` whose code is `scanned`, the oldest when
+/// several are. Codes are encrypted, so cards are found by their blind index.
+fn scanned_card(c: &SqliteConnection, retailer: i32, scanned: &str) -> QueryResult<Option<i32>> {
+    use db::schema::cards::dsl::*;
+
+    let digest = match crypto::blind_index(scanned) {
+        Some(digest) => digest,
+        None => return Ok(None),
+    };
+
+    cards
+        .filter(retailer_id.eq(retailer))
+        .filter(code_index.eq(digest))
+        .filter(deleted_at.is_null())
+        .filter(archived_at.is_null())
+        .filter(is_active.eq(true))
+        .order(id.asc())
+        .select(id)
+        .first::<i32>(c)
+        .optional()
+}
+
+/// Removes the purchases of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::purchases::dsl::*;
+
+    diesel::delete(purchases.filter(card_id.eq(card))).execute(c)
+}
+
+/// Credits a purchase to the card scanned at the till. A purchase whose
+/// reference was already posted is refused, so retries don't earn twice.
+#[post("/pos/purchases", format = "json", data = "<body>")]
+async fn post_purchase(
+    db: LoyaltyDbConn,
+    terminal: PosTerminal,
+    tiers_config: State<'_, TiersConfig>,
+    body: Json<PurchaseEvent>,
+) -> Result<status::Custom<Json<PurchaseResponse>>, APIError> {
+    use db::schema::purchases;
+
+    body.0.validate()?;
+    for item in &body.0.items {
+        item.validate()?;
+    }
+    let now = Utc::now().naive_utc();
+    let when = body.0.occurred_at.unwrap_or(now);
+    if when > now {
+        return Err(invalid("occurred_at", "purchases can't be posted ahead").into());
+    }
+
+    let tiers_config = *tiers_config;
+    let retailer = terminal.0;
+    let posted = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let event = &body.0;
+                let card =
+                    scanned_card(c, retailer, event.card_code.trim())?.ok_or(APIError::NotFound)?;
+                let existing = purchases::table
+                    .filter(purchases::retailer_id.eq(retailer))
+                    .filter(purchases::reference.eq(&event.reference))
+                    .select(purchases::id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if existing.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                let base = earning::points_for(c, retailer, event.amount_cents, &event.items)?;
+                let mut earned = 0;
+                if base > 0 {
+                    let (multiplied, campaign) = campaigns::apply(c, card, base, when)?;
+                    let reason = match campaign {
+                        Some(campaign) => format!("Purchase {} ({})", event.reference, campaign),
+                        None => format!("Purchase {}", event.reference),
+                    };
+                    points::record(c, card, multiplied, &reason, when)?;
+                    tiers::refresh(c, tiers_config, card)?;
+                    earned = multiplied;
+                }
+
+                diesel::insert_into(purchases::table)
+                    .values(&NewPurchase {
+                        retailer_id: retailer,
+                        card_id: card,
+                        reference: &event.reference,
+                        amount_cents: event.amount_cents,
+                        points: earned,
+                        occurred_at: when,
+                    })
+                    .execute(c)?;
+
+                Ok(purchases::table
+                    .filter(purchases::retailer_id.eq(retailer))
+                    .filter(purchases::reference.eq(&event.reference))
+                    .first::<Purchase>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(PurchaseResponse {
+            id: posted.id,
+            card_id: posted.card_id,
+            reference: posted.reference,
+            amount_cents: posted.amount_cents,
+            points: posted.points,
+            occurred_at: posted.occurred_at,
+        }),
+    ))
+}
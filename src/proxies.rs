@@ -0,0 +1,51 @@
+//! The address requests come from. Clients can send any `X-Real-IP` header
+//! they like, so it is only believed from the reverse proxies listed in the
+//! `proxies` section; every other request is taken from its peer address.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use rocket::fairing::AdHoc;
+use rocket::Request;
+use serde::Deserialize;
+
+/// The `proxies` section of the Rocket configuration.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct ProxiesConfig {
+    /// CIDR ranges of the reverse proxies setting `X-Real-IP`. Empty trusts
+    /// none.
+    pub trusted: Vec<IpNet>,
+}
+
+impl ProxiesConfig {
+    fn trusts(&self, peer: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(&peer))
+    }
+}
+
+/// The address of the client of `request`: its peer, or the address the
+/// peer forwards for when it is a trusted proxy.
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let peer = request.remote().map(|addr| addr.ip())?;
+
+    match request.managed_state::<ProxiesConfig>() {
+        Some(config) if config.trusts(peer) => request.real_ip().or(Some(peer)),
+        _ => Some(peer),
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Trusted Proxies", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<ProxiesConfig>("proxies") {
+            Ok(config) => config,
+            Err(e) if e.missing() => ProxiesConfig::default(),
+            Err(e) => {
+                log::error!("invalid proxies configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,63 @@
+//! How many cards a user may keep, for free-tier deployments. Cards in the
+//! trash don't count, so restoring one is checked too.
+
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::db;
+use crate::APIError;
+
+/// The `quota` section of the Rocket configuration.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Unlimited when left out.
+    pub max_cards_per_user: Option<i64>,
+}
+
+/// How many more cards `user` may have, `None` when there is no limit.
+pub fn remaining(c: &SqliteConnection, config: QuotaConfig, user: i32) -> QueryResult<Option<i64>> {
+    use db::schema::cards::dsl::*;
+
+    let max = match config.max_cards_per_user {
+        Some(max) => max,
+        None => return Ok(None),
+    };
+    let owned = cards
+        .filter(user_id.eq(user))
+        .filter(deleted_at.is_null())
+        .count()
+        .get_result::<i64>(c)?;
+
+    Ok(Some((max - owned).max(0)))
+}
+
+/// Fails unless `user` may have `adding` more cards. Meant to run in the
+/// transaction that adds them.
+pub fn check(
+    c: &SqliteConnection,
+    config: QuotaConfig,
+    user: i32,
+    adding: i64,
+) -> Result<(), APIError> {
+    match (remaining(c, config, user)?, config.max_cards_per_user) {
+        (Some(left), Some(max)) if left < adding => Err(APIError::QuotaExceeded(max)),
+        _ => Ok(()),
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Quota Config", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<QuotaConfig>("quota") {
+            Ok(config) => config,
+            Err(e) if e.missing() => QuotaConfig::default(),
+            Err(e) => {
+                log::error!("invalid quota configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, Response, Rocket};
+use serde::Deserialize;
+
+use crate::{proxies, APIError};
+
+/// Buckets are pruned once the map grows past this size.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct Quota {
+    /// Number of requests allowed in a burst.
+    pub requests: u32,
+    /// Seconds needed to refill the whole burst.
+    pub period: u64,
+}
+
+impl Quota {
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.period.max(1) as f64
+    }
+}
+
+/// IPs collecting `strikes` within `window` seconds are banned for
+/// `duration` seconds.
+#[derive(Clone, Copy, Deserialize)]
+pub struct BanConfig {
+    pub strikes: u32,
+    pub window: u64,
+    pub duration: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            strikes: 10,
+            window: 10 * 60,
+            duration: 15 * 60,
+        }
+    }
+}
+
+/// The `rate_limit` section of the Rocket configuration.
+#[derive(Clone, Copy, Deserialize)]
+pub struct RateLimitConfig {
+    pub per_ip: Quota,
+    pub per_account: Quota,
+    #[serde(default)]
+    pub ban: BanConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_ip: Quota {
+                requests: 20,
+                period: 60,
+            },
+            per_account: Quota {
+                requests: 5,
+                period: 60,
+            },
+            ban: BanConfig::default(),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    quota: Quota,
+    entries: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    fn new(quota: Quota) -> Self {
+        Buckets {
+            quota,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or returns the number of seconds to wait.
+    fn take(&self, key: &str) -> Result<(), u64> {
+        let capacity = self.quota.requests as f64;
+        let rate = self.quota.rate();
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() > MAX_TRACKED_KEYS {
+            entries.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = entries.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+}
+
+struct Record {
+    strikes: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// A snapshot of an IP's standing, for the admin endpoints.
+pub struct Standing {
+    pub ip: String,
+    pub strikes: u32,
+    /// Seconds left on the ban, if the IP is banned.
+    pub banned_for: Option<u64>,
+}
+
+/// Strikes and temporary bans of misbehaving IPs.
+struct Reputation {
+    config: BanConfig,
+    entries: Mutex<HashMap<String, Record>>,
+}
+
+impl Reputation {
+    fn new(config: BanConfig) -> Self {
+        Reputation {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the seconds left if `ip` is banned.
+    fn banned(&self, ip: &str) -> Option<u64> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(ip)
+            .and_then(|record| record.banned_until)
+            .filter(|until| *until > now)
+            .map(|until| until.duration_since(now).as_secs().max(1))
+    }
+
+    fn strike(&self, ip: &str) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() > MAX_TRACKED_KEYS {
+            entries.retain(|_, r| {
+                now.duration_since(r.window_start) < window
+                    || r.banned_until.map_or(false, |until| until > now)
+            });
+        }
+
+        let record = entries.entry(ip.to_string()).or_insert(Record {
+            strikes: 0,
+            window_start: now,
+            banned_until: None,
+        });
+
+        if now.duration_since(record.window_start) >= window {
+            record.strikes = 0;
+            record.window_start = now;
+        }
+
+        record.strikes += 1;
+        if record.strikes >= self.config.strikes {
+            record.banned_until = Some(now + Duration::from_secs(self.config.duration));
+            record.strikes = 0;
+            record.window_start = now;
+            log::warn!("banned {} for {} seconds", ip, self.config.duration);
+        }
+    }
+
+    fn standings(&self) -> Vec<Standing> {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .iter()
+            .filter_map(|(ip, record)| {
+                let banned_for = record
+                    .banned_until
+                    .filter(|until| *until > now)
+                    .map(|until| until.duration_since(now).as_secs().max(1));
+                let strikes = match now.duration_since(record.window_start) < window {
+                    true => record.strikes,
+                    false => 0,
+                };
+
+                match (strikes, banned_for) {
+                    (0, None) => None,
+                    _ => Some(Standing {
+                        ip: ip.clone(),
+                        strikes,
+                        banned_for,
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    fn clear(&self, ip: &str) -> bool {
+        self.entries.lock().unwrap().remove(ip).is_some()
+    }
+}
+
+pub struct RateLimiter {
+    per_ip: Buckets,
+    per_account: Buckets,
+    reputation: Reputation,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            per_ip: Buckets::new(config.per_ip),
+            per_account: Buckets::new(config.per_account),
+            reputation: Reputation::new(config.ban),
+        }
+    }
+
+    pub fn check_account(&self, account: &str) -> Result<(), APIError> {
+        self.per_account
+            .take(&account.trim().to_lowercase())
+            .map_err(APIError::RateLimited)
+    }
+
+    /// Counts a failed attempt against `ip`; enough of them ban it.
+    pub fn strike(&self, ip: &str) {
+        self.reputation.strike(ip);
+    }
+
+    pub fn standings(&self) -> Vec<Standing> {
+        self.reputation.standings()
+    }
+
+    /// Lifts the ban and strikes of `ip`. Returns whether it had any.
+    pub fn clear(&self, ip: &str) -> bool {
+        self.reputation.clear(ip)
+    }
+}
+
+/// Seconds to wait, remembered for the response fairing when a guard
+/// rejects the request before any handler runs.
+struct RetryAfter(Option<u64>);
+
+/// Request guard rejecting banned IPs and spending one token from the client
+/// IP's bucket. Running out of tokens counts as a strike.
+pub struct IpThrottle;
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for IpThrottle {
+    type Error = APIError;
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let limiter = match request.managed_state::<RateLimiter>() {
+            Some(limiter) => limiter,
+            None => return Outcome::Success(IpThrottle),
+        };
+
+        let ip = proxies::client_ip(request)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+
+        let throttled = match limiter.reputation.banned(&ip) {
+            Some(wait) => Err(wait),
+            None => limiter.per_ip.take(&ip).map_err(|wait| {
+                limiter.strike(&ip);
+                wait
+            }),
+        };
+
+        match throttled {
+            Ok(()) => Outcome::Success(IpThrottle),
+            Err(wait) => {
+                request.local_cache(|| RetryAfter(Some(wait)));
+                Outcome::Failure((Status::TooManyRequests, APIError::RateLimited(wait)))
+            }
+        }
+    }
+}
+
+/// Loads the `rate_limit` configuration and adds `Retry-After` to 429
+/// responses produced by `IpThrottle`.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limit",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    async fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let config = match rocket
+            .figment()
+            .extract_inner::<RateLimitConfig>("rate_limit")
+        {
+            Ok(config) => config,
+            Err(e) if e.missing() => RateLimitConfig::default(),
+            Err(e) => {
+                log::error!("invalid rate_limit configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(RateLimiter::new(config)))
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.status() != Status::TooManyRequests
+            || response.headers().contains("Retry-After")
+        {
+            return;
+        }
+
+        if let RetryAfter(Some(wait)) = request.local_cache(|| RetryAfter(None)) {
+            response.set_header(Header::new("Retry-After", wait.to_string()));
+        }
+    }
+}
@@ -0,0 +1,479 @@
+//! Receipt photos users upload to earn points on a card, uploaded as
+//! `multipart/form-data` with the photo in a `file` field. Each waits in a
+//! queue for review: when the `receipts` section of the configuration sets
+//! an `ocr_url`, the background jobs send the queued photos there and keep
+//! the total read, suggesting the points the earning rules of the card's
+//! retailer award for it. An administrator then approves the receipt,
+//! crediting the points, or rejects it.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::data::Data;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::response::status;
+use rocket::{get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+use crate::auth::{token, LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{NewReceipt, Receipt},
+};
+use crate::requests::{invalid, ReceiptResponse};
+use crate::storage::Storage;
+use crate::tiers::{self, TiersConfig};
+use crate::{campaigns, earning, images, shares, APIError, LoyaltyDbConn};
+
+pub const PENDING: &str = "pending";
+pub const APPROVED: &str = "approved";
+pub const REJECTED: &str = "rejected";
+
+const FIELD: &str = "file";
+const MAX_BYTES: usize = 10 * 1024 * 1024;
+/// Receipts sent to the OCR service per run.
+const BATCH: i64 = 10;
+/// Reading a photo slower than this is given up, leaving it to review.
+const TIMEOUT: Duration = Duration::from_secs(30);
+const REASON: &str = "Receipt";
+
+/// The `receipts` section of the Rocket configuration.
+#[derive(Clone, Deserialize)]
+pub struct ReceiptsConfig {
+    /// Endpoint taking the photo as the request body and answering with
+    /// `{ "total": 12.5 }`, the total being `null` when it can't be read.
+    pub ocr_url: String,
+}
+
+/// The OCR service, when one is configured.
+#[derive(Clone)]
+pub struct Ocr {
+    config: Option<ReceiptsConfig>,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct Reading {
+    total: Option<f64>,
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![upload_receipt, get_receipts, get_receipt]
+}
+
+/// `download` is the route serving the photo when the storage backend can't
+/// sign links, which differs for administrators.
+pub fn describe(storage: &Storage, receipt: Receipt, download: String) -> ReceiptResponse {
+    let url = storage.presign(&receipt.storage_key).unwrap_or(download);
+
+    ReceiptResponse {
+        id: receipt.id,
+        card_id: receipt.card_id,
+        status: receipt.status,
+        total: receipt.total,
+        suggested_points: receipt.suggested_points,
+        points: receipt.points,
+        created_at: receipt.created_at,
+        reviewed_at: receipt.reviewed_at,
+        url,
+    }
+}
+
+fn download_route(receipt: &Receipt) -> String {
+    format!("/loyalties/{}/receipts/{}", receipt.card_id, receipt.id)
+}
+
+/// Removes the receipts of `card`, returning the keys of their photos to
+/// delete from storage.
+pub fn detach(c: &SqliteConnection, card: i32) -> QueryResult<Vec<String>> {
+    use db::schema::receipts::dsl::*;
+
+    let keys = receipts
+        .filter(card_id.eq(card))
+        .select(storage_key)
+        .load::<String>(c)?;
+    diesel::delete(receipts.filter(card_id.eq(card))).execute(c)?;
+
+    Ok(keys)
+}
+
+/// Removes the receipts `user` uploaded to cards shared with them, before
+/// the account is purged, returning the keys of their photos.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<Vec<String>> {
+    use db::schema::receipts::dsl::*;
+
+    let keys = receipts
+        .filter(user_id.eq(user))
+        .select(storage_key)
+        .load::<String>(c)?;
+    diesel::delete(receipts.filter(user_id.eq(user))).execute(c)?;
+
+    Ok(keys)
+}
+
+/// Approves a pending receipt, crediting `credited` points or else those
+/// suggested, multiplied by the campaign running when it was uploaded. This
+/// may move the card's owner up a tier. Cards in the trash earn nothing.
+pub fn approve(
+    c: &SqliteConnection,
+    tiers_config: TiersConfig,
+    receipt: i32,
+    credited: Option<i32>,
+) -> Result<Receipt, APIError> {
+    use db::schema::cards;
+    use db::schema::receipts::dsl::*;
+
+    c.transaction(|| {
+        let found = receipts
+            .find(receipt)
+            .first::<Receipt>(c)
+            .optional()?
+            .ok_or(APIError::NotFound)?;
+        let wanted = match credited.or(found.suggested_points) {
+            Some(wanted) if wanted > 0 => wanted,
+            _ => return Err(invalid("points", "the receipt needs points to credit").into()),
+        };
+        let card = cards::table
+            .find(found.card_id)
+            .filter(cards::deleted_at.is_null())
+            .select(cards::id)
+            .first::<i32>(c)
+            .optional()?;
+        if card.is_none() {
+            return Err(invalid("card_id", "the card of the receipt is in the trash").into());
+        }
+
+        let reviewed = diesel::update(receipts.find(found.id).filter(status.eq(PENDING)))
+            .set((status.eq(APPROVED), reviewed_at.eq(Utc::now().naive_utc())))
+            .execute(c)?;
+        if reviewed == 0 {
+            return Err(APIError::Conflict);
+        }
+
+        let (earned, campaign) = campaigns::apply(c, found.card_id, wanted, found.created_at)?;
+        let reason = match campaign {
+            Some(campaign) => format!("{} ({})", REASON, campaign),
+            None => REASON.to_string(),
+        };
+        // `points` is the column here.
+        crate::points::record(c, found.card_id, earned, &reason, found.created_at)?;
+        tiers::refresh(c, tiers_config, found.card_id)?;
+
+        diesel::update(receipts.find(found.id))
+            .set(points.eq(earned))
+            .execute(c)?;
+        Ok(receipts.find(found.id).first::<Receipt>(c)?)
+    })
+}
+
+/// Rejects a pending receipt, crediting nothing.
+pub fn reject(c: &SqliteConnection, receipt: i32) -> Result<Receipt, APIError> {
+    use db::schema::receipts::dsl::*;
+
+    c.transaction(|| {
+        let found = receipts
+            .find(receipt)
+            .select(id)
+            .first::<i32>(c)
+            .optional()?
+            .ok_or(APIError::NotFound)?;
+        let reviewed = diesel::update(receipts.find(found).filter(status.eq(PENDING)))
+            .set((status.eq(REJECTED), reviewed_at.eq(Utc::now().naive_utc())))
+            .execute(c)?;
+        if reviewed == 0 {
+            return Err(APIError::Conflict);
+        }
+
+        Ok(receipts.find(found).first::<Receipt>(c)?)
+    })
+}
+
+/// The points the earning rules of the retailer of `card` award for a
+/// receipt of `cents`, `None` when they award none.
+fn suggest(c: &SqliteConnection, card: i32, cents: i32) -> QueryResult<Option<i32>> {
+    use db::schema::cards;
+
+    let retailer = cards::table
+        .find(card)
+        .select(cards::retailer_id)
+        .first::<Option<i32>>(c)
+        .optional()?
+        .flatten();
+    let retailer = match retailer {
+        Some(retailer) => retailer,
+        None => return Ok(None),
+    };
+
+    let suggested = earning::points_for(c, retailer, cents, &[])?;
+    Ok(Some(suggested).filter(|suggested| *suggested > 0))
+}
+
+impl Ocr {
+    pub fn is_configured(&self) -> bool {
+        self.config.is_some()
+    }
+
+    async fn read(
+        &self,
+        config: &ReceiptsConfig,
+        bytes: Vec<u8>,
+        kind: &str,
+    ) -> Result<Option<f64>, APIError> {
+        let reading: Reading = self
+            .http
+            .post(&config.ocr_url)
+            .header("Content-Type", kind)
+            .body(bytes)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(reading
+            .total
+            .filter(|total| total.is_finite() && *total > 0.0))
+    }
+
+    /// Sends the next queued receipts to the OCR service, returning how many
+    /// were read. Those it fails to fetch or read are left to review without
+    /// a total.
+    pub async fn read_queued(
+        &self,
+        conn: &LoyaltyDbConn,
+        storage: &Storage,
+    ) -> Result<usize, APIError> {
+        use db::schema::receipts::dsl::*;
+
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(0),
+        };
+
+        let queued = conn
+            .run(|c| {
+                receipts
+                    .filter(status.eq(PENDING))
+                    .filter(ocr_at.is_null())
+                    .order(id.asc())
+                    .limit(BATCH)
+                    .load::<Receipt>(c)
+            })
+            .await?;
+
+        let mut read = 0;
+        for receipt in queued {
+            let found = match storage.get(&receipt.storage_key).await {
+                Ok(Some(bytes)) => match self.read(config, bytes, &receipt.content_type).await {
+                    Ok(found) => found,
+                    Err(e) => {
+                        log::warn!("failed to read receipt {}: {}", receipt.id, e);
+                        None
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!("failed to fetch receipt {}: {}", receipt.id, e);
+                    None
+                }
+            };
+            if found.is_some() {
+                read += 1;
+            }
+
+            let read_total = found.map(|found| format!("{:.2}", found));
+            let cents = found.map(|found| (found * 100.0).round().min(f64::from(i32::MAX)) as i32);
+            let now = Utc::now().naive_utc();
+            conn.run(move |c| {
+                let suggested = match cents {
+                    Some(cents) => suggest(c, receipt.card_id, cents)?,
+                    None => None,
+                };
+                diesel::update(receipts.find(receipt.id))
+                    .set((
+                        total.eq(read_total),
+                        suggested_points.eq(suggested),
+                        ocr_at.eq(now),
+                    ))
+                    .execute(c)
+            })
+            .await?;
+        }
+
+        Ok(read)
+    }
+}
+
+/// Queues a receipt photo for review on a card the caller can edit.
+#[post("/loyalties/<loyalty_id>/receipts", data = "<data>")]
+async fn upload_receipt(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    content_type: &ContentType,
+    loyalty_id: String,
+    data: Data,
+) -> Result<status::Custom<Json<ReceiptResponse>>, APIError> {
+    use db::schema::receipts;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let owner = user.0;
+    if !db
+        .run(move |c| images::can_edit(c, owner, loyalty_id))
+        .await?
+    {
+        return Err(APIError::NotFound);
+    }
+
+    let (_, bytes) = images::read_upload(content_type, data, FIELD, MAX_BYTES).await?;
+    let kind = images::sniff(&bytes).ok_or(APIError::UnsupportedMediaType)?;
+
+    let key = format!("receipts/{}/{}", loyalty_id, token::generate());
+    storage.put(&key, bytes, kind).await?;
+
+    let stored = key.clone();
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                // The card may have been deleted during the upload.
+                if !images::can_edit(c, owner, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                diesel::insert_into(receipts::table)
+                    .values(&NewReceipt {
+                        card_id: loyalty_id,
+                        user_id: owner,
+                        storage_key: &stored,
+                        content_type: kind,
+                    })
+                    .execute(c)?;
+
+                Ok(receipts::table
+                    .filter(receipts::storage_key.eq(&stored))
+                    .first::<Receipt>(c)?)
+            })
+        })
+        .await;
+
+    match created {
+        Ok(receipt) => {
+            let download = download_route(&receipt);
+            Ok(status::Custom(
+                Status::Created,
+                Json(describe(&storage, receipt, download)),
+            ))
+        }
+        Err(e) => {
+            storage.discard(&[key]).await;
+            Err(e)
+        }
+    }
+}
+
+/// The receipts of a card the caller can read, the newest first.
+#[get("/loyalties/<loyalty_id>/receipts")]
+async fn get_receipts(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+) -> Result<Json<Vec<ReceiptResponse>>, APIError> {
+    use db::schema::receipts::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let found = db
+        .run(move |c| {
+            if !shares::can_read(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(receipts
+                .filter(card_id.eq(loyalty_id))
+                .order(id.desc())
+                .load::<Receipt>(c)?)
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|receipt| {
+                let download = download_route(&receipt);
+                describe(&storage, receipt, download)
+            })
+            .collect(),
+    ))
+}
+
+/// The photo of a receipt.
+#[get("/loyalties/<loyalty_id>/receipts/<receipt_id>")]
+async fn get_receipt(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    receipt_id: String,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::receipts::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let receipt_id: i32 = receipt_id.parse()?;
+    let receipt = db
+        .run(move |c| {
+            if !shares::can_read(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(receipts
+                .filter(id.eq(receipt_id))
+                .filter(card_id.eq(loyalty_id))
+                .first::<Receipt>(c)
+                .optional()?)
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    photo(&storage, &receipt).await
+}
+
+/// The stored photo of `receipt`.
+pub async fn photo(
+    storage: &Storage,
+    receipt: &Receipt,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    let bytes = storage
+        .get(&receipt.storage_key)
+        .await?
+        .ok_or(APIError::NotFound)?;
+    let content_type =
+        ContentType::parse_flexible(&receipt.content_type).unwrap_or(ContentType::Binary);
+
+    Ok((content_type, bytes))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Receipts", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<ReceiptsConfig>("receipts") {
+            Ok(config) => Some(config),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                log::error!("invalid receipts configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let http = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+            Ok(http) => http,
+            Err(e) => {
+                log::error!("failed to build the OCR client: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(Ocr { config, http }))
+    })
+}
@@ -0,0 +1,251 @@
+//! Referrals: each user has a code to hand to friends, who pass it to
+//! `POST /signup?ref=`. Once the friend adds their first card, both get bonus
+//! points: the friend on that card, the referrer on their favorite or most
+//! used one, or on the next card they add when they have none yet.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rand::Rng;
+use rocket::fairing::AdHoc;
+use rocket::{get, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+use crate::auth::LoyaltiesReader;
+use crate::db::{
+    self,
+    models::{NewReferral, Referral},
+};
+use crate::requests::{invalid, ReferralResponse};
+use crate::tiers::{self, TiersConfig};
+use crate::{points, APIError, LoyaltyDbConn};
+
+/// Without `0`, `O`, `1` and `I`, which are mistaken for one another when
+/// read out.
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+const CODE_LENGTH: usize = 8;
+
+/// The `referrals` section of the Rocket configuration.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ReferralsConfig {
+    /// Points credited to each side of a referral.
+    pub bonus_points: i32,
+}
+
+impl Default for ReferralsConfig {
+    fn default() -> Self {
+        ReferralsConfig { bonus_points: 500 }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_referrals]
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| char::from(CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())]))
+        .collect()
+}
+
+/// The referral code of `user`, made the first time.
+fn code_of(c: &SqliteConnection, user: i32) -> QueryResult<String> {
+    use db::schema::users::dsl::*;
+
+    if let Some(existing) = users
+        .find(user)
+        .select(referral_code)
+        .first::<Option<String>>(c)?
+    {
+        return Ok(existing);
+    }
+
+    loop {
+        let code = generate_code();
+        let taken = users
+            .filter(referral_code.eq(&code))
+            .select(id)
+            .first::<i32>(c)
+            .optional()?;
+        if taken.is_none() {
+            diesel::update(users.find(user))
+                .set(referral_code.eq(&code))
+                .execute(c)?;
+            return Ok(code);
+        }
+    }
+}
+
+/// Records that `referred`, who just signed up, was referred with `code`.
+pub fn record(c: &SqliteConnection, referred: i32, code: &str) -> Result<(), APIError> {
+    use db::schema::{referrals, users};
+
+    let referrer = users::table
+        .filter(users::referral_code.eq(code.trim().to_uppercase()))
+        .filter(users::deleted_at.is_null())
+        .select(users::id)
+        .first::<i32>(c)
+        .optional()?;
+    let referrer = match referrer {
+        Some(referrer) if referrer != referred => referrer,
+        _ => {
+            return Err(invalid("ref", "unknown referral code").into());
+        }
+    };
+
+    diesel::insert_into(referrals::table)
+        .values(&NewReferral {
+            referrer_id: referrer,
+            referred_id: referred,
+        })
+        .execute(c)?;
+
+    Ok(())
+}
+
+/// The card of `user` to credit a referral bonus on.
+fn card_to_credit(c: &SqliteConnection, user: i32) -> QueryResult<Option<i32>> {
+    use db::schema::cards::dsl::*;
+
+    cards
+        .filter(user_id.eq(user))
+        .filter(deleted_at.is_null())
+        .filter(archived_at.is_null())
+        .filter(is_active.eq(true))
+        .order((is_favorite.desc(), use_count.desc(), id.asc()))
+        .select(id)
+        .first::<i32>(c)
+        .optional()
+}
+
+fn bonus(
+    c: &SqliteConnection,
+    config: ReferralsConfig,
+    tiers_config: TiersConfig,
+    user: i32,
+    card: i32,
+) -> Result<(), APIError> {
+    points::append(
+        c,
+        user,
+        card,
+        config.bonus_points,
+        "Referral bonus",
+        Utc::now().naive_utc(),
+    )?;
+    tiers::refresh(c, tiers_config, card)?;
+    Ok(())
+}
+
+/// Credits the referral bonuses due now that `user` added `card`: theirs
+/// when they were referred and it is their first card, and those of the
+/// referrals they made that were waiting for them to have a card. Meant to
+/// run in the transaction that adds the card.
+pub fn credit(
+    c: &SqliteConnection,
+    config: ReferralsConfig,
+    tiers_config: TiersConfig,
+    user: i32,
+    card: i32,
+) -> Result<(), APIError> {
+    use db::schema::referrals::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let referred = referrals
+        .filter(referred_id.eq(user))
+        .filter(referred_credited_at.is_null())
+        .first::<Referral>(c)
+        .optional()?;
+    if let Some(referral) = referred {
+        bonus(c, config, tiers_config, user, card)?;
+        diesel::update(referrals.find(referral.id))
+            .set(referred_credited_at.eq(now))
+            .execute(c)?;
+
+        if let Some(theirs) = card_to_credit(c, referral.referrer_id)? {
+            bonus(c, config, tiers_config, referral.referrer_id, theirs)?;
+            diesel::update(referrals.find(referral.id))
+                .set(referrer_credited_at.eq(now))
+                .execute(c)?;
+        }
+    }
+
+    let waiting = referrals
+        .filter(referrer_id.eq(user))
+        .filter(referred_credited_at.is_not_null())
+        .filter(referrer_credited_at.is_null())
+        .load::<Referral>(c)?;
+    for referral in waiting {
+        bonus(c, config, tiers_config, user, card)?;
+        diesel::update(referrals.find(referral.id))
+            .set(referrer_credited_at.eq(now))
+            .execute(c)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the referrals `user` made or came from, before the account is
+/// purged. Bonuses already credited stay on the cards.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::referrals::dsl::*;
+
+    diesel::delete(referrals.filter(referrer_id.eq(user).or(referred_id.eq(user)))).execute(c)
+}
+
+/// The caller's referral code, with how it went.
+#[get("/referrals")]
+async fn get_referrals(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    config: State<'_, ReferralsConfig>,
+) -> Result<Json<ReferralResponse>, APIError> {
+    use db::schema::referrals::dsl::*;
+
+    let bonus_points = config.bonus_points;
+    let (code, referred, credited) = db
+        .run(move |c| {
+            c.transaction(|| {
+                let code = code_of(c, user.0)?;
+                let made = referrals.filter(referrer_id.eq(user.0));
+                let referred = made.clone().count().get_result::<i64>(c)?;
+                let credited = made
+                    .filter(referred_credited_at.is_not_null())
+                    .count()
+                    .get_result::<i64>(c)?;
+                Ok::<_, diesel::result::Error>((code, referred, credited))
+            })
+        })
+        .await?;
+
+    Ok(Json(ReferralResponse {
+        code,
+        referred,
+        credited,
+        bonus_points,
+    }))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Referrals Config", |rocket| async move {
+        let config = match rocket
+            .figment()
+            .extract_inner::<ReferralsConfig>("referrals")
+        {
+            Ok(config) if config.bonus_points > 0 => config,
+            Ok(_) => {
+                log::error!("invalid referrals configuration: bonus_points must be positive");
+                return Err(rocket);
+            }
+            Err(e) if e.missing() => ReferralsConfig::default(),
+            Err(e) => {
+                log::error!("invalid referrals configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,244 @@
+//! Reminders users set on cards, such as "use before Friday". The background
+//! jobs mail the ones that are due; there is no push channel to the apps.
+//!
+//! A reminder is marked sent before it is mailed, so a failing mail server
+//! drops it rather than sending it again on every run.
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Route};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    crypto::EncryptedString,
+    models::{CardReminder, NewCardReminder},
+};
+use crate::mail::Mailer;
+use crate::requests::{invalid, CreateReminder, ReminderResponse};
+use crate::shares;
+use crate::{APIError, LoyaltyDbConn};
+
+/// Pending reminders a user can have.
+const MAX_PENDING: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![create_reminder, get_reminders, delete_reminder]
+}
+
+/// Removes the reminders of `card`, before it is deleted.
+pub fn clear(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_reminders::dsl::*;
+
+    diesel::delete(card_reminders.filter(card_id.eq(card))).execute(c)
+}
+
+/// Removes the reminders `user` set, on any card, before the account is
+/// purged.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::card_reminders::dsl::*;
+
+    diesel::delete(card_reminders.filter(user_id.eq(user))).execute(c)
+}
+
+/// A reminder to mail.
+struct Due {
+    email: String,
+    card_name: String,
+    message: String,
+}
+
+/// Marks the reminders due by now as sent, returning the ones to mail.
+/// Reminders of cards in the trash are kept for when they are restored, and
+/// those of deleted accounts or of cards no longer readable are dropped.
+fn claim_due(c: &SqliteConnection) -> QueryResult<Vec<Due>> {
+    use db::schema::{card_reminders, cards, users};
+
+    c.transaction(|| {
+        let now = Utc::now().naive_utc();
+        let found = card_reminders::table
+            .inner_join(cards::table)
+            .inner_join(users::table)
+            .filter(card_reminders::sent_at.is_null())
+            .filter(card_reminders::remind_at.le(now))
+            .filter(cards::deleted_at.is_null())
+            .select((
+                card_reminders::all_columns,
+                cards::name,
+                users::email,
+                users::deleted_at,
+            ))
+            .load::<(CardReminder, String, String, Option<NaiveDateTime>)>(c)?;
+
+        let mut due = Vec::new();
+        for (reminder, card_name, email, deleted_at) in found {
+            diesel::update(card_reminders::table.find(reminder.id))
+                .set(card_reminders::sent_at.eq(now))
+                .execute(c)?;
+            if deleted_at.is_none() && shares::can_read(c, reminder.user_id, reminder.card_id)? {
+                due.push(Due {
+                    email,
+                    card_name,
+                    message: reminder.message.0,
+                });
+            }
+        }
+
+        Ok(due)
+    })
+}
+
+/// Mails the reminders that are due, returning how many were sent.
+pub async fn send_due(conn: &LoyaltyDbConn, mailer: &Mailer) -> QueryResult<usize> {
+    let due = conn.run(claim_due).await?;
+
+    let mut sent = 0;
+    for reminder in due {
+        let body = format!(
+            "You asked to be reminded of your {} card:\n\n{}\n",
+            reminder.card_name, reminder.message
+        );
+        let subject = format!("Reminder: {}", reminder.card_name);
+        match mailer.send(&reminder.email, &subject, body).await {
+            Ok(()) => sent += 1,
+            Err(e) => log::warn!("could not send reminder: {}", e),
+        }
+    }
+
+    Ok(sent)
+}
+
+fn describe(reminder: CardReminder, card_name: String) -> ReminderResponse {
+    ReminderResponse {
+        id: reminder.id,
+        card_id: reminder.card_id,
+        card_name,
+        message: reminder.message.0,
+        remind_at: reminder.remind_at,
+        created_at: reminder.created_at,
+    }
+}
+
+/// Sets a reminder for the caller on a card they can read.
+#[post("/loyalties/<loyalty_id>/reminders", format = "json", data = "<body>")]
+async fn create_reminder(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    body: Json<CreateReminder>,
+) -> Result<status::Custom<Json<ReminderResponse>>, APIError> {
+    use db::schema::{card_reminders, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+    let body = body.into_inner();
+    let wanted = body.message.trim().to_string();
+
+    if wanted.is_empty() {
+        return Err(invalid("message", "reminders need a message").into());
+    }
+    if body.remind_at <= Utc::now().naive_utc() {
+        return Err(invalid("remind_at", "reminders must be in the future").into());
+    }
+
+    let created = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                if !shares::can_read(c, user.0, loyalty_id)? {
+                    return Err(APIError::NotFound);
+                }
+
+                let pending = card_reminders::table
+                    .filter(card_reminders::user_id.eq(user.0))
+                    .filter(card_reminders::sent_at.is_null())
+                    .count()
+                    .get_result::<i64>(c)?;
+                if pending >= MAX_PENDING {
+                    return Err(invalid("remind_at", "you have up to 100 pending reminders").into());
+                }
+
+                diesel::insert_into(card_reminders::table)
+                    .values(&NewCardReminder {
+                        card_id: loyalty_id,
+                        user_id: user.0,
+                        message: EncryptedString(wanted),
+                        remind_at: body.remind_at,
+                    })
+                    .execute(c)?;
+
+                let created = card_reminders::table
+                    .order(card_reminders::id.desc())
+                    .first::<CardReminder>(c)?;
+                let card_name = cards::table
+                    .find(loyalty_id)
+                    .select(cards::name)
+                    .first::<String>(c)?;
+                Ok(describe(created, card_name))
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(created)))
+}
+
+/// The caller's reminders that were not sent yet, soonest first. Those of
+/// cards in the trash are left out.
+#[get("/reminders")]
+async fn get_reminders(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<Vec<ReminderResponse>>, APIError> {
+    use db::schema::{card_reminders, cards};
+
+    let pending = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            card_reminders::table
+                .inner_join(cards::table)
+                .filter(card_reminders::user_id.eq(user.0))
+                .filter(card_reminders::sent_at.is_null())
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .order((card_reminders::remind_at.asc(), card_reminders::id.asc()))
+                .select((card_reminders::all_columns, cards::name))
+                .load::<(CardReminder, String)>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        pending
+            .into_iter()
+            .map(|(reminder, card_name)| describe(reminder, card_name))
+            .collect(),
+    ))
+}
+
+#[delete("/reminders/<reminder_id>")]
+async fn delete_reminder(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    reminder_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_reminders::dsl::*;
+
+    let reminder_id: i32 = reminder_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            diesel::delete(
+                card_reminders
+                    .filter(id.eq(reminder_id))
+                    .filter(user_id.eq(user.0)),
+            )
+            .execute(c)
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "reminder deleted")),
+    }
+}
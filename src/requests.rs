@@ -1,5 +1,19 @@
-use serde::{Deserialize, Serialize};
-use validator::Validate;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use chrono::{NaiveDate, NaiveDateTime, Weekday};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::auth::{api_keys::Scope, Role};
+use crate::barcode::BarcodeType;
+use crate::db::models::{
+    BalanceEntry, Campaign, CardAttachment, CardLocation, CardReminder, CardShare, CardTransfer,
+    Category, Coupon, CouponClaim, EarningRule, Loyalty, PointTransaction, PointTransfer, PosKey,
+    Purchase, Receipt, Referral, Retailer, Reward, StampReward, User, UserTier,
+};
+use crate::earning::RuleKind;
+use crate::shares::Access;
+use validator::{Validate, ValidationError, ValidationErrors};
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct UserSignup {
@@ -7,19 +21,122 @@ pub struct UserSignup {
     pub email: String,
     pub name: String,
     pub pass: String,
+    /// Required when signup is invite-only.
+    pub invite_code: Option<String>,
+}
+
+/// Turns a guest account into a full one.
+#[derive(Deserialize, Validate)]
+pub struct ClaimAccount {
+    #[validate(email)]
+    pub email: String,
+    pub name: Option<String>,
+    pub pass: String,
 }
 
 #[derive(Deserialize)]
 pub struct UserSignIn {
     pub email: String,
     pub pass: String,
+    /// TOTP or recovery code, for clients that collect it up front.
+    #[serde(default)]
+    pub code: Option<String>,
+    /// Keeps `/signin` users signed in on this device across browser restarts.
+    #[serde(default)]
+    pub remember_me: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct AddLoyalty {
+    #[validate(length(min = 1, max = 100))]
     pub name: String,
+    /// Stored as `#RRGGBB`.
+    #[validate(custom = "crate::colors::validate")]
     pub color: Option<String>,
+    #[validate(length(min = 1, max = 255))]
     pub code: String,
+    /// Taken from the retailer when left out, `code128` otherwise.
+    pub barcode_type: Option<BarcodeType>,
+    /// The retailer template the card was created from, which gives the
+    /// color and format left out.
+    pub retailer_id: Option<i32>,
+    /// PIN hints, membership tier and the like. Stored encrypted.
+    #[validate(length(max = 500))]
+    pub notes: Option<String>,
+    pub expires_at: Option<NaiveDate>,
+    /// What is left on gift and prepaid cards, such as `12.50`, in
+    /// `balance_currency`.
+    #[validate(custom = "crate::balance::validate_amount")]
+    pub balance: Option<String>,
+    #[validate(custom = "crate::balance::validate_currency")]
+    pub balance_currency: Option<String>,
+    /// An emoji or one of `GET /icons`, for cards without a logo.
+    #[validate(custom = "crate::icons::validate")]
+    pub icon: Option<String>,
+    /// Makes a stamp card, rewarded every that many stamps.
+    #[validate(range(min = 2, max = 100))]
+    pub stamps_required: Option<i32>,
+    /// New cards naming a retailer of the catalog take its spelling, such
+    /// as `Tesco Clubcard` for `TESCO Extra`, unless this is set.
+    #[serde(default)]
+    pub keep_name: bool,
+}
+
+/// Tells a field set to `null` from one left out.
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// The error of a value breaking a rule the derive can't express.
+pub fn invalid_value(message: &'static str) -> ValidationError {
+    let mut error = ValidationError::new("invalid");
+    error.message = Some(Cow::Borrowed(message));
+    error
+}
+
+/// The errors of a request whose `field` breaks such a rule.
+pub fn invalid(field: &'static str, message: &'static str) -> ValidationErrors {
+    let mut errors = ValidationErrors::new();
+    errors.add(field, invalid_value(message));
+    errors
+}
+
+/// Fields left out of a `PATCH` are kept.
+#[derive(Deserialize, Validate)]
+pub struct UpdateLoyalty {
+    #[validate(length(min = 1, max = 100))]
+    pub name: Option<String>,
+    #[validate(length(min = 1, max = 32), custom = "crate::colors::validate")]
+    pub color: Option<String>,
+    #[validate(length(min = 1, max = 255))]
+    pub code: Option<String>,
+    /// The code, new or kept, is checked against the format.
+    pub barcode_type: Option<BarcodeType>,
+    /// An empty string clears the notes.
+    #[validate(length(max = 500))]
+    pub notes: Option<String>,
+    /// `null` clears the date.
+    #[serde(default, deserialize_with = "double_option")]
+    pub expires_at: Option<Option<NaiveDate>>,
+    /// `null` clears the balance and its currency.
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(custom = "crate::balance::validate_amount")]
+    pub balance: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(custom = "crate::balance::validate_currency")]
+    pub balance_currency: Option<Option<String>>,
+    /// `null` clears the icon.
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(custom = "crate::icons::validate")]
+    pub icon: Option<Option<String>>,
+    /// `null` makes it a card without stamps. Stamps collected are kept.
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(range(min = 2, max = 100))]
+    pub stamps_required: Option<Option<i32>>,
 }
 
 #[derive(Serialize)]
@@ -28,6 +145,853 @@ pub struct AddLoyaltyResponse {
     pub name: String,
     pub color: Option<String>,
     pub code: String,
+    pub barcode_type: String,
+    pub front_image_url: Option<String>,
+    pub back_image_url: Option<String>,
+    pub tags: Vec<String>,
+    /// Custom fields, by name.
+    pub metadata: BTreeMap<String, String>,
+    /// Logo of the retailer, from the catalog or looked up from the name.
+    pub logo_url: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub is_favorite: bool,
+    pub position: i32,
+    /// When the card was moved to the trash.
+    pub deleted_at: Option<NaiveDateTime>,
+    /// When the card was hidden from the main list.
+    pub archived_at: Option<NaiveDateTime>,
+    pub notes: Option<String>,
+    pub expires_at: Option<NaiveDate>,
+    pub owner_id: i32,
+    /// The family group the card is shared with.
+    pub group_id: Option<i32>,
+    /// How many times the card was shown at a till.
+    pub use_count: i32,
+    pub retailer_id: Option<i32>,
+    pub balance: Option<String>,
+    pub balance_currency: Option<String>,
+    /// False once the card was deactivated, for instance when it was lost.
+    pub is_active: bool,
+    pub icon: Option<String>,
+    /// The owner's tier in the program of the retailer, such as `silver`.
+    pub tier: Option<String>,
+    /// `null` for cards that aren't stamp cards.
+    pub stamps: Option<StampProgress>,
+}
+
+#[derive(Serialize)]
+pub struct StampProgress {
+    pub collected: i32,
+    pub required: i32,
+    /// Rewards earned by completing the card and not redeemed yet.
+    pub rewards: i64,
+}
+
+/// Without the photo links, tags, custom fields and logo, see
+/// `cards::describe`.
+impl From<Loyalty> for AddLoyaltyResponse {
+    fn from(card: Loyalty) -> Self {
+        AddLoyaltyResponse {
+            id: card.id,
+            name: card.name,
+            color: card.color,
+            code: card.code.0,
+            barcode_type: card.barcode_type,
+            front_image_url: None,
+            back_image_url: None,
+            tags: Vec::new(),
+            metadata: BTreeMap::new(),
+            logo_url: None,
+            created_at: card.created_at,
+            last_used_at: card.last_used_at,
+            is_favorite: card.is_favorite,
+            position: card.position,
+            deleted_at: card.deleted_at,
+            archived_at: card.archived_at,
+            notes: card.notes.map(|notes| notes.0),
+            expires_at: card.expires_at,
+            owner_id: card.user_id,
+            group_id: card.group_id,
+            use_count: card.use_count,
+            retailer_id: card.retailer_id,
+            balance: card.balance,
+            balance_currency: card.balance_currency,
+            is_active: card.is_active,
+            icon: card.icon,
+            tier: None,
+            stamps: card.stamps_required.map(|required| StampProgress {
+                collected: card.stamps,
+                required,
+                rewards: 0,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreateCategory {
+    #[validate(length(min = 1, max = 50))]
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct CategoryResponse {
+    pub id: i32,
+    pub name: String,
+    /// How many cards have the tag, not counting the trash.
+    pub cards: i64,
+}
+
+/// Replaces the tags of a card. Unknown names create categories.
+#[derive(Deserialize, Validate)]
+pub struct SetTags {
+    #[validate(length(max = 20))]
+    pub tags: Vec<String>,
+}
+
+/// Replaces the custom fields of a card, such as a membership tier.
+#[derive(Deserialize)]
+pub struct SetMetadata {
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    /// Ids of the cards added, in the order they were sent.
+    pub ids: Vec<i32>,
+}
+
+#[derive(Serialize)]
+pub struct GoogleWalletResponse {
+    /// Opens Google Wallet to save the card.
+    pub save_url: String,
+}
+
+#[derive(Serialize)]
+pub struct PaletteColor {
+    pub name: &'static str,
+    pub hex: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct RetailerResponse {
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+    pub barcode_type: String,
+    pub logo_url: Option<String>,
+}
+
+impl From<Retailer> for RetailerResponse {
+    fn from(retailer: Retailer) -> Self {
+        RetailerResponse {
+            id: retailer.id,
+            name: retailer.name,
+            color: retailer.color,
+            barcode_type: retailer.barcode_type,
+            logo_url: retailer.logo_url,
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreateGroup {
+    #[validate(length(min = 1, max = 50))]
+    pub name: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct InviteToGroup {
+    #[validate(email)]
+    pub email: String,
+}
+
+/// `null` takes the card out of its group.
+#[derive(Deserialize)]
+pub struct SetCardGroup {
+    pub group_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct GroupMemberResponse {
+    pub user_id: i32,
+    pub name: String,
+    pub role: String,
+    pub joined_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct GroupResponse {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub created_at: NaiveDateTime,
+    pub members: Vec<GroupMemberResponse>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ShareCard {
+    #[validate(email)]
+    pub email: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateShare {
+    pub access: Access,
+}
+
+#[derive(Serialize)]
+pub struct ShareResponse {
+    pub id: i32,
+    pub email: String,
+    pub access: Access,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreateShareLink {
+    /// 24 by default, a month at most.
+    #[validate(range(min = 1, max = 720))]
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkResponse {
+    pub id: i32,
+    /// Only returned when the link is created.
+    pub url: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub view_count: i32,
+    pub last_viewed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// What a card looked like before an edit.
+#[derive(Serialize)]
+pub struct RevisionResponse {
+    pub id: i32,
+    pub changed_at: NaiveDateTime,
+    /// The owner, or a user the card is shared with.
+    pub editor_id: i32,
+    /// Fields the edit changed.
+    pub changes: Vec<&'static str>,
+    pub name: String,
+    pub color: Option<String>,
+    pub code: String,
+    pub barcode_type: String,
+    pub notes: Option<String>,
+    pub expires_at: Option<NaiveDate>,
+    pub retailer_id: Option<i32>,
+}
+
+/// A receipt or document attached to a card.
+#[derive(Serialize)]
+pub struct AttachmentResponse {
+    pub id: i32,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i32,
+    pub created_at: NaiveDateTime,
+    /// Presigned when the storage backend can sign links, the download route
+    /// otherwise.
+    pub url: String,
+}
+
+/// A store a card is used at.
+#[derive(Deserialize, Validate)]
+pub struct SetLocation {
+    #[validate(length(min = 1, max = 100))]
+    pub label: String,
+    #[validate(range(min = -90.0, max = 90.0))]
+    pub latitude: f64,
+    #[validate(range(min = -180.0, max = 180.0))]
+    pub longitude: f64,
+}
+
+#[derive(Serialize)]
+pub struct LocationResponse {
+    pub id: i32,
+    pub label: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub created_at: NaiveDateTime,
+}
+
+/// A card with a location close to the caller.
+#[derive(Serialize)]
+pub struct NearbyCard {
+    /// Meters to the closest location of the card.
+    pub distance: f64,
+    pub location: LocationResponse,
+    pub card: AddLoyaltyResponse,
+}
+
+/// Reminds the caller of a card by email, such as "use before Friday".
+#[derive(Deserialize, Validate)]
+pub struct CreateReminder {
+    #[validate(length(min = 1, max = 200))]
+    pub message: String,
+    /// In UTC.
+    pub remind_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct ReminderResponse {
+    pub id: i32,
+    pub card_id: i32,
+    pub card_name: String,
+    pub message: String,
+    pub remind_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+/// The balance of a card after an edit.
+#[derive(Serialize)]
+pub struct BalanceEntryResponse {
+    pub id: i32,
+    pub recorded_at: NaiveDateTime,
+    /// The owner, or a user the card is shared with.
+    pub editor_id: i32,
+    /// `null` when the balance was cleared.
+    pub balance: Option<String>,
+    pub balance_currency: Option<String>,
+    /// Signed difference with the entry before, such as `-12.50` after a
+    /// purchase, when both are in the same currency.
+    pub change: Option<String>,
+}
+
+/// Points earned or redeemed, such as 500 for a voucher.
+#[derive(Deserialize, Validate)]
+pub struct ChangePoints {
+    #[validate(range(min = 1, max = 1000000))]
+    pub points: i32,
+    #[validate(length(min = 1, max = 100))]
+    pub reason: String,
+    /// Now when left out; may be in the past for points entered late.
+    pub occurred_at: Option<NaiveDateTime>,
+}
+
+/// Points given from a card the caller owns to the card of another user in
+/// the same program.
+#[derive(Deserialize, Validate)]
+pub struct TransferPoints {
+    pub card_id: i32,
+    #[validate(email)]
+    pub email: String,
+    #[validate(range(min = 1, max = 1000000))]
+    pub points: i32,
+}
+
+#[derive(Serialize)]
+pub struct PointTransferResponse {
+    pub id: i32,
+    /// Whether the caller gave the points rather than received them.
+    pub sent: bool,
+    /// The other side of the transfer, null once they deleted their account.
+    pub email: Option<String>,
+    /// The caller's card the points left or arrived on, null once deleted.
+    pub card_id: Option<i32>,
+    pub points: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct PointTransactionResponse {
+    pub id: i32,
+    pub delta: i32,
+    pub reason: String,
+    pub occurred_at: NaiveDateTime,
+    /// The points on the card once this one was counted.
+    pub balance: i64,
+}
+
+#[derive(Serialize)]
+pub struct PointsResponse {
+    pub balance: i64,
+    /// The points that expire first, `null` when points don't expire or the
+    /// card has none.
+    pub next_expiry: Option<ExpiringPoints>,
+    /// Newest first.
+    pub transactions: Vec<PointTransactionResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionsPage {
+    /// Newest first.
+    pub transactions: Vec<PointTransactionResponse>,
+    /// `null` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ExpiringPoints {
+    pub points: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A reward an administrator adds to the catalog, or its new settings.
+#[derive(Deserialize, Validate)]
+pub struct SetReward {
+    pub retailer_id: i32,
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    #[validate(range(min = 1, max = 1000000))]
+    pub cost: i32,
+    /// Left out for items that don't run out.
+    #[validate(range(min = 0))]
+    pub stock: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct RewardResponse {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    /// In points.
+    pub cost: i32,
+    /// `null` for items that don't run out.
+    pub stock: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<Reward> for RewardResponse {
+    fn from(reward: Reward) -> Self {
+        RewardResponse {
+            id: reward.id,
+            retailer_id: reward.retailer_id,
+            name: reward.name,
+            cost: reward.cost,
+            stock: reward.stock,
+            created_at: reward.created_at,
+        }
+    }
+}
+
+/// The card of the reward's retailer to spend the points of.
+#[derive(Deserialize)]
+pub struct RedeemReward {
+    pub card_id: i32,
+}
+
+#[derive(Serialize)]
+pub struct RedemptionResponse {
+    pub reward: RewardResponse,
+    /// The entry spending the points.
+    pub transaction: PointTransactionResponse,
+}
+
+#[derive(Serialize)]
+pub struct StampResponse {
+    pub stamps: StampProgress,
+    /// The reward earned by this stamp, when it completed the card.
+    pub issued: Option<StampRewardResponse>,
+}
+
+#[derive(Serialize)]
+pub struct StampRewardResponse {
+    pub id: i32,
+    pub issued_at: NaiveDateTime,
+    pub redeemed_at: Option<NaiveDateTime>,
+}
+
+impl From<StampReward> for StampRewardResponse {
+    fn from(reward: StampReward) -> Self {
+        StampRewardResponse {
+            id: reward.id,
+            issued_at: reward.issued_at,
+            redeemed_at: reward.redeemed_at,
+        }
+    }
+}
+
+/// A promotion an administrator runs for a merchant, or its new settings.
+#[derive(Deserialize, Validate)]
+pub struct SetCampaign {
+    pub retailer_id: i32,
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    /// Applied to the points earned, `200` for double points.
+    #[validate(range(min = 101, max = 1000))]
+    pub multiplier_percent: i32,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    /// Such as `["sat", "sun"]`; every day when left out.
+    #[validate(length(min = 1))]
+    pub weekdays: Option<Vec<Weekday>>,
+}
+
+#[derive(Serialize)]
+pub struct CampaignResponse {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    pub multiplier_percent: i32,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    /// `null` for every day.
+    pub weekdays: Option<Vec<Weekday>>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<Campaign> for CampaignResponse {
+    fn from(campaign: Campaign) -> Self {
+        CampaignResponse {
+            weekdays: campaign
+                .weekdays
+                .as_deref()
+                .map(crate::campaigns::parse_weekdays),
+            id: campaign.id,
+            retailer_id: campaign.retailer_id,
+            name: campaign.name,
+            multiplier_percent: campaign.multiplier_percent,
+            starts_at: campaign.starts_at,
+            ends_at: campaign.ends_at,
+            created_at: campaign.created_at,
+        }
+    }
+}
+
+/// An earning rule an administrator sets for a merchant, or its new
+/// settings. `per_cents` is required by `amount` rules and `sku` by `item`
+/// rules.
+#[derive(Deserialize, Validate)]
+pub struct SetEarningRule {
+    pub retailer_id: i32,
+    pub kind: RuleKind,
+    #[validate(range(min = 1, max = 1000000))]
+    pub points: i32,
+    #[validate(range(min = 1))]
+    pub per_cents: Option<i32>,
+    #[validate(length(min = 1, max = 100))]
+    pub sku: Option<String>,
+    #[validate(range(min = 0))]
+    pub min_cents: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct EarningRuleResponse {
+    pub id: i32,
+    pub retailer_id: i32,
+    /// `amount`, `item` or `visit`.
+    pub kind: String,
+    pub points: i32,
+    pub per_cents: Option<i32>,
+    pub sku: Option<String>,
+    pub min_cents: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<EarningRule> for EarningRuleResponse {
+    fn from(rule: EarningRule) -> Self {
+        EarningRuleResponse {
+            id: rule.id,
+            retailer_id: rule.retailer_id,
+            kind: rule.kind,
+            points: rule.points,
+            per_cents: rule.per_cents,
+            sku: rule.sku,
+            min_cents: rule.min_cents,
+            created_at: rule.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreatePosKey {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct PosKeyResponse {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl From<PosKey> for PosKeyResponse {
+    fn from(key: PosKey) -> Self {
+        PosKeyResponse {
+            id: key.id,
+            retailer_id: key.retailer_id,
+            name: key.name,
+            prefix: key.prefix,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+/// Only returned once, when the key is created.
+#[derive(Serialize)]
+pub struct CreatedPosKey {
+    pub id: i32,
+    pub name: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct PurchaseItem {
+    #[validate(length(min = 1, max = 100))]
+    pub sku: String,
+    #[validate(range(min = 1, max = 10000))]
+    pub quantity: i32,
+}
+
+/// A sale a point of sale posts for the card scanned at the till.
+#[derive(Deserialize, Validate)]
+pub struct PurchaseEvent {
+    /// The code of the card, as scanned.
+    #[validate(length(min = 1, max = 100))]
+    pub card_code: String,
+    /// The merchant's id of the sale, posted once.
+    #[validate(length(min = 1, max = 100))]
+    pub reference: String,
+    #[validate(range(min = 0))]
+    pub amount_cents: i32,
+    /// Each checked on its own.
+    #[serde(default)]
+    #[validate(length(max = 500))]
+    pub items: Vec<PurchaseItem>,
+    /// Now when left out.
+    pub occurred_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct PurchaseResponse {
+    pub id: i32,
+    pub card_id: i32,
+    pub reference: String,
+    pub amount_cents: i32,
+    /// Earned by the rules of the merchant, with the running campaign.
+    pub points: i32,
+    pub occurred_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct ReferralResponse {
+    /// Passed to `POST /signup?ref=`.
+    pub code: String,
+    /// Users who signed up with the code.
+    pub referred: i64,
+    /// Those of them who added a card, earning both sides the bonus.
+    pub credited: i64,
+    pub bonus_points: i32,
+}
+
+/// A coupon an administrator issues for a merchant, or its new settings.
+#[derive(Deserialize, Validate)]
+pub struct SetCoupon {
+    pub retailer_id: i32,
+    #[validate(length(min = 1, max = 100))]
+    pub title: String,
+    /// What the till scans or the cashier types.
+    #[validate(length(min = 1, max = 50))]
+    pub code: String,
+    /// Coupons are spent once redeemed unless reusable.
+    #[serde(default)]
+    pub reusable: bool,
+    /// Left out for coupons valid right away.
+    pub valid_from: Option<NaiveDateTime>,
+    /// Left out for coupons that don't expire.
+    pub valid_until: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct CouponResponse {
+    pub id: i32,
+    pub retailer_id: i32,
+    pub title: String,
+    /// `null` until claimed.
+    pub code: Option<String>,
+    pub reusable: bool,
+    pub valid_from: Option<NaiveDateTime>,
+    pub valid_until: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<Coupon> for CouponResponse {
+    fn from(coupon: Coupon) -> Self {
+        CouponResponse {
+            id: coupon.id,
+            retailer_id: coupon.retailer_id,
+            title: coupon.title,
+            code: Some(coupon.code),
+            reusable: coupon.reusable,
+            valid_from: coupon.valid_from,
+            valid_until: coupon.valid_until,
+            created_at: coupon.created_at,
+        }
+    }
+}
+
+/// A coupon in the caller's wallet.
+#[derive(Serialize)]
+pub struct ClaimResponse {
+    pub coupon: CouponResponse,
+    pub claimed_at: NaiveDateTime,
+    /// When it was last redeemed, `null` while it wasn't.
+    pub redeemed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct ReceiptResponse {
+    pub id: i32,
+    pub card_id: i32,
+    /// `pending`, `approved` or `rejected`.
+    pub status: String,
+    /// As read by the OCR service, `null` until then.
+    pub total: Option<String>,
+    pub suggested_points: Option<i32>,
+    /// Credited on approval.
+    pub points: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub reviewed_at: Option<NaiveDateTime>,
+    /// Presigned when the storage backend can sign links, the download route
+    /// otherwise.
+    pub url: String,
+}
+
+/// Approves a receipt, crediting `points` or else those suggested from its
+/// total.
+#[derive(Deserialize, Validate)]
+pub struct ApproveReceipt {
+    #[validate(range(min = 1, max = 1000000))]
+    pub points: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct TierResponse {
+    pub retailer_id: i32,
+    pub retailer_name: String,
+    pub tier: String,
+    /// Points ever earned on the user's cards of the retailer.
+    pub lifetime_points: i64,
+    /// `null` at the top tier.
+    pub next_tier: Option<&'static str>,
+    pub points_to_next: Option<i64>,
+}
+
+/// Gives a card to another account, found by email.
+#[derive(Deserialize, Validate)]
+pub struct TransferCard {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct TransferResponse {
+    pub id: i32,
+    pub card_id: i32,
+    pub card_name: String,
+    pub from_email: String,
+    pub to_email: String,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+/// A card another user shared with the caller.
+#[derive(Serialize)]
+pub struct SharedCardResponse {
+    pub owner: String,
+    pub access: Access,
+    pub card: AddLoyaltyResponse,
+}
+
+#[derive(Serialize)]
+pub struct CategoryCount {
+    pub name: String,
+    pub cards: i64,
+}
+
+#[derive(Serialize)]
+pub struct CardUsage {
+    pub id: i32,
+    pub name: String,
+    pub use_count: i32,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct DailyUses {
+    pub day: NaiveDate,
+    pub uses: i64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub cards: i64,
+    pub by_category: Vec<CategoryCount>,
+    /// Cards without any tag.
+    pub uncategorized: i64,
+    pub most_used: Vec<CardUsage>,
+    /// Uses over the period of `usage`.
+    pub total_uses: i64,
+    pub usage: Vec<DailyUses>,
+}
+
+#[derive(Serialize)]
+pub struct BarcodeTypeCount {
+    pub barcode_type: String,
+    pub cards: i64,
+}
+
+/// The counts of the home screen. Archived cards are counted in `total`,
+/// unlike those in the trash.
+#[derive(Serialize)]
+pub struct SummaryResponse {
+    pub total: i64,
+    pub archived: i64,
+    pub trashed: i64,
+    pub by_category: Vec<CategoryCount>,
+    pub uncategorized: i64,
+    pub by_barcode_type: Vec<BarcodeTypeCount>,
+}
+
+/// The outcome of one entry of an import: the created card, or why the
+/// entry was skipped. `line` is the position of Stocard cards in the list.
+#[derive(Serialize)]
+pub struct ImportedRow {
+    pub line: u64,
+    pub id: Option<i32>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    /// `csv`, `klarna` or `stocard`, as detected from the file.
+    pub format: &'static str,
+    pub imported: usize,
+    pub failed: usize,
+    pub rows: Vec<ImportedRow>,
+}
+
+/// Cards to move to the trash together: all of them, or none when one
+/// isn't the caller's.
+#[derive(Deserialize, Validate)]
+pub struct DeleteCards {
+    #[validate(length(min = 1, max = 500))]
+    pub ids: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct MergeCards {
+    /// The duplicate, deleted once merged.
+    pub other_id: i32,
+}
+
+/// The cards of the user in the order they were arranged. Cards left out
+/// keep their order, after the listed ones.
+#[derive(Deserialize)]
+pub struct CardOrder {
+    pub ids: Vec<i32>,
 }
 
 #[derive(Serialize)]
@@ -35,3 +999,235 @@ pub struct PageResponse {
     pub count: i64,
     pub cards: Vec<AddLoyaltyResponse>,
 }
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub pass: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub current_pass: String,
+    pub pass: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangeEmail {
+    #[validate(email)]
+    pub email: String,
+    pub pass: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRole {
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    /// Defaults to every scope.
+    pub scopes: Option<Vec<Scope>>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyResponse {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+/// Only returned once, when the key is created.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub id: i32,
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub current: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeviceResponse {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeySignIn {
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyResponse {
+    pub id: i32,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreateInvite {
+    /// Defaults to a single use.
+    #[validate(range(min = 1))]
+    pub max_uses: Option<i32>,
+    #[validate(range(min = 1))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct InviteResponse {
+    pub id: i32,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Only returned once, when the invite is minted.
+#[derive(Serialize)]
+pub struct CreatedInvite {
+    pub id: i32,
+    pub code: String,
+    pub max_uses: i32,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct IpStandingResponse {
+    pub ip: String,
+    pub strikes: u32,
+    pub banned_for: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct AuthEventResponse {
+    pub id: i32,
+    pub kind: String,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    pub at: NaiveDateTime,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    pub succeeded: bool,
+}
+
+#[derive(Serialize)]
+pub struct LinkedAccountExport {
+    pub provider: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A custom field of a card.
+#[derive(Serialize)]
+pub struct CardFieldExport {
+    pub card_id: i32,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+pub struct CardTagExport {
+    pub card_id: i32,
+    pub category_id: i32,
+}
+
+/// Everything stored about a user, for data-portability requests. Card
+/// data covers the user's own cards; files are listed, not included.
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub exported_at: NaiveDateTime,
+    pub profile: User,
+    pub cards: Vec<Loyalty>,
+    pub card_fields: Vec<CardFieldExport>,
+    pub categories: Vec<Category>,
+    pub card_tags: Vec<CardTagExport>,
+    pub attachments: Vec<CardAttachment>,
+    pub locations: Vec<CardLocation>,
+    pub reminders: Vec<CardReminder>,
+    pub balance_history: Vec<BalanceEntry>,
+    pub points: Vec<PointTransaction>,
+    pub point_transfers: Vec<PointTransfer>,
+    pub tiers: Vec<UserTier>,
+    pub purchases: Vec<Purchase>,
+    pub receipts: Vec<Receipt>,
+    pub stamp_rewards: Vec<StampReward>,
+    pub coupons: Vec<CouponClaim>,
+    pub card_transfers: Vec<CardTransfer>,
+    /// Cards of other users shared with this one.
+    pub shares: Vec<CardShare>,
+    pub referrals: Vec<Referral>,
+    pub linked_accounts: Vec<LinkedAccountExport>,
+    pub api_keys: Vec<ApiKeyResponse>,
+    pub sessions: Vec<SessionResponse>,
+    pub devices: Vec<DeviceResponse>,
+    pub activity: Vec<AuthEventResponse>,
+}
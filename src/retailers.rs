@@ -0,0 +1,148 @@
+//! A catalog of known retailers, seeded by the migrations, which clients
+//! offer as templates when creating a card.
+
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::auth::LoyaltiesReader;
+use crate::barcode::BarcodeType;
+use crate::colors;
+use crate::db::{self, models::Retailer};
+use crate::requests::{AddLoyalty, RetailerResponse};
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 50;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_retailers]
+}
+
+/// The color and format of a new card: those of the body, or else those of
+/// its retailer, rejecting unknown retailers on the `retailer_id` field.
+pub fn prefill(
+    c: &SqliteConnection,
+    body: &AddLoyalty,
+) -> Result<(Option<String>, BarcodeType), APIError> {
+    use db::schema::retailers::dsl::*;
+
+    let retailer = match body.retailer_id {
+        Some(wanted) => Some(
+            retailers
+                .find(wanted)
+                .first::<Retailer>(c)
+                .optional()?
+                .ok_or_else(unknown)?,
+        ),
+        None => None,
+    };
+
+    let prefilled_color = body
+        .color
+        .as_deref()
+        .and_then(colors::normalize)
+        .or_else(|| retailer.as_ref().and_then(|r| r.color.clone()));
+    let prefilled_type = body
+        .barcode_type
+        .or_else(|| {
+            retailer
+                .as_ref()
+                .and_then(|r| BarcodeType::from_name(&r.barcode_type))
+        })
+        .unwrap_or_default();
+
+    Ok((prefilled_color, prefilled_type))
+}
+
+/// Lowercase words of letters and digits, so `Sainsbury's` reads like
+/// `sainsburys`.
+fn words(name: &str) -> Vec<String> {
+    name.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// The retailer of the catalog a card name refers to: the one with the
+/// same name, or else the one starting with the most of the card's first
+/// words, so `Tesco` and `TESCO Extra` find `Tesco Clubcard`.
+pub fn find_by_name<'a>(catalog: &'a [Retailer], card_name: &str) -> Option<&'a Retailer> {
+    let wanted = words(card_name);
+
+    catalog
+        .iter()
+        .filter_map(|retailer| {
+            let known = words(&retailer.name);
+            let common = known
+                .iter()
+                .zip(&wanted)
+                .take_while(|(known, wanted)| known == wanted)
+                .count();
+            if common > 0 {
+                // Between as many words in common, the shorter name is the
+                // closer one.
+                Some(((known == wanted, common, -(known.len() as i64)), retailer))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, retailer)| retailer)
+}
+
+/// The spelling of the catalog for `card_name`, if it names a retailer of
+/// the catalog, so cards of the same retailer are named alike.
+pub fn canonical_name(c: &SqliteConnection, card_name: &str) -> QueryResult<Option<String>> {
+    let catalog = db::schema::retailers::table.load::<Retailer>(c)?;
+
+    Ok(find_by_name(&catalog, card_name).map(|retailer| retailer.name.clone()))
+}
+
+/// The error of a `retailer_id` missing from the catalog.
+pub fn unknown() -> ValidationErrors {
+    let mut error = ValidationError::new("unknown");
+    error.message = Some("no retailer with this id".into());
+
+    let mut errors = ValidationErrors::new();
+    errors.add("retailer_id", error);
+    errors
+}
+
+/// Retailers whose name contains `q`, ignoring case, sorted by name.
+#[get("/retailers?<q>&<limit>")]
+async fn list_retailers(
+    db: LoyaltyDbConn,
+    _user: LoyaltiesReader,
+    q: Option<String>,
+    limit: Option<String>,
+) -> Result<Json<Vec<RetailerResponse>>, APIError> {
+    use db::schema::retailers::dsl::*;
+
+    let limit = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .min(MAX_LIMIT);
+    let found = db
+        .run(move |c| {
+            let mut query = retailers.into_boxed();
+            if let Some(q) = q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+                // `like` ignores ASCII case in SQLite; `%` and `_` are
+                // escaped so they match themselves.
+                let escaped = q
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                query = query.filter(name.like(format!("%{}%", escaped)).escape('\\'));
+            }
+            query.order(name.asc()).limit(limit).load::<Retailer>(c)
+        })
+        .await?;
+
+    Ok(Json(found.into_iter().map(Into::into).collect()))
+}
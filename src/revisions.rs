@@ -0,0 +1,238 @@
+//! Card history: the editable fields of a card are saved before each edit,
+//! so users can see what changed and put an older version back.
+
+use chrono::{NaiveDate, Utc};
+use diesel::prelude::*;
+use rocket::{get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self, crypto,
+    models::{CardRevision, Loyalty, NewCardRevision},
+};
+use crate::requests::{AddLoyaltyResponse, RevisionResponse};
+use crate::shares;
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_history, revert]
+}
+
+/// Saves the editable fields of `card` before `editor` changes them.
+pub fn record(c: &SqliteConnection, card: &Loyalty, editor: i32) -> QueryResult<usize> {
+    diesel::insert_into(db::schema::card_revisions::table)
+        .values(&NewCardRevision {
+            card_id: card.id,
+            user_id: editor,
+            changed_at: Utc::now().naive_utc(),
+            name: &card.name,
+            color: card.color.as_deref(),
+            code: card.code.clone(),
+            barcode_type: &card.barcode_type,
+            notes: card.notes.clone(),
+            expires_at: card.expires_at,
+            retailer_id: card.retailer_id,
+        })
+        .execute(c)
+}
+
+/// Removes the history of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_revisions::dsl::*;
+
+    diesel::delete(card_revisions.filter(card_id.eq(card))).execute(c)
+}
+
+/// The editable fields of a card, to compare two versions.
+struct Version<'a> {
+    name: &'a str,
+    color: Option<&'a str>,
+    code: &'a str,
+    barcode_type: &'a str,
+    notes: Option<&'a str>,
+    expires_at: Option<NaiveDate>,
+    retailer_id: Option<i32>,
+}
+
+impl<'a> From<&'a CardRevision> for Version<'a> {
+    fn from(revision: &'a CardRevision) -> Self {
+        Version {
+            name: &revision.name,
+            color: revision.color.as_deref(),
+            code: &revision.code.0,
+            barcode_type: &revision.barcode_type,
+            notes: revision.notes.as_ref().map(|notes| notes.0.as_str()),
+            expires_at: revision.expires_at,
+            retailer_id: revision.retailer_id,
+        }
+    }
+}
+
+impl<'a> From<&'a Loyalty> for Version<'a> {
+    fn from(card: &'a Loyalty) -> Self {
+        Version {
+            name: &card.name,
+            color: card.color.as_deref(),
+            code: &card.code.0,
+            barcode_type: &card.barcode_type,
+            notes: card.notes.as_ref().map(|notes| notes.0.as_str()),
+            expires_at: card.expires_at,
+            retailer_id: card.retailer_id,
+        }
+    }
+}
+
+impl Version<'_> {
+    fn changes(&self, after: &Version) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.name != after.name {
+            changes.push("name");
+        }
+        if self.color != after.color {
+            changes.push("color");
+        }
+        if self.code != after.code {
+            changes.push("code");
+        }
+        if self.barcode_type != after.barcode_type {
+            changes.push("barcode_type");
+        }
+        if self.notes != after.notes {
+            changes.push("notes");
+        }
+        if self.expires_at != after.expires_at {
+            changes.push("expires_at");
+        }
+        if self.retailer_id != after.retailer_id {
+            changes.push("retailer_id");
+        }
+        changes
+    }
+}
+
+/// The versions of the card before each edit, newest first.
+#[get("/loyalties/<loyalty_id>/history?<limit>&<offset>")]
+async fn get_history(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<RevisionResponse>>, APIError> {
+    use db::schema::{card_revisions, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let limit: i64 = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .max(1)
+        .min(MAX_LIMIT);
+    let offset: i64 = offset.and_then(|p| p.parse().ok()).unwrap_or(0).max(0);
+
+    let (card, revisions) = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            let card = cards::table
+                .filter(cards::id.eq(loyalty_id))
+                .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                .filter(cards::deleted_at.is_null())
+                .first::<Loyalty>(c)
+                .optional()?
+                .ok_or(APIError::NotFound)?;
+            let revisions = card_revisions::table
+                .filter(card_revisions::card_id.eq(loyalty_id))
+                .order(card_revisions::id.desc())
+                .load::<CardRevision>(c)?;
+
+            Ok::<_, APIError>((card, revisions))
+        })
+        .await?;
+
+    // Each version was followed by the one saved after it, the newest one
+    // by the card as it is now.
+    let mut history = Vec::new();
+    let mut after = Version::from(&card);
+    for revision in &revisions {
+        let before = Version::from(revision);
+        history.push(RevisionResponse {
+            id: revision.id,
+            changed_at: revision.changed_at,
+            editor_id: revision.user_id,
+            changes: before.changes(&after),
+            name: revision.name.clone(),
+            color: revision.color.clone(),
+            code: revision.code.0.clone(),
+            barcode_type: revision.barcode_type.clone(),
+            notes: revision.notes.as_ref().map(|notes| notes.0.clone()),
+            expires_at: revision.expires_at,
+            retailer_id: revision.retailer_id,
+        });
+        after = before;
+    }
+
+    Ok(Json(history.into_iter().skip(offset).take(limit).collect()))
+}
+
+/// Puts the card back as it was before an edit. The version replaced is
+/// saved too, so a revert can itself be reverted.
+#[post("/loyalties/<loyalty_id>/history/<revision_id>/revert")]
+async fn revert(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    revision_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::{card_revisions, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let revision_id: i32 = revision_id.parse()?;
+
+    let storage = storage.inner().clone();
+    let reverted = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let shared = shares::shared_ids(c, user.0, true)?;
+                let target = cards::table
+                    .filter(cards::id.eq(loyalty_id))
+                    .filter(cards::user_id.eq(user.0).or(cards::id.eq_any(shared)))
+                    .filter(cards::deleted_at.is_null());
+                let current = target
+                    .clone()
+                    .first::<Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                let revision = card_revisions::table
+                    .filter(card_revisions::id.eq(revision_id))
+                    .filter(card_revisions::card_id.eq(loyalty_id))
+                    .first::<CardRevision>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+
+                record(c, &current, user.0)?;
+                diesel::update(target.clone())
+                    .set((
+                        cards::name.eq(&revision.name),
+                        cards::color.eq(&revision.color),
+                        cards::code.eq(revision.code.clone()),
+                        cards::code_index.eq(crypto::blind_index(&revision.code.0)),
+                        cards::barcode_type.eq(&revision.barcode_type),
+                        cards::notes.eq(revision.notes.clone()),
+                        cards::expires_at.eq(revision.expires_at),
+                        cards::retailer_id.eq(revision.retailer_id),
+                    ))
+                    .execute(c)?;
+
+                let updated = target.first::<Loyalty>(c)?;
+                crate::search::index(c, updated.id)?;
+                Ok(crate::cards::describe_one(c, &storage, updated)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(reverted))
+}
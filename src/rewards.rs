@@ -0,0 +1,129 @@
+//! Items retailers offer for points, such as a free coffee, redeemed with
+//! the points of a card made from the same retailer of the catalog.
+//! Administrators keep the catalog up to date for the merchants.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{get, post, routes, Route};
+use rocket_contrib::json::Json;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{Loyalty, Reward},
+};
+use crate::requests::{invalid, RedeemReward, RedemptionResponse, RewardResponse};
+use crate::{images, points, APIError, LoyaltyDbConn};
+
+const MAX_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_rewards, redeem_reward]
+}
+
+/// Rewards in stock, the cheapest first.
+#[get("/rewards?<retailer_id>&<limit>&<offset>")]
+async fn list_rewards(
+    db: LoyaltyDbConn,
+    _user: LoyaltiesReader,
+    retailer_id: Option<i32>,
+    limit: Option<String>,
+    offset: Option<String>,
+) -> Result<Json<Vec<RewardResponse>>, APIError> {
+    use db::schema::rewards;
+
+    let limit = limit
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20)
+        .min(MAX_LIMIT);
+    let offset = offset.and_then(|p| p.parse().ok()).unwrap_or(0);
+
+    let found = db
+        .run(move |c| {
+            let mut query = rewards::table
+                .filter(rewards::stock.is_null().or(rewards::stock.gt(0)))
+                .into_boxed();
+            if let Some(retailer) = retailer_id {
+                query = query.filter(rewards::retailer_id.eq(retailer));
+            }
+
+            query
+                .order((rewards::cost.asc(), rewards::id.asc()))
+                .limit(limit)
+                .offset(offset)
+                .load::<Reward>(c)
+        })
+        .await?;
+
+    Ok(Json(found.into_iter().map(RewardResponse::from).collect()))
+}
+
+/// Spends the points of a card the caller can edit on a reward of its
+/// retailer, taking one out of stock.
+#[post("/rewards/<reward_id>/redeem", format = "json", data = "<body>")]
+async fn redeem_reward(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    reward_id: String,
+    body: Json<RedeemReward>,
+) -> Result<status::Custom<Json<RedemptionResponse>>, APIError> {
+    use db::schema::{cards, rewards};
+
+    let reward_id: i32 = reward_id.parse()?;
+    let card_id = body.0.card_id;
+
+    let redeemed = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let reward = rewards::table
+                    .find(reward_id)
+                    .first::<Reward>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if !images::can_edit(c, user.0, card_id)? {
+                    return Err(APIError::NotFound);
+                }
+                let card = cards::table
+                    .find(card_id)
+                    .filter(cards::deleted_at.is_null())
+                    .first::<Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if card.retailer_id != Some(reward.retailer_id) {
+                    return Err(invalid("card_id", "the card is of another retailer").into());
+                }
+
+                // Two redemptions of the last one can't both get it.
+                if reward.stock.is_some() {
+                    let taken =
+                        diesel::update(rewards::table.find(reward.id).filter(rewards::stock.gt(0)))
+                            .set(rewards::stock.eq(rewards::stock - 1))
+                            .execute(c)?;
+                    if taken == 0 {
+                        return Err(APIError::Conflict);
+                    }
+                }
+
+                let reason = format!("Reward: {}", reward.name);
+                let transaction = points::append(
+                    c,
+                    user.0,
+                    card.id,
+                    -reward.cost,
+                    &reason,
+                    Utc::now().naive_utc(),
+                )?;
+                let reward = rewards::table.find(reward.id).first::<Reward>(c)?;
+
+                Ok(RedemptionResponse {
+                    reward: reward.into(),
+                    transaction,
+                })
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(Status::Created, Json(redeemed)))
+}
@@ -0,0 +1,125 @@
+//! Full-text search of cards, over an SQLite FTS5 index kept by hand as
+//! cards change. Card names and the names of custom fields are indexed as
+//! they are and match by prefix. Codes, notes and field values are
+//! encrypted, so only blind tokens of their words are indexed: keyed
+//! digests that match whole words without keeping them in the clear. Equal
+//! words still have equal tokens, which tells cards sharing a word apart,
+//! though not the word.
+
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+
+use crate::db::{self, crypto, crypto::EncryptedString, models::NewCardSearch};
+use crate::{metadata, LoyaltyDbConn};
+
+/// The lowercase words of `text`, split on anything but letters and digits.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn tokens<'a>(texts: impl Iterator<Item = &'a str>) -> String {
+    texts
+        .flat_map(words)
+        .filter_map(|word| crypto::blind_index(&word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Rebuilds the index entry of `card`, once it was created or edited.
+pub fn index(c: &SqliteConnection, card: i32) -> QueryResult<()> {
+    use db::schema::{card_search, cards};
+
+    let (card_name, card_code, card_notes) = cards::table
+        .find(card)
+        .select((cards::name, cards::code, cards::notes))
+        .first::<(String, EncryptedString, Option<EncryptedString>)>(c)?;
+    let fields = metadata::fields_of(c, &[card])?;
+
+    let field_names: Vec<&str> = fields.iter().map(|(_, name, _)| name.as_str()).collect();
+    let sealed = std::iter::once(card_code.0.as_str())
+        .chain(card_notes.as_ref().map(|notes| notes.0.as_str()))
+        .chain(fields.iter().map(|(_, _, text)| text.as_str()));
+
+    forget(c, card)?;
+    diesel::insert_into(card_search::table)
+        .values(&NewCardSearch {
+            rowid: card,
+            name: &card_name,
+            fields: field_names.join(" "),
+            tokens: tokens(sealed),
+        })
+        .execute(c)?;
+
+    Ok(())
+}
+
+/// Removes the index entry of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_search::dsl::*;
+
+    diesel::delete(card_search.filter(rowid.eq(card))).execute(c)
+}
+
+/// Indexes the cards stored before the index existed. Returns how many
+/// were indexed.
+pub fn index_missing(c: &SqliteConnection) -> QueryResult<usize> {
+    use db::schema::{card_search, cards};
+
+    c.transaction(|| {
+        let missing = cards::table
+            .filter(cards::id.ne_all(card_search::table.select(card_search::rowid)))
+            .select(cards::id)
+            .load::<i32>(c)?;
+
+        for card in &missing {
+            index(c, *card)?;
+        }
+
+        Ok(missing.len())
+    })
+}
+
+/// The FTS5 query matching the cards with every word of `q`, `None` when
+/// it has none. Words are letters and digits only, so they need no quoting.
+pub fn query(q: &str) -> Option<String> {
+    let groups: Vec<String> = words(q)
+        .map(|word| match crypto::blind_index(&word) {
+            Some(token) => format!(
+                "({{name fields}} : \"{}\"* OR tokens : \"{}\")",
+                word, token
+            ),
+            None => format!("{{name fields}} : \"{}\"*", word),
+        })
+        .collect();
+
+    if groups.is_empty() {
+        None
+    } else {
+        Some(groups.join(" AND "))
+    }
+}
+
+/// Indexes leftover cards. Attached after the encryption fairing, whose
+/// key the blind tokens are derived from.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Card Search", |rocket| async move {
+        let conn = match LoyaltyDbConn::get_one(&rocket).await {
+            Some(conn) => conn,
+            None => return Err(rocket),
+        };
+
+        match conn.run(|c| index_missing(c)).await {
+            Ok(0) => Ok(rocket),
+            Ok(count) => {
+                log::info!("indexed {} card(s) for search", count);
+                Ok(rocket)
+            }
+            Err(e) => {
+                log::error!("failed to index cards for search: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,230 @@
+//! Public links showing the barcode of a card to anyone who has them, such
+//! as a relative at the till. Links expire, can be revoked, and count their
+//! views. Like other tokens they are only stored as a digest.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::{ContentType, Status};
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::{token, LoyaltiesReader, LoyaltiesWriter};
+use crate::barcode::BarcodeType;
+use crate::db::{self, models::Loyalty, models::NewShareLink, models::ShareLink};
+use crate::mail::Mailer;
+use crate::requests::{CreateShareLink, ShareLinkResponse};
+use crate::{APIError, LoyaltyDbConn};
+
+const DEFAULT_TTL_HOURS: i64 = 24;
+
+pub fn routes() -> Vec<Route> {
+    routes![create_link, list_links, revoke_link, view_link]
+}
+
+/// Revokes the links of `card`, before it is deleted.
+pub fn unlink(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::share_links::dsl::*;
+
+    diesel::delete(share_links.filter(card_id.eq(card))).execute(c)
+}
+
+fn owns_card(c: &SqliteConnection, user: i32, card: i32) -> QueryResult<bool> {
+    use db::schema::cards::dsl::*;
+
+    let found = cards
+        .filter(id.eq(card).and(user_id.eq(user)))
+        .filter(deleted_at.is_null())
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+
+    Ok(found.is_some())
+}
+
+fn describe(link: ShareLink, url: Option<String>) -> ShareLinkResponse {
+    ShareLinkResponse {
+        id: link.id,
+        url,
+        expires_at: link.expires_at,
+        revoked_at: link.revoked_at,
+        view_count: link.view_count,
+        last_viewed_at: link.last_viewed_at,
+        created_at: link.created_at,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn render(card: &Loyalty) -> String {
+    let kind = BarcodeType::from_name(&card.barcode_type).unwrap_or_default();
+    let symbol = kind.svg(&card.code.0).unwrap_or_default();
+    // Colors are free text: only hex codes and names go into the style.
+    let color = card
+        .color
+        .as_deref()
+        .filter(|color| color.chars().all(|c| c == '#' || c.is_ascii_alphanumeric()))
+        .unwrap_or("#fff");
+
+    format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <meta name=\"robots\" content=\"noindex\">\n<title>{name}</title>\n</head>\n\
+         <body style=\"font-family: sans-serif; text-align: center; background: {color}\">\n\
+         <h1>{name}</h1>\n<div style=\"background: #fff; padding: 16px\">{symbol}</div>\n\
+         <p>{code}</p>\n</body>\n</html>\n",
+        name = escape(&card.name),
+        color = color,
+        symbol = symbol,
+        code = escape(&card.code.0),
+    )
+}
+
+#[post("/loyalties/<loyalty_id>/share-link", format = "json", data = "<body>")]
+async fn create_link(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    mailer: State<'_, Mailer>,
+    loyalty_id: String,
+    body: Json<CreateShareLink>,
+) -> Result<status::Custom<Json<ShareLinkResponse>>, APIError> {
+    use db::schema::share_links::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+
+    let raw = token::generate();
+    let hashed = token::digest(&raw);
+    let ttl = Duration::hours(body.0.expires_in_hours.unwrap_or(DEFAULT_TTL_HOURS));
+    let created = db
+        .run(move |c| {
+            if !owns_card(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            diesel::insert_into(share_links)
+                .values(&NewShareLink {
+                    card_id: loyalty_id,
+                    token_hash: &hashed,
+                    expires_at: Utc::now().naive_utc() + ttl,
+                })
+                .execute(c)?;
+
+            Ok(share_links
+                .filter(token_hash.eq(&hashed))
+                .first::<ShareLink>(c)?)
+        })
+        .await?;
+
+    let url = mailer.link(&format!("/s/{}", raw));
+    Ok(status::Custom(
+        Status::Created,
+        Json(describe(created, Some(url))),
+    ))
+}
+
+#[get("/loyalties/<loyalty_id>/share-links")]
+async fn list_links(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+) -> Result<Json<Vec<ShareLinkResponse>>, APIError> {
+    use db::schema::share_links::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let links = db
+        .run(move |c| {
+            if !owns_card(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(share_links
+                .filter(card_id.eq(loyalty_id))
+                .order(created_at.desc())
+                .load::<ShareLink>(c)?)
+        })
+        .await?;
+
+    Ok(Json(
+        links.into_iter().map(|link| describe(link, None)).collect(),
+    ))
+}
+
+#[delete("/loyalties/<loyalty_id>/share-links/<link_id>")]
+async fn revoke_link(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    link_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::share_links::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let link_id: i32 = link_id.parse()?;
+
+    let revoked = db
+        .run(move |c| {
+            if !owns_card(c, user.0, loyalty_id)? {
+                return Err(APIError::NotFound);
+            }
+
+            Ok(diesel::update(
+                share_links
+                    .filter(id.eq(link_id).and(card_id.eq(loyalty_id)))
+                    .filter(revoked_at.is_null()),
+            )
+            .set(revoked_at.eq(Utc::now().naive_utc()))
+            .execute(c)?)
+        })
+        .await?;
+
+    match revoked {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "share link revoked")),
+    }
+}
+
+/// The public page of a link. Needs no account.
+#[get("/s/<link>")]
+async fn view_link(db: LoyaltyDbConn, link: String) -> Result<(ContentType, String), APIError> {
+    use db::schema::{cards, share_links};
+
+    let card = db
+        .run(move |c| {
+            let now = Utc::now().naive_utc();
+            let valid = share_links::table
+                .filter(share_links::token_hash.eq(token::digest(&link)))
+                .filter(share_links::revoked_at.is_null())
+                .filter(share_links::expires_at.gt(now));
+
+            let card = valid
+                .clone()
+                .inner_join(cards::table)
+                .filter(cards::deleted_at.is_null())
+                .select(cards::all_columns)
+                .first::<Loyalty>(c)
+                .optional()?;
+            if card.is_some() {
+                diesel::update(valid)
+                    .set((
+                        share_links::view_count.eq(share_links::view_count + 1),
+                        share_links::last_viewed_at.eq(now),
+                    ))
+                    .execute(c)?;
+            }
+
+            Ok::<_, diesel::result::Error>(card)
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    Ok((ContentType::HTML, render(&card)))
+}
@@ -0,0 +1,359 @@
+//! Cards shared by their owner with other accounts, found by email. Read
+//! access shows the card and its photos; full access also lets the card be
+//! edited. Only the owner deletes, archives, tags or reshares a card.
+
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, patch, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{self, models::CardShare, models::Loyalty, models::NewCardShare};
+use crate::groups;
+use crate::mail::Mailer;
+use crate::requests::{ShareCard, ShareResponse, SharedCardResponse, UpdateShare};
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        share_card,
+        list_shares,
+        update_share,
+        revoke_share,
+        shared_with_me
+    ]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Full,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Read
+    }
+}
+
+impl Access {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Access::Read),
+            "full" => Some(Access::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Full => "full",
+        }
+    }
+}
+
+/// Cards in use shared with `user`, only those it may edit when `write`.
+/// Cards of its family groups can be read but not edited.
+pub fn shared_ids(c: &SqliteConnection, user: i32, write: bool) -> QueryResult<Vec<i32>> {
+    use db::schema::{card_shares, cards};
+
+    let mut query = card_shares::table
+        .inner_join(cards::table)
+        .filter(card_shares::user_id.eq(user))
+        .filter(cards::deleted_at.is_null())
+        .select(card_shares::card_id)
+        .into_boxed();
+    if write {
+        query = query.filter(card_shares::access.eq(Access::Full.name()));
+        return query.load(c);
+    }
+
+    let mut shared = query.load::<i32>(c)?;
+    shared.extend(groups::card_ids(c, user)?);
+    Ok(shared)
+}
+
+/// Whether `user` can read `card`, owned or shared, and not in the trash.
+/// Shares may have been revoked since `user` last saw the card.
+pub fn can_read(c: &SqliteConnection, user: i32, card: i32) -> QueryResult<bool> {
+    use db::schema::cards::dsl::*;
+
+    let shared = shared_ids(c, user, false)?;
+    let found = cards
+        .filter(id.eq(card))
+        .filter(user_id.eq(user).or(id.eq_any(shared)))
+        .filter(deleted_at.is_null())
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+
+    Ok(found.is_some())
+}
+
+/// Revokes every share of `card`, before it is deleted.
+pub fn unshare(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_shares::dsl::*;
+
+    diesel::delete(card_shares.filter(card_id.eq(card))).execute(c)
+}
+
+/// Drops the shares `user` received, before the account is purged.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::card_shares::dsl::*;
+
+    diesel::delete(card_shares.filter(user_id.eq(user))).execute(c)
+}
+
+fn owned_card(c: &SqliteConnection, user: i32, card: i32) -> QueryResult<Option<Loyalty>> {
+    use db::schema::cards::dsl::*;
+
+    cards
+        .filter(id.eq(card).and(user_id.eq(user)))
+        .filter(deleted_at.is_null())
+        .first::<Loyalty>(c)
+        .optional()
+}
+
+fn describe_share(share: CardShare, email: String) -> ShareResponse {
+    ShareResponse {
+        id: share.id,
+        email,
+        access: Access::from_name(&share.access).unwrap_or_default(),
+        created_at: share.created_at,
+    }
+}
+
+#[post("/loyalties/<loyalty_id>/shares", format = "json", data = "<body>")]
+async fn share_card(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    mailer: State<'_, Mailer>,
+    loyalty_id: String,
+    body: Json<ShareCard>,
+) -> Result<status::Custom<Json<ShareResponse>>, APIError> {
+    use db::schema::{card_shares, users};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+
+    let email = body.0.email.clone();
+    let access = body.0.access;
+    let (share, card_name, owner_name) = db
+        .run(move |c| {
+            c.transaction(|| {
+                let card = owned_card(c, user.0, loyalty_id)?.ok_or(APIError::NotFound)?;
+                let recipient = users::table
+                    .filter(users::email.eq(&email))
+                    .filter(users::deleted_at.is_null())
+                    .filter(users::is_guest.eq(false))
+                    .select(users::id)
+                    .first::<i32>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if recipient == user.0 {
+                    return Err(APIError::Conflict);
+                }
+
+                let existing = card_shares::table
+                    .filter(card_shares::card_id.eq(card.id))
+                    .filter(card_shares::user_id.eq(recipient))
+                    .select(card_shares::id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if existing.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                diesel::insert_into(card_shares::table)
+                    .values(&NewCardShare {
+                        card_id: card.id,
+                        user_id: recipient,
+                        access: access.name(),
+                    })
+                    .execute(c)?;
+                let share = card_shares::table
+                    .filter(card_shares::card_id.eq(card.id))
+                    .filter(card_shares::user_id.eq(recipient))
+                    .first::<CardShare>(c)?;
+                let owner_name = users::table
+                    .find(user.0)
+                    .select(users::name)
+                    .first::<String>(c)?;
+
+                Ok((share, card.name, owner_name))
+            })
+        })
+        .await?;
+
+    let body_text = format!(
+        "{} shared the card \"{}\" with you. It now shows in your shared cards.\n\n{}\n",
+        owner_name,
+        card_name,
+        mailer.link("/loyalties/shared")
+    );
+    if let Err(e) = mailer
+        .send(&body.0.email, "A card was shared with you", body_text)
+        .await
+    {
+        log::warn!("could not send share notification: {}", e);
+    }
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(describe_share(share, body.0.email.clone())),
+    ))
+}
+
+#[get("/loyalties/<loyalty_id>/shares")]
+async fn list_shares(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    loyalty_id: String,
+) -> Result<Json<Vec<ShareResponse>>, APIError> {
+    use db::schema::{card_shares, users};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+
+    let shares = db
+        .run(move |c| {
+            owned_card(c, user.0, loyalty_id)?.ok_or(APIError::NotFound)?;
+
+            Ok::<_, APIError>(
+                card_shares::table
+                    .inner_join(users::table)
+                    .filter(card_shares::card_id.eq(loyalty_id))
+                    .order(card_shares::created_at.asc())
+                    .select((
+                        (
+                            card_shares::id,
+                            card_shares::card_id,
+                            card_shares::user_id,
+                            card_shares::access,
+                            card_shares::created_at,
+                        ),
+                        users::email,
+                    ))
+                    .load::<(CardShare, String)>(c)?,
+            )
+        })
+        .await?;
+
+    Ok(Json(
+        shares
+            .into_iter()
+            .map(|(share, email)| describe_share(share, email))
+            .collect(),
+    ))
+}
+
+#[patch(
+    "/loyalties/<loyalty_id>/shares/<share_id>",
+    format = "json",
+    data = "<body>"
+)]
+async fn update_share(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    share_id: String,
+    body: Json<UpdateShare>,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_shares::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let share_id: i32 = share_id.parse()?;
+
+    let updated = db
+        .run(move |c| {
+            owned_card(c, user.0, loyalty_id)?.ok_or(APIError::NotFound)?;
+
+            Ok::<_, APIError>(
+                diesel::update(card_shares.filter(id.eq(share_id).and(card_id.eq(loyalty_id))))
+                    .set(access.eq(body.0.access.name()))
+                    .execute(c)?,
+            )
+        })
+        .await?;
+
+    match updated {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "share updated")),
+    }
+}
+
+#[delete("/loyalties/<loyalty_id>/shares/<share_id>")]
+async fn revoke_share(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+    share_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_shares::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let share_id: i32 = share_id.parse()?;
+
+    let revoked = db
+        .run(move |c| {
+            owned_card(c, user.0, loyalty_id)?.ok_or(APIError::NotFound)?;
+
+            Ok::<_, APIError>(
+                diesel::delete(card_shares.filter(id.eq(share_id).and(card_id.eq(loyalty_id))))
+                    .execute(c)?,
+            )
+        })
+        .await?;
+
+    match revoked {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "share revoked")),
+    }
+}
+
+#[get("/loyalties/shared")]
+async fn shared_with_me(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    storage: State<'_, Storage>,
+) -> Result<Json<Vec<SharedCardResponse>>, APIError> {
+    use db::schema::{card_shares, cards, users};
+
+    let storage = storage.inner().clone();
+    let shared = db
+        .run(move |c| {
+            let found = card_shares::table
+                .inner_join(cards::table.inner_join(users::table))
+                .filter(card_shares::user_id.eq(user.0))
+                .filter(cards::deleted_at.is_null())
+                .order((cards::name.asc(), cards::id.asc()))
+                .select((card_shares::access, users::name, cards::all_columns))
+                .load::<(String, String, Loyalty)>(c)?;
+
+            let (grants, found): (Vec<(String, String)>, Vec<Loyalty>) = found
+                .into_iter()
+                .map(|(granted, owner, card)| ((granted, owner), card))
+                .unzip();
+            let described = crate::cards::describe(c, &storage, found)?;
+
+            Ok::<_, diesel::result::Error>(
+                grants
+                    .into_iter()
+                    .zip(described)
+                    .map(|((granted, owner), card)| SharedCardResponse {
+                        owner,
+                        access: Access::from_name(&granted).unwrap_or_default(),
+                        card,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await?;
+
+    Ok(Json(shared))
+}
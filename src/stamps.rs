@@ -0,0 +1,161 @@
+//! Stamp cards, such as a coffee shop's tenth coffee free: each stamp counts
+//! towards `stamps_required`, and completing the card issues a reward and
+//! starts it over.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::{post, routes, Route};
+use rocket_contrib::json::Json;
+
+use crate::auth::LoyaltiesWriter;
+use crate::db::{
+    self,
+    models::{Loyalty, NewStampReward, StampReward},
+};
+use crate::requests::{invalid, StampProgress, StampResponse, StampRewardResponse};
+use crate::{images, APIError, LoyaltyDbConn};
+
+pub fn routes() -> Vec<Route> {
+    routes![stamp, redeem_stamp_reward]
+}
+
+/// The cards of `ids` with a reward to redeem, once per reward.
+pub fn unredeemed(c: &SqliteConnection, ids: &[i32]) -> QueryResult<Vec<i32>> {
+    use db::schema::stamp_rewards::dsl::*;
+
+    stamp_rewards
+        .filter(card_id.eq_any(ids.to_vec()))
+        .filter(redeemed_at.is_null())
+        .select(card_id)
+        .load::<i32>(c)
+}
+
+/// Removes the rewards of `card`, before it is deleted.
+pub fn forget(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::stamp_rewards::dsl::*;
+
+    diesel::delete(stamp_rewards.filter(card_id.eq(card))).execute(c)
+}
+
+/// A card the caller can edit that collects stamps, with the stamps it needs.
+fn stamp_card(c: &SqliteConnection, user: i32, card: i32) -> Result<(Loyalty, i32), APIError> {
+    use db::schema::cards::dsl::*;
+
+    if !images::can_edit(c, user, card)? {
+        return Err(APIError::NotFound);
+    }
+    let found = cards
+        .find(card)
+        .filter(deleted_at.is_null())
+        .first::<Loyalty>(c)
+        .optional()?
+        .ok_or(APIError::NotFound)?;
+
+    match found.stamps_required {
+        Some(required) => Ok((found, required)),
+        None => Err(invalid("stamps_required", "the card isn't a stamp card").into()),
+    }
+}
+
+fn progress(c: &SqliteConnection, card: &Loyalty, required: i32) -> QueryResult<StampProgress> {
+    Ok(StampProgress {
+        collected: card.stamps,
+        required,
+        rewards: unredeemed(c, &[card.id])?.len() as i64,
+    })
+}
+
+/// Adds a stamp to a stamp card the caller can edit, issuing a reward when
+/// it completes the card.
+#[post("/loyalties/<loyalty_id>/stamp")]
+async fn stamp(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+) -> Result<Json<StampResponse>, APIError> {
+    use db::schema::{cards, stamp_rewards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let stamped = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let (_, required) = stamp_card(c, user.0, loyalty_id)?;
+
+                // Counting in the update, so that stamps given at once all count.
+                diesel::update(cards::table.find(loyalty_id))
+                    .set(cards::stamps.eq(cards::stamps + 1))
+                    .execute(c)?;
+                let mut card = cards::table.find(loyalty_id).first::<Loyalty>(c)?;
+
+                let mut issued = None;
+                if card.stamps >= required {
+                    diesel::update(cards::table.find(loyalty_id))
+                        .set(cards::stamps.eq(cards::stamps - required))
+                        .execute(c)?;
+                    diesel::insert_into(stamp_rewards::table)
+                        .values(&NewStampReward {
+                            card_id: loyalty_id,
+                        })
+                        .execute(c)?;
+                    card = cards::table.find(loyalty_id).first::<Loyalty>(c)?;
+                    issued = Some(
+                        stamp_rewards::table
+                            .order(stamp_rewards::id.desc())
+                            .first::<StampReward>(c)?
+                            .into(),
+                    );
+                }
+
+                Ok(StampResponse {
+                    stamps: progress(c, &card, required)?,
+                    issued,
+                })
+            })
+        })
+        .await?;
+
+    Ok(Json(stamped))
+}
+
+/// Spends the oldest reward of a stamp card the caller can edit.
+#[post("/loyalties/<loyalty_id>/stamp/redeem")]
+async fn redeem_stamp_reward(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+) -> Result<Json<StampRewardResponse>, APIError> {
+    use db::schema::stamp_rewards;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let redeemed = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                stamp_card(c, user.0, loyalty_id)?;
+                let reward = stamp_rewards::table
+                    .filter(stamp_rewards::card_id.eq(loyalty_id))
+                    .filter(stamp_rewards::redeemed_at.is_null())
+                    .order(stamp_rewards::id.asc())
+                    .first::<StampReward>(c)
+                    .optional()?
+                    .ok_or(APIError::Conflict)?;
+
+                let taken = diesel::update(
+                    stamp_rewards::table
+                        .find(reward.id)
+                        .filter(stamp_rewards::redeemed_at.is_null()),
+                )
+                .set(stamp_rewards::redeemed_at.eq(Utc::now().naive_utc()))
+                .execute(c)?;
+                if taken == 0 {
+                    return Err(APIError::Conflict);
+                }
+
+                Ok(stamp_rewards::table
+                    .find(reward.id)
+                    .first::<StampReward>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(redeemed.into()))
+}
@@ -0,0 +1,204 @@
+//! Usage statistics of the signed-in user, for the insights screen of the
+//! app. Rows are aggregated here: wallets are small and Diesel 1 has no
+//! `GROUP BY`.
+
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::prelude::*;
+use rocket::{get, routes, Route};
+use rocket_contrib::json::Json;
+
+use crate::auth::LoyaltiesReader;
+use crate::db;
+use crate::requests::{
+    BarcodeTypeCount, CardUsage, CategoryCount, DailyUses, StatsResponse, SummaryResponse,
+};
+use crate::{APIError, LoyaltyDbConn};
+
+const MOST_USED: usize = 5;
+const DEFAULT_DAYS: i64 = 30;
+const MAX_DAYS: i64 = 365;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_stats, get_summary]
+}
+
+/// One entry per day from `since` to `today`, days without uses included.
+fn per_day(since: NaiveDate, today: NaiveDate, days: &[NaiveDate]) -> Vec<DailyUses> {
+    let mut usage = Vec::new();
+    let mut day = since;
+    while day <= today {
+        usage.push(DailyUses {
+            day,
+            uses: days.iter().filter(|used| **used == day).count() as i64,
+        });
+        day = day.succ();
+    }
+    usage
+}
+
+/// Cards by category, the most used ones, and uses per day over the last
+/// `days` days, 30 by default.
+#[get("/stats?<days>")]
+async fn get_stats(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    days: Option<String>,
+) -> Result<Json<StatsResponse>, APIError> {
+    use db::schema::{card_tags, card_uses, cards, categories};
+
+    let days: i64 = days
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_DAYS)
+        .max(1)
+        .min(MAX_DAYS);
+    let today = Utc::now().naive_utc().date();
+    let since = today - Duration::days(days - 1);
+
+    let stats = db
+        .run(move |c| {
+            let owned = cards::table
+                .filter(cards::user_id.eq(user.0))
+                .filter(cards::deleted_at.is_null())
+                .order((cards::use_count.desc(), cards::id.asc()))
+                .select((
+                    cards::id,
+                    cards::name,
+                    cards::use_count,
+                    cards::last_used_at,
+                ))
+                .load::<(i32, String, i32, Option<chrono::NaiveDateTime>)>(c)?;
+            let ids: Vec<i32> = owned.iter().map(|(id, ..)| *id).collect();
+
+            let named = categories::table
+                .filter(categories::user_id.eq(user.0))
+                .order(categories::name.asc())
+                .select((categories::id, categories::name))
+                .load::<(i32, String)>(c)?;
+            let tagged = card_tags::table
+                .filter(card_tags::card_id.eq_any(ids.clone()))
+                .select((card_tags::card_id, card_tags::category_id))
+                .load::<(i32, i32)>(c)?;
+
+            let used_on = card_uses::table
+                .filter(card_uses::user_id.eq(user.0))
+                .filter(card_uses::used_at.ge(since.and_hms(0, 0, 0)))
+                .select(card_uses::used_at)
+                .load::<chrono::NaiveDateTime>(c)?
+                .into_iter()
+                .map(|used_at| used_at.date())
+                .collect::<Vec<_>>();
+
+            let by_category = named
+                .into_iter()
+                .map(|(category, name)| CategoryCount {
+                    name,
+                    cards: tagged.iter().filter(|(_, tag)| *tag == category).count() as i64,
+                })
+                .collect();
+            let uncategorized = ids
+                .iter()
+                .filter(|id| !tagged.iter().any(|(card, _)| card == *id))
+                .count() as i64;
+            let most_used = owned
+                .iter()
+                .filter(|(_, _, uses, _)| *uses > 0)
+                .take(MOST_USED)
+                .map(|(id, name, uses, last_used_at)| CardUsage {
+                    id: *id,
+                    name: name.clone(),
+                    use_count: *uses,
+                    last_used_at: *last_used_at,
+                })
+                .collect();
+
+            Ok::<_, diesel::result::Error>(StatsResponse {
+                cards: owned.len() as i64,
+                by_category,
+                uncategorized,
+                most_used,
+                total_uses: used_on.len() as i64,
+                usage: per_day(since, today, &used_on),
+            })
+        })
+        .await?;
+
+    Ok(Json(stats))
+}
+
+/// Counts of the cards of the user, by state, category and format.
+#[get("/loyalties/summary")]
+async fn get_summary(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<SummaryResponse>, APIError> {
+    use db::schema::{card_tags, cards, categories};
+
+    let summary = db
+        .run(move |c| {
+            let owned = cards::table
+                .filter(cards::user_id.eq(user.0))
+                .select((
+                    cards::id,
+                    cards::barcode_type,
+                    cards::archived_at.is_not_null(),
+                    cards::deleted_at.is_not_null(),
+                ))
+                .load::<(i32, String, bool, bool)>(c)?;
+            let named = categories::table
+                .filter(categories::user_id.eq(user.0))
+                .order(categories::name.asc())
+                .select((categories::id, categories::name))
+                .load::<(i32, String)>(c)?;
+            let tagged = card_tags::table
+                .inner_join(categories::table)
+                .filter(categories::user_id.eq(user.0))
+                .select((card_tags::card_id, card_tags::category_id))
+                .load::<(i32, i32)>(c)?;
+
+            let live: Vec<&(i32, String, bool, bool)> =
+                owned.iter().filter(|(_, _, _, trashed)| !trashed).collect();
+            let is_live = |card: i32| live.iter().any(|(id, ..)| *id == card);
+
+            let by_category = named
+                .into_iter()
+                .map(|(category, name)| CategoryCount {
+                    name,
+                    cards: tagged
+                        .iter()
+                        .filter(|(card, tag)| *tag == category && is_live(*card))
+                        .count() as i64,
+                })
+                .collect();
+            let uncategorized = live
+                .iter()
+                .filter(|(id, ..)| !tagged.iter().any(|(card, _)| card == id))
+                .count() as i64;
+
+            let mut by_barcode_type: Vec<BarcodeTypeCount> = Vec::new();
+            for (_, kind, _, _) in &live {
+                match by_barcode_type
+                    .iter_mut()
+                    .find(|count| count.barcode_type == *kind)
+                {
+                    Some(count) => count.cards += 1,
+                    None => by_barcode_type.push(BarcodeTypeCount {
+                        barcode_type: kind.clone(),
+                        cards: 1,
+                    }),
+                }
+            }
+            by_barcode_type.sort_by(|a, b| a.barcode_type.cmp(&b.barcode_type));
+
+            Ok::<_, diesel::result::Error>(SummaryResponse {
+                total: live.len() as i64,
+                archived: live.iter().filter(|(_, _, archived, _)| *archived).count() as i64,
+                trashed: (owned.len() - live.len()) as i64,
+                by_category,
+                uncategorized,
+                by_barcode_type,
+            })
+        })
+        .await?;
+
+    Ok(Json(summary))
+}
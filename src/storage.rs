@@ -0,0 +1,244 @@
+//! Storage of uploaded files, such as card images. Objects are addressed by
+//! keys like `cards/12/<token>` and written by one of the backends of the
+//! `storage` configuration section: the local disk, or an S3-compatible
+//! bucket (AWS, MinIO, R2, ...).
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio::fs;
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("storage i/o error")]
+    Io(#[from] std::io::Error),
+    #[error("object storage error: {0}")]
+    Backend(String),
+}
+
+#[rocket::async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), StorageError>;
+    /// `None` when there is no object under `key`.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    /// A time-limited link clients can download the object from directly,
+    /// for backends that can sign one.
+    fn presign(&self, key: &str) -> Option<String>;
+}
+
+/// Files under a directory of the local disk. Content types are not kept:
+/// they are stored with the object's row.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+#[rocket::async_trait]
+impl ObjectStore for LocalStore {
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), StorageError> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        Ok(fs::write(path, bytes).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.root.join(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.root.join(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn presign(&self, _key: &str) -> Option<String> {
+        None
+    }
+}
+
+pub struct S3Store {
+    bucket: Bucket,
+    url_ttl: u32,
+}
+
+fn backend_error(e: impl std::fmt::Display) -> StorageError {
+    StorageError::Backend(e.to_string())
+}
+
+fn check_status(code: u16) -> Result<(), StorageError> {
+    match code {
+        200..=299 => Ok(()),
+        _ => Err(StorageError::Backend(format!("status {}", code))),
+    }
+}
+
+#[rocket::async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), StorageError> {
+        let (_, code) = self
+            .bucket
+            .put_object_with_content_type(key, &bytes, content_type)
+            .await
+            .map_err(backend_error)?;
+        check_status(code)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let (bytes, code) = self.bucket.get_object(key).await.map_err(backend_error)?;
+        match code {
+            404 => Ok(None),
+            _ => check_status(code).map(|_| Some(bytes)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let (_, code) = self
+            .bucket
+            .delete_object(key)
+            .await
+            .map_err(backend_error)?;
+        match code {
+            404 => Ok(()),
+            _ => check_status(code),
+        }
+    }
+
+    fn presign(&self, key: &str) -> Option<String> {
+        match self.bucket.presign_get(key, self.url_ttl) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                log::warn!("could not presign {}: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+fn default_url_ttl() -> u32 {
+    60 * 60
+}
+
+/// The `storage` section of the Rocket configuration.
+#[derive(Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local {
+        path: String,
+    },
+    S3 {
+        bucket: String,
+        region: String,
+        /// For S3-compatible services. Buckets are then addressed by path.
+        endpoint: Option<String>,
+        access_key: String,
+        secret_key: String,
+        /// Seconds presigned links stay valid.
+        #[serde(default = "default_url_ttl")]
+        url_ttl: u32,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Local {
+            path: "uploads".to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Storage(Arc<dyn ObjectStore>);
+
+impl Storage {
+    pub fn new(config: StorageConfig) -> Result<Self, StorageError> {
+        match config {
+            StorageConfig::Local { path } => Ok(Storage(Arc::new(LocalStore {
+                root: PathBuf::from(path),
+            }))),
+            StorageConfig::S3 {
+                bucket,
+                region,
+                endpoint,
+                access_key,
+                secret_key,
+                url_ttl,
+            } => {
+                let credentials =
+                    Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)
+                        .map_err(backend_error)?;
+                let bucket = match endpoint {
+                    Some(endpoint) => Bucket::new_with_path_style(
+                        &bucket,
+                        Region::Custom { region, endpoint },
+                        credentials,
+                    ),
+                    None => {
+                        Bucket::new(&bucket, region.parse().map_err(backend_error)?, credentials)
+                    }
+                }
+                .map_err(backend_error)?;
+
+                Ok(Storage(Arc::new(S3Store { bucket, url_ttl })))
+            }
+        }
+    }
+
+    /// Deletes objects that are no longer referenced. Failures only leave
+    /// garbage behind, so they are logged rather than returned.
+    pub async fn discard(&self, keys: &[String]) {
+        for key in keys {
+            if let Err(e) = self.delete(key).await {
+                log::warn!("could not delete stored object {}: {}", key, e);
+            }
+        }
+    }
+}
+
+impl std::ops::Deref for Storage {
+    type Target = dyn ObjectStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Storage", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<StorageConfig>("storage") {
+            Ok(config) => config,
+            Err(e) if e.missing() => StorageConfig::default(),
+            Err(e) => {
+                log::error!("invalid storage configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match Storage::new(config) {
+            Ok(storage) => Ok(rocket.manage(storage)),
+            Err(e) => {
+                log::error!("could not set up storage: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,255 @@
+//! Categories users tag their cards with, such as `groceries`. Names are
+//! unique per user, ignoring case.
+
+use std::borrow::Cow;
+
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, put, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{self, models::Category, models::NewCardTag, models::NewCategory};
+use crate::requests::{AddLoyaltyResponse, CategoryResponse, CreateCategory, SetTags};
+use crate::storage::Storage;
+use crate::{APIError, LoyaltyDbConn};
+
+const MAX_NAME_CHARS: usize = 50;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_categories, create_category, delete_category, set_tags]
+}
+
+/// Tags of `cards` as `(card, name)`, sorted by name.
+pub fn tags_of(c: &SqliteConnection, cards: &[i32]) -> QueryResult<Vec<(i32, String)>> {
+    use db::schema::{card_tags, categories};
+
+    card_tags::table
+        .inner_join(categories::table)
+        .filter(card_tags::card_id.eq_any(cards.to_vec()))
+        .select((card_tags::card_id, categories::name))
+        .order(categories::name.asc())
+        .load(c)
+}
+
+/// Removes the tags of `card`, before it is deleted.
+pub fn untag(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_tags::dsl::*;
+
+    diesel::delete(card_tags.filter(card_id.eq(card))).execute(c)
+}
+
+fn find_or_create(c: &SqliteConnection, user: i32, tag: &str) -> QueryResult<i32> {
+    use db::schema::categories::dsl::*;
+
+    let existing = categories
+        .filter(user_id.eq(user).and(name.eq(tag)))
+        .select(id)
+        .first::<i32>(c)
+        .optional()?;
+    if let Some(existing) = existing {
+        return Ok(existing);
+    }
+
+    diesel::insert_into(categories)
+        .values(&NewCategory {
+            user_id: user,
+            name: tag,
+        })
+        .execute(c)?;
+
+    categories
+        .filter(user_id.eq(user).and(name.eq(tag)))
+        .select(id)
+        .first::<i32>(c)
+}
+
+/// Trims the names and drops duplicates, rejecting blank or long ones on the
+/// `tags` field.
+fn normalize(tags: &[String]) -> Result<Vec<String>, ValidationErrors> {
+    let mut normalized: Vec<String> = Vec::new();
+
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.chars().count() > MAX_NAME_CHARS {
+            let mut error = ValidationError::new("length");
+            error.message = Some(Cow::Borrowed("tags need 1 to 50 characters"));
+
+            let mut errors = ValidationErrors::new();
+            errors.add("tags", error);
+            return Err(errors);
+        }
+
+        if !normalized
+            .iter()
+            .any(|seen| seen.to_lowercase() == tag.to_lowercase())
+        {
+            normalized.push(tag.to_string());
+        }
+    }
+
+    Ok(normalized)
+}
+
+#[get("/categories")]
+async fn list_categories(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<Vec<CategoryResponse>>, APIError> {
+    use db::schema::{card_tags, cards, categories};
+
+    let (found, tagged) = db
+        .run(move |c| {
+            let found = categories::table
+                .filter(categories::user_id.eq(user.0))
+                .order(categories::name.asc())
+                .load::<Category>(c)?;
+            let tagged = card_tags::table
+                .inner_join(cards::table)
+                .filter(cards::deleted_at.is_null())
+                .filter(
+                    card_tags::category_id
+                        .eq_any(found.iter().map(|cat| cat.id).collect::<Vec<_>>()),
+                )
+                .select(card_tags::category_id)
+                .load::<i32>(c)?;
+
+            Ok::<_, diesel::result::Error>((found, tagged))
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|category| CategoryResponse {
+                cards: tagged.iter().filter(|id| **id == category.id).count() as i64,
+                id: category.id,
+                name: category.name,
+            })
+            .collect(),
+    ))
+}
+
+#[post("/categories", format = "json", data = "<body>")]
+async fn create_category(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    body: Json<CreateCategory>,
+) -> Result<status::Custom<Json<CategoryResponse>>, APIError> {
+    use db::schema::categories::dsl::*;
+
+    body.0.validate()?;
+    let wanted = body.0.name.trim().to_string();
+
+    let created = db
+        .run(move |c| {
+            c.transaction(|| {
+                let existing = categories
+                    .filter(user_id.eq(user.0).and(name.eq(&wanted)))
+                    .select(id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if existing.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                let created = find_or_create(c, user.0, &wanted)?;
+                Ok(categories.find(created).first::<Category>(c)?)
+            })
+        })
+        .await?;
+
+    Ok(status::Custom(
+        Status::Created,
+        Json(CategoryResponse {
+            id: created.id,
+            name: created.name,
+            cards: 0,
+        }),
+    ))
+}
+
+/// Deletes a category and untags its cards. The cards are kept.
+#[delete("/categories/<category_id>")]
+async fn delete_category(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    category_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::{card_tags, categories};
+
+    let target: i32 = category_id.parse()?;
+
+    let deleted = db
+        .run(move |c| {
+            c.transaction(|| {
+                let owned = categories::table
+                    .filter(categories::id.eq(target))
+                    .filter(categories::user_id.eq(user.0));
+                if owned
+                    .select(categories::id)
+                    .first::<i32>(c)
+                    .optional()?
+                    .is_none()
+                {
+                    return Ok(0);
+                }
+
+                diesel::delete(card_tags::table.filter(card_tags::category_id.eq(target)))
+                    .execute(c)?;
+                diesel::delete(owned).execute(c)
+            })
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "category deleted")),
+    }
+}
+
+#[put("/loyalties/<loyalty_id>/tags", format = "json", data = "<body>")]
+async fn set_tags(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    loyalty_id: String,
+    body: Json<SetTags>,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::{card_tags, cards};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+    let tags = normalize(&body.0.tags)?;
+
+    let storage = storage.inner().clone();
+    let updated = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let card = cards::table
+                    .filter(cards::id.eq(loyalty_id).and(cards::user_id.eq(user.0)))
+                    .filter(cards::deleted_at.is_null())
+                    .first::<db::models::Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+
+                untag(c, card.id)?;
+                for tag in &tags {
+                    let category = find_or_create(c, user.0, tag)?;
+                    diesel::insert_into(card_tags::table)
+                        .values(&NewCardTag {
+                            card_id: card.id,
+                            category_id: category,
+                        })
+                        .execute(c)?;
+                }
+
+                Ok(crate::cards::describe_one(c, &storage, card)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(updated))
+}
@@ -0,0 +1,252 @@
+//! Bronze, silver and gold tiers of the retailers' programs. A user's tier
+//! in a program follows the points ever earned on their cards of that
+//! retailer, redeemed or not, against the thresholds of the configuration.
+//!
+//! Tiers are stored, so card responses don't have to add up ledgers, and
+//! never go down: deleting a card keeps what was earned on it.
+
+use chrono::Utc;
+use diesel::dsl::sum;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::{get, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+use crate::auth::LoyaltiesReader;
+use crate::db::{
+    self,
+    models::{NewUserTier, UserTier},
+};
+use crate::requests::TierResponse;
+use crate::{APIError, LoyaltyDbConn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tier {
+    Bronze,
+    Silver,
+    Gold,
+}
+
+impl Tier {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tier::Bronze => "bronze",
+            Tier::Silver => "silver",
+            Tier::Gold => "gold",
+        }
+    }
+}
+
+/// The `tiers` section of the Rocket configuration.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct TiersConfig {
+    /// Lifetime points from which a user is silver.
+    pub silver: i64,
+    /// Lifetime points from which a user is gold.
+    pub gold: i64,
+}
+
+impl Default for TiersConfig {
+    fn default() -> Self {
+        TiersConfig {
+            silver: 1000,
+            gold: 5000,
+        }
+    }
+}
+
+impl TiersConfig {
+    pub fn tier(&self, lifetime_points: i64) -> Tier {
+        if lifetime_points >= self.gold {
+            Tier::Gold
+        } else if lifetime_points >= self.silver {
+            Tier::Silver
+        } else {
+            Tier::Bronze
+        }
+    }
+
+    /// The tier after `tier`, with its threshold.
+    fn next(&self, tier: Tier) -> Option<(Tier, i64)> {
+        match tier {
+            Tier::Bronze => Some((Tier::Silver, self.silver)),
+            Tier::Silver => Some((Tier::Gold, self.gold)),
+            Tier::Gold => None,
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_tiers]
+}
+
+/// Points earned on the cards `user` owns of `retailer`, not counting those
+/// other users gave.
+fn lifetime_points(c: &SqliteConnection, user: i32, retailer: i32) -> QueryResult<i64> {
+    use db::schema::{cards, point_transactions, point_transfers};
+
+    let earned = point_transactions::table
+        .inner_join(cards::table)
+        .filter(cards::user_id.eq(user))
+        .filter(cards::retailer_id.eq(retailer))
+        .filter(point_transactions::delta.gt(0))
+        .filter(
+            point_transactions::id.nullable().ne_all(
+                point_transfers::table
+                    .filter(point_transfers::credit_id.is_not_null())
+                    .select(point_transfers::credit_id),
+            ),
+        )
+        .select(sum(point_transactions::delta))
+        .first::<Option<i64>>(c)?;
+
+    Ok(earned.unwrap_or(0))
+}
+
+/// Updates the tier of the owner of `card` in the program of its retailer,
+/// after points were earned on it or it changed hands. Cards made without
+/// the catalog are in no program.
+pub fn refresh(c: &SqliteConnection, config: TiersConfig, card: i32) -> QueryResult<()> {
+    use db::schema::{cards, user_tiers};
+
+    let (owner, retailer) = cards::table
+        .find(card)
+        .select((cards::user_id, cards::retailer_id))
+        .first::<(i32, Option<i32>)>(c)?;
+    let retailer = match retailer {
+        Some(retailer) => retailer,
+        None => return Ok(()),
+    };
+
+    let stored = user_tiers::table
+        .find((owner, retailer))
+        .select(user_tiers::lifetime_points)
+        .first::<i64>(c)
+        .optional()?;
+    let lifetime = lifetime_points(c, owner, retailer)?.max(stored.unwrap_or(0));
+    diesel::replace_into(user_tiers::table)
+        .values(&NewUserTier {
+            user_id: owner,
+            retailer_id: retailer,
+            tier: config.tier(lifetime).name(),
+            lifetime_points: lifetime,
+            updated_at: Utc::now().naive_utc(),
+        })
+        .execute(c)?;
+
+    Ok(())
+}
+
+/// The stored tiers of `users` in the programs of `retailers`.
+pub fn tiers_of(
+    c: &SqliteConnection,
+    users: &[i32],
+    retailers: &[i32],
+) -> QueryResult<Vec<UserTier>> {
+    use db::schema::user_tiers::dsl::*;
+
+    user_tiers
+        .filter(user_id.eq_any(users.to_vec()))
+        .filter(retailer_id.eq_any(retailers.to_vec()))
+        .load::<UserTier>(c)
+}
+
+/// Removes the tiers of `user`, before the account is purged.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::user_tiers::dsl::*;
+
+    diesel::delete(user_tiers.filter(user_id.eq(user))).execute(c)
+}
+
+/// Moves the stored tiers to the thresholds of `config`, which may have
+/// changed since they were computed.
+fn retier(c: &SqliteConnection, config: TiersConfig) -> QueryResult<usize> {
+    use db::schema::user_tiers::dsl::*;
+
+    c.transaction(|| {
+        let gold = diesel::update(user_tiers.filter(lifetime_points.ge(config.gold)))
+            .set(tier.eq(Tier::Gold.name()))
+            .execute(c)?;
+        let silver = diesel::update(
+            user_tiers
+                .filter(lifetime_points.ge(config.silver))
+                .filter(lifetime_points.lt(config.gold)),
+        )
+        .set(tier.eq(Tier::Silver.name()))
+        .execute(c)?;
+        let bronze = diesel::update(user_tiers.filter(lifetime_points.lt(config.silver)))
+            .set(tier.eq(Tier::Bronze.name()))
+            .execute(c)?;
+
+        Ok(gold + silver + bronze)
+    })
+}
+
+/// The tiers of the caller in the programs they earned points in.
+#[get("/tiers")]
+async fn list_tiers(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    config: State<'_, TiersConfig>,
+) -> Result<Json<Vec<TierResponse>>, APIError> {
+    use db::schema::{retailers, user_tiers};
+
+    let config = *config;
+    let found = db
+        .run(move |c| {
+            user_tiers::table
+                .inner_join(retailers::table)
+                .filter(user_tiers::user_id.eq(user.0))
+                .order(retailers::name.asc())
+                .select((user_tiers::all_columns, retailers::name))
+                .load::<(UserTier, String)>(c)
+        })
+        .await?;
+
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|(stored, retailer_name)| {
+                let next = config.next(config.tier(stored.lifetime_points));
+                TierResponse {
+                    retailer_id: stored.retailer_id,
+                    retailer_name,
+                    tier: stored.tier,
+                    lifetime_points: stored.lifetime_points,
+                    next_tier: next.map(|(tier, _)| tier.name()),
+                    points_to_next: next.map(|(_, threshold)| threshold - stored.lifetime_points),
+                }
+            })
+            .collect(),
+    ))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Loyalty Tiers", |rocket| async move {
+        let config = match rocket.figment().extract_inner::<TiersConfig>("tiers") {
+            Ok(config) if config.silver > 0 && config.silver < config.gold => config,
+            Ok(_) => {
+                log::error!("invalid tiers configuration: silver must be below gold");
+                return Err(rocket);
+            }
+            Err(e) if e.missing() => TiersConfig::default(),
+            Err(e) => {
+                log::error!("invalid tiers configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let conn = match LoyaltyDbConn::get_one(&rocket).await {
+            Some(conn) => conn,
+            None => return Err(rocket),
+        };
+        if let Err(e) = conn.run(move |c| retier(c, config)).await {
+            log::error!("failed to update the stored tiers: {}", e);
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(config))
+    })
+}
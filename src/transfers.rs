@@ -0,0 +1,340 @@
+//! Giving a card to another account, found by email. The card only changes
+//! hands once the recipient accepts; until then the owner can call it off.
+//!
+//! The card keeps its history, photos, attachments, custom fields and
+//! balance. What belongs to the former owner is dropped: tags, shares,
+//! share links, group and their reminders.
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::{delete, get, post, routes, Route, State};
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::auth::{LoyaltiesReader, LoyaltiesWriter};
+use crate::db::{
+    self,
+    models::{CardTransfer, Loyalty, NewCardTransfer},
+};
+use crate::mail::Mailer;
+use crate::quota::{self, QuotaConfig};
+use crate::requests::{AddLoyaltyResponse, TransferCard, TransferResponse};
+use crate::storage::Storage;
+use crate::tiers::{self, TiersConfig};
+use crate::{share_links, shares, tags, APIError, LoyaltyDbConn};
+
+const TRANSFER_TTL_DAYS: i64 = 7;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        transfer_card,
+        cancel_transfer,
+        list_transfers,
+        accept_transfer,
+        decline_transfer
+    ]
+}
+
+/// Calls off the pending transfer of `card`, before it is deleted.
+pub fn cancel(c: &SqliteConnection, card: i32) -> QueryResult<usize> {
+    use db::schema::card_transfers::dsl::*;
+
+    diesel::delete(card_transfers.filter(card_id.eq(card))).execute(c)
+}
+
+/// Drops the transfers offered to `user`, before the account is purged.
+/// Those it offered go with its cards.
+pub fn forget(c: &SqliteConnection, user: i32) -> QueryResult<usize> {
+    use db::schema::card_transfers::dsl::*;
+
+    diesel::delete(card_transfers.filter(to_user_id.eq(user))).execute(c)
+}
+
+fn describe(
+    c: &SqliteConnection,
+    transfers: Vec<CardTransfer>,
+) -> QueryResult<Vec<TransferResponse>> {
+    use db::schema::{cards, users};
+
+    let card_ids: Vec<i32> = transfers.iter().map(|transfer| transfer.card_id).collect();
+    let names = cards::table
+        .filter(cards::id.eq_any(card_ids))
+        .select((cards::id, cards::name))
+        .load::<(i32, String)>(c)?;
+    let user_ids: Vec<i32> = transfers
+        .iter()
+        .flat_map(|transfer| vec![transfer.from_user_id, transfer.to_user_id])
+        .collect();
+    let emails = users::table
+        .filter(users::id.eq_any(user_ids))
+        .select((users::id, users::email))
+        .load::<(i32, String)>(c)?;
+
+    let email_of = |user: i32| {
+        emails
+            .iter()
+            .find(|(found, _)| *found == user)
+            .map(|(_, email)| email.clone())
+            .unwrap_or_default()
+    };
+    Ok(transfers
+        .into_iter()
+        .map(|transfer| TransferResponse {
+            id: transfer.id,
+            card_id: transfer.card_id,
+            card_name: names
+                .iter()
+                .find(|(card, _)| *card == transfer.card_id)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_default(),
+            from_email: email_of(transfer.from_user_id),
+            to_email: email_of(transfer.to_user_id),
+            expires_at: transfer.expires_at,
+            created_at: transfer.created_at,
+        })
+        .collect())
+}
+
+/// Offers a card the caller owns to another account.
+#[post("/loyalties/<loyalty_id>/transfer", format = "json", data = "<body>")]
+async fn transfer_card(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    mailer: State<'_, Mailer>,
+    loyalty_id: String,
+    body: Json<TransferCard>,
+) -> Result<status::Custom<Json<TransferResponse>>, APIError> {
+    use db::schema::{card_transfers, cards, users};
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    body.0.validate()?;
+
+    let email = body.0.email.clone();
+    let (transfer, owner_name) = db
+        .run(move |c| {
+            c.transaction(|| {
+                let card = cards::table
+                    .filter(cards::id.eq(loyalty_id))
+                    .filter(cards::user_id.eq(user.0))
+                    .filter(cards::deleted_at.is_null())
+                    .first::<Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                let recipient = users::table
+                    .filter(users::email.eq(&email))
+                    .filter(users::deleted_at.is_null())
+                    .filter(users::is_guest.eq(false))
+                    .select(users::id)
+                    .first::<i32>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if recipient == user.0 {
+                    return Err(APIError::Conflict);
+                }
+
+                // An expired offer makes way for a new one.
+                diesel::delete(
+                    card_transfers::table
+                        .filter(card_transfers::card_id.eq(card.id))
+                        .filter(card_transfers::expires_at.le(Utc::now().naive_utc())),
+                )
+                .execute(c)?;
+                let pending = card_transfers::table
+                    .filter(card_transfers::card_id.eq(card.id))
+                    .select(card_transfers::id)
+                    .first::<i32>(c)
+                    .optional()?;
+                if pending.is_some() {
+                    return Err(APIError::Conflict);
+                }
+
+                diesel::insert_into(card_transfers::table)
+                    .values(&NewCardTransfer {
+                        card_id: card.id,
+                        from_user_id: user.0,
+                        to_user_id: recipient,
+                        expires_at: Utc::now().naive_utc() + Duration::days(TRANSFER_TTL_DAYS),
+                    })
+                    .execute(c)?;
+                let transfer = card_transfers::table
+                    .filter(card_transfers::card_id.eq(card.id))
+                    .first::<CardTransfer>(c)?;
+                let owner_name = users::table
+                    .find(user.0)
+                    .select(users::name)
+                    .first::<String>(c)?;
+
+                Ok((describe(c, vec![transfer])?.remove(0), owner_name))
+            })
+        })
+        .await?;
+
+    let body_text = format!(
+        "{} would like to give you the card \"{}\". Accept it from your pending \
+         transfers within {} days:\n\n{}\n",
+        owner_name,
+        transfer.card_name,
+        TRANSFER_TTL_DAYS,
+        mailer.link("/transfers")
+    );
+    if let Err(e) = mailer
+        .send(&transfer.to_email, "A card is waiting for you", body_text)
+        .await
+    {
+        log::warn!("could not send transfer notification: {}", e);
+    }
+
+    Ok(status::Custom(Status::Created, Json(transfer)))
+}
+
+/// Calls off the transfer of a card the caller offered.
+#[delete("/loyalties/<loyalty_id>/transfer")]
+async fn cancel_transfer(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    loyalty_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_transfers::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            diesel::delete(
+                card_transfers
+                    .filter(card_id.eq(loyalty_id))
+                    .filter(from_user_id.eq(user.0)),
+            )
+            .execute(c)
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "transfer cancelled")),
+    }
+}
+
+/// Pending transfers the caller offered or was offered, newest first.
+#[get("/transfers")]
+async fn list_transfers(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+) -> Result<Json<Vec<TransferResponse>>, APIError> {
+    use db::schema::card_transfers::dsl::*;
+
+    let pending = db
+        .run(move |c| {
+            let found = card_transfers
+                .filter(from_user_id.eq(user.0).or(to_user_id.eq(user.0)))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .order(id.desc())
+                .load::<CardTransfer>(c)?;
+
+            describe(c, found)
+        })
+        .await?;
+
+    Ok(Json(pending))
+}
+
+/// Takes a card offered to the caller. It goes after their other cards.
+#[post("/transfers/<transfer_id>/accept")]
+async fn accept_transfer(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    storage: State<'_, Storage>,
+    quota: State<'_, QuotaConfig>,
+    tiers_config: State<'_, TiersConfig>,
+    transfer_id: String,
+) -> Result<Json<AddLoyaltyResponse>, APIError> {
+    use db::schema::{card_reminders, card_transfers, cards};
+
+    let transfer_id: i32 = transfer_id.parse()?;
+    let storage = storage.inner().clone();
+    let quota = *quota;
+    let tiers_config = *tiers_config;
+    let accepted = db
+        .run(move |c| {
+            c.transaction::<_, APIError, _>(|| {
+                let transfer = card_transfers::table
+                    .filter(card_transfers::id.eq(transfer_id))
+                    .filter(card_transfers::to_user_id.eq(user.0))
+                    .filter(card_transfers::expires_at.gt(Utc::now().naive_utc()))
+                    .first::<CardTransfer>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                // The card may have been trashed since it was offered.
+                let card = cards::table
+                    .filter(cards::id.eq(transfer.card_id))
+                    .filter(cards::user_id.eq(transfer.from_user_id))
+                    .filter(cards::deleted_at.is_null())
+                    .first::<Loyalty>(c)
+                    .optional()?
+                    .ok_or(APIError::NotFound)?;
+                if let Some(existing) = crate::cards::find_duplicate(c, user.0, &card.code.0)? {
+                    return Err(APIError::DuplicateCard(existing));
+                }
+                quota::check(c, quota, user.0, 1)?;
+
+                tags::untag(c, card.id)?;
+                shares::unshare(c, card.id)?;
+                share_links::unlink(c, card.id)?;
+                diesel::delete(
+                    card_reminders::table
+                        .filter(card_reminders::card_id.eq(card.id))
+                        .filter(card_reminders::user_id.ne(user.0)),
+                )
+                .execute(c)?;
+
+                let last_position = cards::table
+                    .filter(cards::user_id.eq(user.0))
+                    .select(diesel::dsl::max(cards::position))
+                    .first::<Option<i32>>(c)?;
+                diesel::update(cards::table.find(card.id))
+                    .set((
+                        cards::user_id.eq(user.0),
+                        cards::group_id.eq(None::<i32>),
+                        cards::position.eq(last_position.map_or(0, |last| last + 1)),
+                    ))
+                    .execute(c)?;
+                diesel::delete(card_transfers::table.find(transfer.id)).execute(c)?;
+                // The points earned on the card count for its new owner.
+                tiers::refresh(c, tiers_config, card.id)?;
+
+                let card = cards::table.find(card.id).first::<Loyalty>(c)?;
+                Ok(crate::cards::describe_one(c, &storage, card)?)
+            })
+        })
+        .await?;
+
+    Ok(Json(accepted))
+}
+
+/// Turns down a card offered to the caller. It stays with its owner.
+#[post("/transfers/<transfer_id>/decline")]
+async fn decline_transfer(
+    db: LoyaltyDbConn,
+    user: LoyaltiesWriter,
+    transfer_id: String,
+) -> Result<status::Custom<&'static str>, APIError> {
+    use db::schema::card_transfers::dsl::*;
+
+    let transfer_id: i32 = transfer_id.parse()?;
+    let deleted = db
+        .run(move |c| {
+            diesel::delete(
+                card_transfers
+                    .filter(id.eq(transfer_id))
+                    .filter(to_user_id.eq(user.0)),
+            )
+            .execute(c)
+        })
+        .await?;
+
+    match deleted {
+        0 => Err(APIError::NotFound),
+        _ => Ok(status::Custom(Status::Ok, "transfer declined")),
+    }
+}
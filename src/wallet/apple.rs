@@ -0,0 +1,278 @@
+//! Apple Wallet store cards, downloaded from `/loyalties/<id>/pkpass`.
+//!
+//! A pass is a zip of `pass.json`, its icons, a `manifest.json` of their
+//! SHA-1 digests and a detached PKCS #7 `signature` of the manifest, made
+//! with a Pass Type ID certificate. Signing needs OpenSSL, which is why the
+//! module is behind the `apple_wallet` feature.
+
+use std::io::{Cursor, Write};
+
+use diesel::prelude::*;
+use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb};
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use rocket::fairing::AdHoc;
+use rocket::http::ContentType;
+use rocket::{get, routes, Route, State};
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::auth::LoyaltiesReader;
+use crate::barcode::BarcodeType;
+use crate::colors;
+use crate::db::{self, models::Loyalty};
+use crate::shares;
+use crate::{APIError, LoyaltyDbConn};
+
+/// Used for cards without a color.
+const DEFAULT_COLOR: (u8, u8, u8) = (0x42, 0x42, 0x42);
+
+pub fn routes() -> Vec<Route> {
+    routes![get_pass]
+}
+
+/// The `wallet.apple` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct AppleWalletConfig {
+    /// Such as `pass.com.example.loyalty`.
+    pub pass_type_id: String,
+    pub team_id: String,
+    /// Shown on the lock screen and in the pass details.
+    pub organization_name: String,
+    /// PEM Pass Type ID certificate and its private key.
+    pub certificate: String,
+    pub key: String,
+    /// PEM Apple Worldwide Developer Relations intermediate certificate.
+    pub wwdr_certificate: String,
+}
+
+struct Signer {
+    config: AppleWalletConfig,
+    certificate: X509,
+    key: PKey<Private>,
+    wwdr: X509,
+}
+
+/// Without a `wallet.apple` section passes can't be signed and the route
+/// answers 404.
+pub struct AppleWallet {
+    signer: Option<Signer>,
+}
+
+impl Signer {
+    fn load(config: AppleWalletConfig) -> Result<Self, String> {
+        let read = |path: &str| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+
+        let certificate = X509::from_pem(&read(&config.certificate)?)
+            .map_err(|e| format!("{}: {}", config.certificate, e))?;
+        let key = PKey::private_key_from_pem(&read(&config.key)?)
+            .map_err(|e| format!("{}: {}", config.key, e))?;
+        let wwdr = X509::from_pem(&read(&config.wwdr_certificate)?)
+            .map_err(|e| format!("{}: {}", config.wwdr_certificate, e))?;
+
+        Ok(Signer {
+            config,
+            certificate,
+            key,
+            wwdr,
+        })
+    }
+
+    fn sign(&self, manifest: &[u8]) -> Result<Vec<u8>, String> {
+        let sign = || {
+            let mut chain = Stack::new()?;
+            chain.push(self.wwdr.clone())?;
+            Pkcs7::sign(
+                &self.certificate,
+                &self.key,
+                &chain,
+                manifest,
+                Pkcs7Flags::BINARY | Pkcs7Flags::DETACHED,
+            )?
+            .to_der()
+        };
+
+        sign().map_err(|e| e.to_string())
+    }
+}
+
+/// The components of a `#RRGGBB` color.
+fn rgb(color: Option<&str>) -> (u8, u8, u8) {
+    color
+        .and_then(colors::normalize)
+        .and_then(|hex| {
+            let channel = |at: usize| u8::from_str_radix(&hex[at..at + 2], 16).ok();
+            Some((channel(1)?, channel(3)?, channel(5)?))
+        })
+        .unwrap_or(DEFAULT_COLOR)
+}
+
+/// Wallet has no EAN-13 or UPC-A symbols: those codes are shown as Code 128,
+/// which scanners at the till read just as well.
+fn symbology(kind: BarcodeType) -> &'static str {
+    match kind {
+        BarcodeType::Qr => "PKBarcodeFormatQR",
+        BarcodeType::Pdf417 => "PKBarcodeFormatPDF417",
+        BarcodeType::Ean13 | BarcodeType::UpcA | BarcodeType::Code128 => "PKBarcodeFormatCode128",
+    }
+}
+
+fn pass_json(config: &AppleWalletConfig, card: &Loyalty) -> serde_json::Value {
+    let (r, g, b) = rgb(card.color.as_deref());
+    // Dark text on light cards, following the relative luminance.
+    let luminance = 0.2126 * f64::from(r) + 0.7152 * f64::from(g) + 0.0722 * f64::from(b);
+    let foreground = if luminance > 140.0 {
+        "rgb(0, 0, 0)"
+    } else {
+        "rgb(255, 255, 255)"
+    };
+    let kind = BarcodeType::from_name(&card.barcode_type).unwrap_or_default();
+    let barcode = json!({
+        "format": symbology(kind),
+        "message": card.code.0,
+        "messageEncoding": "iso-8859-1",
+        "altText": card.code.0,
+    });
+
+    let mut back_fields = Vec::new();
+    if let Some(notes) = &card.notes {
+        back_fields.push(json!({ "key": "notes", "label": "Notes", "value": notes.0 }));
+    }
+
+    let mut pass = json!({
+        "formatVersion": 1,
+        "passTypeIdentifier": config.pass_type_id,
+        "teamIdentifier": config.team_id,
+        "organizationName": config.organization_name,
+        "serialNumber": format!("card-{}", card.id),
+        "description": card.name,
+        "logoText": card.name,
+        "backgroundColor": format!("rgb({}, {}, {})", r, g, b),
+        "foregroundColor": foreground,
+        "labelColor": foreground,
+        "barcode": barcode,
+        "barcodes": [barcode],
+        "storeCard": {
+            "secondaryFields": [
+                { "key": "code", "label": "Card number", "value": card.code.0 },
+            ],
+            "backFields": back_fields,
+        },
+    });
+    if let Some(expires_at) = card.expires_at {
+        pass["expirationDate"] = json!(format!("{}T23:59:59Z", expires_at));
+    }
+
+    pass
+}
+
+/// A square icon in the card's color.
+fn icon(color: (u8, u8, u8), size: u32) -> Result<Vec<u8>, String> {
+    let (r, g, b) = color;
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(ImageBuffer::from_pixel(size, size, Rgb([r, g, b])))
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(png)
+}
+
+fn build(signer: &Signer, card: &Loyalty) -> Result<Vec<u8>, String> {
+    let color = rgb(card.color.as_deref());
+    let files = vec![
+        (
+            "pass.json",
+            serde_json::to_vec(&pass_json(&signer.config, card)).map_err(|e| e.to_string())?,
+        ),
+        ("icon.png", icon(color, 29)?),
+        ("icon@2x.png", icon(color, 58)?),
+        ("icon@3x.png", icon(color, 87)?),
+    ];
+
+    let manifest: serde_json::Map<String, serde_json::Value> = files
+        .iter()
+        .map(|(name, bytes)| (name.to_string(), json!(hex::encode(Sha1::digest(bytes)))))
+        .collect();
+    let manifest = serde_json::to_vec(&manifest).map_err(|e| e.to_string())?;
+    let signature = signer.sign(&manifest)?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let entries = files
+        .iter()
+        .map(|(name, bytes)| (*name, bytes.as_slice()))
+        .chain(vec![
+            ("manifest.json", manifest.as_slice()),
+            ("signature", signature.as_slice()),
+        ]);
+    for (name, bytes) in entries {
+        zip.start_file(name, FileOptions::default())
+            .map_err(|e| e.to_string())?;
+        zip.write_all(bytes).map_err(|e| e.to_string())?;
+    }
+
+    Ok(zip.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+#[get("/loyalties/<loyalty_id>/pkpass")]
+async fn get_pass(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    wallet: State<'_, AppleWallet>,
+    loyalty_id: String,
+) -> Result<(ContentType, Vec<u8>), APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let signer = wallet.signer.as_ref().ok_or(APIError::NotFound)?;
+
+    let card = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null())
+                .filter(is_active.eq(true))
+                .first::<Loyalty>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    let pass = build(signer, &card).map_err(|e| {
+        log::error!("could not build the pass of card {}: {}", card.id, e);
+        APIError::Unknown
+    })?;
+
+    Ok((ContentType::new("application", "vnd.apple.pkpass"), pass))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Apple Wallet", |rocket| async move {
+        let config = match rocket
+            .figment()
+            .extract_inner::<AppleWalletConfig>("wallet.apple")
+        {
+            Ok(config) => config,
+            Err(e) if e.missing() => return Ok(rocket.manage(AppleWallet { signer: None })),
+            Err(e) => {
+                log::error!("invalid wallet.apple configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match Signer::load(config) {
+            Ok(signer) => Ok(rocket.manage(AppleWallet {
+                signer: Some(signer),
+            })),
+            Err(e) => {
+                log::error!("invalid Apple Wallet signing material: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
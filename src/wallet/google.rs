@@ -0,0 +1,254 @@
+//! Google Wallet loyalty cards, saved from the link of
+//! `/loyalties/<id>/gwallet`.
+//!
+//! The card is written as a loyalty object of the class configured under
+//! `wallet.google` with the Wallet API, acting as the issuer's service
+//! account, and the link carries a JWT naming that object, signed with the
+//! same account.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rocket::fairing::AdHoc;
+use rocket::{get, routes, Route, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::LoyaltiesReader;
+use crate::barcode::BarcodeType;
+use crate::colors;
+use crate::db::{self, models::Loyalty};
+use crate::requests::GoogleWalletResponse;
+use crate::shares;
+use crate::{APIError, LoyaltyDbConn};
+
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/wallet_object.issuer";
+const OBJECTS_URL: &str = "https://walletobjects.googleapis.com/walletobjects/v1/loyaltyObject";
+const SAVE_URL: &str = "https://pay.google.com/gp/v/save";
+
+pub fn routes() -> Vec<Route> {
+    routes![get_save_link]
+}
+
+/// The `wallet.google` section of the Rocket configuration.
+#[derive(Deserialize)]
+pub struct GoogleWalletConfig {
+    /// The issuer account of the Google Pay & Wallet Console.
+    pub issuer_id: String,
+    /// Suffix of the loyalty class the cards are objects of, created in the
+    /// console beforehand.
+    pub class_id: String,
+    pub service_account_email: String,
+    /// PEM private key of the service account.
+    pub private_key: String,
+    /// Web origins allowed to show the save button.
+    #[serde(default)]
+    pub origins: Vec<String>,
+}
+
+/// Without a `wallet.google` section the route answers 404.
+pub struct GoogleWallet {
+    config: Option<GoogleWalletConfig>,
+    http: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Serialize)]
+struct SaveClaims<'a> {
+    iss: &'a str,
+    aud: &'a str,
+    typ: &'a str,
+    iat: i64,
+    origins: &'a [String],
+    payload: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+fn barcode_type(kind: BarcodeType) -> &'static str {
+    match kind {
+        BarcodeType::Ean13 => "EAN_13",
+        BarcodeType::UpcA => "UPC_A",
+        BarcodeType::Code128 => "CODE_128",
+        BarcodeType::Qr => "QR_CODE",
+        BarcodeType::Pdf417 => "PDF_417",
+    }
+}
+
+fn loyalty_object(config: &GoogleWalletConfig, card: &Loyalty) -> serde_json::Value {
+    let kind = BarcodeType::from_name(&card.barcode_type).unwrap_or_default();
+    let mut object = json!({
+        "id": format!("{}.card-{}", config.issuer_id, card.id),
+        "classId": format!("{}.{}", config.issuer_id, config.class_id),
+        "state": "ACTIVE",
+        "accountId": card.code.0,
+        "accountName": card.name,
+        "barcode": {
+            "type": barcode_type(kind),
+            "value": card.code.0,
+            "alternateText": card.code.0,
+        },
+    });
+    if let Some(hex) = card.color.as_deref().and_then(colors::normalize) {
+        object["hexBackgroundColor"] = json!(hex);
+    }
+    if let Some(expires_at) = card.expires_at {
+        object["validTimeInterval"] = json!({
+            "end": { "date": format!("{}T23:59:59Z", expires_at) },
+        });
+    }
+
+    object
+}
+
+impl GoogleWallet {
+    /// An access token of the service account for the Wallet API.
+    async fn access_token(&self, config: &GoogleWalletConfig) -> Result<String, APIError> {
+        let now = Utc::now().timestamp();
+        let claims = AssertionClaims {
+            iss: &config.service_account_email,
+            scope: SCOPE,
+            aud: TOKEN_URL,
+            iat: now,
+            exp: now + 60 * 60,
+        };
+        let key = EncodingKey::from_rsa_pem(config.private_key.as_bytes())?;
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)?;
+
+        let response: TokenResponse = self
+            .http
+            .post(TOKEN_URL)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.access_token)
+    }
+
+    /// Creates the loyalty object of `card`, or updates it when the card was
+    /// saved before.
+    async fn upsert(&self, config: &GoogleWalletConfig, card: &Loyalty) -> Result<(), APIError> {
+        let token = self.access_token(config).await?;
+        let object = loyalty_object(config, card);
+
+        let created = self
+            .http
+            .post(OBJECTS_URL)
+            .bearer_auth(&token)
+            .json(&object)
+            .send()
+            .await?;
+        if created.status() != reqwest::StatusCode::CONFLICT {
+            created.error_for_status()?;
+            return Ok(());
+        }
+
+        self.http
+            .put(&format!(
+                "{}/{}",
+                OBJECTS_URL,
+                object["id"].as_str().unwrap_or_default()
+            ))
+            .bearer_auth(&token)
+            .json(&object)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn save_url(config: &GoogleWalletConfig, card: &Loyalty) -> Result<String, APIError> {
+        let claims = SaveClaims {
+            iss: &config.service_account_email,
+            aud: "google",
+            typ: "savetowallet",
+            iat: Utc::now().timestamp(),
+            origins: &config.origins,
+            payload: json!({
+                "loyaltyObjects": [
+                    { "id": format!("{}.card-{}", config.issuer_id, card.id) },
+                ],
+            }),
+        };
+        let key = EncodingKey::from_rsa_pem(config.private_key.as_bytes())?;
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)?;
+
+        Ok(format!("{}/{}", SAVE_URL, jwt))
+    }
+}
+
+#[get("/loyalties/<loyalty_id>/gwallet")]
+async fn get_save_link(
+    db: LoyaltyDbConn,
+    user: LoyaltiesReader,
+    wallet: State<'_, GoogleWallet>,
+    loyalty_id: String,
+) -> Result<Json<GoogleWalletResponse>, APIError> {
+    use db::schema::cards::dsl::*;
+
+    let loyalty_id: i32 = loyalty_id.parse()?;
+    let config = wallet.config.as_ref().ok_or(APIError::NotFound)?;
+
+    let card = db
+        .run(move |c| {
+            let shared = shares::shared_ids(c, user.0, false)?;
+            cards
+                .filter(id.eq(loyalty_id))
+                .filter(user_id.eq(user.0).or(id.eq_any(shared)))
+                .filter(deleted_at.is_null())
+                .filter(is_active.eq(true))
+                .first::<Loyalty>(c)
+                .optional()
+        })
+        .await?
+        .ok_or(APIError::NotFound)?;
+
+    wallet.upsert(config, &card).await.map_err(|e| {
+        log::warn!("could not write card {} to Google Wallet: {}", card.id, e);
+        e
+    })?;
+
+    Ok(Json(GoogleWalletResponse {
+        save_url: GoogleWallet::save_url(config, &card)?,
+    }))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Google Wallet", |rocket| async move {
+        let config = match rocket
+            .figment()
+            .extract_inner::<GoogleWalletConfig>("wallet.google")
+        {
+            Ok(config) => Some(config),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                log::error!("invalid wallet.google configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(GoogleWallet {
+            config,
+            http: reqwest::Client::new(),
+        }))
+    })
+}
@@ -0,0 +1,5 @@
+//! Passes adding cards to phone wallets.
+
+#[cfg(feature = "apple_wallet")]
+pub mod apple;
+pub mod google;